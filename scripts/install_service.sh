@@ -33,8 +33,8 @@ fi
 
 # Ensure application directory exists
 $SUDO mkdir -p /opt/torwell84
-if [ ! -f /opt/torwell84/torwell84 ]; then
-  echo "Warning: /opt/torwell84/torwell84 not found" >&2
+if [ ! -f /opt/torwell84/torwelld ]; then
+  echo "Warning: /opt/torwell84/torwelld not found" >&2
 fi
 
 # Copy service file to systemd directory
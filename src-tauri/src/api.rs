@@ -0,0 +1,1697 @@
+//! Implementation of the frontend commands.
+//!
+//! The Tauri commands in `commands` and the REST API in `http_bridge` both
+//! call into these functions, so they share validation and rate limits.
+
+use crate::bundle::{self, BundleContents, ImportMode, ImportPreview, Secrets};
+use crate::config::EffectiveConfig;
+use crate::core::executor::TaskError;
+use crate::error::{Error, Result};
+use crate::events::{emit_event, EventSink};
+use crate::forward::{ForwardInfo, ForwardSpec};
+use crate::history::{self, SettingChange, Snapshot};
+use crate::icmp;
+use crate::listeners::{ListenerInfo, ListenerSpec};
+use crate::logstore::{self, Cursor, LogPage, LogQuery, LogQueryPage};
+use crate::policy::DestinationPolicyConfig;
+use crate::profiles::{Profile, Profiles};
+use crate::redact::RedactionLevel;
+#[cfg(feature = "gui")]
+use crate::renderer::FrameMetricsSnapshot;
+use crate::routing::ExitRoutingConfig;
+use crate::shaping::{BandwidthLimits, RateLimit};
+use crate::state::{
+    AppState, ConnectionEventSnapshot, ConnectionHealthSummary, LogEntry, MetricPoint,
+};
+use crate::streams::StreamInfo;
+use crate::system_proxy;
+use crate::tor_manager::{BridgePreset, CircuitPolicyReport, RelayInfo, RetryInfo, TorrcProfile};
+use crate::trace::{self, TraceStatus};
+use governor::{
+    clock::DefaultClock,
+    state::{InMemoryState, NotKeyed},
+    Quota, RateLimiter,
+};
+use keyring;
+use log::Level;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use sysinfo::System;
+use tokio::sync::Mutex;
+
+/// Total bytes sent and received through Tor.
+#[derive(Serialize, Clone)]
+pub struct TrafficStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Memory and circuit metrics.
+#[derive(Serialize, Clone)]
+pub struct Metrics {
+    pub memory_bytes: u64,
+    pub circuit_count: usize,
+    pub oldest_circuit_age: u64,
+    pub cpu_percent: f32,
+    pub network_bytes: u64,
+    pub total_network_bytes: u64,
+    pub complete: bool,
+}
+
+/// Aggregated status information used by the frontend dashboard.
+#[derive(Serialize, Clone)]
+pub struct StatusSummary {
+    pub status: String,
+    pub connected_since: Option<String>,
+    pub uptime_seconds: Option<u64>,
+    pub total_traffic_bytes: u64,
+    pub network_bytes_per_sec: u64,
+    pub total_network_bytes: u64,
+    pub latency_ms: u64,
+    pub memory_bytes: u64,
+    pub circuit_count: usize,
+    pub oldest_circuit_age: u64,
+    pub cpu_percent: f32,
+    pub tray_warning: Option<String>,
+    pub retry_count: u32,
+}
+
+const INVOCATION_WINDOW: Duration = Duration::from_secs(60);
+static INVOCATIONS: Lazy<Mutex<HashMap<&'static str, Vec<Instant>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+const LOG_LIMIT: u32 = 20;
+static LOG_LIMITER: Lazy<RateLimiter<NotKeyed, InMemoryState, DefaultClock>> =
+    Lazy::new(|| RateLimiter::direct(Quota::per_minute(NonZeroU32::new(LOG_LIMIT).unwrap())));
+
+const API_LIMIT: u32 = 60;
+static API_LIMITER: Lazy<RateLimiter<NotKeyed, InMemoryState, DefaultClock>> =
+    Lazy::new(|| RateLimiter::direct(Quota::per_minute(NonZeroU32::new(API_LIMIT).unwrap())));
+
+const MAX_PING_COUNT: u8 = 10;
+static HOST_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[A-Za-z0-9.-]+$").unwrap());
+
+/// Apply the global command rate limit.
+pub(crate) fn check_api_rate() -> Result<()> {
+    API_LIMITER
+        .check()
+        .map_err(|_| Error::RateLimitExceeded("api".into()))
+}
+
+async fn track_call(name: &'static str) -> usize {
+    let mut map = INVOCATIONS.lock().await;
+    let now = Instant::now();
+    let entry = map.entry(name).or_insert_with(Vec::new);
+    entry.retain(|t| now.duration_since(*t) <= INVOCATION_WINDOW);
+    entry.push(now);
+    entry.len()
+}
+
+pub async fn request_token(state: &AppState) -> Result<String> {
+    check_api_rate()?;
+    if let Some(tok) = state.session.take_startup_token().await {
+        Ok(tok)
+    } else {
+        Ok(state.create_session().await)
+    }
+}
+
+/// Start connecting in the background, reporting progress to `sink`.
+/// Used by the tray menu and the `connect` command.
+pub async fn perform_connect(sink: Option<Arc<dyn EventSink>>, state: AppState) -> Result<()> {
+    let sink = Some(state.event_sink_for(sink));
+    let tor_manager = state.tor_manager.clone();
+    let state_clone = state.clone();
+
+    // Fire and forget
+    tokio::spawn(async move {
+        if let Err(e) = emit_event(
+            sink.as_ref(),
+            "tor-status-update",
+            serde_json::json!({
+                "status": "CONNECTING",
+                "bootstrapProgress": 0,
+                "bootstrapMessage": "",
+                "retryCount": 0,
+                "retryDelay": 0,
+                "errorStep": null,
+                "errorSource": null,
+                "errorMessage": null
+            }),
+        ) {
+            log::error!("Failed to emit status update: {}", e);
+        }
+
+        state_clone
+            .record_connection_event(
+                "CONNECTING",
+                Some("Attempting Tor connection".into()),
+                None,
+                None,
+            )
+            .await;
+
+        let _ = state_clone.reset_retry_counter().await;
+        let mgr = tor_manager.read().await.clone();
+        match mgr
+            .connect_with_backoff(
+                5,
+                Duration::from_secs(60),
+                |info: RetryInfo| {
+                    let attempt = info.attempt;
+                    let delay = info.delay;
+                    let err = &info.error;
+                    let err_str = err.to_string();
+                    let sc = state_clone.clone();
+                    let err_clone = err_str.clone();
+                    tokio::spawn(async move {
+                        sc.increment_retry_counter().await;
+                        let _ = sc
+                            .add_log(
+                                Level::Warn,
+                                format!("connection attempt {} failed: {}", attempt, err_clone),
+                                None,
+                            )
+                            .await;
+                    });
+                    let (step, source_message) = match err {
+                        Error::ConnectionFailed {
+                            step,
+                            source_message,
+                            ..
+                        } => (step.to_string(), source_message.to_string()),
+                        Error::Identity {
+                            step,
+                            source_message,
+                            ..
+                        } => (step.clone(), source_message.to_string()),
+                        Error::NetworkFailure {
+                            step,
+                            source_message,
+                            ..
+                        } => (step.clone(), source_message.to_string()),
+                        Error::ConfigError {
+                            step,
+                            source_message,
+                            ..
+                        } => (step.clone(), source_message.to_string()),
+                        _ => (String::new(), String::new()),
+                    };
+                    let _ = emit_event(
+                        sink.as_ref(),
+                        "tor-status-update",
+                        serde_json::json!({
+                            "status": "RETRYING",
+                            "retryCount": attempt,
+                            "retryDelay": delay.as_secs(),
+                            "errorMessage": err_str,
+                            "errorStep": step,
+                            "errorSource": source_message
+                        }),
+                    );
+                    let mut detail_text = if !step.is_empty() && !source_message.is_empty() {
+                        format!("{step} ({source_message}) – {err_str}")
+                    } else if !step.is_empty() {
+                        format!("{step} – {err_str}")
+                    } else if !source_message.is_empty() {
+                        format!("{source_message} – {err_str}")
+                    } else {
+                        err_str.clone()
+                    };
+                    let delay_secs = delay.as_secs();
+                    if delay_secs > 0 {
+                        detail_text.push_str(&format!(" – retrying in {delay_secs}s"));
+                    }
+                    let message = format!("Attempt {attempt} failed");
+                    let sc_event = state_clone.clone();
+                    tokio::spawn(async move {
+                        sc_event
+                            .record_connection_event(
+                                "RETRYING",
+                                Some(message),
+                                Some(detail_text),
+                                Some(attempt),
+                            )
+                            .await;
+                    });
+                },
+                |progress, msg| {
+                    let _ = emit_event(
+                        sink.as_ref(),
+                        "tor-status-update",
+                        serde_json::json!({
+                            "status": "CONNECTING",
+                            "bootstrapProgress": progress,
+                            "bootstrapMessage": msg
+                        }),
+                    );
+                },
+            )
+            .await
+        {
+            Ok(_) => {
+                if let Err(e) = emit_event(
+                    sink.as_ref(),
+                    "tor-status-update",
+                    serde_json::json!({
+                        "status": "CONNECTED",
+                        "bootstrapProgress": 100,
+                        "bootstrapMessage": "done",
+                        "retryCount": 0,
+                        "retryDelay": 0,
+                        "errorStep": null,
+                        "errorSource": null,
+                        "errorMessage": null
+                    }),
+                ) {
+                    log::error!("Failed to emit status update: {}", e);
+                }
+                state_clone.mark_connected_now().await;
+                state_clone.mark_settings_good().await;
+                state_clone
+                    .record_connection_event(
+                        "CONNECTED",
+                        Some("Tor connection established".into()),
+                        None,
+                        None,
+                    )
+                    .await;
+
+                if let Some(port) = mgr.get_socks_port().await {
+                    let proxy_url = format!("socks5h://127.0.0.1:{}", port);
+                    if let Err(e) = state_clone.http_client.set_proxy(Some(proxy_url)).await {
+                        log::error!("Failed to set proxy: {}", e);
+                    } else {
+                        log::info!("Configured http client with proxy at 127.0.0.1:{}", port);
+                    }
+                }
+
+                state_clone.update_tray_menu().await;
+
+                // Enable System Proxy if enabled
+                if state_clone.is_system_proxy_enabled().await {
+                    let port = mgr.get_socks_port().await.unwrap_or(9150);
+                    let res = tokio::task::spawn_blocking(move || {
+                        system_proxy::enable_global_proxy(port)
+                    })
+                    .await;
+
+                    match res {
+                        Ok(Ok(_)) => {
+                            let _ = state_clone
+                                .add_log(
+                                    Level::Info,
+                                    format!("System proxy enabled on port {}", port),
+                                    None,
+                                )
+                                .await;
+                            let _ = emit_event(
+                                sink.as_ref(),
+                                "system-proxy-update",
+                                serde_json::json!({ "enabled": true }),
+                            );
+                        }
+                        Ok(Err(e)) => {
+                            let _ = state_clone
+                                .add_log(
+                                    Level::Warn,
+                                    format!("Failed to set system proxy: {}", e),
+                                    None,
+                                )
+                                .await;
+                        }
+                        Err(join_err) => {
+                            log::error!("Failed to spawn proxy task: {}", join_err);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                let (step, source_message) = match &e {
+                    Error::ConnectionFailed {
+                        step,
+                        source_message,
+                        ..
+                    } => (step.to_string(), source_message.to_string()),
+                    Error::Identity {
+                        step,
+                        source_message,
+                        ..
+                    } => (step.clone(), source_message.to_string()),
+                    Error::NetworkFailure {
+                        step,
+                        source_message,
+                        ..
+                    } => (step.clone(), source_message.to_string()),
+                    Error::ConfigError {
+                        step,
+                        source_message,
+                        ..
+                    } => (step.clone(), source_message.to_string()),
+                    _ => (String::new(), String::new()),
+                };
+                if let Err(e_emit) = emit_event(
+                    sink.as_ref(),
+                    "tor-status-update",
+                    serde_json::json!({
+                        "status": "ERROR",
+                        "errorMessage": e.to_string(),
+                        "errorStep": step,
+                        "errorSource": source_message,
+                        "bootstrapMessage": "",
+                        "retryCount": 0, "retryDelay": 0
+                    }),
+                ) {
+                    log::error!("Failed to emit error status update: {}", e_emit);
+                }
+                state_clone.mark_disconnected().await;
+                state_clone.update_tray_menu().await;
+                let detail_text = if step.is_empty() && source_message.is_empty() {
+                    e.to_string()
+                } else if source_message.is_empty() {
+                    format!("{} – {}", step, e)
+                } else {
+                    format!("{} ({}) – {}", step, source_message, e)
+                };
+                state_clone
+                    .record_connection_event(
+                        "ERROR",
+                        Some("Tor connection failed".into()),
+                        Some(detail_text),
+                        None,
+                    )
+                    .await;
+
+                // Settings changed since the last successful connect
+                if let Some(snapshot) = state_clone.history.rollback_candidate() {
+                    let message = format!(
+                        "Connecting failed after a settings change. The settings of snapshot {} ({}) connected last and can be restored.",
+                        snapshot.id, snapshot.command
+                    );
+                    let _ = state_clone
+                        .add_log(Level::Warn, message.clone(), None)
+                        .await;
+                    let _ = emit_event(
+                        sink.as_ref(),
+                        "config-rollback-available",
+                        serde_json::json!({
+                            "snapshotId": snapshot.id,
+                            "command": snapshot.command,
+                            "timestamp": snapshot.timestamp.to_rfc3339(),
+                        }),
+                    );
+                    if let Some(sink) = sink.as_ref() {
+                        sink.notify("Torwell84", &message);
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+pub async fn connect(sink: Option<Arc<dyn EventSink>>, state: &AppState) -> Result<()> {
+    track_call("connect").await;
+    check_api_rate()?;
+    perform_connect(sink, state.clone()).await
+}
+
+pub async fn perform_disconnect(sink: Option<Arc<dyn EventSink>>, state: AppState) -> Result<()> {
+    let sink = Some(state.event_sink_for(sink));
+    if let Err(e) = emit_event(
+        sink.as_ref(),
+        "tor-status-update",
+        serde_json::json!({
+            "status": "DISCONNECTING",
+            "bootstrapMessage": "",
+            "retryCount": 0,
+            "retryDelay": 0,
+            "errorStep": null,
+            "errorSource": null,
+            "errorMessage": null
+        }),
+    ) {
+        log::error!("Failed to emit status update: {}", e);
+    }
+
+    state
+        .record_connection_event(
+            "DISCONNECTING",
+            Some("Disconnect requested".into()),
+            None,
+            None,
+        )
+        .await;
+
+    // Disable System Proxy
+    let _ = tokio::task::spawn_blocking(|| {
+        if let Err(e) = system_proxy::disable_global_proxy() {
+            log::error!("Failed to disable system proxy: {}", e);
+        }
+    })
+    .await;
+    let _ = emit_event(
+        sink.as_ref(),
+        "system-proxy-update",
+        serde_json::json!({ "enabled": false }),
+    );
+
+    {
+        let mgr = state.tor_manager.read().await.clone();
+        mgr.disconnect().await?;
+    }
+
+    if let Err(e) = state.http_client.set_proxy(None).await {
+        log::error!("Failed to clear proxy: {}", e);
+    }
+
+    state.mark_disconnected().await;
+
+    if let Err(e) = emit_event(
+        sink.as_ref(),
+        "tor-status-update",
+        serde_json::json!({
+            "status": "DISCONNECTED",
+            "bootstrapProgress": 0,
+            "bootstrapMessage": "",
+            "retryCount": 0,
+            "retryDelay": 0,
+            "errorStep": null,
+            "errorSource": null,
+            "errorMessage": null
+        }),
+    ) {
+        log::error!("Failed to emit status update: {}", e);
+    }
+
+    state
+        .record_connection_event(
+            "DISCONNECTED",
+            Some("Tor connection closed".into()),
+            None,
+            None,
+        )
+        .await;
+
+    state.update_tray_menu().await;
+
+    Ok(())
+}
+
+pub async fn disconnect(sink: Option<Arc<dyn EventSink>>, state: &AppState) -> Result<()> {
+    track_call("disconnect").await;
+    check_api_rate()?;
+    perform_disconnect(sink, state.clone()).await
+}
+
+pub async fn get_status(state: &AppState) -> Result<String> {
+    track_call("get_status").await;
+    check_api_rate()?;
+    if {
+        let mgr = state.tor_manager.read().await.clone();
+        mgr.is_connected().await
+    } {
+        Ok("CONNECTED".to_string())
+    } else {
+        Ok("DISCONNECTED".to_string())
+    }
+}
+
+pub async fn get_status_summary(state: &AppState) -> Result<StatusSummary> {
+    track_call("get_status_summary").await;
+    check_api_rate()?;
+
+    let mgr = state.tor_manager.read().await.clone();
+    let is_connected = mgr.is_connected().await;
+    let total_traffic_bytes = if is_connected {
+        mgr.traffic_stats()
+            .await
+            .map(|stats| stats.bytes_sent + stats.bytes_received)
+            .unwrap_or(0)
+    } else {
+        0
+    };
+
+    let (
+        memory_bytes,
+        circuit_count,
+        oldest_circuit_age,
+        cpu_percent,
+        network_bytes_per_sec,
+        total_network_bytes,
+    ) = state.metrics().await;
+    let latency_ms = state.latency().await;
+    let connected_since = state.connected_since().await.map(|ts| ts.to_rfc3339());
+    let uptime_seconds = state.connection_uptime().await;
+    let tray_warning = state.tray_warning_message().await;
+    let retry_count = state.retry_counter_value().await;
+
+    Ok(StatusSummary {
+        status: if is_connected {
+            "CONNECTED".into()
+        } else {
+            "DISCONNECTED".into()
+        },
+        connected_since,
+        uptime_seconds,
+        total_traffic_bytes,
+        network_bytes_per_sec,
+        total_network_bytes,
+        latency_ms,
+        memory_bytes,
+        circuit_count,
+        oldest_circuit_age,
+        cpu_percent,
+        tray_warning,
+        retry_count,
+    })
+}
+
+pub async fn get_connection_timeline(
+    state: &AppState,
+    limit: Option<usize>,
+) -> Result<Vec<ConnectionEventSnapshot>> {
+    track_call("get_connection_timeline").await;
+    check_api_rate()?;
+    Ok(state.connection_events_snapshot(limit).await)
+}
+
+pub async fn get_connection_health_summary(state: &AppState) -> Result<ConnectionHealthSummary> {
+    track_call("get_connection_health_summary").await;
+    check_api_rate()?;
+    Ok(state.connection_health_summary().await)
+}
+
+pub async fn get_active_circuit(state: &AppState) -> Result<Vec<RelayInfo>> {
+    track_call("get_active_circuit").await;
+    check_api_rate()?;
+    {
+        let mgr = state.tor_manager.read().await.clone();
+        mgr.get_active_circuit().await
+    }
+}
+
+pub async fn get_circuit_policy_report(state: &AppState) -> Result<CircuitPolicyReport> {
+    track_call("get_circuit_policy_report").await;
+    check_api_rate()?;
+    {
+        let mgr = state.tor_manager.read().await.clone();
+        mgr.circuit_policy_report().await
+    }
+}
+
+pub async fn get_isolated_circuit(state: &AppState, domain: String) -> Result<Vec<RelayInfo>> {
+    track_call("get_isolated_circuit").await;
+    check_api_rate()?;
+    {
+        let mgr = state.tor_manager.read().await.clone();
+        mgr.get_isolated_circuit(domain).await
+    }
+}
+
+pub async fn set_exit_country(state: &AppState, country: Option<String>) -> Result<()> {
+    track_call("set_exit_country").await;
+    check_api_rate()?;
+    state.set_exit_country(country).await?;
+    state.record_snapshot("set_exit_country").await;
+    Ok(())
+}
+
+pub async fn set_entry_country(state: &AppState, country: Option<String>) -> Result<()> {
+    track_call("set_entry_country").await;
+    check_api_rate()?;
+    state.set_entry_country(country).await?;
+    state.record_snapshot("set_entry_country").await;
+    Ok(())
+}
+
+pub async fn set_middle_country(state: &AppState, country: Option<String>) -> Result<()> {
+    track_call("set_middle_country").await;
+    check_api_rate()?;
+    state.set_middle_country(country).await?;
+    state.record_snapshot("set_middle_country").await;
+    Ok(())
+}
+
+pub async fn set_bridges(state: &AppState, bridges: Vec<String>) -> Result<()> {
+    track_call("set_bridges").await;
+    check_api_rate()?;
+    state.set_bridges(bridges).await?;
+    state.record_snapshot("set_bridges").await;
+    Ok(())
+}
+
+pub async fn set_torrc_config(state: &AppState, config: String) -> Result<()> {
+    check_api_rate()?;
+    state.set_torrc_config(config).await?;
+    state.record_snapshot("set_torrc_config").await;
+    Ok(())
+}
+
+pub async fn get_destination_policy(state: &AppState) -> Result<DestinationPolicyConfig> {
+    track_call("get_destination_policy").await;
+    check_api_rate()?;
+    let mgr = state.tor_manager.read().await.clone();
+    Ok(mgr.destination_policy().config())
+}
+
+pub async fn set_destination_policy(
+    state: &AppState,
+    policy: DestinationPolicyConfig,
+) -> Result<()> {
+    track_call("set_destination_policy").await;
+    check_api_rate()?;
+    let mgr = state.tor_manager.read().await.clone();
+    mgr.destination_policy().set_config(policy)
+}
+
+pub async fn get_exit_rules(state: &AppState) -> Result<ExitRoutingConfig> {
+    track_call("get_exit_rules").await;
+    check_api_rate()?;
+    let mgr = state.tor_manager.read().await.clone();
+    Ok(mgr.exit_routing().config())
+}
+
+pub async fn set_exit_rules(state: &AppState, rules: ExitRoutingConfig) -> Result<()> {
+    track_call("set_exit_rules").await;
+    check_api_rate()?;
+    state.set_exit_rules(rules).await?;
+    state.record_snapshot("set_exit_rules").await;
+    Ok(())
+}
+
+pub async fn list_profiles(state: &AppState) -> Result<Profiles> {
+    track_call("list_profiles").await;
+    check_api_rate()?;
+    Ok(state.profiles.list())
+}
+
+pub async fn save_profile(state: &AppState, profile: Profile) -> Result<()> {
+    track_call("save_profile").await;
+    check_api_rate()?;
+    state.profiles.save(profile)?;
+    state.update_tray_menu().await;
+    Ok(())
+}
+
+/// Store the current settings as profile `name`.
+pub async fn save_current_profile(state: &AppState, name: String) -> Result<Profile> {
+    track_call("save_current_profile").await;
+    check_api_rate()?;
+    let profile = state.capture_profile(&name).await;
+    state.profiles.save(profile.clone())?;
+    state.update_tray_menu().await;
+    Ok(profile)
+}
+
+pub async fn duplicate_profile(state: &AppState, from: String, to: String) -> Result<Profile> {
+    track_call("duplicate_profile").await;
+    check_api_rate()?;
+    let profile = state.profiles.duplicate(&from, &to)?;
+    state.update_tray_menu().await;
+    Ok(profile)
+}
+
+pub async fn delete_profile(state: &AppState, name: String) -> Result<bool> {
+    track_call("delete_profile").await;
+    check_api_rate()?;
+    let removed = state.profiles.delete(&name)?;
+    state.update_tray_menu().await;
+    Ok(removed)
+}
+
+pub async fn export_profile(state: &AppState, name: String) -> Result<String> {
+    track_call("export_profile").await;
+    check_api_rate()?;
+    state.profiles.export(&name)
+}
+
+pub async fn import_profile(state: &AppState, json: String) -> Result<Profile> {
+    track_call("import_profile").await;
+    check_api_rate()?;
+    let profile = state.profiles.import(&json)?;
+    state.update_tray_menu().await;
+    Ok(profile)
+}
+
+/// Switch to profile `name`. If the profile changes settings the running
+/// client cannot pick up, the client is reconnected; otherwise only the
+/// system proxy is updated when its setting changed.
+pub async fn apply_profile(
+    sink: Option<Arc<dyn EventSink>>,
+    state: &AppState,
+    name: String,
+) -> Result<()> {
+    track_call("apply_profile").await;
+    check_api_rate()?;
+    let connected = is_connected(state).await;
+    let proxy_before = state.is_system_proxy_enabled().await;
+    let needs_reconnect = state.apply_profile(&name).await?;
+    state.record_snapshot("apply_profile").await;
+    finish_settings_switch(sink, state, connected, proxy_before, needs_reconnect).await
+}
+
+/// Whether the current Tor manager is connected.
+async fn is_connected(state: &AppState) -> bool {
+    let mgr = state.tor_manager.read().await.clone();
+    mgr.is_connected().await
+}
+
+/// Reconnect after applied settings need it, or apply a changed system
+/// proxy choice to the running connection. `connected` tells whether Tor was
+/// connected before the settings were applied, which may have replaced the
+/// manager with a disconnected one.
+async fn finish_settings_switch(
+    sink: Option<Arc<dyn EventSink>>,
+    state: &AppState,
+    connected: bool,
+    proxy_before: bool,
+    needs_reconnect: bool,
+) -> Result<()> {
+    if connected && needs_reconnect {
+        perform_reconnect(sink, state.clone()).await?;
+        state.update_tray_menu().await;
+    } else {
+        let proxy_after = state.is_system_proxy_enabled().await;
+        if proxy_after != proxy_before {
+            toggle_system_proxy(sink, state, proxy_after).await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn list_config_history(state: &AppState) -> Result<Vec<Snapshot>> {
+    track_call("list_config_history").await;
+    check_api_rate()?;
+    Ok(state.history.list())
+}
+
+/// Changes from snapshot `from` to snapshot `to`, or to the current
+/// settings if `to` is `None`.
+pub async fn diff_config_snapshots(
+    state: &AppState,
+    from: u64,
+    to: Option<u64>,
+) -> Result<Vec<SettingChange>> {
+    track_call("diff_config_snapshots").await;
+    check_api_rate()?;
+    let old = state.history.get(from)?.settings;
+    let new = match to {
+        Some(id) => state.history.get(id)?.settings,
+        None => state.current_settings().await,
+    };
+    Ok(history::diff(&old, &new))
+}
+
+/// Restore the settings of snapshot `id`, reconnecting if needed.
+pub async fn restore_config_snapshot(
+    sink: Option<Arc<dyn EventSink>>,
+    state: &AppState,
+    id: u64,
+) -> Result<()> {
+    track_call("restore_config_snapshot").await;
+    check_api_rate()?;
+    let connected = is_connected(state).await;
+    let proxy_before = state.is_system_proxy_enabled().await;
+    let needs_reconnect = state.restore_snapshot(id).await?;
+    finish_settings_switch(sink, state, connected, proxy_before, needs_reconnect).await
+}
+
+/// Restore the settings of the last successful connection and connect
+/// with them. Offered after a connect fails following a settings change.
+pub async fn rollback_config(
+    sink: Option<Arc<dyn EventSink>>,
+    state: &AppState,
+) -> Result<Snapshot> {
+    track_call("rollback_config").await;
+    check_api_rate()?;
+    let snapshot = state
+        .history
+        .last_good()
+        .ok_or_else(|| Error::InvalidConfig {
+            key: "snapshot".into(),
+            message: "no settings have connected successfully yet".into(),
+        })?;
+    state.restore_snapshot(snapshot.id).await?;
+    perform_reconnect(sink, state.clone()).await?;
+    Ok(snapshot)
+}
+
+pub async fn toggle_system_proxy(
+    sink: Option<Arc<dyn EventSink>>,
+    state: &AppState,
+    enabled: bool,
+) -> Result<()> {
+    check_api_rate()?;
+    state.set_system_proxy_enabled(enabled).await?;
+    state.record_snapshot("toggle_system_proxy").await;
+    let sink = Some(state.event_sink_for(sink));
+    let state_clone = state.clone();
+
+    // If connected, apply immediately
+    let mgr = state.tor_manager.read().await.clone();
+    if mgr.is_connected().await {
+        let port = mgr.get_socks_port().await.unwrap_or(9150);
+        let _ = tokio::task::spawn_blocking(move || {
+            if enabled {
+                if let Err(e) = system_proxy::enable_global_proxy(port) {
+                    log::error!("Failed to enable system proxy: {}", e);
+                } else {
+                    let _ = emit_event(
+                        sink.as_ref(),
+                        "system-proxy-update",
+                        serde_json::json!({ "enabled": true }),
+                    );
+                    let _ = state_clone.add_log(
+                        Level::Info,
+                        "System proxy enabled via toggle".into(),
+                        None,
+                    );
+                }
+            } else {
+                if let Err(e) = system_proxy::disable_global_proxy() {
+                    log::error!("Failed to disable system proxy: {}", e);
+                } else {
+                    let _ = emit_event(
+                        sink.as_ref(),
+                        "system-proxy-update",
+                        serde_json::json!({ "enabled": false }),
+                    );
+                    let _ = state_clone.add_log(
+                        Level::Info,
+                        "System proxy disabled via toggle".into(),
+                        None,
+                    );
+                }
+            }
+        }); // Note: spawn_blocking is async, we don't await here to return quickly, but we should probably await if we want to report error?
+            // For toggle, "fire and forget" but log error is acceptable UX usually, but let's stick to pattern.
+    } else {
+        // If not connected, just emit the preference update so UI stays in sync
+        let _ = emit_event(
+            sink.as_ref(),
+            "system-proxy-update",
+            serde_json::json!({ "enabled": enabled }),
+        );
+    }
+    Ok(())
+}
+
+pub async fn get_system_proxy_status(state: &AppState) -> Result<bool> {
+    check_api_rate()?;
+    Ok(state.is_system_proxy_enabled().await)
+}
+
+pub async fn generate_torrc_profile(
+    state: &AppState,
+    fast_only: bool,
+    preferred_fast_countries: Option<Vec<String>>,
+    include_bridges: bool,
+) -> Result<TorrcProfile> {
+    track_call("generate_torrc_profile").await;
+    check_api_rate()?;
+    let mgr = state.tor_manager.read().await.clone();
+    mgr.generate_torrc_profile(fast_only, preferred_fast_countries, include_bridges)
+        .await
+}
+
+pub async fn set_worker_config(
+    state: &AppState,
+    workers: Vec<String>,
+    token: Option<String>,
+) -> Result<()> {
+    check_api_rate()?;
+    state.http_client.set_worker_config(workers, token).await;
+    Ok(())
+}
+
+pub async fn validate_worker_token(state: &AppState) -> Result<bool> {
+    check_api_rate()?;
+    match state.http_client.get_text("https://example.com").await {
+        Ok(_) => Ok(true),
+        Err(_) => Ok(false),
+    }
+}
+
+pub async fn set_hsm_config(
+    state: &AppState,
+    lib: Option<String>,
+    slot: Option<u64>,
+) -> Result<()> {
+    check_api_rate()?;
+    state.set_hsm_config(lib, slot).await
+}
+
+pub async fn set_update_interval(state: &AppState, interval: u64) -> Result<()> {
+    check_api_rate()?;
+    state.set_update_interval(interval).await
+}
+
+pub async fn set_geoip_path(state: &AppState, path: Option<String>) -> Result<()> {
+    check_api_rate()?;
+    state.set_geoip_path(path).await
+}
+
+pub async fn set_insecure_hosts(state: &AppState, hosts: Vec<String>) -> Result<()> {
+    check_api_rate()?;
+    state.set_insecure_hosts(hosts).await
+}
+
+/// Effective configuration with the layer each value came from.
+pub async fn get_effective_config(state: &AppState) -> Result<EffectiveConfig> {
+    track_call("get_effective_config").await;
+    check_api_rate()?;
+    Ok(state.config.effective())
+}
+
+pub async fn list_bridge_presets() -> Result<Vec<BridgePreset>> {
+    crate::tor_manager::load_default_bridge_presets()
+}
+
+pub async fn get_traffic_stats(state: &AppState) -> Result<TrafficStats> {
+    track_call("get_traffic_stats").await;
+    check_api_rate()?;
+    let stats = {
+        let mgr = state.tor_manager.read().await.clone();
+        mgr.traffic_stats().await?
+    };
+    Ok(TrafficStats {
+        bytes_sent: stats.bytes_sent,
+        bytes_received: stats.bytes_received,
+    })
+}
+
+pub async fn get_metrics(state: &AppState) -> Result<Metrics> {
+    track_call("get_metrics").await;
+    check_api_rate()?;
+    let circ = {
+        let mgr = state.tor_manager.read().await.clone();
+        mgr.circuit_metrics().await?
+    }; // capture more metrics like build time when available
+    let mut sys = sysinfo::System::new();
+    let pid = sysinfo::get_current_pid().map_err(|e| Error::Io(e.to_string()))?;
+    sys.refresh_process(pid);
+    let mut networks = sysinfo::Networks::new_with_refreshed_list();
+    let mem = sys.process(pid).map(|p| p.memory()).unwrap_or(0);
+    let cpu = sys.process(pid).map(|p| p.cpu_usage()).unwrap_or(0.0);
+    state
+        .update_metrics(mem, circ.count, circ.oldest_age, cpu, 0, 30)
+        .await;
+    let net = *state.network_throughput.lock().await;
+
+    if mem / 1024 / 1024 > state.max_memory_mb {
+        let _ = state
+            .add_log(
+                Level::Warn,
+                format!(
+                    "memory usage {} MB exceeds limit {}",
+                    mem / 1024 / 1024,
+                    state.max_memory_mb
+                ),
+                None,
+            )
+            .await;
+    }
+
+    if circ.count > state.max_circuits {
+        let _ = state
+            .add_log(
+                Level::Warn,
+                format!(
+                    "circuit count {} exceeds limit {}",
+                    circ.count, state.max_circuits
+                ),
+                None,
+            )
+            .await;
+    }
+
+    Ok(Metrics {
+        memory_bytes: mem,
+        circuit_count: circ.count,
+        oldest_circuit_age: circ.oldest_age,
+        cpu_percent: cpu,
+        network_bytes: net,
+        total_network_bytes: *state.network_total.lock().await,
+        complete: circ.complete,
+    })
+}
+
+pub async fn new_identity(sink: Option<Arc<dyn EventSink>>, state: &AppState) -> Result<()> {
+    let sink = Some(state.event_sink_for(sink));
+    track_call("new_identity").await;
+    check_api_rate()?;
+    let result = {
+        let mgr = state.tor_manager.read().await.clone();
+        mgr.new_identity().await
+    };
+
+    match result {
+        Ok(_) => {
+            emit_event(
+                sink.as_ref(),
+                "tor-status-update",
+                serde_json::json!({
+                    "status": "NEW_IDENTITY",
+                    "errorStep": null,
+                    "errorSource": null,
+                    "errorMessage": null
+                }),
+            )?;
+            state
+                .record_connection_event(
+                    "NEW_IDENTITY",
+                    Some("New Tor identity requested".into()),
+                    None,
+                    None,
+                )
+                .await;
+            Ok(())
+        }
+        Err(e) => {
+            let (step, source_message) = match &e {
+                Error::Identity {
+                    step,
+                    source_message,
+                    ..
+                } => (step.clone(), source_message.to_string()),
+                Error::ConnectionFailed {
+                    step,
+                    source_message,
+                    ..
+                } => (step.to_string(), source_message.to_string()),
+                Error::NetworkFailure {
+                    step,
+                    source_message,
+                    ..
+                } => (step.clone(), source_message.to_string()),
+                Error::ConfigError {
+                    step,
+                    source_message,
+                    ..
+                } => (step.clone(), source_message.to_string()),
+                _ => (String::new(), String::new()),
+            };
+            if let Err(em) = emit_event(
+                sink.as_ref(),
+                "tor-status-update",
+                serde_json::json!({
+                    "status": "ERROR",
+                    "errorMessage": e.to_string(),
+                    "errorStep": step,
+                    "errorSource": source_message
+                }),
+            ) {
+                log::error!("Failed to emit error status update: {}", em);
+            }
+            Err(e)
+        }
+    }
+}
+
+pub async fn build_circuit(sink: Option<Arc<dyn EventSink>>, state: &AppState) -> Result<()> {
+    let sink = Some(state.event_sink_for(sink));
+    track_call("build_circuit").await;
+    check_api_rate()?;
+    let result = {
+        let mgr = state.tor_manager.read().await.clone();
+        mgr.build_circuit().await
+    };
+
+    match result {
+        Ok(_) => {
+            emit_event(
+                sink.as_ref(),
+                "tor-status-update",
+                serde_json::json!({
+                    "status": "NEW_CIRCUIT",
+                    "errorStep": null,
+                    "errorSource": null,
+                    "errorMessage": null
+                }),
+            )?;
+            state
+                .record_connection_event(
+                    "NEW_CIRCUIT",
+                    Some("Manual circuit build triggered".into()),
+                    None,
+                    None,
+                )
+                .await;
+            Ok(())
+        }
+        Err(e) => {
+            let (step, source_message) = match &e {
+                Error::Identity {
+                    step,
+                    source_message,
+                    ..
+                } => (step.clone(), source_message.to_string()),
+                Error::ConnectionFailed {
+                    step,
+                    source_message,
+                    ..
+                } => (step.to_string(), source_message.to_string()),
+                Error::NetworkFailure {
+                    step,
+                    source_message,
+                    ..
+                } => (step.clone(), source_message.to_string()),
+                Error::ConfigError {
+                    step,
+                    source_message,
+                    ..
+                } => (step.clone(), source_message.to_string()),
+                _ => (String::new(), String::new()),
+            };
+            if let Err(em) = emit_event(
+                sink.as_ref(),
+                "tor-status-update",
+                serde_json::json!({
+                    "status": "ERROR",
+                    "errorMessage": e.to_string(),
+                    "errorStep": step,
+                    "errorSource": source_message
+                }),
+            ) {
+                log::error!("Failed to emit error status update: {}", em);
+            }
+            Err(e)
+        }
+    }
+}
+
+pub async fn list_circuits(state: &AppState) -> Result<Vec<u64>> {
+    track_call("list_circuits").await;
+    check_api_rate()?;
+    {
+        let mgr = state.tor_manager.read().await.clone();
+        mgr.list_circuit_ids().await
+    }
+}
+
+pub async fn close_circuit(state: &AppState, id: u64) -> Result<()> {
+    track_call("close_circuit").await;
+    check_api_rate()?;
+    {
+        let mgr = state.tor_manager.read().await.clone();
+        mgr.close_circuit(id).await
+    }
+}
+pub async fn list_streams(state: &AppState) -> Result<Vec<StreamInfo>> {
+    track_call("list_streams").await;
+    check_api_rate()?;
+    let mgr = state.tor_manager.read().await.clone();
+    Ok(mgr.streams().snapshot())
+}
+
+pub async fn close_stream(state: &AppState, id: u64) -> Result<bool> {
+    track_call("close_stream").await;
+    check_api_rate()?;
+    let mgr = state.tor_manager.read().await.clone();
+    Ok(mgr.streams().close(id))
+}
+
+pub async fn close_streams_to(state: &AppState, host: String, port: Option<u16>) -> Result<usize> {
+    track_call("close_streams_to").await;
+    check_api_rate()?;
+    let mgr = state.tor_manager.read().await.clone();
+    Ok(mgr.streams().close_destination(&host, port))
+}
+
+pub async fn get_bandwidth_limits(state: &AppState) -> Result<BandwidthLimits> {
+    track_call("get_bandwidth_limits").await;
+    check_api_rate()?;
+    let mgr = state.tor_manager.read().await.clone();
+    Ok(mgr.bandwidth_shaper().limits())
+}
+
+pub async fn set_bandwidth_limits(state: &AppState, limits: BandwidthLimits) -> Result<()> {
+    track_call("set_bandwidth_limits").await;
+    check_api_rate()?;
+    let mgr = state.tor_manager.read().await.clone();
+    mgr.bandwidth_shaper().set_limits(limits)
+}
+
+pub async fn set_stream_bandwidth_limit(state: &AppState, id: u64, limit: RateLimit) -> Result<()> {
+    track_call("set_stream_bandwidth_limit").await;
+    check_api_rate()?;
+    let mgr = state.tor_manager.read().await.clone();
+    mgr.bandwidth_shaper().set_stream_limit(&mgr.streams(), id, limit)
+}
+
+pub async fn get_logs(state: &AppState, token: String) -> Result<Vec<LogEntry>> {
+    track_call("get_logs").await;
+    check_api_rate()?;
+    if !state.validate_session(&token).await {
+        log::error!("get_logs: invalid token");
+        return Err(Error::InvalidToken);
+    }
+    if LOG_LIMITER.check().is_err() {
+        log::error!("get_logs: rate limit exceeded");
+        return Err(Error::RateLimitExceeded("get_logs".into()));
+    }
+    state.read_logs().await
+}
+
+/// A page of log entries across rotated segments, newest page first.
+/// Pass the returned `next_cursor` to continue with older entries.
+pub async fn get_logs_page(
+    state: &AppState,
+    token: String,
+    cursor: Option<String>,
+    limit: Option<usize>,
+) -> Result<LogPage> {
+    track_call("get_logs_page").await;
+    check_api_rate()?;
+    if !state.validate_session(&token).await {
+        log::error!("get_logs_page: invalid token");
+        return Err(Error::InvalidToken);
+    }
+    if LOG_LIMITER.check().is_err() {
+        log::error!("get_logs_page: rate limit exceeded");
+        return Err(Error::RateLimitExceeded("get_logs_page".into()));
+    }
+    let cursor = cursor.as_deref().map(Cursor::parse).transpose()?;
+    let limit = limit
+        .unwrap_or(logstore::DEFAULT_PAGE_SIZE)
+        .clamp(1, logstore::MAX_PAGE_SIZE);
+    state.read_logs_page(cursor, limit).await
+}
+
+/// Log entries matching the filters in `query` across all segments, with
+/// per-level totals.
+pub async fn query_logs(state: &AppState, token: String, query: LogQuery) -> Result<LogQueryPage> {
+    track_call("query_logs").await;
+    check_api_rate()?;
+    if !state.validate_session(&token).await {
+        log::error!("query_logs: invalid token");
+        return Err(Error::InvalidToken);
+    }
+    if LOG_LIMITER.check().is_err() {
+        log::error!("query_logs: rate limit exceeded");
+        return Err(Error::RateLimitExceeded("query_logs".into()));
+    }
+    state.query_logs(query).await
+}
+
+pub async fn clear_logs(state: &AppState, token: String) -> Result<()> {
+    track_call("clear_logs").await;
+    check_api_rate()?;
+    if !state.validate_session(&token).await {
+        log::error!("clear_logs: invalid token");
+        return Err(Error::InvalidToken);
+    }
+    state.clear_log_file().await
+}
+
+pub async fn get_log_file_path(state: &AppState, token: String) -> Result<String> {
+    track_call("get_log_file_path").await;
+    check_api_rate()?;
+    if !state.validate_session(&token).await {
+        log::error!("get_log_file_path: invalid token");
+        return Err(Error::InvalidToken);
+    }
+    Ok(state.log_file_path())
+}
+
+pub async fn set_log_limit(state: &AppState, limit: usize) -> Result<()> {
+    check_api_rate()?;
+    state.set_max_log_lines(limit).await
+}
+
+/// Set how addresses are redacted in logs, connection events and exports.
+pub async fn set_log_redaction(state: &AppState, level: RedactionLevel) -> Result<()> {
+    track_call("set_log_redaction").await;
+    check_api_rate()?;
+    state.set_redaction_level(level)
+}
+
+/// Target filters of the `tracing` capture and the number of dropped events.
+pub async fn get_trace_filters() -> Result<TraceStatus> {
+    track_call("get_trace_filters").await;
+    check_api_rate()?;
+    Ok(trace::status())
+}
+
+/// Capture `tracing` events of targets starting with `target` up to
+/// `level`, or remove the filter when `level` is `None`.
+pub async fn set_trace_filter(
+    state: &AppState,
+    target: String,
+    level: Option<String>,
+) -> Result<TraceStatus> {
+    track_call("set_trace_filter").await;
+    check_api_rate()?;
+    state.set_trace_filter(target, level)
+}
+
+pub async fn load_metrics(
+    state: &AppState,
+    token: String,
+    limit: Option<usize>,
+) -> Result<Vec<MetricPoint>> {
+    track_call("load_metrics").await;
+    check_api_rate()?;
+    if !state.validate_session(&token).await {
+        log::error!("load_metrics: invalid token");
+        return Err(Error::InvalidToken);
+    }
+    state.load_metrics(limit).await
+}
+
+#[cfg(feature = "gui")]
+pub async fn get_frame_metrics(state: &AppState) -> Result<FrameMetricsSnapshot> {
+    check_api_rate()?;
+    Ok(state.renderer_service().metrics_snapshot())
+}
+
+pub async fn ping_host(
+    state: &AppState,
+    token: String,
+    host: Option<String>,
+    count: Option<u8>,
+) -> Result<u64> {
+    track_call("ping_host").await;
+    check_api_rate()?;
+    if !state.validate_session(&token).await {
+        log::error!("ping_host: invalid token");
+        return Err(Error::InvalidToken);
+    }
+    let host = host.unwrap_or_else(|| "google.com".to_string());
+    if !HOST_RE.is_match(&host) {
+        log::error!("ping_host: invalid host '{}'", host);
+        return Err(Error::Io("invalid host".into()));
+    }
+    let count = count.unwrap_or(5).min(MAX_PING_COUNT);
+    icmp::ping_host(&host, count)
+        .await
+        .map_err(|e| Error::Io(e.to_string()))
+}
+
+pub async fn ping_host_series(
+    state: &AppState,
+    token: String,
+    host: Option<String>,
+    count: Option<u8>,
+) -> Result<Vec<u64>> {
+    track_call("ping_host_series").await;
+    check_api_rate()?;
+    if !state.validate_session(&token).await {
+        log::error!("ping_host_series: invalid token");
+        return Err(Error::InvalidToken);
+    }
+    let host = host.unwrap_or_else(|| "google.com".to_string());
+    if !HOST_RE.is_match(&host) {
+        log::error!("ping_host_series: invalid host '{}'", host);
+        return Err(Error::Io("invalid host".into()));
+    }
+    let count = count.unwrap_or(5).min(MAX_PING_COUNT);
+    icmp::ping_host_series(&host, count)
+        .await
+        .map_err(|e| Error::Io(e.to_string()))
+}
+
+pub async fn dns_lookup(state: &AppState, token: String, host: String) -> Result<Vec<String>> {
+    track_call("dns_lookup").await;
+    check_api_rate()?;
+    if !state.validate_session(&token).await {
+        log::error!("dns_lookup: invalid token");
+        return Err(Error::InvalidToken);
+    }
+    // Resolve through Tor so lookups do not leak to the local resolver
+    let mgr = state.tor_manager.read().await.clone();
+    if let Ok(addr) = host.parse::<std::net::IpAddr>() {
+        return mgr.resolve_ptr(addr).await;
+    }
+    if !HOST_RE.is_match(&host) {
+        log::error!("dns_lookup: invalid host '{}'", host);
+        return Err(Error::Io("invalid host".into()));
+    }
+    let addrs = mgr.resolve(&host).await?;
+    Ok(addrs.into_iter().map(|ip| ip.to_string()).collect())
+}
+
+pub async fn list_forwards(state: &AppState) -> Result<Vec<ForwardInfo>> {
+    track_call("list_forwards").await;
+    Ok(state.tor_manager.read().await.list_forwards())
+}
+
+pub async fn add_forward(state: &AppState, forward: ForwardSpec) -> Result<()> {
+    track_call("add_forward").await;
+    state.add_forward(forward).await
+}
+
+pub async fn remove_forward(state: &AppState, listen_port: u16) -> Result<bool> {
+    track_call("remove_forward").await;
+    state.remove_forward(listen_port).await
+}
+
+pub async fn list_listeners(state: &AppState) -> Result<Vec<ListenerInfo>> {
+    track_call("list_listeners").await;
+    Ok(state.tor_manager.read().await.list_listeners())
+}
+
+pub async fn set_listener(state: &AppState, listener: ListenerSpec) -> Result<()> {
+    track_call("set_listener").await;
+    state.set_listener(listener).await?;
+    state.record_snapshot("set_listener").await;
+    Ok(())
+}
+
+pub async fn remove_listener(state: &AppState, port: u16) -> Result<bool> {
+    track_call("remove_listener").await;
+    let removed = state.remove_listener(port).await?;
+    if removed {
+        state.record_snapshot("remove_listener").await;
+    }
+    Ok(removed)
+}
+
+/// Configured and active port of the local DNS listener.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsListenerInfo {
+    pub configured_port: Option<u16>,
+    pub active_port: Option<u16>,
+}
+
+pub async fn get_dns_listener(state: &AppState) -> Result<DnsListenerInfo> {
+    track_call("get_dns_listener").await;
+    let mgr = state.tor_manager.read().await.clone();
+    Ok(DnsListenerInfo {
+        configured_port: mgr.dns_port().await,
+        active_port: mgr.dns_listener_port().await,
+    })
+}
+
+pub async fn set_dns_port(state: &AppState, port: Option<u16>) -> Result<()> {
+    track_call("set_dns_port").await;
+    state.set_dns_port(port).await
+}
+
+pub async fn traceroute_host(
+    state: &AppState,
+    token: String,
+    host: String,
+    max_hops: Option<u8>,
+) -> Result<Vec<String>> {
+    use traceroute::TraceResult;
+    track_call("traceroute_host").await;
+    check_api_rate()?;
+    if !state.validate_session(&token).await {
+        log::error!("traceroute_host: invalid token");
+        return Err(Error::InvalidToken);
+    }
+    if !HOST_RE.is_match(&host) {
+        log::error!("traceroute_host: invalid host '{}'", host);
+        return Err(Error::Io("invalid host".into()));
+    }
+    let host_clone = host.clone();
+    let limit = max_hops.unwrap_or(30) as usize;
+    let scheduler = state.scheduler();
+    let hops = scheduler
+        .spawn(
+            "traceroute_host",
+            move || -> std::result::Result<Vec<String>, String> {
+                let addr = format!("{}:0", host_clone);
+                let trace: TraceResult =
+                    traceroute::execute(addr.as_str()).map_err(|e| e.to_string())?;
+                let mut out = Vec::new();
+                for hop in trace.take(limit) {
+                    let hop = hop.map_err(|e| e.to_string())?;
+                    out.push(hop.host.ip().to_string());
+                }
+                Ok(out)
+            },
+        )
+        .await
+        .map_err(|err| match err {
+            TaskError::Canceled => Error::Io("traceroute task cancelled".into()),
+            TaskError::Panicked { message, .. } => Error::Io(message),
+        })?
+        .map_err(Error::Io)?;
+    Ok(hops)
+}
+
+pub async fn lookup_country(state: &AppState, ip: String) -> Result<String> {
+    track_call("lookup_country").await;
+    check_api_rate()?;
+    {
+        let mgr = state.tor_manager.read().await.clone();
+        mgr.lookup_country_code(&ip).await
+    }
+}
+
+pub async fn get_secure_key(state: &AppState, token: String) -> Result<Option<String>> {
+    track_call("get_secure_key").await;
+    check_api_rate()?;
+    if !state.validate_session(&token).await {
+        log::error!("get_secure_key: invalid token");
+        return Err(Error::InvalidToken);
+    }
+    read_secure_key()
+}
+
+fn secure_key_entry() -> Result<keyring::Entry> {
+    keyring::Entry::new("torwell84", "aes-key").map_err(|e| Error::Io(e.to_string()))
+}
+
+fn read_secure_key() -> Result<Option<String>> {
+    match secure_key_entry()?.get_password() {
+        Ok(v) => Ok(Some(v)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(Error::Io(e.to_string())),
+    }
+}
+
+fn write_secure_key(value: &str) -> Result<()> {
+    secure_key_entry()?
+        .set_password(value)
+        .map_err(|e| Error::Io(e.to_string()))
+}
+
+pub async fn set_secure_key(state: &AppState, token: String, value: String) -> Result<()> {
+    track_call("set_secure_key").await;
+    check_api_rate()?;
+    if !state.validate_session(&token).await {
+        log::error!("set_secure_key: invalid token");
+        return Err(Error::InvalidToken);
+    }
+    write_secure_key(&value)
+}
+
+/// Pack the configuration into a bundle encrypted with `passphrase`.
+/// Secrets from the keyring are only included with `include_secrets`,
+/// which needs a valid session token like [`get_secure_key`].
+pub async fn export_config_bundle(
+    state: &AppState,
+    token: String,
+    passphrase: String,
+    include_secrets: bool,
+) -> Result<String> {
+    track_call("export_config_bundle").await;
+    check_api_rate()?;
+    let mut contents = state.bundle_contents().await;
+    if include_secrets {
+        if !state.validate_session(&token).await {
+            log::error!("export_config_bundle: invalid token");
+            return Err(Error::InvalidToken);
+        }
+        contents.secrets = Some(Secrets {
+            aes_key: read_secure_key()?,
+            worker_token: state.http_client.worker_config().await.1,
+        });
+    } else {
+        contents.app_config.remove("control_password");
+    }
+    // Key derivation takes a noticeable amount of CPU time
+    tokio::task::spawn_blocking(move || bundle::encrypt(&contents, &passphrase))
+        .await
+        .map_err(|e| Error::Io(e.to_string()))?
+}
+
+/// Decrypt and validate a bundle, returning it merged with the current
+/// configuration.
+async fn open_bundle(
+    state: &AppState,
+    data: String,
+    passphrase: String,
+    mode: ImportMode,
+) -> Result<(BundleContents, ImportPreview)> {
+    let incoming = tokio::task::spawn_blocking(move || bundle::decrypt(&data, &passphrase))
+        .await
+        .map_err(|e| Error::Io(e.to_string()))??;
+    bundle::validate(&incoming)?;
+    let current = state.bundle_contents().await;
+    let merged = bundle::merge(&current, &incoming, mode);
+    bundle::validate(&merged)?;
+    Ok((merged, bundle::preview(&current, &incoming, mode)))
+}
+
+/// Show what importing a bundle with `mode` would change.
+pub async fn preview_config_bundle(
+    state: &AppState,
+    data: String,
+    passphrase: String,
+    mode: ImportMode,
+) -> Result<ImportPreview> {
+    track_call("preview_config_bundle").await;
+    check_api_rate()?;
+    Ok(open_bundle(state, data, passphrase, mode).await?.1)
+}
+
+/// Import a bundle and apply it, reconnecting if needed. Bundles with
+/// secrets need a valid session token like [`set_secure_key`].
+pub async fn import_config_bundle(
+    sink: Option<Arc<dyn EventSink>>,
+    state: &AppState,
+    token: String,
+    data: String,
+    passphrase: String,
+    mode: ImportMode,
+) -> Result<ImportPreview> {
+    track_call("import_config_bundle").await;
+    check_api_rate()?;
+    let (merged, preview) = open_bundle(state, data, passphrase, mode).await?;
+    if merged.secrets.is_some() && !state.validate_session(&token).await {
+        log::error!("import_config_bundle: invalid token");
+        return Err(Error::InvalidToken);
+    }
+    let aes_key = merged.secrets.as_ref().and_then(|s| s.aes_key.clone());
+    let connected = is_connected(state).await;
+    let proxy_before = state.is_system_proxy_enabled().await;
+    // Everything is applied before the secret is written and before any
+    // reconnect, so a failed reconnect leaves a complete import behind
+    let needs_reconnect = state.import_bundle(merged).await?;
+    if let Some(key) = aes_key {
+        write_secure_key(&key)?;
+    }
+    finish_settings_switch(sink, state, connected, proxy_before, needs_reconnect).await?;
+    Ok(preview)
+}
+
+pub async fn perform_reconnect(sink: Option<Arc<dyn EventSink>>, state: AppState) -> Result<()> {
+    // Attempt graceful disconnect; ignore errors if already disconnected
+    {
+        let mgr = state.tor_manager.read().await.clone();
+        let _ = mgr.disconnect().await;
+    }
+
+    // Reuse existing connect logic
+    perform_connect(sink, state).await
+}
+
+pub async fn reconnect(sink: Option<Arc<dyn EventSink>>, state: &AppState) -> Result<()> {
+    track_call("reconnect").await;
+    check_api_rate()?;
+
+    perform_reconnect(sink, state.clone()).await
+}
@@ -0,0 +1,43 @@
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use log::LevelFilter;
+use torwell84::{run_daemon, DaemonOptions};
+
+const USAGE: &str = "usage: torwelld [--config <path>] [--socks-port <port>] [--log-level <level>]";
+
+fn main() -> Result<()> {
+    let mut opts = DaemonOptions::default();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--config requires a path"))?;
+                opts.config_path = Some(PathBuf::from(value));
+            }
+            "--socks-port" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--socks-port requires a value"))?;
+                opts.socks_port = Some(value.parse::<u16>()?);
+            }
+            "--log-level" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--log-level requires a value"))?;
+                opts.log_level = Some(value.parse::<LevelFilter>()?);
+            }
+            "--help" | "-h" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            other => return Err(anyhow!("unknown argument '{other}'\n{USAGE}")),
+        }
+    }
+
+    run_daemon(opts)
+}
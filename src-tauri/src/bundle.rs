@@ -0,0 +1,423 @@
+//! Encrypted configuration bundles for moving a setup to another machine.
+//!
+//! A bundle holds the app config file, the routing and proxy settings, the
+//! connection profiles, the worker list and optionally secrets from the
+//! keyring. It is encrypted with XChaCha20-Poly1305 under a key derived
+//! from a passphrase with Argon2id. The KDF parameters and salt are stored
+//! in the clear next to the ciphertext so they can be raised later.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::history::{diff_values, SettingChange};
+use crate::profiles::Profile;
+use crate::settings::Settings;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+
+/// Value of the `format` field of a bundle file
+pub const BUNDLE_FORMAT: &str = "torwell84-bundle";
+/// Bundle version written by this build
+pub const BUNDLE_VERSION: u32 = 1;
+/// Shortest accepted passphrase
+pub const MIN_PASSPHRASE_LEN: usize = 8;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+/// Upper bounds for KDF parameters read from a bundle, so a crafted file
+/// cannot make the import allocate or spin without limit
+const MAX_MEMORY_KIB: u32 = 1 << 20;
+const MAX_ITERATIONS: u32 = 16;
+const MAX_PARALLELISM: u32 = 16;
+
+/// Argon2id parameters of a bundle.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct KdfParams {
+    pub algorithm: String,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    /// Base64 encoded salt
+    pub salt: String,
+}
+
+impl KdfParams {
+    /// Parameters recommended for interactive use, with a fresh salt.
+    fn generate() -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self {
+            algorithm: "argon2id".into(),
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+            salt: encode(&salt),
+        }
+    }
+
+    fn derive_key(&self, passphrase: &str) -> Result<[u8; 32]> {
+        if self.algorithm != "argon2id"
+            || self.memory_kib > MAX_MEMORY_KIB
+            || self.iterations > MAX_ITERATIONS
+            || self.parallelism > MAX_PARALLELISM
+        {
+            return Err(invalid("unsupported key derivation parameters"));
+        }
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| invalid(format!("invalid key derivation parameters: {e}")))?;
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &decode(&self.salt)?, &mut key)
+            .map_err(|e| invalid(format!("key derivation failed: {e}")))?;
+        Ok(key)
+    }
+}
+
+/// On-disk form of a bundle.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Envelope {
+    pub format: String,
+    pub version: u32,
+    pub kdf: KdfParams,
+    pub cipher: String,
+    /// Base64 encoded nonce
+    pub nonce: String,
+    /// Base64 encoded ciphertext of the JSON encoded [`BundleContents`]
+    pub ciphertext: String,
+}
+
+/// Secrets copied from the keyring and memory when requested.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Secrets {
+    #[serde(default)]
+    pub aes_key: Option<String>,
+    #[serde(default)]
+    pub worker_token: Option<String>,
+}
+
+impl Secrets {
+    fn names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        if self.aes_key.is_some() {
+            names.push("aesKey".to_string());
+        }
+        if self.worker_token.is_some() {
+            names.push("workerToken".to_string());
+        }
+        names
+    }
+}
+
+/// Decrypted contents of a bundle.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleContents {
+    pub created: DateTime<Utc>,
+    /// Keys of the app config file
+    #[serde(default)]
+    pub app_config: Map<String, Value>,
+    #[serde(default)]
+    pub settings: Settings,
+    #[serde(default)]
+    pub profiles: Vec<Profile>,
+    #[serde(default)]
+    pub workers: Vec<String>,
+    #[serde(default)]
+    pub secrets: Option<Secrets>,
+}
+
+/// How an imported bundle is combined with the current configuration.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ImportMode {
+    /// Bundle values win, everything else is kept
+    #[default]
+    Merge,
+    /// The bundle replaces the config file, profiles and workers
+    Replace,
+}
+
+/// What importing a bundle would change.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportPreview {
+    pub created: DateTime<Utc>,
+    pub mode: ImportMode,
+    /// Changed keys, prefixed with `config.` or `settings.`
+    pub changes: Vec<SettingChange>,
+    pub profiles_added: Vec<String>,
+    pub profiles_replaced: Vec<String>,
+    pub profiles_removed: Vec<String>,
+    pub workers: Vec<String>,
+    /// Names of the secrets the import writes
+    pub secrets: Vec<String>,
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::InvalidConfig {
+        key: "bundle".into(),
+        message: message.into(),
+    }
+}
+
+fn encode(data: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(data)
+}
+
+fn decode(data: &str) -> Result<Vec<u8>> {
+    base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| invalid(format!("invalid base64: {e}")))
+}
+
+/// Associated data binding the ciphertext to the format and version
+fn associated_data(version: u32) -> Vec<u8> {
+    format!("{BUNDLE_FORMAT}/{version}").into_bytes()
+}
+
+/// Encrypt `contents` with `passphrase` and return the bundle file.
+pub fn encrypt(contents: &BundleContents, passphrase: &str) -> Result<String> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+        return Err(invalid(format!(
+            "passphrase must have at least {MIN_PASSPHRASE_LEN} characters"
+        )));
+    }
+    let kdf = KdfParams::generate();
+    let key = kdf.derive_key(passphrase)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let plaintext = serde_json::to_vec(contents)?;
+    let ciphertext = XChaCha20Poly1305::new(Key::from_slice(&key))
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: &plaintext,
+                aad: &associated_data(BUNDLE_VERSION),
+            },
+        )
+        .map_err(|_| invalid("encryption failed"))?;
+    let envelope = Envelope {
+        format: BUNDLE_FORMAT.into(),
+        version: BUNDLE_VERSION,
+        kdf,
+        cipher: "xchacha20poly1305".into(),
+        nonce: encode(&nonce),
+        ciphertext: encode(&ciphertext),
+    };
+    Ok(serde_json::to_string_pretty(&envelope)?)
+}
+
+/// Decrypt a bundle file written by [`encrypt`].
+pub fn decrypt(data: &str, passphrase: &str) -> Result<BundleContents> {
+    let envelope: Envelope =
+        serde_json::from_str(data).map_err(|e| invalid(format!("not a bundle file: {e}")))?;
+    if envelope.format != BUNDLE_FORMAT {
+        return Err(invalid("not a bundle file"));
+    }
+    if envelope.version > BUNDLE_VERSION {
+        return Err(invalid(format!(
+            "bundle version {} is newer than supported version {BUNDLE_VERSION}",
+            envelope.version
+        )));
+    }
+    if envelope.cipher != "xchacha20poly1305" {
+        return Err(invalid(format!("unsupported cipher {}", envelope.cipher)));
+    }
+    let nonce = decode(&envelope.nonce)?;
+    if nonce.len() != NONCE_LEN {
+        return Err(invalid("invalid nonce"));
+    }
+    let key = envelope.kdf.derive_key(passphrase)?;
+    let plaintext = XChaCha20Poly1305::new(Key::from_slice(&key))
+        .decrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: &decode(&envelope.ciphertext)?,
+                aad: &associated_data(envelope.version),
+            },
+        )
+        .map_err(|_| invalid("wrong passphrase or damaged bundle"))?;
+    serde_json::from_slice(&plaintext).map_err(|e| invalid(format!("invalid bundle contents: {e}")))
+}
+
+/// Check that `contents` can be applied: the config keys are known and
+/// valid, profiles are valid with unique names, and workers are URLs.
+pub fn validate(contents: &BundleContents) -> Result<()> {
+    let config: Config = serde_json::from_value(Value::Object(contents.app_config.clone()))
+        .map_err(|e| invalid(format!("invalid app config: {e}")))?;
+    if let Some((key, message)) = config.validate().into_iter().next() {
+        return Err(invalid(format!("invalid app config: {key} {message}")));
+    }
+    let mut names = HashSet::new();
+    for profile in &contents.profiles {
+        profile.validate()?;
+        if !names.insert(&profile.name) {
+            return Err(invalid(format!("duplicate profile '{}'", profile.name)));
+        }
+    }
+    if let Some(worker) = contents
+        .workers
+        .iter()
+        .find(|w| !w.starts_with("https://") && !w.starts_with("http://"))
+    {
+        return Err(invalid(format!("invalid worker URL '{worker}'")));
+    }
+    Ok(())
+}
+
+/// Result of importing `incoming` into `current` with `mode`.
+pub fn merge(
+    current: &BundleContents,
+    incoming: &BundleContents,
+    mode: ImportMode,
+) -> BundleContents {
+    let mut result = incoming.clone();
+    if mode == ImportMode::Merge {
+        let mut config = current.app_config.clone();
+        config.extend(incoming.app_config.clone());
+        result.app_config = config;
+
+        let mut profiles = current.profiles.clone();
+        for profile in &incoming.profiles {
+            match profiles.iter_mut().find(|p| p.name == profile.name) {
+                Some(existing) => *existing = profile.clone(),
+                None => profiles.push(profile.clone()),
+            }
+        }
+        result.profiles = profiles;
+
+        let mut workers = current.workers.clone();
+        for worker in &incoming.workers {
+            if !workers.contains(worker) {
+                workers.push(worker.clone());
+            }
+        }
+        result.workers = workers;
+    }
+    result
+}
+
+/// Describe what importing `incoming` into `current` changes.
+pub fn preview(
+    current: &BundleContents,
+    incoming: &BundleContents,
+    mode: ImportMode,
+) -> ImportPreview {
+    let result = merge(current, incoming, mode);
+    let document = |b: &BundleContents| {
+        serde_json::json!({
+            "config": b.app_config,
+            "settings": {
+                "routing": b.settings.routing,
+                "proxy": b.settings.proxy,
+            },
+        })
+    };
+    let find = |profiles: &[Profile], name: &str| profiles.iter().find(|p| p.name == name).cloned();
+
+    let mut preview = ImportPreview {
+        created: incoming.created,
+        mode,
+        changes: diff_values(&document(current), &document(&result)),
+        profiles_added: Vec::new(),
+        profiles_replaced: Vec::new(),
+        profiles_removed: Vec::new(),
+        workers: result.workers.clone(),
+        secrets: incoming
+            .secrets
+            .as_ref()
+            .map(Secrets::names)
+            .unwrap_or_default(),
+    };
+    for profile in &incoming.profiles {
+        match find(&current.profiles, &profile.name) {
+            None => preview.profiles_added.push(profile.name.clone()),
+            Some(existing) if existing != *profile => {
+                preview.profiles_replaced.push(profile.name.clone())
+            }
+            Some(_) => {}
+        }
+    }
+    for profile in &current.profiles {
+        if find(&result.profiles, &profile.name).is_none() {
+            preview.profiles_removed.push(profile.name.clone());
+        }
+    }
+    preview
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::RoutingSettings;
+
+    fn contents(bridge: &str) -> BundleContents {
+        let mut app_config = Map::new();
+        app_config.insert("max_log_lines".into(), 500.into());
+        BundleContents {
+            created: Utc::now(),
+            app_config,
+            settings: Settings::default(),
+            profiles: vec![Profile {
+                name: "work".into(),
+                routing: RoutingSettings {
+                    bridges: vec![bridge.into()],
+                    ..Default::default()
+                },
+                listeners: Vec::new(),
+                exit_rules: Default::default(),
+                system_proxy_enabled: true,
+            }],
+            workers: vec!["https://worker.example".into()],
+            secrets: Some(Secrets {
+                aes_key: Some("key".into()),
+                worker_token: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn round_trips_and_rejects_wrong_passphrase() {
+        let bundle = contents("obfs4 1.2.3.4:443");
+        let data = encrypt(&bundle, "correct horse").unwrap();
+        assert!(!data.contains("obfs4"));
+        assert_eq!(decrypt(&data, "correct horse").unwrap(), bundle);
+        assert!(decrypt(&data, "wrong horse").is_err());
+        assert!(encrypt(&bundle, "short").is_err());
+    }
+
+    #[test]
+    fn merge_keeps_unrelated_values() {
+        let mut current = contents("obfs4 1.2.3.4:443");
+        current.app_config.insert("socks_port".into(), 9150.into());
+        current.profiles[0].name = "default".into();
+        let incoming = contents("snowflake 192.0.2.3:80");
+
+        let merged = preview(&current, &incoming, ImportMode::Merge);
+        assert!(merged.changes.is_empty());
+        assert_eq!(merged.profiles_added, vec!["work"]);
+        assert!(merged.profiles_removed.is_empty());
+        assert_eq!(merged.secrets, vec!["aesKey"]);
+
+        let replaced = preview(&current, &incoming, ImportMode::Replace);
+        assert_eq!(replaced.changes[0].key, "config.socks_port");
+        assert_eq!(replaced.profiles_removed, vec!["default"]);
+    }
+
+    #[test]
+    fn validation_rejects_unknown_keys() {
+        let mut bundle = contents("obfs4 1.2.3.4:443");
+        assert!(validate(&bundle).is_ok());
+        bundle.app_config.insert("no_such_key".into(), true.into());
+        assert!(validate(&bundle).is_err());
+    }
+}
@@ -0,0 +1,716 @@
+//! Layered application configuration. Every value is resolved from the
+//! built-in defaults, the config files, `TORWELL_*` environment variables
+//! and runtime overrides, later layers winning. Values that fail validation
+//! are reported with their key and fall back to the default.
+
+use crate::dns;
+use crate::error::{Error, Result};
+use crate::forward::ForwardSpec;
+use crate::listeners::ListenerSpec;
+use crate::logstore::{DEFAULT_LOG_RETENTION_DAYS, DEFAULT_LOG_SEGMENTS, DEFAULT_LOG_SEGMENT_KB};
+use crate::paths::paths;
+use crate::redact::RedactionLevel;
+use crate::routing::ExitRoutingConfig;
+use crate::secure_http::{self, CertSettings};
+use crate::state::{
+    DEFAULT_MAX_CONNECTION_EVENTS, DEFAULT_MAX_LOG_LINES, DEFAULT_MAX_METRIC_LINES,
+    DEFAULT_MAX_METRIC_MB, DEFAULT_METRIC_INTERVAL_SECS, DEFAULT_SESSION_TTL,
+};
+use crate::trace;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Interval at which the config files are checked for changes
+pub const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Keys taken from `cert_config.json`
+const CERT_KEYS: &[&str] = &[
+    "cert_path",
+    "cert_path_windows",
+    "cert_path_macos",
+    "cert_url",
+    "fallback_cert_url",
+    "min_tls_version",
+    "update_interval",
+];
+
+/// Keys whose values are hidden in [`EffectiveConfig`]
+const SECRET_KEYS: &[&str] = &["control_password"];
+
+/// Environment variables and the keys they set
+pub const ENV_VARS: &[(&str, &str)] = &[
+    ("TORWELL_MAX_LOG_LINES", "max_log_lines"),
+    ("TORWELL_MAX_LOG_SEGMENT_KB", "max_log_segment_kb"),
+    ("TORWELL_MAX_LOG_SEGMENTS", "max_log_segments"),
+    ("TORWELL_LOG_RETENTION_DAYS", "log_retention_days"),
+    ("TORWELL_LOG_ENDPOINT", "log_endpoint"),
+    ("TORWELL_LOG_REDACTION", "log_redaction"),
+    ("TORWELL_GEOIP_PATH", "geoip_path"),
+    ("TORWELL_METRICS_FILE", "metrics_file"),
+    ("TORWELL_MAX_METRIC_LINES", "max_metric_lines"),
+    ("TORWELL_MAX_METRIC_MB", "max_metric_mb"),
+    ("TORWELL_METRIC_INTERVAL", "metric_interval_secs"),
+    ("TORWELL_MAX_CONNECTION_EVENTS", "max_connection_events"),
+    ("TORWELL_MAX_MEMORY_MB", "max_memory_mb"),
+    ("TORWELL_MAX_CIRCUITS", "max_circuits"),
+    ("TORWELL_SESSION_TTL", "session_ttl"),
+    ("TORWELL_SOCKS_PORT", "socks_port"),
+    ("TORWELL_DNS_PORT", "dns_port"),
+    ("TORWELL_DNS_MAX_TTL", "dns_max_ttl_secs"),
+    ("TORWELL_CONTROL_PORT", "control_port"),
+    ("TORWELL_CONTROL_PASSWORD", "control_password"),
+    ("TORWELL_API_PORT", "api_port"),
+    ("TORWELL_METRICS_PORT", "metrics_port"),
+    ("TORWELL_CERT_PATH", "cert_path"),
+    ("TORWELL_CERT_URL", "cert_url"),
+    ("TORWELL_FALLBACK_CERT_URL", "fallback_cert_url"),
+    ("TORWELL_UPDATE_INTERVAL", "update_interval"),
+    ("TORWELL_HSM_LIB", "hsm_lib"),
+    ("TORWELL_HSM_SLOT", "hsm_slot"),
+];
+
+/// Effective application configuration.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub max_log_lines: usize,
+    /// Size of a log segment in KiB before it is rotated
+    pub max_log_segment_kb: usize,
+    /// Number of rotated log segments kept
+    pub max_log_segments: usize,
+    /// Days after which rotated log segments are removed, `0` keeps them
+    pub log_retention_days: u64,
+    /// Endpoint receiving every log entry as JSON
+    pub log_endpoint: Option<String>,
+    /// Redaction of addresses in logs, connection events and exports
+    pub log_redaction: RedactionLevel,
+    /// Level of `tracing` events captured into the log, per target prefix
+    pub trace_filters: BTreeMap<String, String>,
+    pub geoip_path: Option<String>,
+    pub metrics_file: Option<String>,
+    pub max_metric_lines: usize,
+    pub max_metric_mb: usize,
+    pub metric_interval_secs: u64,
+    pub max_connection_events: usize,
+    pub max_memory_mb: u64,
+    pub max_circuits: usize,
+    /// Lifetime of session tokens in seconds
+    pub session_ttl: u64,
+    pub insecure_allowed_hosts: Vec<String>,
+    pub socks_port: Option<u16>,
+    pub dns_port: Option<u16>,
+    /// Longest time in seconds the DNS listener caches an answer
+    pub dns_max_ttl_secs: u64,
+    pub control_port: Option<u16>,
+    pub control_password: Option<String>,
+    pub api_port: Option<u16>,
+    pub metrics_port: Option<u16>,
+    pub forwards: Vec<ForwardSpec>,
+    pub listeners: Vec<ListenerSpec>,
+    pub exit_rules: ExitRoutingConfig,
+    pub cert_path: String,
+    pub cert_path_windows: Option<String>,
+    pub cert_path_macos: Option<String>,
+    pub cert_url: String,
+    pub fallback_cert_url: Option<String>,
+    pub min_tls_version: Option<String>,
+    /// Interval between certificate updates in seconds, `0` disables them
+    pub update_interval: u64,
+    pub hsm_lib: Option<String>,
+    pub hsm_slot: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_log_lines: DEFAULT_MAX_LOG_LINES,
+            max_log_segment_kb: DEFAULT_LOG_SEGMENT_KB,
+            max_log_segments: DEFAULT_LOG_SEGMENTS,
+            log_retention_days: DEFAULT_LOG_RETENTION_DAYS,
+            log_endpoint: None,
+            log_redaction: RedactionLevel::default(),
+            trace_filters: trace::default_filters(),
+            geoip_path: None,
+            metrics_file: None,
+            max_metric_lines: DEFAULT_MAX_METRIC_LINES,
+            max_metric_mb: DEFAULT_MAX_METRIC_MB,
+            metric_interval_secs: DEFAULT_METRIC_INTERVAL_SECS,
+            max_connection_events: DEFAULT_MAX_CONNECTION_EVENTS,
+            max_memory_mb: 1024,
+            max_circuits: 20,
+            session_ttl: DEFAULT_SESSION_TTL,
+            insecure_allowed_hosts: vec!["127.0.0.1".into(), "localhost".into()],
+            socks_port: None,
+            dns_port: None,
+            dns_max_ttl_secs: dns::DEFAULT_DNS_MAX_TTL_SECS,
+            control_port: None,
+            control_password: None,
+            api_port: None,
+            metrics_port: None,
+            forwards: Vec::new(),
+            listeners: Vec::new(),
+            exit_rules: ExitRoutingConfig::default(),
+            cert_path: paths().cert_file().to_string_lossy().into_owned(),
+            cert_path_windows: None,
+            cert_path_macos: None,
+            cert_url: secure_http::DEFAULT_CERT_URL.into(),
+            fallback_cert_url: None,
+            min_tls_version: Some("1.2".into()),
+            update_interval: 60 * 60 * 24,
+            hsm_lib: None,
+            hsm_slot: None,
+        }
+    }
+}
+
+impl Config {
+    /// Check values that parse but make no sense. Returns the offending
+    /// keys with a message.
+    pub fn validate(&self) -> Vec<(&'static str, String)> {
+        let mut issues = Vec::new();
+        for (key, value) in [
+            ("max_log_lines", self.max_log_lines as u64),
+            ("max_log_segment_kb", self.max_log_segment_kb as u64),
+            ("max_log_segments", self.max_log_segments as u64),
+            ("max_metric_lines", self.max_metric_lines as u64),
+            ("max_metric_mb", self.max_metric_mb as u64),
+            ("metric_interval_secs", self.metric_interval_secs),
+            ("session_ttl", self.session_ttl),
+            ("dns_max_ttl_secs", self.dns_max_ttl_secs),
+        ] {
+            if value == 0 {
+                issues.push((key, "must be greater than 0".to_string()));
+            }
+        }
+        if self.max_connection_events < 10 {
+            issues.push(("max_connection_events", "must be at least 10".to_string()));
+        }
+        for (key, url) in [
+            ("cert_url", Some(&self.cert_url)),
+            ("fallback_cert_url", self.fallback_cert_url.as_ref()),
+            ("log_endpoint", self.log_endpoint.as_ref()),
+        ] {
+            if let Some(url) = url {
+                if !is_http_url(url) {
+                    issues.push((key, format!("'{url}' is not an http(s) URL")));
+                }
+            }
+        }
+        if let Err(Error::InvalidConfig { message, .. }) =
+            trace::validate_filters(&self.trace_filters)
+        {
+            issues.push(("trace_filters", message));
+        }
+        if let Some(version) = &self.min_tls_version {
+            if !matches!(version.as_str(), "1.2" | "1.3") {
+                issues.push(("min_tls_version", "must be \"1.2\" or \"1.3\"".to_string()));
+            }
+        }
+        let mut used: Vec<(&str, u16)> = Vec::new();
+        for (key, port) in self.ports() {
+            let Some(port) = port.filter(|p| *p != 0) else {
+                continue;
+            };
+            match used.iter().find(|(_, p)| *p == port) {
+                Some((other, _)) => {
+                    issues.push((key, format!("port {port} is already used by {other}")))
+                }
+                None => used.push((key, port)),
+            }
+        }
+        issues
+    }
+
+    fn ports(&self) -> [(&'static str, Option<u16>); 5] {
+        [
+            ("socks_port", self.socks_port),
+            ("dns_port", self.dns_port),
+            ("control_port", self.control_port),
+            ("api_port", self.api_port),
+            ("metrics_port", self.metrics_port),
+        ]
+    }
+
+    /// Port `0` disables a listener.
+    fn normalize(&mut self) {
+        for port in [
+            &mut self.socks_port,
+            &mut self.dns_port,
+            &mut self.control_port,
+            &mut self.api_port,
+            &mut self.metrics_port,
+        ] {
+            *port = port.filter(|p| *p != 0);
+        }
+    }
+
+    /// Whether the Tor manager has to be rebuilt to apply `other`.
+    pub fn manager_changed(&self, other: &Config) -> bool {
+        self.geoip_path != other.geoip_path
+            || self.socks_port != other.socks_port
+            || self.dns_port != other.dns_port
+            || self.dns_max_ttl_secs != other.dns_max_ttl_secs
+            || self.forwards != other.forwards
+            || self.listeners != other.listeners
+            || self.exit_rules != other.exit_rules
+    }
+}
+
+fn is_http_url(url: &str) -> bool {
+    reqwest::Url::parse(url)
+        .map(|u| matches!(u.scheme(), "http" | "https"))
+        .unwrap_or(false)
+}
+
+/// Layer a value was taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Source {
+    Default,
+    File,
+    Env,
+    Override,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Source::Default => "default",
+            Source::File => "file",
+            Source::Env => "environment",
+            Source::Override => "override",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A value that was rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigIssue {
+    pub key: String,
+    pub source: Source,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}): {}", self.key, self.source, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EffectiveValue {
+    pub value: Value,
+    pub source: Source,
+}
+
+/// Resolved configuration with the origin of every value.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EffectiveConfig {
+    pub path: String,
+    pub values: BTreeMap<String, EffectiveValue>,
+    pub issues: Vec<ConfigIssue>,
+}
+
+/// Check that `value` has the type expected for `key`.
+fn check_value(key: &str, value: &Value) -> std::result::Result<(), String> {
+    let mut doc = Map::new();
+    doc.insert(key.to_string(), value.clone());
+    serde_json::from_value::<Config>(Value::Object(doc))
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn default_values() -> Map<String, Value> {
+    match serde_json::to_value(Config::default()) {
+        Ok(Value::Object(map)) => map,
+        _ => Map::new(),
+    }
+}
+
+/// Read a JSON object from `path`. A missing file is empty; `None` means
+/// the file could not be used and the caller keeps what it had before.
+fn read_object(path: &Path, issues: &mut Vec<ConfigIssue>) -> Option<Map<String, Value>> {
+    let parsed = match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str::<Value>(&contents).map_err(|e| e.to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Some(Map::new()),
+        Err(e) => Err(e.to_string()),
+    };
+    let message = match parsed {
+        Ok(Value::Object(map)) => return Some(map),
+        Ok(_) => "expected a JSON object".to_string(),
+        Err(message) => message,
+    };
+    issues.push(ConfigIssue {
+        key: path.display().to_string(),
+        source: Source::File,
+        message,
+    });
+    None
+}
+
+/// Environment values are parsed as JSON where that yields the expected
+/// type and taken as strings otherwise.
+fn env_layer() -> Map<String, Value> {
+    let mut layer = Map::new();
+    for (var, key) in ENV_VARS {
+        let Ok(raw) = std::env::var(var) else {
+            continue;
+        };
+        let value = serde_json::from_str::<Value>(&raw)
+            .ok()
+            .filter(|v| check_value(key, v).is_ok())
+            .unwrap_or(Value::String(raw));
+        layer.insert(key.to_string(), value);
+    }
+    layer
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+struct Resolved {
+    config: Config,
+    sources: BTreeMap<String, Source>,
+    issues: Vec<ConfigIssue>,
+}
+
+fn resolve(layers: [(Source, &Map<String, Value>); 3], mut issues: Vec<ConfigIssue>) -> Resolved {
+    let defaults = default_values();
+    let mut merged = defaults.clone();
+    let mut sources: BTreeMap<String, Source> = defaults
+        .keys()
+        .map(|key| (key.clone(), Source::Default))
+        .collect();
+    for (source, layer) in layers {
+        for (key, value) in layer {
+            if !defaults.contains_key(key) {
+                issues.push(ConfigIssue {
+                    key: key.clone(),
+                    source,
+                    message: "unknown key".into(),
+                });
+                continue;
+            }
+            if let Err(message) = check_value(key, value) {
+                let message = match ENV_VARS.iter().find(|(_, k)| *k == key.as_str()) {
+                    Some((var, _)) if source == Source::Env => format!("{message} (from {var})"),
+                    _ => message,
+                };
+                issues.push(ConfigIssue {
+                    key: key.clone(),
+                    source,
+                    message,
+                });
+                continue;
+            }
+            merged.insert(key.clone(), value.clone());
+            sources.insert(key.clone(), source);
+        }
+    }
+
+    let parse = |merged: &Map<String, Value>| {
+        serde_json::from_value::<Config>(Value::Object(merged.clone())).unwrap_or_default()
+    };
+    let mut config = parse(&merged);
+    let invalid = config.validate();
+    if !invalid.is_empty() {
+        for (key, message) in invalid {
+            let source = sources.insert(key.to_string(), Source::Default);
+            issues.push(ConfigIssue {
+                key: key.to_string(),
+                source: source.unwrap_or(Source::Default),
+                message,
+            });
+            if let Some(default) = defaults.get(key) {
+                merged.insert(key.to_string(), default.clone());
+            }
+        }
+        config = parse(&merged);
+    }
+    config.normalize();
+    Resolved {
+        config,
+        sources,
+        issues,
+    }
+}
+
+struct StoreState {
+    /// Last usable contents of the app and certificate config files
+    files: (Map<String, Value>, Map<String, Value>),
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    overrides: Map<String, Value>,
+    resolved: Resolved,
+}
+
+impl StoreState {
+    /// Re-read the files and environment and resolve all layers.
+    fn refresh(&mut self, path: &Path, cert_path: &Path) -> Resolved {
+        self.modified = (modified(path), modified(cert_path));
+        let mut issues = Vec::new();
+        if let Some(app) = read_object(path, &mut issues) {
+            self.files.0 = app;
+        }
+        if let Some(cert) = read_object(cert_path, &mut issues) {
+            self.files.1 = cert
+                .into_iter()
+                .filter(|(key, _)| CERT_KEYS.contains(&key.as_str()))
+                .collect();
+        }
+        let mut file = self.files.1.clone();
+        file.extend(self.files.0.clone());
+        let env = env_layer();
+        let resolved = resolve(
+            [
+                (Source::File, &file),
+                (Source::Env, &env),
+                (Source::Override, &self.overrides),
+            ],
+            issues,
+        );
+        for issue in resolved
+            .issues
+            .iter()
+            .filter(|i| !self.resolved.issues.contains(i))
+        {
+            log::warn!("ignoring config value {}", issue);
+        }
+        std::mem::replace(&mut self.resolved, resolved)
+    }
+}
+
+/// Config layers and the configuration resolved from them.
+pub struct ConfigStore {
+    path: PathBuf,
+    cert_path: PathBuf,
+    state: Mutex<StoreState>,
+}
+
+impl ConfigStore {
+    /// Load `path` on top of the certificate settings in
+    /// [`AppPaths::cert_config_file`](crate::paths::AppPaths::cert_config_file).
+    pub fn load(path: PathBuf) -> Self {
+        Self::open(path, paths().cert_config_file())
+    }
+
+    /// Load the app config at `path` on top of the certificate config at
+    /// `cert_path`. Rejected values are logged.
+    pub fn open(path: PathBuf, cert_path: PathBuf) -> Self {
+        let mut state = StoreState {
+            files: Default::default(),
+            modified: (None, None),
+            overrides: Map::new(),
+            resolved: Resolved {
+                config: Config::default(),
+                sources: BTreeMap::new(),
+                issues: Vec::new(),
+            },
+        };
+        state.refresh(&path, &cert_path);
+        Self {
+            path,
+            cert_path,
+            state: Mutex::new(state),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, StoreState> {
+        self.state.lock().unwrap_or_else(|p| p.into_inner())
+    }
+
+    /// Location of the app config file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn current(&self) -> Config {
+        self.lock().resolved.config.clone()
+    }
+
+    /// Run `f` on the current configuration without cloning it.
+    pub fn read<R>(&self, f: impl FnOnce(&Config) -> R) -> R {
+        f(&self.lock().resolved.config)
+    }
+
+    pub fn issues(&self) -> Vec<ConfigIssue> {
+        self.lock().resolved.issues.clone()
+    }
+
+    /// Re-read the files and environment. Returns the previous
+    /// configuration. A file that cannot be parsed, for example while an
+    /// editor is still writing it, keeps its previous values.
+    pub fn reload(&self) -> Config {
+        self.lock().refresh(&self.path, &self.cert_path).config
+    }
+
+    /// Whether a config file changed since it was last read.
+    pub fn files_changed(&self) -> bool {
+        self.lock().modified != (modified(&self.path), modified(&self.cert_path))
+    }
+
+    /// Set `key` for the lifetime of the process, taking precedence over
+    /// files and environment. `null` clears optional values.
+    pub fn set_override(&self, key: &str, value: Value) -> Result<()> {
+        let mut state = self.lock();
+        let mut overrides = state.overrides.clone();
+        overrides.insert(key.to_string(), value);
+        let resolved = resolve(
+            [
+                (Source::File, &Map::new()),
+                (Source::Env, &Map::new()),
+                (Source::Override, &overrides),
+            ],
+            Vec::new(),
+        );
+        if let Some(issue) = resolved.issues.into_iter().find(|i| i.key == key) {
+            return Err(Error::InvalidConfig {
+                key: issue.key,
+                message: issue.message,
+            });
+        }
+        state.overrides = overrides;
+        state.refresh(&self.path, &self.cert_path);
+        Ok(())
+    }
+
+    /// Certificate settings, with the platform specific path unless
+    /// `cert_path` was set by the environment or an override.
+    pub fn cert_settings(&self) -> CertSettings {
+        let state = self.lock();
+        let config = &state.resolved.config;
+        let explicit = matches!(
+            state.resolved.sources.get("cert_path"),
+            Some(Source::Env | Source::Override)
+        );
+        let platform_path = if cfg!(target_os = "windows") {
+            config.cert_path_windows.clone()
+        } else if cfg!(target_os = "macos") {
+            config.cert_path_macos.clone()
+        } else {
+            None
+        };
+        let cert_path = match platform_path {
+            Some(path) if !explicit => path,
+            _ => config.cert_path.clone(),
+        };
+        CertSettings {
+            cert_path,
+            cert_url: config.cert_url.clone(),
+            fallback_cert_url: config.fallback_cert_url.clone(),
+            min_tls_version: config.min_tls_version.clone(),
+            update_interval: Duration::from_secs(config.update_interval),
+        }
+    }
+
+    /// Every value with the layer it came from. Secrets are masked.
+    pub fn effective(&self) -> EffectiveConfig {
+        let state = self.lock();
+        let values = match serde_json::to_value(&state.resolved.config) {
+            Ok(Value::Object(map)) => map,
+            _ => Map::new(),
+        };
+        let values = values
+            .into_iter()
+            .map(|(key, mut value)| {
+                if SECRET_KEYS.contains(&key.as_str()) && !value.is_null() {
+                    value = Value::String("********".into());
+                }
+                let source = state
+                    .resolved
+                    .sources
+                    .get(&key)
+                    .copied()
+                    .unwrap_or(Source::Default);
+                (key, EffectiveValue { value, source })
+            })
+            .collect();
+        EffectiveConfig {
+            path: self.path.display().to_string(),
+            values,
+            issues: state.resolved.issues.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn layer(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let file = layer(json!({ "max_log_lines": 50, "socks_port": 9050 }));
+        let env = layer(json!({ "socks_port": 9150 }));
+        let resolved = resolve(
+            [
+                (Source::File, &file),
+                (Source::Env, &env),
+                (Source::Override, &Map::new()),
+            ],
+            Vec::new(),
+        );
+        assert_eq!(resolved.config.max_log_lines, 50);
+        assert_eq!(resolved.config.socks_port, Some(9150));
+        assert_eq!(resolved.sources["socks_port"], Source::Env);
+        assert_eq!(resolved.sources["dns_port"], Source::Default);
+    }
+
+    #[test]
+    fn invalid_values_name_the_key() {
+        let file = layer(json!({
+            "max_log_lines": "many",
+            "dns_port": 9050,
+            "socks_port": 9050,
+            "hsm_lib": "/usr/lib/softhsm.so",
+            "bogus": true
+        }));
+        let resolved = resolve(
+            [
+                (Source::File, &file),
+                (Source::Env, &Map::new()),
+                (Source::Override, &Map::new()),
+            ],
+            Vec::new(),
+        );
+        let mut keys: Vec<&str> = resolved.issues.iter().map(|i| i.key.as_str()).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["bogus", "dns_port", "max_log_lines"]);
+        assert_eq!(resolved.config.max_log_lines, DEFAULT_MAX_LOG_LINES);
+        assert_eq!(resolved.config.dns_port, None);
+        assert_eq!(resolved.config.socks_port, Some(9050));
+        assert_eq!(
+            resolved.config.hsm_lib.as_deref(),
+            Some("/usr/lib/softhsm.so")
+        );
+    }
+
+    #[test]
+    fn overrides_are_validated() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ConfigStore::open(
+            dir.path().join("app_config.json"),
+            dir.path().join("cert_config.json"),
+        );
+        assert!(store.set_override("max_log_lines", json!(0)).is_err());
+        store
+            .set_override("geoip_path", json!("/tmp/geoip"))
+            .unwrap();
+        let effective = store.effective();
+        assert_eq!(effective.values["geoip_path"].source, Source::Override);
+        assert_eq!(store.current().geoip_path.as_deref(), Some("/tmp/geoip"));
+    }
+}
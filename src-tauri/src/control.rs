@@ -0,0 +1,575 @@
+use crate::listeners::ListenerKind;
+use crate::paths::paths;
+use crate::socks::ListenerHandle;
+use crate::streams::StreamInfo;
+use crate::tor_manager::{BootstrapStatus, TorClientBehavior, TorManager};
+use log::{debug, error};
+use rand::RngCore;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::RwLock;
+
+/// Tor version reported to controllers. Tools parse this to pick the
+/// protocol features they use.
+const TOR_COMPAT_VERSION: &str = "0.4.8.0";
+/// Length of the authentication cookie in bytes
+const COOKIE_LEN: usize = 32;
+/// Commands longer than this close the connection
+const MAX_LINE_LEN: usize = 4096;
+/// Interval between checks for CIRC, STREAM and STATUS_CLIENT events
+const EVENT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+type SharedManager<C> = Arc<RwLock<Arc<TorManager<C>>>>;
+
+/// Settings of the control port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlConfig {
+    pub port: u16,
+    /// Accept `AUTHENTICATE "<password>"` in addition to the cookie
+    pub password: Option<String>,
+    pub cookie_path: PathBuf,
+}
+
+impl ControlConfig {
+    pub fn new(port: u16, password: Option<String>) -> Self {
+        Self {
+            port,
+            password: password.filter(|p| !p.is_empty()),
+            cookie_path: default_cookie_path(),
+        }
+    }
+}
+
+/// Location of the control auth cookie, next to the log file.
+pub fn default_cookie_path() -> PathBuf {
+    paths().data_dir.join("control_auth_cookie")
+}
+
+fn write_cookie(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut cookie = vec![0u8; COOKIE_LEN];
+    rand::thread_rng().fill_bytes(&mut cookie);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    std::io::Write::write_all(&mut options.open(path)?, &cookie)?;
+    Ok(cookie)
+}
+
+/// Secrets a connection may authenticate with.
+struct Credentials {
+    cookie: Vec<u8>,
+    cookie_path: PathBuf,
+    password: Option<String>,
+}
+
+impl Credentials {
+    fn methods(&self) -> &'static str {
+        if self.password.is_some() {
+            "COOKIE,HASHEDPASSWORD"
+        } else {
+            "COOKIE"
+        }
+    }
+
+    /// Check the argument of AUTHENTICATE, either a quoted password or the
+    /// hex encoded cookie or password.
+    fn verify(&self, arg: &str) -> bool {
+        let arg = arg.trim();
+        let secret = if arg.starts_with('"') {
+            match unquote(arg) {
+                Some(s) => s.into_bytes(),
+                None => return false,
+            }
+        } else {
+            match decode_hex(arg) {
+                Some(bytes) => bytes,
+                None => return false,
+            }
+        };
+        if secret.is_empty() {
+            return false;
+        }
+        let password_ok = self
+            .password
+            .as_ref()
+            .map(|p| constant_time_eq(p.as_bytes(), &secret))
+            .unwrap_or(false);
+        password_ok | constant_time_eq(&self.cookie, &secret)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parse a control protocol quoted string.
+fn unquote(s: &str) -> Option<String> {
+    let inner = s.strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.push(chars.next()?),
+            '"' => return None,
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Events a controller subscribed to with SETEVENTS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct EventMask {
+    circ: bool,
+    stream: bool,
+    status_client: bool,
+}
+
+impl EventMask {
+    fn parse(args: &str) -> Result<Self, String> {
+        let mut mask = Self::default();
+        for name in args.split_whitespace() {
+            match name.to_ascii_uppercase().as_str() {
+                "EXTENDED" => {}
+                "CIRC" => mask.circ = true,
+                "STREAM" => mask.stream = true,
+                "STATUS_CLIENT" => mask.status_client = true,
+                _ => return Err(name.to_string()),
+            }
+        }
+        Ok(mask)
+    }
+
+    fn any(&self) -> bool {
+        self.circ || self.stream || self.status_client
+    }
+}
+
+/// Maps arti's circuit labels to the numeric IDs of the control protocol.
+#[derive(Default)]
+struct CircuitIds {
+    ids: HashMap<String, u64>,
+    next: u64,
+}
+
+impl CircuitIds {
+    fn id(&mut self, label: Option<&str>) -> u64 {
+        let Some(label) = label else { return 0 };
+        if let Some(id) = self.ids.get(label) {
+            return *id;
+        }
+        self.next += 1;
+        self.ids.insert(label.to_string(), self.next);
+        self.next
+    }
+}
+
+fn bootstrap_phase(status: &BootstrapStatus, connected: bool) -> String {
+    let (progress, summary) = if connected {
+        (100, "Done")
+    } else {
+        (status.progress.min(99), status.summary.as_str())
+    };
+    let tag = match progress {
+        0 => "starting",
+        100 => "done",
+        _ => "loading_descriptors",
+    };
+    format!(
+        "NOTICE BOOTSTRAP PROGRESS={} TAG={} SUMMARY={}",
+        progress,
+        tag,
+        quote(if summary.is_empty() { "Starting" } else { summary })
+    )
+}
+
+fn stream_entry(info: &StreamInfo, status: &str, ids: &mut CircuitIds) -> String {
+    format!(
+        "{} {} {} {}:{}",
+        info.id,
+        status,
+        ids.id(info.circuit.as_deref()),
+        info.host,
+        info.port
+    )
+}
+
+/// Circuits carrying at least one stream, in ID order.
+fn circuit_entries(streams: &[StreamInfo], ids: &mut CircuitIds) -> Vec<u64> {
+    let mut circuits: Vec<u64> = streams
+        .iter()
+        .filter(|s| s.circuit.is_some())
+        .map(|s| ids.id(s.circuit.as_deref()))
+        .collect();
+    circuits.sort_unstable();
+    circuits.dedup();
+    circuits
+}
+
+/// Per-connection state.
+struct Session {
+    authenticated: bool,
+    events: EventMask,
+    circuits: CircuitIds,
+    /// Streams and circuits seen at the last event poll
+    seen_streams: HashMap<u64, StreamInfo>,
+    seen_circuits: Vec<u64>,
+    seen_phase: Option<String>,
+}
+
+enum Reply {
+    Lines(String),
+    Close(String),
+}
+
+fn ok() -> Reply {
+    Reply::Lines("250 OK\r\n".into())
+}
+
+impl Session {
+    fn new() -> Self {
+        Self {
+            authenticated: false,
+            events: EventMask::default(),
+            circuits: CircuitIds::default(),
+            seen_streams: HashMap::new(),
+            seen_circuits: Vec::new(),
+            seen_phase: None,
+        }
+    }
+
+    async fn handle<C: TorClientBehavior>(
+        &mut self,
+        line: &str,
+        manager: &SharedManager<C>,
+        creds: &Credentials,
+    ) -> Reply {
+        let (keyword, args) = line.split_once(' ').unwrap_or((line, ""));
+        let keyword = keyword.to_ascii_uppercase();
+        match keyword.as_str() {
+            "PROTOCOLINFO" => {
+                return Reply::Lines(format!(
+                    "250-PROTOCOLINFO 1\r\n250-AUTH METHODS={} COOKIEFILE={}\r\n250-VERSION Tor={}\r\n250 OK\r\n",
+                    creds.methods(),
+                    quote(&creds.cookie_path.to_string_lossy()),
+                    quote(&version())
+                ));
+            }
+            "AUTHENTICATE" => {
+                if creds.verify(args) {
+                    self.authenticated = true;
+                    return ok();
+                }
+                return Reply::Close(
+                    "515 Authentication failed: Password did not match HashedControlPassword *or* authentication cookie.\r\n"
+                        .into(),
+                );
+            }
+            "QUIT" => return Reply::Close("250 closing connection\r\n".into()),
+            _ => {}
+        }
+        if !self.authenticated {
+            return Reply::Close("514 Authentication required.\r\n".into());
+        }
+
+        let mgr = manager.read().await.clone();
+        match keyword.as_str() {
+            "GETINFO" => self.getinfo(args, &mgr).await,
+            "SETEVENTS" => match EventMask::parse(args) {
+                Ok(mask) => {
+                    self.events = mask;
+                    // Only report changes after the subscription
+                    self.poll_events(&mgr).await;
+                    ok()
+                }
+                Err(name) => Reply::Lines(format!("552 Unrecognized event \"{}\"\r\n", name)),
+            },
+            "SIGNAL" => match args.trim().to_ascii_uppercase().as_str() {
+                "NEWNYM" => match mgr.new_identity().await {
+                    Ok(()) => ok(),
+                    Err(e) => Reply::Lines(format!("551 {}\r\n", e)),
+                },
+                other => Reply::Lines(format!("552 Unrecognized signal code \"{}\"\r\n", other)),
+            },
+            _ => Reply::Lines(format!("510 Unrecognized command \"{}\"\r\n", keyword)),
+        }
+    }
+
+    async fn getinfo<C: TorClientBehavior>(&mut self, args: &str, mgr: &TorManager<C>) -> Reply {
+        let keys: Vec<&str> = args.split_whitespace().collect();
+        if keys.is_empty() {
+            return Reply::Lines("512 Missing GETINFO key\r\n".into());
+        }
+        let mut out = String::new();
+        for key in keys {
+            let value = match key {
+                "version" => version(),
+                "status/bootstrap-phase" => {
+                    bootstrap_phase(&mgr.bootstrap_status(), mgr.is_connected().await)
+                }
+                "net/listeners/socks" => {
+                    let mut ports: Vec<u16> = mgr.get_socks_port().await.into_iter().collect();
+                    ports.extend(
+                        mgr.list_listeners()
+                            .into_iter()
+                            .filter(|l| l.active && l.spec.kind == ListenerKind::Socks)
+                            .map(|l| l.spec.port),
+                    );
+                    ports
+                        .iter()
+                        .map(|p| quote(&format!("127.0.0.1:{}", p)))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                }
+                "circuit-status" => {
+                    let streams = mgr.streams().snapshot();
+                    let circuits = circuit_entries(&streams, &mut self.circuits);
+                    out.push_str(&data_reply(
+                        key,
+                        circuits
+                            .iter()
+                            .map(|id| format!("{} BUILT PURPOSE=GENERAL", id)),
+                    ));
+                    continue;
+                }
+                "stream-status" => {
+                    let streams = mgr.streams().snapshot();
+                    let entries: Vec<String> = streams
+                        .iter()
+                        .map(|s| stream_entry(s, "SUCCEEDED", &mut self.circuits))
+                        .collect();
+                    out.push_str(&data_reply(key, entries.into_iter()));
+                    continue;
+                }
+                other => return Reply::Lines(format!("552 Unrecognized key \"{}\"\r\n", other)),
+            };
+            out.push_str(&format!("250-{}={}\r\n", key, value));
+        }
+        out.push_str("250 OK\r\n");
+        Reply::Lines(out)
+    }
+
+    /// Compare the current state with the last poll and format the events
+    /// the controller subscribed to.
+    async fn poll_events<C: TorClientBehavior>(&mut self, mgr: &TorManager<C>) -> Vec<String> {
+        let mut events = Vec::new();
+
+        let phase = bootstrap_phase(&mgr.bootstrap_status(), mgr.is_connected().await);
+        if self.seen_phase.as_ref() != Some(&phase) {
+            if self.events.status_client && self.seen_phase.is_some() {
+                events.push(format!("650 STATUS_CLIENT {}\r\n", phase));
+            }
+            self.seen_phase = Some(phase);
+        }
+
+        let streams = mgr.streams().snapshot();
+        let circuits = circuit_entries(&streams, &mut self.circuits);
+        if self.events.circ {
+            for id in circuits.iter().filter(|id| !self.seen_circuits.contains(id)) {
+                events.push(format!("650 CIRC {} BUILT PURPOSE=GENERAL\r\n", id));
+            }
+            for id in self.seen_circuits.iter().filter(|id| !circuits.contains(id)) {
+                events.push(format!("650 CIRC {} CLOSED PURPOSE=GENERAL REASON=FINISHED\r\n", id));
+            }
+        }
+        self.seen_circuits = circuits;
+
+        let current: HashMap<u64, StreamInfo> =
+            streams.into_iter().map(|s| (s.id, s)).collect();
+        if self.events.stream {
+            for (id, info) in &current {
+                if !self.seen_streams.contains_key(id) {
+                    let entry = stream_entry(info, "SUCCEEDED", &mut self.circuits);
+                    events.push(format!("650 STREAM {}\r\n", entry));
+                }
+            }
+            for (id, info) in &self.seen_streams {
+                if !current.contains_key(id) {
+                    let entry = stream_entry(info, "CLOSED", &mut self.circuits);
+                    events.push(format!("650 STREAM {}\r\n", entry));
+                }
+            }
+        }
+        self.seen_streams = current;
+
+        events
+    }
+}
+
+fn version() -> String {
+    format!("{} (Torwell84 {})", TOR_COMPAT_VERSION, env!("CARGO_PKG_VERSION"))
+}
+
+/// Format a multi-line GETINFO value.
+fn data_reply(key: &str, lines: impl Iterator<Item = String>) -> String {
+    let mut out = format!("250+{}=\r\n", key);
+    for line in lines {
+        out.push_str(&line);
+        out.push_str("\r\n");
+    }
+    out.push_str(".\r\n");
+    out
+}
+
+/// Start the control port on `127.0.0.1:config.port` and write a fresh
+/// authentication cookie.
+pub async fn start_control_server<C: TorClientBehavior>(
+    manager: SharedManager<C>,
+    config: ControlConfig,
+) -> anyhow::Result<ListenerHandle> {
+    let cookie = write_cookie(&config.cookie_path)?;
+    let creds = Arc::new(Credentials {
+        cookie,
+        cookie_path: config.cookie_path,
+        password: config.password,
+    });
+    let listener = TcpListener::bind(("127.0.0.1", config.port)).await?;
+    let port = listener.local_addr()?.port();
+
+    let task = tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((socket, peer)) => {
+                    let manager = manager.clone();
+                    let creds = creds.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_control_conn(socket, manager, creds).await {
+                            debug!("control connection {} error: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => error!("control accept error: {}", e),
+            }
+        }
+    });
+
+    Ok(ListenerHandle::new(port, vec![task.abort_handle()]))
+}
+
+async fn write_events(writer: &mut OwnedWriteHalf, events: Vec<String>) -> std::io::Result<()> {
+    for event in events {
+        writer.write_all(event.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+async fn handle_control_conn<C: TorClientBehavior>(
+    socket: TcpStream,
+    manager: SharedManager<C>,
+    creds: Arc<Credentials>,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut session = Session::new();
+    let mut ticker = tokio::time::interval(EVENT_POLL_INTERVAL);
+
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else { return Ok(()) };
+                if line.len() > MAX_LINE_LEN {
+                    return Ok(());
+                }
+                let line = line.trim_end_matches('\r');
+                if line.is_empty() {
+                    continue;
+                }
+                match session.handle(line, &manager, &creds).await {
+                    Reply::Lines(reply) => writer.write_all(reply.as_bytes()).await?,
+                    Reply::Close(reply) => {
+                        writer.write_all(reply.as_bytes()).await?;
+                        return Ok(());
+                    }
+                }
+            }
+            _ = ticker.tick(), if session.authenticated && session.events.any() => {
+                let mgr = manager.read().await.clone();
+                let events = session.poll_events(&mgr).await;
+                write_events(&mut writer, events).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn creds(password: Option<&str>) -> Credentials {
+        Credentials {
+            cookie: vec![0xab; COOKIE_LEN],
+            cookie_path: PathBuf::from("cookie"),
+            password: password.map(String::from),
+        }
+    }
+
+    #[test]
+    fn verifies_cookie_and_password() {
+        let c = creds(Some("secret"));
+        assert!(c.verify(&"ab".repeat(COOKIE_LEN)));
+        assert!(c.verify("\"secret\""));
+        // hex encoded password
+        assert!(c.verify("736563726574"));
+        assert!(!c.verify("\"wrong\""));
+        assert!(!c.verify(""));
+        assert!(!creds(None).verify("\"\""));
+        assert_eq!(creds(None).methods(), "COOKIE");
+    }
+
+    #[test]
+    fn parses_quoted_strings() {
+        assert_eq!(unquote(r#""a \"b\" \\c""#).as_deref(), Some(r#"a "b" \c"#));
+        assert!(unquote("\"open").is_none());
+        assert_eq!(quote(r#"C:\tor "x""#), r#""C:\\tor \"x\"""#);
+    }
+
+    #[test]
+    fn parses_event_masks() {
+        let mask = EventMask::parse("circ STREAM status_client").unwrap();
+        assert!(mask.circ && mask.stream && mask.status_client);
+        assert!(!EventMask::parse("").unwrap().any());
+        assert_eq!(EventMask::parse("CIRC BW"), Err("BW".to_string()));
+    }
+
+    #[test]
+    fn reports_bootstrap_phase() {
+        let status = BootstrapStatus {
+            progress: 40,
+            summary: "loading directory".into(),
+        };
+        assert_eq!(
+            bootstrap_phase(&status, false),
+            "NOTICE BOOTSTRAP PROGRESS=40 TAG=loading_descriptors SUMMARY=\"loading directory\""
+        );
+        assert_eq!(
+            bootstrap_phase(&BootstrapStatus::default(), true),
+            "NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\""
+        );
+    }
+}
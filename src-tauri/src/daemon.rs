@@ -0,0 +1,150 @@
+use crate::config::ConfigStore;
+use crate::paths;
+use crate::secure_http::SecureHttpClient;
+use crate::state::{self, AppState};
+use crate::trace;
+use arti_client::TorClient;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tor_rtcompat::PreferredRuntime;
+
+/// Command line options of the headless daemon.
+#[derive(Debug, Clone, Default)]
+pub struct DaemonOptions {
+    /// Path to `app_config.json`, defaults to [`state::config_path`]
+    pub config_path: Option<PathBuf>,
+    /// Fixed port for the SOCKS listener, overrides the config file
+    pub socks_port: Option<u16>,
+    /// Maximum level written to stderr
+    pub log_level: Option<LevelFilter>,
+}
+
+/// Writes log records to stderr, where systemd picks them up.
+struct StderrLogger {
+    level: LevelFilter,
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{} {:<5} {}: {}",
+                chrono::Utc::now().to_rfc3339(),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Run Torwell without the GUI until SIGTERM or Ctrl+C is received.
+/// The app config is reloaded when it changes on disk or on SIGHUP.
+pub fn run_daemon(opts: DaemonOptions) -> anyhow::Result<()> {
+    let level = opts.log_level.unwrap_or(LevelFilter::Info);
+    if log::set_boxed_logger(Box::new(StderrLogger { level })).is_ok() {
+        log::set_max_level(level);
+    }
+    // Set before the runtime starts so no other thread reads the environment
+    if let Some(path) = &opts.config_path {
+        std::env::set_var(state::CONFIG_PATH_ENV, path);
+    }
+
+    paths::migrate_from_working_dir();
+    trace::install();
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let config = Arc::new(ConfigStore::load(state::config_path()));
+        if let Some(port) = opts.socks_port {
+            config.set_override("socks_port", port.into())?;
+        }
+        let http_client = SecureHttpClient::init_with(config.cert_settings()).await?;
+        let state: Arc<AppState<TorClient<PreferredRuntime>>> =
+            Arc::new(AppState::with_config(http_client, config));
+        log::info!("torwelld starting, config {}", state::config_path().display());
+        state.start_control_port().await;
+        state.start_http_api().await;
+        state.start_metrics_exporter().await;
+        state.watch_config();
+        state.start_trace_capture();
+        let state_for_policy = state.clone();
+        state
+            .tor_manager
+            .read()
+            .await
+            .destination_policy()
+            .set_warning_callback(move |msg| {
+                let st = state_for_policy.clone();
+                tokio::spawn(async move {
+                    st.emit_security_warning(msg).await;
+                });
+            })
+            .await;
+
+        let mgr = state.tor_manager.read().await.clone();
+        match mgr
+            .connect_with_backoff(
+                5,
+                Duration::from_secs(120),
+                |info| log::warn!("connection attempt {} failed: {}", info.attempt, info.error),
+                |pct, msg| log::debug!("bootstrap {}%: {}", pct, msg),
+            )
+            .await
+        {
+            Ok(()) => {
+                state.mark_connected_now().await;
+                let _ = state
+                    .add_log(Level::Info, "torwelld connected".into(), None)
+                    .await;
+            }
+            // The metrics task keeps retrying in the background
+            Err(e) => log::error!("initial connect failed: {}", e),
+        }
+        if let Some(port) = mgr.get_socks_port().await {
+            log::info!("SOCKS proxy listening on 127.0.0.1:{}", port);
+        }
+
+        state.clone().start_metrics_task(None);
+        wait_for_shutdown(&state).await?;
+
+        log::info!("torwelld shutting down");
+        let mgr = state.tor_manager.read().await.clone();
+        if mgr.is_connected().await {
+            let _ = mgr.disconnect().await;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown(state: &AppState) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut term = signal(SignalKind::terminate())?;
+    let mut hup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            _ = term.recv() => return Ok(()),
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+            _ = hup.recv() => {
+                log::info!("SIGHUP received, reloading configuration");
+                state.reload().await;
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown(_state: &AppState) -> anyhow::Result<()> {
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
@@ -0,0 +1,551 @@
+use crate::socks::ListenerHandle;
+use arti_client::{ErrorKind, HasKind, TorClient};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+use tor_rtcompat::PreferredRuntime;
+
+/// Upper bound on the lifetime of cached answers. Answers without a TTL of
+/// their own are cached for this long.
+pub const DEFAULT_DNS_MAX_TTL_SECS: u64 = 60;
+/// Maximum number of cached question/answer pairs
+pub const DEFAULT_DNS_CACHE_ENTRIES: usize = 1024;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const RCODE_FORMERR: u8 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_NOTIMP: u8 = 4;
+const RCODE_REFUSED: u8 = 5;
+
+const MAX_UDP_PACKET: usize = 4096;
+/// Largest reply sent over UDP, the limit without EDNS (RFC 1035 4.2.1)
+const MAX_UDP_REPLY: usize = 512;
+/// Largest reply that fits the length prefix used over TCP
+const MAX_TCP_REPLY: usize = u16::MAX as usize;
+/// Queries resolved through Tor at once, over UDP and TCP together
+const MAX_INFLIGHT_QUERIES: usize = 64;
+/// Time a TCP client may take to send its next query
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Bounds of the pause after a failed UDP receive
+const RECV_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const RECV_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Question {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+}
+
+#[derive(Debug, Clone)]
+struct Query {
+    id: u16,
+    opcode: u8,
+    recursion_desired: bool,
+    question: Option<Question>,
+    raw_question: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Answer {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+}
+
+/// Parse a DNS query. Returns `None` for packets that do not deserve a reply.
+fn parse_query(buf: &[u8]) -> Option<Query> {
+    if buf.len() < 12 {
+        return None;
+    }
+    let flags = u16::from_be_bytes([buf[2], buf[3]]);
+    if flags & 0x8000 != 0 {
+        return None;
+    }
+    let mut query = Query {
+        id: u16::from_be_bytes([buf[0], buf[1]]),
+        opcode: ((flags >> 11) & 0x0f) as u8,
+        recursion_desired: flags & 0x0100 != 0,
+        question: None,
+        raw_question: Vec::new(),
+    };
+    if u16::from_be_bytes([buf[4], buf[5]]) != 1 {
+        return Some(query);
+    }
+
+    let mut pos = 12;
+    let mut labels = Vec::new();
+    loop {
+        let len = match buf.get(pos) {
+            Some(len) => *len as usize,
+            None => return Some(query),
+        };
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // Compression pointers are not valid in a question
+        if len & 0xc0 != 0 {
+            return Some(query);
+        }
+        match buf.get(pos..pos + len) {
+            Some(label) => labels.push(String::from_utf8_lossy(label).to_ascii_lowercase()),
+            None => return Some(query),
+        }
+        pos += len;
+    }
+    let tail = match buf.get(pos..pos + 4) {
+        Some(tail) => tail,
+        None => return Some(query),
+    };
+    query.question = Some(Question {
+        name: labels.join("."),
+        qtype: u16::from_be_bytes([tail[0], tail[1]]),
+        qclass: u16::from_be_bytes([tail[2], tail[3]]),
+    });
+    query.raw_question = buf[12..pos + 4].to_vec();
+    Some(query)
+}
+
+fn encode_name(name: &str, out: &mut Vec<u8>) {
+    for label in name.trim_end_matches('.').split('.').filter(|l| !l.is_empty()) {
+        let bytes = &label.as_bytes()[..label.len().min(63)];
+        out.push(bytes.len() as u8);
+        out.extend_from_slice(bytes);
+    }
+    out.push(0);
+}
+
+fn build_response(query: &Query, rcode: u8, answers: &[Answer], ttl: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(512);
+    out.extend_from_slice(&query.id.to_be_bytes());
+    // QR, recursion available and the opcode/RD bits of the query
+    let mut flags: u16 = 0x8000 | 0x0080 | ((query.opcode as u16) << 11) | rcode as u16;
+    if query.recursion_desired {
+        flags |= 0x0100;
+    }
+    out.extend_from_slice(&flags.to_be_bytes());
+    let has_question = query.question.is_some();
+    let answers = if has_question { answers } else { &[] };
+    out.extend_from_slice(&(has_question as u16).to_be_bytes());
+    out.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    out.extend_from_slice(&query.raw_question);
+    for answer in answers {
+        // Pointer to the name in the question section
+        out.extend_from_slice(&[0xc0, 0x0c]);
+        let (rtype, rdata) = match answer {
+            Answer::A(ip) => (TYPE_A, ip.octets().to_vec()),
+            Answer::Aaaa(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+            Answer::Ptr(name) => {
+                let mut data = Vec::new();
+                encode_name(name, &mut data);
+                (TYPE_PTR, data)
+            }
+        };
+        out.extend_from_slice(&rtype.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&ttl.to_be_bytes());
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(&rdata);
+    }
+    out
+}
+
+/// Build a successful response of at most `max_len` bytes. Answers that do
+/// not fit are left out and the TC bit is set, so that resolvers retry over
+/// TCP.
+fn build_truncated_response(
+    query: &Query,
+    answers: &[Answer],
+    ttl: u32,
+    max_len: usize,
+) -> Vec<u8> {
+    let mut count = answers.len();
+    let mut reply = build_response(query, 0, answers, ttl);
+    while reply.len() > max_len && count > 0 {
+        count -= 1;
+        reply = build_response(query, 0, &answers[..count], ttl);
+    }
+    if count < answers.len() {
+        reply[2] |= 0x02;
+    }
+    reply
+}
+
+/// Convert an `in-addr.arpa` or `ip6.arpa` name into the address it names.
+fn ptr_name_to_ip(name: &str) -> Option<IpAddr> {
+    if let Some(rest) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = rest
+            .split('.')
+            .map(|p| p.parse::<u8>().ok())
+            .collect::<Option<Vec<u8>>>()?;
+        if octets.len() != 4 {
+            return None;
+        }
+        octets.reverse();
+        return Some(IpAddr::V4(Ipv4Addr::new(
+            octets[0], octets[1], octets[2], octets[3],
+        )));
+    }
+    let rest = name.strip_suffix(".ip6.arpa")?;
+    let mut nibbles = rest
+        .split('.')
+        .map(|p| {
+            if p.len() == 1 {
+                u8::from_str_radix(p, 16).ok()
+            } else {
+                None
+            }
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    if nibbles.len() != 32 {
+        return None;
+    }
+    nibbles.reverse();
+    let mut bytes = [0u8; 16];
+    for (i, pair) in nibbles.chunks(2).enumerate() {
+        bytes[i] = (pair[0] << 4) | pair[1];
+    }
+    Some(IpAddr::V6(Ipv6Addr::from(bytes)))
+}
+
+/// Small answer cache keyed by name and record type.
+#[derive(Clone)]
+struct DnsCache {
+    entries: Arc<Mutex<HashMap<(String, u16), (Vec<Answer>, Instant)>>>,
+    max_ttl: Duration,
+    max_entries: usize,
+}
+
+impl DnsCache {
+    fn new(max_ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            max_ttl,
+            max_entries: max_entries.max(1),
+        }
+    }
+
+    /// Lifetime of an answer: its own TTL, at most the ceiling.
+    fn ttl(&self, answer_ttl: Option<u32>) -> Duration {
+        answer_ttl
+            .map(|ttl| Duration::from_secs(ttl.into()).min(self.max_ttl))
+            .unwrap_or(self.max_ttl)
+    }
+
+    /// Return cached answers together with their remaining lifetime.
+    fn get(&self, name: &str, qtype: u16, now: Instant) -> Option<(Vec<Answer>, u32)> {
+        let mut entries = self.entries.lock().unwrap_or_else(|p| p.into_inner());
+        let key = (name.to_string(), qtype);
+        match entries.get(&key) {
+            Some((answers, expires)) if *expires > now => {
+                let remaining = (*expires - now).as_secs().max(1) as u32;
+                Some((answers.clone(), remaining))
+            }
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, name: &str, qtype: u16, answers: Vec<Answer>, ttl: Duration, now: Instant) {
+        let mut entries = self.entries.lock().unwrap_or_else(|p| p.into_inner());
+        if entries.len() >= self.max_entries {
+            entries.retain(|_, (_, expires)| *expires > now);
+        }
+        if entries.len() >= self.max_entries {
+            if let Some(key) = entries
+                .iter()
+                .min_by_key(|(_, (_, expires))| *expires)
+                .map(|(k, _)| k.clone())
+            {
+                entries.remove(&key);
+            }
+        }
+        entries.insert((name.to_string(), qtype), (answers, now + ttl));
+    }
+}
+
+struct TorResolver {
+    client: TorClient<PreferredRuntime>,
+    cache: DnsCache,
+    /// Bounds the queries being resolved at once
+    inflight: Arc<Semaphore>,
+}
+
+impl TorResolver {
+    /// Resolve `question` through Tor. Returns the answers together with
+    /// their TTL, which is `None` while arti's resolver does not pass on the
+    /// TTL of Tor's RESOLVED cell.
+    async fn lookup(
+        &self,
+        question: &Question,
+    ) -> std::result::Result<(Vec<Answer>, Option<u32>), u8> {
+        let map_err = |e: arti_client::Error| {
+            log::debug!("DNS lookup for {} failed: {}", question.name, e);
+            if e.kind() == ErrorKind::RemoteHostNotFound {
+                RCODE_NXDOMAIN
+            } else {
+                RCODE_SERVFAIL
+            }
+        };
+        match question.qtype {
+            TYPE_A | TYPE_AAAA => {
+                let addrs = self.client.resolve(&question.name).await.map_err(map_err)?;
+                let answers = addrs
+                    .into_iter()
+                    .filter_map(|ip| match (ip, question.qtype) {
+                        (IpAddr::V4(v4), TYPE_A) => Some(Answer::A(v4)),
+                        (IpAddr::V6(v6), TYPE_AAAA) => Some(Answer::Aaaa(v6)),
+                        _ => None,
+                    })
+                    .collect();
+                Ok((answers, None))
+            }
+            TYPE_PTR => {
+                let ip = ptr_name_to_ip(&question.name).ok_or(RCODE_NXDOMAIN)?;
+                let names = self.client.resolve_ptr(ip).await.map_err(map_err)?;
+                Ok((names.into_iter().map(Answer::Ptr).collect(), None))
+            }
+            _ => Err(RCODE_REFUSED),
+        }
+    }
+
+    /// Reply to `packet`, truncated to `max_len` bytes.
+    async fn answer(&self, packet: &[u8], max_len: usize) -> Option<Vec<u8>> {
+        let query = parse_query(packet)?;
+        let question = match &query.question {
+            Some(q) => q.clone(),
+            None => return Some(build_response(&query, RCODE_FORMERR, &[], 0)),
+        };
+        if query.opcode != 0 {
+            return Some(build_response(&query, RCODE_NOTIMP, &[], 0));
+        }
+        if question.qclass != CLASS_IN || !matches!(question.qtype, TYPE_A | TYPE_AAAA | TYPE_PTR) {
+            return Some(build_response(&query, RCODE_REFUSED, &[], 0));
+        }
+
+        let now = Instant::now();
+        if let Some((answers, ttl)) = self.cache.get(&question.name, question.qtype, now) {
+            return Some(build_truncated_response(&query, &answers, ttl, max_len));
+        }
+        match self.lookup(&question).await {
+            Ok((answers, answer_ttl)) => {
+                let ttl = self.cache.ttl(answer_ttl);
+                self.cache
+                    .insert(&question.name, question.qtype, answers.clone(), ttl, now);
+                let ttl = ttl.as_secs() as u32;
+                Some(build_truncated_response(&query, &answers, ttl, max_len))
+            }
+            Err(rcode) => Some(build_response(&query, rcode, &[], 0)),
+        }
+    }
+}
+
+/// Start a DNSPort-style resolver on `127.0.0.1:port` (UDP and TCP) that
+/// answers A, AAAA and PTR queries through Tor. Answers are cached for their
+/// TTL, at most `max_ttl`.
+pub async fn start_dns_server(
+    client: TorClient<PreferredRuntime>,
+    port: u16,
+    max_ttl: Duration,
+) -> anyhow::Result<ListenerHandle> {
+    let udp = Arc::new(UdpSocket::bind(("127.0.0.1", port)).await?);
+    let local = udp.local_addr()?;
+    let tcp = TcpListener::bind(local).await?;
+    let resolver = Arc::new(TorResolver {
+        client,
+        cache: DnsCache::new(max_ttl, DEFAULT_DNS_CACHE_ENTRIES),
+        inflight: Arc::new(Semaphore::new(MAX_INFLIGHT_QUERIES)),
+    });
+
+    let udp_resolver = resolver.clone();
+    let udp_task = tokio::spawn(async move {
+        let mut buf = vec![0u8; MAX_UDP_PACKET];
+        let mut backoff = RECV_BACKOFF_MIN;
+        loop {
+            let (len, peer) = match udp.recv_from(&mut buf).await {
+                Ok(v) => {
+                    backoff = RECV_BACKOFF_MIN;
+                    v
+                }
+                Err(e) => {
+                    log::error!("DNS receive error: {}", e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(RECV_BACKOFF_MAX);
+                    continue;
+                }
+            };
+            // Waiting here leaves further datagrams in the socket buffer
+            let Ok(permit) = udp_resolver.inflight.clone().acquire_owned().await else {
+                return;
+            };
+            let packet = buf[..len].to_vec();
+            let socket = udp.clone();
+            let resolver = udp_resolver.clone();
+            tokio::spawn(async move {
+                if let Some(reply) = resolver.answer(&packet, MAX_UDP_REPLY).await {
+                    let _ = socket.send_to(&reply, peer).await;
+                }
+                drop(permit);
+            });
+        }
+    });
+
+    let tcp_task = tokio::spawn(async move {
+        loop {
+            match tcp.accept().await {
+                Ok((stream, _)) => {
+                    let resolver = resolver.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve_tcp(stream, resolver).await {
+                            log::debug!("DNS TCP error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => log::error!("DNS accept error: {}", e),
+            }
+        }
+    });
+
+    Ok(ListenerHandle::new(
+        local.port(),
+        vec![udp_task.abort_handle(), tcp_task.abort_handle()],
+    ))
+}
+
+/// Answer length-prefixed queries until the client closes the connection or
+/// stays idle for [`TCP_IDLE_TIMEOUT`].
+async fn serve_tcp(mut stream: TcpStream, resolver: Arc<TorResolver>) -> std::io::Result<()> {
+    loop {
+        let mut len = [0u8; 2];
+        match tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut len)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(_)) | Err(_) => return Ok(()),
+        }
+        let mut packet = vec![0u8; u16::from_be_bytes(len) as usize];
+        tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut packet))
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+        let Ok(_permit) = resolver.inflight.acquire().await else {
+            return Ok(());
+        };
+        if let Some(reply) = resolver.answer(&packet, MAX_TCP_REPLY).await {
+            stream.write_all(&(reply.len() as u16).to_be_bytes()).await?;
+            stream.write_all(&reply).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query_packet(name: &str, qtype: u16) -> Vec<u8> {
+        let mut buf = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        encode_name(name, &mut buf);
+        buf.extend_from_slice(&qtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn parses_question() {
+        let query = parse_query(&query_packet("Example.COM", TYPE_AAAA)).unwrap();
+        assert_eq!(query.id, 0x1234);
+        assert!(query.recursion_desired);
+        let q = query.question.unwrap();
+        assert_eq!(q.name, "example.com");
+        assert_eq!(q.qtype, TYPE_AAAA);
+    }
+
+    #[test]
+    fn builds_answer_with_question_pointer() {
+        let query = parse_query(&query_packet("example.com", TYPE_A)).unwrap();
+        let reply = build_response(&query, 0, &[Answer::A(Ipv4Addr::new(93, 184, 216, 34))], 60);
+        assert_eq!(&reply[0..2], &[0x12, 0x34]);
+        assert_eq!(reply[3] & 0x0f, 0);
+        assert_eq!(u16::from_be_bytes([reply[6], reply[7]]), 1);
+        assert_eq!(&reply[reply.len() - 4..], &[93, 184, 216, 34]);
+    }
+
+    #[test]
+    fn truncates_large_udp_answers() {
+        let query = parse_query(&query_packet("example.com", TYPE_AAAA)).unwrap();
+        let answers: Vec<Answer> = (0..30)
+            .map(|i| Answer::Aaaa(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i)))
+            .collect();
+        let reply = build_truncated_response(&query, &answers, 60, MAX_UDP_REPLY);
+        assert!(reply.len() <= MAX_UDP_REPLY);
+        assert_ne!(reply[2] & 0x02, 0);
+        let count = u16::from_be_bytes([reply[6], reply[7]]) as usize;
+        assert!(count > 0 && count < answers.len());
+
+        let reply = build_truncated_response(&query, &answers, 60, MAX_TCP_REPLY);
+        assert_eq!(reply[2] & 0x02, 0);
+        assert_eq!(u16::from_be_bytes([reply[6], reply[7]]), 30);
+    }
+
+    #[test]
+    fn refuses_malformed_question() {
+        let mut packet = query_packet("example.com", TYPE_A);
+        packet[5] = 2;
+        let query = parse_query(&packet).unwrap();
+        assert!(query.question.is_none());
+        let reply = build_response(&query, RCODE_FORMERR, &[], 0);
+        assert_eq!(reply[3] & 0x0f, RCODE_FORMERR);
+        assert_eq!(reply.len(), 12);
+    }
+
+    #[test]
+    fn ptr_names() {
+        assert_eq!(
+            ptr_name_to_ip("4.3.2.1.in-addr.arpa"),
+            Some("1.2.3.4".parse().unwrap())
+        );
+        let v6 = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa";
+        assert_eq!(ptr_name_to_ip(v6), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(ptr_name_to_ip("example.com"), None);
+    }
+
+    #[test]
+    fn cache_expires_entries() {
+        let cache = DnsCache::new(Duration::from_secs(10), 2);
+        let now = Instant::now();
+        let ttl = cache.ttl(None);
+        cache.insert("a", TYPE_A, vec![Answer::A(Ipv4Addr::LOCALHOST)], ttl, now);
+        let (_, ttl) = cache.get("a", TYPE_A, now + Duration::from_secs(4)).unwrap();
+        assert_eq!(ttl, 6);
+        assert!(cache.get("a", TYPE_A, now + Duration::from_secs(11)).is_none());
+        let ttl = cache.ttl(None);
+        cache.insert("b", TYPE_A, Vec::new(), ttl, now);
+        cache.insert("c", TYPE_A, Vec::new(), ttl, now + Duration::from_secs(1));
+        cache.insert("d", TYPE_A, Vec::new(), ttl, now + Duration::from_secs(2));
+        assert!(cache.get("b", TYPE_A, now).is_none());
+        assert!(cache.get("d", TYPE_A, now).is_some());
+    }
+
+    #[test]
+    fn answer_ttl_is_clamped() {
+        let cache = DnsCache::new(Duration::from_secs(300), 4);
+        assert_eq!(cache.ttl(Some(30)), Duration::from_secs(30));
+        assert_eq!(cache.ttl(Some(86400)), Duration::from_secs(300));
+        assert_eq!(cache.ttl(None), Duration::from_secs(300));
+
+        let now = Instant::now();
+        cache.insert("short", TYPE_A, Vec::new(), cache.ttl(Some(5)), now);
+        assert!(cache.get("short", TYPE_A, now + Duration::from_secs(6)).is_none());
+    }
+}
@@ -73,6 +73,9 @@ pub enum Error {
         backtrace: String,
     },
 
+    #[error("invalid value for {key}: {message}")]
+    InvalidConfig { key: String, message: String },
+
     #[error("network failure during {step}: {source_message}")]
     NetworkFailure {
         step: String,
@@ -149,6 +152,7 @@ impl From<arti_client::Error> for Error {
     }
 }
 
+#[cfg(feature = "gui")]
 impl From<tauri::Error> for Error {
     fn from(err: tauri::Error) -> Self {
         Error::Tauri(err.to_string())
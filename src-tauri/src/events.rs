@@ -0,0 +1,313 @@
+use crate::error::Result;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Number of events [`EventStream`] keeps for clients resuming a stream
+pub const EVENT_BACKLOG: usize = 256;
+
+/// Data shown in the system tray menu.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TrayStatus {
+    pub connected: bool,
+    pub memory_mb: u64,
+    pub circuits: usize,
+    /// Memory limit above which the entry is flagged
+    pub max_memory_mb: u64,
+    /// Circuit limit above which the entry is flagged
+    pub max_circuits: usize,
+    /// Warning appended to the menu, cleared by clicking it
+    pub warning: Option<String>,
+    /// Names of the connection profiles offered in the menu
+    pub profiles: Vec<String>,
+    /// Profile applied last
+    pub active_profile: Option<String>,
+}
+
+/// Receiver of the events `AppState` produces for a frontend.
+///
+/// The GUI implements this for `tauri::AppHandle`. Embedders can supply
+/// their own sink or run without one.
+pub trait EventSink: Send + Sync {
+    /// Deliver `event` with a JSON payload.
+    fn emit(&self, event: &str, payload: serde_json::Value) -> Result<()>;
+
+    /// Show the current status, e.g. in a tray menu.
+    fn update_tray(&self, _status: &TrayStatus) {}
+
+    /// Show a desktop notification.
+    fn notify(&self, _title: &str, _body: &str) {}
+}
+
+/// Emit `event` on `sink` if there is one.
+pub fn emit_event<S: Serialize>(
+    sink: Option<&Arc<dyn EventSink>>,
+    event: &str,
+    payload: S,
+) -> Result<()> {
+    match sink {
+        Some(sink) => sink.emit(event, serde_json::to_value(payload)?),
+        None => Ok(()),
+    }
+}
+
+/// Event recorded by an [`EventStream`].
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct StreamEvent {
+    /// Increasing identifier, starting at 1 in every process
+    pub id: u64,
+    pub event: String,
+    pub data: serde_json::Value,
+}
+
+/// Position in an event stream as sent to clients, `<epoch>-<id>`. The
+/// epoch is chosen per process, so ids from before a restart are told apart
+/// from current ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventId {
+    pub epoch: String,
+    pub id: u64,
+}
+
+impl fmt::Display for EventId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.epoch, self.id)
+    }
+}
+
+impl FromStr for EventId {
+    type Err = std::num::ParseIntError;
+
+    /// Ids without an epoch parse with an empty one, which matches no stream.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        let (epoch, id) = s.rsplit_once('-').unwrap_or(("", s));
+        Ok(Self {
+            epoch: epoch.to_string(),
+            id: id.parse()?,
+        })
+    }
+}
+
+/// Result of [`EventStream::subscribe`].
+pub struct Subscription {
+    /// Events to deliver before the live ones, oldest first
+    pub missed: Vec<StreamEvent>,
+    /// Whether events were lost since the client's last id, because it is
+    /// from an earlier process or no longer in the backlog. `missed` then
+    /// holds the whole backlog and the client should resync its state.
+    pub resync: bool,
+    pub receiver: broadcast::Receiver<StreamEvent>,
+}
+
+struct Backlog {
+    next_id: u64,
+    events: VecDeque<StreamEvent>,
+}
+
+/// Sink numbering every event and keeping the most recent ones so clients
+/// outside the webview can follow and resume the event stream.
+pub struct EventStream {
+    epoch: String,
+    backlog: Mutex<Backlog>,
+    sender: broadcast::Sender<StreamEvent>,
+    capacity: usize,
+}
+
+impl EventStream {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        Self {
+            epoch: format!("{:08x}", rand::random::<u32>()),
+            backlog: Mutex::new(Backlog {
+                next_id: 1,
+                events: VecDeque::with_capacity(capacity),
+            }),
+            sender,
+            capacity,
+        }
+    }
+
+    /// Epoch of this process, the first part of every [`EventId`].
+    pub fn epoch(&self) -> &str {
+        &self.epoch
+    }
+
+    /// Client facing id of `event`.
+    pub fn event_id(&self, event: &StreamEvent) -> EventId {
+        EventId {
+            epoch: self.epoch.clone(),
+            id: event.id,
+        }
+    }
+
+    /// Events after `last_id` still in the backlog, plus a receiver for all
+    /// later ones. Without `last_id` only new events are delivered. If
+    /// events after `last_id` were lost the whole backlog is replayed and
+    /// the subscription is flagged for a resync.
+    pub fn subscribe(&self, last_id: Option<&EventId>) -> Subscription {
+        let backlog = self.backlog.lock().unwrap_or_else(|p| p.into_inner());
+        let oldest = backlog
+            .events
+            .front()
+            .map_or(backlog.next_id, |event| event.id);
+        let (missed, resync) = match last_id {
+            Some(last) if last.epoch == self.epoch && last.id.saturating_add(1) >= oldest => (
+                backlog
+                    .events
+                    .iter()
+                    .filter(|e| e.id > last.id)
+                    .cloned()
+                    .collect(),
+                false,
+            ),
+            Some(_) => (backlog.events.iter().cloned().collect(), true),
+            None => (Vec::new(), false),
+        };
+        // Subscribing while holding the lock keeps the two parts gapless
+        Subscription {
+            missed,
+            resync,
+            receiver: self.sender.subscribe(),
+        }
+    }
+}
+
+impl EventSink for EventStream {
+    fn emit(&self, event: &str, payload: serde_json::Value) -> Result<()> {
+        let mut backlog = self.backlog.lock().unwrap_or_else(|p| p.into_inner());
+        let entry = StreamEvent {
+            id: backlog.next_id,
+            event: event.to_string(),
+            data: payload,
+        };
+        backlog.next_id += 1;
+        if backlog.events.len() == self.capacity {
+            backlog.events.pop_front();
+        }
+        if self.capacity > 0 {
+            backlog.events.push_back(entry.clone());
+        }
+        // No subscribers is not an error
+        let _ = self.sender.send(entry);
+        Ok(())
+    }
+}
+
+/// Forwards events to a primary sink and an [`EventStream`]. Tray updates
+/// and notifications only go to the primary sink.
+pub struct TeeSink {
+    pub primary: Arc<dyn EventSink>,
+    pub stream: Arc<EventStream>,
+}
+
+impl EventSink for TeeSink {
+    fn emit(&self, event: &str, payload: serde_json::Value) -> Result<()> {
+        let _ = self.stream.emit(event, payload.clone());
+        self.primary.emit(event, payload)
+    }
+
+    fn update_tray(&self, status: &TrayStatus) {
+        self.primary.update_tray(status);
+    }
+
+    fn notify(&self, title: &str, body: &str) {
+        self.primary.notify(title, body);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingSink {
+        events: Mutex<Vec<(String, serde_json::Value)>>,
+    }
+
+    impl EventSink for RecordingSink {
+        fn emit(&self, event: &str, payload: serde_json::Value) -> Result<()> {
+            self.events
+                .lock()
+                .unwrap()
+                .push((event.to_string(), payload));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn emits_serialized_payload() {
+        let recorder = Arc::new(RecordingSink::default());
+        let sink: Arc<dyn EventSink> = recorder.clone();
+        emit_event(Some(&sink), "streams-update", vec![1, 2]).unwrap();
+        emit_event(None, "streams-update", vec![3]).unwrap();
+        let events = recorder.events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, "streams-update");
+        assert_eq!(events[0].1, serde_json::json!([1, 2]));
+    }
+
+    #[test]
+    fn stream_resumes_after_last_id() {
+        let stream = EventStream::new(3);
+        for i in 0..5 {
+            stream.emit("metrics-update", serde_json::json!(i)).unwrap();
+        }
+        let id = |id| EventId {
+            epoch: stream.epoch().to_string(),
+            id,
+        };
+        let sub = stream.subscribe(Some(&id(3)));
+        assert_eq!(sub.missed.iter().map(|e| e.id).collect::<Vec<_>>(), vec![4, 5]);
+        assert!(!sub.resync);
+        assert_eq!(stream.subscribe(Some(&id(2))).missed.len(), 3);
+        // Only the last three events are kept, so event 2 was lost
+        let sub = stream.subscribe(Some(&id(1)));
+        assert_eq!(sub.missed.first().map(|e| e.id), Some(3));
+        assert!(sub.resync);
+        assert!(stream.subscribe(None).missed.is_empty());
+    }
+
+    #[test]
+    fn ids_from_another_process_resync() {
+        let stream = EventStream::new(3);
+        stream.emit("metrics-update", serde_json::json!(1)).unwrap();
+        let mut rx = stream.subscribe(None).receiver;
+        stream.emit("metrics-update", serde_json::json!(2)).unwrap();
+        let sent = stream.event_id(&rx.try_recv().unwrap()).to_string();
+        assert_eq!(sent, format!("{}-2", stream.epoch()));
+        let sub = stream.subscribe(Some(&sent.parse().unwrap()));
+        assert!(sub.missed.is_empty() && !sub.resync);
+
+        // Same number, but from before a restart or without an epoch
+        for stale in ["0badc0de-1", "1"] {
+            let sub = stream.subscribe(Some(&stale.parse().unwrap()));
+            assert!(sub.resync, "{stale}");
+            assert_eq!(sub.missed.len(), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn tee_forwards_to_both_sinks() {
+        let recorder = Arc::new(RecordingSink::default());
+        let stream = Arc::new(EventStream::new(EVENT_BACKLOG));
+        let mut rx = stream.subscribe(None).receiver;
+        let tee = TeeSink {
+            primary: recorder.clone(),
+            stream: stream.clone(),
+        };
+        tee.emit(
+            "tor-status-update",
+            serde_json::json!({"status": "CONNECTED"}),
+        )
+        .unwrap();
+        assert_eq!(recorder.events.lock().unwrap().len(), 1);
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.id, 1);
+        assert_eq!(event.event, "tor-status-update");
+    }
+}
@@ -0,0 +1,244 @@
+use crate::error::{Error, Result};
+use crate::listeners::StreamProfile;
+use crate::socks::{self, ListenerHandle, OpenError, SocksContext};
+use crate::streams::StreamTarget;
+use arti_client::TorClient;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tor_rtcompat::PreferredRuntime;
+
+/// A local TCP forward: connections to `127.0.0.1:listen_port` are relayed
+/// to `target_host:target_port` through Tor, like `ssh -L`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ForwardSpec {
+    pub listen_port: u16,
+    pub target_host: String,
+    pub target_port: u16,
+    /// Streams with the same group share circuits with SOCKS clients that
+    /// authenticate with this username and an empty password
+    #[serde(default)]
+    pub isolation_group: Option<String>,
+    #[serde(default)]
+    pub exit_country: Option<String>,
+}
+
+impl ForwardSpec {
+    pub fn validate(&self) -> Result<()> {
+        if self.listen_port == 0 || self.target_port == 0 {
+            return Err(Self::invalid("ports must be non-zero"));
+        }
+        let host = self.target_host.trim();
+        if host.is_empty()
+            || !host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '_'))
+        {
+            return Err(Self::invalid(format!("invalid target host '{}'", self.target_host)));
+        }
+        self.profile().validate()
+    }
+
+    fn profile(&self) -> StreamProfile {
+        StreamProfile {
+            exit_country: self.exit_country.clone(),
+            ..StreamProfile::default()
+        }
+    }
+
+    fn invalid(reason: impl ToString) -> Error {
+        Error::ConfigError {
+            step: "forward::validate".into(),
+            source_message: reason.to_string(),
+            backtrace: format!("{:?}", std::backtrace::Backtrace::capture()),
+        }
+    }
+}
+
+/// Forward as shown in the UI.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ForwardInfo {
+    #[serde(flatten)]
+    pub spec: ForwardSpec,
+    pub active: bool,
+}
+
+/// Configured forwards and the listeners of those currently running.
+#[derive(Clone, Default)]
+pub struct ForwardManager {
+    specs: Arc<Mutex<Vec<ForwardSpec>>>,
+    running: Arc<Mutex<HashMap<u16, ListenerHandle>>>,
+}
+
+impl ForwardManager {
+    pub fn with_specs(specs: Vec<ForwardSpec>) -> Self {
+        let manager = Self::default();
+        *manager.specs.lock().unwrap_or_else(|p| p.into_inner()) = specs;
+        manager
+    }
+
+    fn running(&self) -> std::sync::MutexGuard<'_, HashMap<u16, ListenerHandle>> {
+        self.running.lock().unwrap_or_else(|p| p.into_inner())
+    }
+
+    pub fn specs(&self) -> Vec<ForwardSpec> {
+        self.specs.lock().unwrap_or_else(|p| p.into_inner()).clone()
+    }
+
+    pub fn list(&self) -> Vec<ForwardInfo> {
+        let running = self.running();
+        self.specs()
+            .into_iter()
+            .map(|spec| ForwardInfo {
+                active: running.contains_key(&spec.listen_port),
+                spec,
+            })
+            .collect()
+    }
+
+    /// Add a forward. Fails if another forward already uses the port.
+    pub fn insert(&self, spec: ForwardSpec) -> Result<()> {
+        spec.validate()?;
+        let mut specs = self.specs.lock().unwrap_or_else(|p| p.into_inner());
+        if specs.iter().any(|s| s.listen_port == spec.listen_port) {
+            return Err(ForwardSpec::invalid(format!(
+                "port {} is already forwarded",
+                spec.listen_port
+            )));
+        }
+        specs.push(spec);
+        Ok(())
+    }
+
+    /// Remove a forward and stop its listener.
+    pub fn remove(&self, listen_port: u16) -> Option<ForwardSpec> {
+        self.running().remove(&listen_port);
+        let mut specs = self.specs.lock().unwrap_or_else(|p| p.into_inner());
+        let idx = specs.iter().position(|s| s.listen_port == listen_port)?;
+        Some(specs.remove(idx))
+    }
+
+    pub fn set_running(&self, handle: ListenerHandle) {
+        self.running().insert(handle.port(), handle);
+    }
+
+    /// Stop all listeners. The configured forwards are kept.
+    pub fn stop_all(&self) {
+        self.running().clear();
+    }
+}
+
+/// Start the listener of a single forward.
+pub async fn start_forward(
+    client: TorClient<PreferredRuntime>,
+    spec: ForwardSpec,
+    ctx: SocksContext,
+) -> anyhow::Result<ListenerHandle> {
+    spec.validate()?;
+    let ctx = ctx.with_profile(spec.profile());
+    let listener = TcpListener::bind(("127.0.0.1", spec.listen_port)).await?;
+    let local_addr = listener.local_addr()?;
+    let spec = Arc::new(spec);
+
+    let task = tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((socket, peer)) => {
+                    let client = client.clone();
+                    let ctx = ctx.clone();
+                    let spec = spec.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_forward_conn(
+                            socket,
+                            peer,
+                            local_addr,
+                            client,
+                            &spec,
+                            ctx,
+                        )
+                        .await
+                        {
+                            debug!("forward {} error: {}", spec.listen_port, e);
+                        }
+                    });
+                }
+                Err(e) => error!("forward accept error: {}", e),
+            }
+        }
+    });
+
+    Ok(ListenerHandle::new(local_addr.port(), vec![task.abort_handle()]))
+}
+
+async fn handle_forward_conn(
+    socket: TcpStream,
+    peer: SocketAddr,
+    local_addr: SocketAddr,
+    client: TorClient<PreferredRuntime>,
+    spec: &ForwardSpec,
+    ctx: SocksContext,
+) -> anyhow::Result<()> {
+    let key = spec.isolation_group.as_ref().map(|group| format!("{group}\u{0}"));
+    let stream = match ctx
+        .open_stream(&client, &spec.target_host, spec.target_port, key.as_deref())
+        .await
+    {
+        Ok(stream) => stream,
+        Err(OpenError::NotAllowed) => return Ok(()),
+        Err(OpenError::Unreachable(e)) => anyhow::bail!(e),
+    };
+    let target = StreamTarget {
+        host: spec.target_host.clone(),
+        port: spec.target_port,
+        isolation_group: spec.isolation_group.clone(),
+        peer,
+        pid: None,
+    };
+    socks::serve_stream(socket, stream, &ctx, target, local_addr).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(port: u16) -> ForwardSpec {
+        ForwardSpec {
+            listen_port: port,
+            target_host: "example.onion".into(),
+            target_port: 22,
+            isolation_group: None,
+            exit_country: None,
+        }
+    }
+
+    #[test]
+    fn validates_specs() {
+        assert!(spec(2222).validate().is_ok());
+        assert!(spec(0).validate().is_err());
+        let mut bad = spec(2222);
+        bad.target_host = "host name".into();
+        assert!(bad.validate().is_err());
+        let mut cc = spec(2222);
+        cc.exit_country = Some("de".into());
+        assert!(cc.validate().is_ok());
+        cc.exit_country = Some("XYZ".into());
+        assert!(cc.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_duplicate_ports() {
+        let manager = ForwardManager::default();
+        manager.insert(spec(2222)).unwrap();
+        assert!(manager.insert(spec(2222)).is_err());
+        assert_eq!(manager.list().len(), 1);
+        assert!(!manager.list()[0].active);
+        assert!(manager.remove(2222).is_some());
+        assert!(manager.remove(2222).is_none());
+    }
+}
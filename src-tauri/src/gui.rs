@@ -0,0 +1,161 @@
+use crate::api;
+use crate::error::Result;
+use crate::events::{EventSink, TrayStatus};
+use crate::instance::{Handler, InstanceCommand};
+use crate::state::AppState;
+use futures::FutureExt;
+use std::sync::Arc;
+#[cfg(target_os = "macos")]
+use tauri::NativeImage;
+use tauri::{AppHandle, CustomMenuItem, Manager, SystemTrayMenu, SystemTraySubmenu};
+
+/// Prefix of the tray menu ids of the profile entries
+pub const PROFILE_ITEM_PREFIX: &str = "profile:";
+
+/// Build the system tray menu for `status`
+pub fn build_tray_menu(status: &TrayStatus) -> SystemTrayMenu {
+    let label = if status.connected {
+        "Connected"
+    } else {
+        "Disconnected"
+    };
+    let mem_label = if status.memory_mb > status.max_memory_mb {
+        format!("Memory: {} MB \u{26A0}\u{fe0f}", status.memory_mb)
+    } else {
+        format!("Memory: {} MB", status.memory_mb)
+    };
+    let circ_label = if status.circuits > status.max_circuits {
+        format!("Circuits: {} \u{26A0}\u{fe0f}", status.circuits)
+    } else {
+        format!("Circuits: {}", status.circuits)
+    };
+
+    let mut menu = SystemTrayMenu::new()
+        .add_item(CustomMenuItem::new("status", format!("Status: {}", label)).disabled())
+        .add_item(CustomMenuItem::new("memory", mem_label).disabled())
+        .add_item(CustomMenuItem::new("circuits", circ_label).disabled())
+        .add_item(CustomMenuItem::new("show", "Show"));
+
+    if status.connected {
+        menu = menu.add_item(CustomMenuItem::new("disconnect", "Disconnect"));
+    } else {
+        menu = menu.add_item(CustomMenuItem::new("connect", "Connect"));
+    }
+
+    if !status.profiles.is_empty() {
+        let mut profiles = SystemTrayMenu::new();
+        for name in &status.profiles {
+            let mut item = CustomMenuItem::new(format!("{PROFILE_ITEM_PREFIX}{name}"), name);
+            if status.active_profile.as_ref() == Some(name) {
+                item = item.selected();
+            }
+            profiles = profiles.add_item(item);
+        }
+        menu = menu.add_submenu(SystemTraySubmenu::new("Profiles", profiles));
+    }
+
+    menu = menu
+        .add_item(CustomMenuItem::new("reconnect", "Reconnect"))
+        .add_item(CustomMenuItem::new("show_dashboard", "Show Dashboard"))
+        .add_item(CustomMenuItem::new("show_logs", "Show Logs"))
+        .add_item(CustomMenuItem::new("open_logs_file", "Open Log File"))
+        .add_item(CustomMenuItem::new("settings", "Settings"))
+        .add_item(CustomMenuItem::new(
+            "open_settings_file",
+            "Open Settings File",
+        ))
+        .add_item(CustomMenuItem::new("quit", "Quit"));
+
+    if let Some(w) = &status.warning {
+        let mut item =
+            CustomMenuItem::new("warning", format!("\u{26A0}\u{FE0F} {}", w)).disabled();
+        #[cfg(target_os = "macos")]
+        {
+            item = item.native_image(NativeImage::Caution);
+        }
+        menu = menu.add_item(item);
+    }
+
+    menu
+}
+
+impl EventSink for AppHandle {
+    fn emit(&self, event: &str, payload: serde_json::Value) -> Result<()> {
+        self.emit_all(event, payload)?;
+        Ok(())
+    }
+
+    fn update_tray(&self, status: &TrayStatus) {
+        let tray = self.tray_handle();
+        // Fully recreate the tray menu to avoid stale entries
+        let _ = tray.set_menu(SystemTrayMenu::new());
+        let _ = tray.set_menu(build_tray_menu(status));
+    }
+
+    fn notify(&self, title: &str, body: &str) {
+        #[cfg(target_os = "windows")]
+        {
+            use winrt_notification::{Duration, Toast};
+            let _ = Toast::new(Toast::POWERSHELL_APP_ID)
+                .title(title)
+                .text1(body)
+                .duration(Duration::Short)
+                .show();
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::process::Command;
+            let _ = Command::new("notify-send").arg(title).arg(body).output();
+        }
+
+        #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+        {
+            let _ = tauri::api::notification::Notification::new(
+                &self.config().tauri.bundle.identifier,
+            )
+            .title(title)
+            .body(body)
+            .show();
+        }
+    }
+}
+
+/// Execute commands given on the command line or forwarded by later
+/// launches, see [`crate::instance`].
+pub fn instance_handler(app: AppHandle) -> Handler {
+    Arc::new(move |command: InstanceCommand| {
+        let app = app.clone();
+        async move {
+            let state = (*app.state::<AppState>()).clone();
+            let sink: Option<Arc<dyn EventSink>> = Some(Arc::new(app.clone()));
+            let result = match command {
+                InstanceCommand::Connect => api::connect(sink, &state)
+                    .await
+                    .map(|_| "connected".to_string()),
+                InstanceCommand::Disconnect => api::disconnect(sink, &state)
+                    .await
+                    .map(|_| "disconnected".to_string()),
+                InstanceCommand::NewIdentity => api::new_identity(sink, &state)
+                    .await
+                    .map(|_| "new identity requested".to_string()),
+                InstanceCommand::Show => {
+                    if let Some(window) = app.get_window("main") {
+                        let _ = window.show();
+                        let _ = window.set_focus();
+                    }
+                    Ok("shown".to_string())
+                }
+                InstanceCommand::Status => api::get_status(&state).await,
+            };
+            if matches!(
+                command,
+                InstanceCommand::Connect | InstanceCommand::Disconnect
+            ) {
+                state.update_tray_menu().await;
+            }
+            result.map_err(|e| e.to_string())
+        }
+        .boxed()
+    })
+}
@@ -0,0 +1,315 @@
+//! Bounded history of settings snapshots. A snapshot is taken after every
+//! command that changes routing, listener, exit rule or system proxy
+//! settings and is marked as known good once a connection with those
+//! settings succeeds, so an edit that breaks bootstrapping can be rolled
+//! back. The history is kept in `history.json` in the data directory.
+
+use crate::error::{Error, Result};
+use crate::paths::paths;
+use crate::profiles::Profile;
+use crate::settings::write_atomic;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Number of snapshots kept
+pub const HISTORY_LIMIT: usize = 50;
+
+/// Location of the history file.
+pub fn history_path() -> PathBuf {
+    paths().data_dir.join("history.json")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    /// Command that produced these settings
+    pub command: String,
+    /// Settings after the command. `name` is the profile active at the
+    /// time, or empty.
+    pub settings: Profile,
+    /// Whether a connection with these settings succeeded
+    #[serde(default)]
+    pub known_good: bool,
+}
+
+/// A setting that differs between two snapshots. Nested keys are joined
+/// with `.`; `None` means the key is absent on that side.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SettingChange {
+    pub key: String,
+    pub old: Option<serde_json::Value>,
+    pub new: Option<serde_json::Value>,
+}
+
+fn flatten(prefix: &str, value: serde_json::Value, out: &mut BTreeMap<String, serde_json::Value>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                flatten(&key, value, out);
+            }
+        }
+        value => {
+            out.insert(prefix.to_string(), value);
+        }
+    }
+}
+
+fn flat(value: serde_json::Value) -> BTreeMap<String, serde_json::Value> {
+    let mut out = BTreeMap::new();
+    flatten("", value, &mut out);
+    out
+}
+
+/// Settings changed from `old` to `new`, sorted by key.
+pub fn diff(old: &Profile, new: &Profile) -> Vec<SettingChange> {
+    diff_values(
+        &serde_json::to_value(old).unwrap_or_default(),
+        &serde_json::to_value(new).unwrap_or_default(),
+    )
+}
+
+/// Leaves of two JSON documents that differ, sorted by key.
+pub fn diff_values(old: &serde_json::Value, new: &serde_json::Value) -> Vec<SettingChange> {
+    let mut old = flat(old.clone());
+    let mut changes = Vec::new();
+    for (key, value) in flat(new.clone()) {
+        match old.remove(&key) {
+            Some(previous) if previous == value => {}
+            previous => changes.push(SettingChange {
+                key,
+                old: previous,
+                new: Some(value),
+            }),
+        }
+    }
+    changes.extend(old.into_iter().map(|(key, value)| SettingChange {
+        key,
+        old: Some(value),
+        new: None,
+    }));
+    changes.sort_by(|a, b| a.key.cmp(&b.key));
+    changes
+}
+
+/// History file and its current contents, oldest snapshot first.
+pub struct ConfigHistory {
+    path: PathBuf,
+    limit: usize,
+    snapshots: Mutex<Vec<Snapshot>>,
+}
+
+impl ConfigHistory {
+    /// Load the history at `path`. A missing or unreadable file starts an
+    /// empty history.
+    pub fn open(path: PathBuf, limit: usize) -> Self {
+        let snapshots = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                log::warn!("ignoring config history {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                log::warn!("failed to read config history {}: {}", path.display(), e);
+                Vec::new()
+            }
+        };
+        Self {
+            path,
+            limit: limit.max(1),
+            snapshots: Mutex::new(snapshots),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn list(&self) -> Vec<Snapshot> {
+        self.snapshots
+            .lock()
+            .unwrap_or_else(|p| p.into_inner())
+            .clone()
+    }
+
+    pub fn get(&self, id: u64) -> Result<Snapshot> {
+        self.list()
+            .into_iter()
+            .find(|s| s.id == id)
+            .ok_or_else(|| Error::InvalidConfig {
+                key: "snapshot".into(),
+                message: format!("unknown snapshot {id}"),
+            })
+    }
+
+    /// Add a snapshot of `settings` made by `command`. Nothing is recorded
+    /// if they equal the newest snapshot. Returns the new snapshot.
+    pub fn record(&self, command: &str, settings: Profile) -> Option<Snapshot> {
+        let mut snapshots = self.snapshots.lock().unwrap_or_else(|p| p.into_inner());
+        let snapshot = self.push(&mut snapshots, command, settings)?;
+        self.save(&snapshots);
+        Some(snapshot)
+    }
+
+    /// Mark `settings` as known good after a successful connection,
+    /// recording them first if they are not the newest snapshot. Both happen
+    /// under one lock, so a snapshot recorded concurrently by another
+    /// command is not marked in their place.
+    pub fn mark_good(&self, settings: Profile) {
+        let mut snapshots = self.snapshots.lock().unwrap_or_else(|p| p.into_inner());
+        let recorded = self
+            .push(&mut snapshots, "connect", settings.clone())
+            .is_some();
+        let Some(snapshot) = snapshots.iter_mut().rev().find(|s| s.settings == settings) else {
+            return;
+        };
+        if recorded || !snapshot.known_good {
+            snapshot.known_good = true;
+            self.save(&snapshots);
+        }
+    }
+
+    fn push(
+        &self,
+        snapshots: &mut Vec<Snapshot>,
+        command: &str,
+        settings: Profile,
+    ) -> Option<Snapshot> {
+        if snapshots.last().map(|s| &s.settings) == Some(&settings) {
+            return None;
+        }
+        let snapshot = Snapshot {
+            id: snapshots.last().map_or(1, |s| s.id + 1),
+            timestamp: Utc::now(),
+            command: command.to_string(),
+            settings,
+            known_good: false,
+        };
+        snapshots.push(snapshot.clone());
+        self.trim(snapshots);
+        Some(snapshot)
+    }
+
+    /// Newest snapshot that connected successfully.
+    pub fn last_good(&self) -> Option<Snapshot> {
+        self.list().into_iter().rev().find(|s| s.known_good)
+    }
+
+    /// Snapshot to roll back to after a failed connect: the last known good
+    /// one, provided the settings changed since.
+    pub fn rollback_candidate(&self) -> Option<Snapshot> {
+        let last = self.list().pop()?;
+        if last.known_good {
+            return None;
+        }
+        self.last_good()
+    }
+
+    /// Drop the oldest snapshots beyond the limit, keeping the newest known
+    /// good one so a rollback stays possible.
+    fn trim(&self, snapshots: &mut Vec<Snapshot>) {
+        let keep = snapshots.iter().rev().find(|s| s.known_good).map(|s| s.id);
+        while snapshots.len() > self.limit {
+            let index = if snapshots.first().map(|s| s.id) == keep {
+                1
+            } else {
+                0
+            };
+            snapshots.remove(index);
+        }
+    }
+
+    fn save(&self, snapshots: &[Snapshot]) {
+        let result = serde_json::to_vec_pretty(snapshots)
+            .map_err(std::io::Error::from)
+            .and_then(|data| write_atomic(&self.path, &data));
+        if let Err(e) = result {
+            log::warn!(
+                "failed to write config history {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::ExitRoutingConfig;
+    use crate::settings::RoutingSettings;
+
+    fn settings(bridges: &[&str]) -> Profile {
+        Profile {
+            name: String::new(),
+            routing: RoutingSettings {
+                bridges: bridges.iter().map(|b| b.to_string()).collect(),
+                ..Default::default()
+            },
+            listeners: Vec::new(),
+            exit_rules: ExitRoutingConfig::default(),
+            system_proxy_enabled: true,
+        }
+    }
+
+    #[test]
+    fn rollback_targets_last_good_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = ConfigHistory::open(dir.path().join("history.json"), 3);
+        history.mark_good(settings(&[]));
+        assert!(history.rollback_candidate().is_none());
+        assert!(history.record("set_bridges", settings(&[])).is_none());
+
+        for (i, bridge) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            let snapshot = history.record("set_bridges", settings(&[bridge])).unwrap();
+            assert_eq!(snapshot.id, i as u64 + 2);
+        }
+        let ids: Vec<u64> = history.list().iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![1, 4, 5]);
+        assert_eq!(history.rollback_candidate().unwrap().id, 1);
+
+        let reopened = ConfigHistory::open(dir.path().join("history.json"), 3);
+        assert_eq!(reopened.list(), history.list());
+    }
+
+    #[test]
+    fn marks_only_the_connected_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = ConfigHistory::open(dir.path().join("history.json"), 10);
+        history.record("set_bridges", settings(&["a"]));
+        history.record("set_bridges", settings(&["b"]));
+        history.mark_good(settings(&["a"]));
+        let marked: Vec<(Vec<String>, bool)> = history
+            .list()
+            .into_iter()
+            .map(|s| (s.settings.routing.bridges, s.known_good))
+            .collect();
+        assert_eq!(
+            marked,
+            vec![
+                (vec!["a".to_string()], false),
+                (vec!["b".to_string()], false),
+                (vec!["a".to_string()], true),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_lists_changed_keys() {
+        let mut new = settings(&["obfs4 1.2.3.4:443"]);
+        new.routing.exit_country = Some("DE".into());
+        let changes = diff(&settings(&[]), &new);
+        let keys: Vec<&str> = changes.iter().map(|c| c.key.as_str()).collect();
+        assert_eq!(keys, vec!["routing.bridges", "routing.exit_country"]);
+        assert_eq!(changes[1].old, Some(serde_json::Value::Null));
+        assert!(diff(&new, &new).is_empty());
+    }
+}
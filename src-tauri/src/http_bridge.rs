@@ -1,23 +1,505 @@
+//! Local REST API mirroring the Tauri commands.
+//!
+//! Every request under [`API_PREFIX`] needs an `Authorization: Bearer`
+//! header carrying a session token, or an `access_token` query parameter for
+//! clients such as `EventSource` that cannot set headers. When the listener
+//! starts it writes a token to [`api_token_path`] and keeps it alive while
+//! running; further tokens can be created through `POST /api/v1/session`.
+
+use crate::api;
+use crate::bundle::{ImportMode, ImportPreview};
+use crate::config::EffectiveConfig;
+use crate::error::Error;
+use crate::events::{EventId, EventSink};
+use crate::forward::ForwardSpec;
+use crate::history::{SettingChange, Snapshot};
+use crate::listeners::ListenerSpec;
+use crate::paths::paths;
+use crate::policy::DestinationPolicyConfig;
+use crate::profiles::{Profile, Profiles};
+use crate::routing::ExitRoutingConfig;
+use crate::shaping::{BandwidthLimits, RateLimit};
+use crate::socks::ListenerHandle;
 use crate::state::AppState;
-use axum::http::StatusCode;
 use axum::{
-    extract::Extension,
-    routing::{get, post},
-    Json, Router,
+    extract::{Path, Query, Request, State},
+    handler::Handler,
+    http::{header::AUTHORIZATION, HeaderMap, Method, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, on, MethodFilter},
+    Extension, Json, Router,
 };
-use serde::Deserialize;
-use std::{net::SocketAddr, sync::Arc};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Port the mobile build listens on when no `api_port` is configured
+pub const DEFAULT_PORT: u16 = 1421;
+/// Path prefix of all API routes
+pub const API_PREFIX: &str = "/api/v1";
+/// File holding the bearer token of the running listener.
+pub fn api_token_path() -> PathBuf {
+    paths().data_dir.join("api_token")
+}
+
+fn write_token(path: &std::path::Path, token: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    std::io::Write::write_all(&mut options.open(path)?, token.as_bytes())
+}
+
+/// Removes the token file once the listener stops.
+struct TokenFileGuard(PathBuf);
+
+impl Drop for TokenFileGuard {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Session token of the current request, inserted by [`require_token`].
+#[derive(Clone)]
+struct Token(String);
+
+/// [`Error`] rendered as a JSON response with a matching status code.
+struct ApiError(Error);
+
+impl From<Error> for ApiError {
+    fn from(err: Error) -> Self {
+        Self(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match &self.0 {
+            Error::InvalidToken => StatusCode::UNAUTHORIZED,
+            Error::RateLimitExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::NotConnected | Error::AlreadyConnected => StatusCode::CONFLICT,
+            Error::BridgeParse(_)
+            | Error::ConfigError { .. }
+            | Error::InvalidConfig { .. }
+            | Error::InsecureScheme { .. } => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = serde_json::json!({ "error": self.0.to_string(), "kind": self.0 });
+        (status, Json(body)).into_response()
+    }
+}
+
+type ApiResult<T> = std::result::Result<Json<T>, ApiError>;
+type Empty = std::result::Result<StatusCode, ApiError>;
+
+fn json<T>(res: crate::error::Result<T>) -> ApiResult<T> {
+    Ok(Json(res?))
+}
+
+fn empty(res: crate::error::Result<()>) -> Empty {
+    res?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    access_token: Option<String>,
+}
+
+async fn require_token(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|t| t.trim().to_string())
+        .or_else(|| {
+            Query::<TokenQuery>::try_from_uri(req.uri())
+                .ok()
+                .and_then(|q| q.0.access_token)
+        });
+    match token {
+        Some(token) if state.validate_session(&token).await => {
+            req.extensions_mut().insert(Token(token));
+            next.run(req).await
+        }
+        _ => ApiError(Error::InvalidToken).into_response(),
+    }
+}
+
+/// Sink receiving the events of commands started through the API.
+async fn sink(state: &AppState) -> Option<Arc<dyn EventSink>> {
+    state.event_sink.lock().await.clone()
+}
+
+#[derive(Serialize)]
+struct SessionResponse {
+    token: String,
+}
+
+async fn create_session(State(state): State<AppState>) -> ApiResult<SessionResponse> {
+    api::check_api_rate()?;
+    Ok(Json(SessionResponse {
+        token: state.create_session().await,
+    }))
+}
+
+async fn revoke_session(
+    State(state): State<AppState>,
+    Extension(token): Extension<Token>,
+) -> Empty {
+    state.session.revoke(&token.0).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn connect(State(state): State<AppState>) -> Empty {
+    empty(api::connect(sink(&state).await, &state).await)
+}
+
+async fn disconnect(State(state): State<AppState>) -> Empty {
+    empty(api::disconnect(sink(&state).await, &state).await)
+}
+
+async fn reconnect(State(state): State<AppState>) -> Empty {
+    empty(api::reconnect(sink(&state).await, &state).await)
+}
+
+async fn get_status(State(state): State<AppState>) -> ApiResult<String> {
+    json(api::get_status(&state).await)
+}
+
+async fn get_status_summary(State(state): State<AppState>) -> ApiResult<api::StatusSummary> {
+    json(api::get_status_summary(&state).await)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EventsQuery {
+    /// Resume point for clients that cannot send `Last-Event-ID`
+    last_event_id: Option<String>,
+    /// Comma separated event names to deliver, all if unset
+    events: Option<String>,
+}
+
+/// Server-sent events carrying the payloads emitted to the webview.
+/// Reconnecting with `Last-Event-ID` replays events still in the backlog.
+/// If events were lost in between, for example because the id is from
+/// before a restart, a `resync` event precedes the replayed backlog.
+async fn event_stream(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(q): Query<EventsQuery>,
+) -> Sse<impl Stream<Item = std::result::Result<Event, Infallible>>> {
+    let last_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .or(q.last_event_id)
+        .and_then(|v| v.parse::<EventId>().ok());
+    let names: Option<Vec<String>> = q.events.map(|list| {
+        list.split(',')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect()
+    });
+
+    let sub = state.event_stream.subscribe(last_id.as_ref());
+    let resync = sub.resync.then(|| {
+        Ok(Event::default()
+            .event("resync")
+            .data(serde_json::json!({ "epoch": state.event_stream.epoch() }).to_string()))
+    });
+    let live = futures::stream::unfold(sub.receiver, |mut rx| async move {
+        match rx.recv().await {
+            Ok(event) => Some((event, rx)),
+            // A lagging client reconnects and resumes from the backlog
+            Err(_) => None,
+        }
+    });
+    let events = state.event_stream.clone();
+    let stream = futures::stream::iter(sub.missed)
+        .chain(live)
+        .filter(move |e| {
+            futures::future::ready(match &names {
+                Some(names) => names.contains(&e.event),
+                None => true,
+            })
+        })
+        .map(move |e| {
+            Ok(Event::default()
+                .id(events.event_id(&e).to_string())
+                .event(e.event)
+                .data(e.data.to_string()))
+        });
+    Sse::new(futures::stream::iter(resync).chain(stream)).keep_alive(KeepAlive::default())
+}
+
+#[derive(Deserialize)]
+struct LimitQuery {
+    limit: Option<usize>,
+}
+
+async fn get_connection_timeline(
+    State(state): State<AppState>,
+    Query(q): Query<LimitQuery>,
+) -> ApiResult<Vec<crate::state::ConnectionEventSnapshot>> {
+    json(api::get_connection_timeline(&state, q.limit).await)
+}
+
+async fn get_connection_health_summary(
+    State(state): State<AppState>,
+) -> ApiResult<crate::state::ConnectionHealthSummary> {
+    json(api::get_connection_health_summary(&state).await)
+}
+
+async fn list_circuits(State(state): State<AppState>) -> ApiResult<Vec<u64>> {
+    json(api::list_circuits(&state).await)
+}
+
+async fn build_circuit(State(state): State<AppState>) -> Empty {
+    empty(api::build_circuit(sink(&state).await, &state).await)
+}
+
+async fn close_circuit(State(state): State<AppState>, Path(id): Path<u64>) -> Empty {
+    empty(api::close_circuit(&state, id).await)
+}
+
+async fn get_active_circuit(
+    State(state): State<AppState>,
+) -> ApiResult<Vec<crate::tor_manager::RelayInfo>> {
+    json(api::get_active_circuit(&state).await)
+}
+
+async fn get_circuit_policy_report(
+    State(state): State<AppState>,
+) -> ApiResult<crate::tor_manager::CircuitPolicyReport> {
+    json(api::get_circuit_policy_report(&state).await)
+}
+
+#[derive(Deserialize)]
+struct DomainQuery {
+    domain: String,
+}
+
+async fn get_isolated_circuit(
+    State(state): State<AppState>,
+    Query(q): Query<DomainQuery>,
+) -> ApiResult<Vec<crate::tor_manager::RelayInfo>> {
+    json(api::get_isolated_circuit(&state, q.domain).await)
+}
+
+async fn new_identity(State(state): State<AppState>) -> Empty {
+    empty(api::new_identity(sink(&state).await, &state).await)
+}
+
+#[derive(Deserialize)]
+struct CountryPayload {
+    country: Option<String>,
+}
 
-async fn status(Extension(state): Extension<Arc<AppState>>) -> Json<&'static str> {
-    let s = if {
-        let mgr = state.tor_manager.read().await.clone();
-        mgr.is_connected().await
-    } {
-        "CONNECTED"
-    } else {
-        "DISCONNECTED"
-    };
-    Json(s)
+async fn set_exit_country(
+    State(state): State<AppState>,
+    Json(body): Json<CountryPayload>,
+) -> Empty {
+    empty(api::set_exit_country(&state, body.country).await)
+}
+
+async fn set_entry_country(
+    State(state): State<AppState>,
+    Json(body): Json<CountryPayload>,
+) -> Empty {
+    empty(api::set_entry_country(&state, body.country).await)
+}
+
+async fn set_middle_country(
+    State(state): State<AppState>,
+    Json(body): Json<CountryPayload>,
+) -> Empty {
+    empty(api::set_middle_country(&state, body.country).await)
+}
+
+async fn lookup_country(
+    State(state): State<AppState>,
+    Path(ip): Path<String>,
+) -> ApiResult<String> {
+    json(api::lookup_country(&state, ip).await)
+}
+
+#[derive(Deserialize)]
+struct BridgesPayload {
+    bridges: Vec<String>,
+}
+
+async fn set_bridges(State(state): State<AppState>, Json(body): Json<BridgesPayload>) -> Empty {
+    empty(api::set_bridges(&state, body.bridges).await)
+}
+
+async fn list_bridge_presets() -> ApiResult<Vec<crate::tor_manager::BridgePreset>> {
+    json(api::list_bridge_presets().await)
+}
+
+#[derive(Deserialize)]
+struct TorrcPayload {
+    config: String,
+}
+
+async fn set_torrc_config(State(state): State<AppState>, Json(body): Json<TorrcPayload>) -> Empty {
+    empty(api::set_torrc_config(&state, body.config).await)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TorrcProfilePayload {
+    #[serde(default)]
+    fast_only: bool,
+    #[serde(default)]
+    preferred_fast_countries: Option<Vec<String>>,
+    #[serde(default)]
+    include_bridges: bool,
+}
+
+async fn generate_torrc_profile(
+    State(state): State<AppState>,
+    Json(body): Json<TorrcProfilePayload>,
+) -> ApiResult<crate::tor_manager::TorrcProfile> {
+    json(
+        api::generate_torrc_profile(
+            &state,
+            body.fast_only,
+            body.preferred_fast_countries,
+            body.include_bridges,
+        )
+        .await,
+    )
+}
+
+async fn get_destination_policy(
+    State(state): State<AppState>,
+) -> ApiResult<DestinationPolicyConfig> {
+    json(api::get_destination_policy(&state).await)
+}
+
+async fn set_destination_policy(
+    State(state): State<AppState>,
+    Json(policy): Json<DestinationPolicyConfig>,
+) -> Empty {
+    empty(api::set_destination_policy(&state, policy).await)
+}
+
+async fn get_exit_rules(State(state): State<AppState>) -> ApiResult<ExitRoutingConfig> {
+    json(api::get_exit_rules(&state).await)
+}
+
+async fn set_exit_rules(
+    State(state): State<AppState>,
+    Json(rules): Json<ExitRoutingConfig>,
+) -> Empty {
+    empty(api::set_exit_rules(&state, rules).await)
+}
+
+async fn list_profiles(State(state): State<AppState>) -> ApiResult<Profiles> {
+    json(api::list_profiles(&state).await)
+}
+
+async fn save_profile(State(state): State<AppState>, Json(profile): Json<Profile>) -> Empty {
+    empty(api::save_profile(&state, profile).await)
+}
+
+#[derive(Deserialize)]
+struct NamePayload {
+    name: String,
+}
+
+async fn save_current_profile(
+    State(state): State<AppState>,
+    Json(body): Json<NamePayload>,
+) -> ApiResult<Profile> {
+    json(api::save_current_profile(&state, body.name).await)
+}
+
+async fn duplicate_profile(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(body): Json<NamePayload>,
+) -> ApiResult<Profile> {
+    json(api::duplicate_profile(&state, name, body.name).await)
+}
+
+async fn delete_profile(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> ApiResult<bool> {
+    json(api::delete_profile(&state, name).await)
+}
+
+async fn export_profile(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> ApiResult<String> {
+    json(api::export_profile(&state, name).await)
+}
+
+/// The body is a profile as returned by `export_profile`.
+async fn import_profile(State(state): State<AppState>, body: String) -> ApiResult<Profile> {
+    json(api::import_profile(&state, body).await)
+}
+
+async fn apply_profile(State(state): State<AppState>, Path(name): Path<String>) -> Empty {
+    empty(api::apply_profile(sink(&state).await, &state, name).await)
+}
+
+async fn list_config_history(State(state): State<AppState>) -> ApiResult<Vec<Snapshot>> {
+    json(api::list_config_history(&state).await)
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    to: Option<u64>,
+}
+
+async fn diff_config_snapshots(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Query(query): Query<DiffQuery>,
+) -> ApiResult<Vec<SettingChange>> {
+    json(api::diff_config_snapshots(&state, id, query.to).await)
+}
+
+async fn restore_config_snapshot(State(state): State<AppState>, Path(id): Path<u64>) -> Empty {
+    empty(api::restore_config_snapshot(sink(&state).await, &state, id).await)
+}
+
+async fn rollback_config(State(state): State<AppState>) -> ApiResult<Snapshot> {
+    json(api::rollback_config(sink(&state).await, &state).await)
+}
+
+#[derive(Deserialize)]
+struct EnabledPayload {
+    enabled: bool,
+}
+
+async fn get_system_proxy_status(State(state): State<AppState>) -> ApiResult<bool> {
+    json(api::get_system_proxy_status(&state).await)
+}
+
+async fn toggle_system_proxy(
+    State(state): State<AppState>,
+    Json(body): Json<EnabledPayload>,
+) -> Empty {
+    empty(api::toggle_system_proxy(sink(&state).await, &state, body.enabled).await)
 }
 
 #[derive(Deserialize)]
@@ -27,40 +509,679 @@ struct WorkerPayload {
     token: Option<String>,
 }
 
-async fn set_workers(
-    Extension(state): Extension<Arc<AppState>>,
-    Json(payload): Json<WorkerPayload>,
-) -> StatusCode {
-    state
-        .http_client
-        .set_worker_config(payload.workers, payload.token)
-        .await;
-    StatusCode::NO_CONTENT
-}
-
-async fn validate_worker(Extension(state): Extension<Arc<AppState>>) -> Json<bool> {
-    let res = state
-        .http_client
-        .get_text("https://example.com")
-        .await
-        .is_ok();
-    Json(res)
-}
-
-pub fn start(state: AppState) {
-    let router = Router::new()
-        .route("/status", get(status))
-        .route("/workers", post(set_workers))
-        .route("/validate", get(validate_worker))
-        .layer(Extension(Arc::new(state)));
-
-    tauri::async_runtime::spawn(async move {
-        let addr = SocketAddr::from(([127, 0, 0, 1], 1421));
-        if let Err(e) = axum::Server::bind(&addr)
-            .serve(router.into_make_service())
-            .await
-        {
-            eprintln!("HTTP bridge failed: {e}");
+async fn set_worker_config(
+    State(state): State<AppState>,
+    Json(body): Json<WorkerPayload>,
+) -> Empty {
+    empty(api::set_worker_config(&state, body.workers, body.token).await)
+}
+
+async fn validate_worker_token(State(state): State<AppState>) -> ApiResult<bool> {
+    json(api::validate_worker_token(&state).await)
+}
+
+#[derive(Deserialize)]
+struct HsmPayload {
+    #[serde(default)]
+    lib: Option<String>,
+    #[serde(default)]
+    slot: Option<u64>,
+}
+
+async fn set_hsm_config(State(state): State<AppState>, Json(body): Json<HsmPayload>) -> Empty {
+    empty(api::set_hsm_config(&state, body.lib, body.slot).await)
+}
+
+#[derive(Deserialize)]
+struct IntervalPayload {
+    interval: u64,
+}
+
+async fn set_update_interval(
+    State(state): State<AppState>,
+    Json(body): Json<IntervalPayload>,
+) -> Empty {
+    empty(api::set_update_interval(&state, body.interval).await)
+}
+
+#[derive(Deserialize)]
+struct PathPayload {
+    path: Option<String>,
+}
+
+async fn set_geoip_path(State(state): State<AppState>, Json(body): Json<PathPayload>) -> Empty {
+    empty(api::set_geoip_path(&state, body.path).await)
+}
+
+#[derive(Deserialize)]
+struct HostsPayload {
+    hosts: Vec<String>,
+}
+
+async fn set_insecure_hosts(
+    State(state): State<AppState>,
+    Json(body): Json<HostsPayload>,
+) -> Empty {
+    empty(api::set_insecure_hosts(&state, body.hosts).await)
+}
+
+async fn list_streams(State(state): State<AppState>) -> ApiResult<Vec<crate::streams::StreamInfo>> {
+    json(api::list_streams(&state).await)
+}
+
+async fn close_stream(State(state): State<AppState>, Path(id): Path<u64>) -> ApiResult<bool> {
+    json(api::close_stream(&state, id).await)
+}
+
+#[derive(Deserialize)]
+struct DestinationPayload {
+    host: String,
+    #[serde(default)]
+    port: Option<u16>,
+}
+
+async fn close_streams_to(
+    State(state): State<AppState>,
+    Json(body): Json<DestinationPayload>,
+) -> ApiResult<usize> {
+    json(api::close_streams_to(&state, body.host, body.port).await)
+}
+
+async fn get_bandwidth_limits(State(state): State<AppState>) -> ApiResult<BandwidthLimits> {
+    json(api::get_bandwidth_limits(&state).await)
+}
+
+async fn set_bandwidth_limits(
+    State(state): State<AppState>,
+    Json(limits): Json<BandwidthLimits>,
+) -> Empty {
+    empty(api::set_bandwidth_limits(&state, limits).await)
+}
+
+async fn set_stream_bandwidth_limit(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(limit): Json<RateLimit>,
+) -> Empty {
+    empty(api::set_stream_bandwidth_limit(&state, id, limit).await)
+}
+
+async fn get_logs(
+    State(state): State<AppState>,
+    Extension(token): Extension<Token>,
+) -> ApiResult<Vec<crate::state::LogEntry>> {
+    json(api::get_logs(&state, token.0).await)
+}
+
+#[derive(Deserialize)]
+struct LogPageQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn get_logs_page(
+    State(state): State<AppState>,
+    Extension(token): Extension<Token>,
+    Query(q): Query<LogPageQuery>,
+) -> ApiResult<crate::logstore::LogPage> {
+    json(api::get_logs_page(&state, token.0, q.cursor, q.limit).await)
+}
+
+async fn query_logs(
+    State(state): State<AppState>,
+    Extension(token): Extension<Token>,
+    Json(query): Json<crate::logstore::LogQuery>,
+) -> ApiResult<crate::logstore::LogQueryPage> {
+    json(api::query_logs(&state, token.0, query).await)
+}
+
+async fn clear_logs(State(state): State<AppState>, Extension(token): Extension<Token>) -> Empty {
+    empty(api::clear_logs(&state, token.0).await)
+}
+
+async fn get_log_file_path(
+    State(state): State<AppState>,
+    Extension(token): Extension<Token>,
+) -> ApiResult<String> {
+    json(api::get_log_file_path(&state, token.0).await)
+}
+
+async fn get_effective_config(State(state): State<AppState>) -> ApiResult<EffectiveConfig> {
+    json(api::get_effective_config(&state).await)
+}
+
+#[derive(Deserialize)]
+struct LogLimitPayload {
+    limit: usize,
+}
+
+async fn set_log_limit(State(state): State<AppState>, Json(body): Json<LogLimitPayload>) -> Empty {
+    empty(api::set_log_limit(&state, body.limit).await)
+}
+
+#[derive(Deserialize)]
+struct RedactionPayload {
+    level: crate::redact::RedactionLevel,
+}
+
+async fn set_log_redaction(
+    State(state): State<AppState>,
+    Json(body): Json<RedactionPayload>,
+) -> Empty {
+    empty(api::set_log_redaction(&state, body.level).await)
+}
+
+async fn get_trace_filters() -> ApiResult<crate::trace::TraceStatus> {
+    json(api::get_trace_filters().await)
+}
+
+#[derive(Deserialize)]
+struct TraceFilterPayload {
+    target: String,
+    level: Option<String>,
+}
+
+async fn set_trace_filter(
+    State(state): State<AppState>,
+    Json(body): Json<TraceFilterPayload>,
+) -> ApiResult<crate::trace::TraceStatus> {
+    json(api::set_trace_filter(&state, body.target, body.level).await)
+}
+
+async fn get_metrics(State(state): State<AppState>) -> ApiResult<api::Metrics> {
+    json(api::get_metrics(&state).await)
+}
+
+async fn get_traffic_stats(State(state): State<AppState>) -> ApiResult<api::TrafficStats> {
+    json(api::get_traffic_stats(&state).await)
+}
+
+async fn load_metrics(
+    State(state): State<AppState>,
+    Extension(token): Extension<Token>,
+    Query(q): Query<LimitQuery>,
+) -> ApiResult<Vec<crate::state::MetricPoint>> {
+    json(api::load_metrics(&state, token.0, q.limit).await)
+}
+
+#[cfg(feature = "gui")]
+async fn get_frame_metrics(
+    State(state): State<AppState>,
+) -> ApiResult<crate::renderer::FrameMetricsSnapshot> {
+    json(api::get_frame_metrics(&state).await)
+}
+
+#[derive(Deserialize)]
+struct PingQuery {
+    host: Option<String>,
+    count: Option<u8>,
+}
+
+async fn ping_host(
+    State(state): State<AppState>,
+    Extension(token): Extension<Token>,
+    Query(q): Query<PingQuery>,
+) -> ApiResult<u64> {
+    json(api::ping_host(&state, token.0, q.host, q.count).await)
+}
+
+async fn ping_host_series(
+    State(state): State<AppState>,
+    Extension(token): Extension<Token>,
+    Query(q): Query<PingQuery>,
+) -> ApiResult<Vec<u64>> {
+    json(api::ping_host_series(&state, token.0, q.host, q.count).await)
+}
+
+#[derive(Deserialize)]
+struct HostQuery {
+    host: String,
+}
+
+async fn dns_lookup(
+    State(state): State<AppState>,
+    Extension(token): Extension<Token>,
+    Query(q): Query<HostQuery>,
+) -> ApiResult<Vec<String>> {
+    json(api::dns_lookup(&state, token.0, q.host).await)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TracerouteQuery {
+    host: String,
+    max_hops: Option<u8>,
+}
+
+async fn traceroute_host(
+    State(state): State<AppState>,
+    Extension(token): Extension<Token>,
+    Query(q): Query<TracerouteQuery>,
+) -> ApiResult<Vec<String>> {
+    json(api::traceroute_host(&state, token.0, q.host, q.max_hops).await)
+}
+
+async fn list_forwards(
+    State(state): State<AppState>,
+) -> ApiResult<Vec<crate::forward::ForwardInfo>> {
+    json(api::list_forwards(&state).await)
+}
+
+async fn add_forward(State(state): State<AppState>, Json(forward): Json<ForwardSpec>) -> Empty {
+    empty(api::add_forward(&state, forward).await)
+}
+
+async fn remove_forward(State(state): State<AppState>, Path(port): Path<u16>) -> ApiResult<bool> {
+    json(api::remove_forward(&state, port).await)
+}
+
+async fn list_listeners(
+    State(state): State<AppState>,
+) -> ApiResult<Vec<crate::listeners::ListenerInfo>> {
+    json(api::list_listeners(&state).await)
+}
+
+async fn set_listener(State(state): State<AppState>, Json(listener): Json<ListenerSpec>) -> Empty {
+    empty(api::set_listener(&state, listener).await)
+}
+
+async fn remove_listener(State(state): State<AppState>, Path(port): Path<u16>) -> ApiResult<bool> {
+    json(api::remove_listener(&state, port).await)
+}
+
+async fn get_dns_listener(State(state): State<AppState>) -> ApiResult<api::DnsListenerInfo> {
+    json(api::get_dns_listener(&state).await)
+}
+
+#[derive(Deserialize)]
+struct PortPayload {
+    port: Option<u16>,
+}
+
+async fn set_dns_port(State(state): State<AppState>, Json(body): Json<PortPayload>) -> Empty {
+    empty(api::set_dns_port(&state, body.port).await)
+}
+
+async fn get_secure_key(
+    State(state): State<AppState>,
+    Extension(token): Extension<Token>,
+) -> ApiResult<Option<String>> {
+    json(api::get_secure_key(&state, token.0).await)
+}
+
+#[derive(Deserialize)]
+struct SecureKeyPayload {
+    value: String,
+}
+
+async fn set_secure_key(
+    State(state): State<AppState>,
+    Extension(token): Extension<Token>,
+    Json(body): Json<SecureKeyPayload>,
+) -> Empty {
+    empty(api::set_secure_key(&state, token.0, body.value).await)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportPayload {
+    passphrase: String,
+    #[serde(default)]
+    include_secrets: bool,
+}
+
+async fn export_config_bundle(
+    State(state): State<AppState>,
+    Extension(token): Extension<Token>,
+    Json(body): Json<ExportPayload>,
+) -> ApiResult<String> {
+    json(api::export_config_bundle(&state, token.0, body.passphrase, body.include_secrets).await)
+}
+
+#[derive(Deserialize)]
+struct ImportPayload {
+    data: String,
+    passphrase: String,
+    #[serde(default)]
+    mode: ImportMode,
+}
+
+async fn preview_config_bundle(
+    State(state): State<AppState>,
+    Json(body): Json<ImportPayload>,
+) -> ApiResult<ImportPreview> {
+    json(api::preview_config_bundle(&state, body.data, body.passphrase, body.mode).await)
+}
+
+async fn import_config_bundle(
+    State(state): State<AppState>,
+    Extension(token): Extension<Token>,
+    Json(body): Json<ImportPayload>,
+) -> ApiResult<ImportPreview> {
+    json(
+        api::import_config_bundle(
+            sink(&state).await,
+            &state,
+            token.0,
+            body.data,
+            body.passphrase,
+            body.mode,
+        )
+        .await,
+    )
+}
+
+/// Description of a route for the OpenAPI document.
+struct Operation {
+    method: Method,
+    path: &'static str,
+    id: &'static str,
+    summary: &'static str,
+    query: &'static [&'static str],
+}
+
+/// Router that records every route it serves so the OpenAPI document
+/// cannot drift from the implementation.
+struct ApiRouter {
+    router: Router<AppState>,
+    operations: Vec<Operation>,
+}
+
+impl ApiRouter {
+    fn new() -> Self {
+        Self {
+            router: Router::new(),
+            operations: Vec::new(),
+        }
+    }
+
+    fn route<H, T>(
+        self,
+        method: Method,
+        path: &'static str,
+        id: &'static str,
+        summary: &'static str,
+        handler: H,
+    ) -> Self
+    where
+        H: Handler<T, AppState>,
+        T: 'static,
+    {
+        self.route_with_query(method, path, id, summary, &[], handler)
+    }
+
+    fn route_with_query<H, T>(
+        mut self,
+        method: Method,
+        path: &'static str,
+        id: &'static str,
+        summary: &'static str,
+        query: &'static [&'static str],
+        handler: H,
+    ) -> Self
+    where
+        H: Handler<T, AppState>,
+        T: 'static,
+    {
+        let filter = MethodFilter::try_from(method.clone()).expect("supported HTTP method");
+        self.router = self.router.route(path, on(filter, handler));
+        self.operations.push(Operation {
+            method,
+            path,
+            id,
+            summary,
+            query,
+        });
+        self
+    }
+}
+
+#[rustfmt::skip]
+fn api_routes() -> ApiRouter {
+    let api = ApiRouter::new()
+        .route(Method::POST, "/session", "create_session", "Create a session token", create_session)
+        .route(Method::DELETE, "/session", "revoke_session", "Revoke the calling token", revoke_session)
+        .route(Method::POST, "/connect", "connect", "Connect to Tor", connect)
+        .route(Method::POST, "/disconnect", "disconnect", "Disconnect from Tor", disconnect)
+        .route(Method::POST, "/reconnect", "reconnect", "Reconnect to Tor", reconnect)
+        .route(Method::GET, "/status", "get_status", "Connection status", get_status)
+        .route_with_query(Method::GET, "/events", "event_stream", "Server-sent frontend events", &["lastEventId", "events", "access_token"], event_stream)
+        .route(Method::GET, "/status/summary", "get_status_summary", "Dashboard status summary", get_status_summary)
+        .route_with_query(Method::GET, "/connection/timeline", "get_connection_timeline", "Recent connection events", &["limit"], get_connection_timeline)
+        .route(Method::GET, "/connection/health", "get_connection_health_summary", "Connection health summary", get_connection_health_summary)
+        .route(Method::GET, "/circuits", "list_circuits", "IDs of open circuits", list_circuits)
+        .route(Method::POST, "/circuits", "build_circuit", "Build a new circuit", build_circuit)
+        .route(Method::DELETE, "/circuits/:id", "close_circuit", "Close a circuit", close_circuit)
+        .route(Method::GET, "/circuits/active", "get_active_circuit", "Relays of the active circuit", get_active_circuit)
+        .route(Method::GET, "/circuits/policy", "get_circuit_policy_report", "Circuit policy report", get_circuit_policy_report)
+        .route_with_query(Method::GET, "/circuits/isolated", "get_isolated_circuit", "Circuit isolated for a domain", &["domain"], get_isolated_circuit)
+        .route(Method::POST, "/identity", "new_identity", "Request a new identity", new_identity)
+        .route(Method::PUT, "/countries/exit", "set_exit_country", "Set the exit country", set_exit_country)
+        .route(Method::PUT, "/countries/entry", "set_entry_country", "Set the entry country", set_entry_country)
+        .route(Method::PUT, "/countries/middle", "set_middle_country", "Set the middle country", set_middle_country)
+        .route(Method::GET, "/geoip/:ip", "lookup_country", "Country of an IP address", lookup_country)
+        .route(Method::PUT, "/bridges", "set_bridges", "Set bridge lines", set_bridges)
+        .route(Method::GET, "/bridges/presets", "list_bridge_presets", "Bundled bridge presets", list_bridge_presets)
+        .route(Method::PUT, "/torrc", "set_torrc_config", "Apply a torrc snippet", set_torrc_config)
+        .route(Method::POST, "/torrc/profile", "generate_torrc_profile", "Generate a torrc profile", generate_torrc_profile)
+        .route(Method::GET, "/policy/destination", "get_destination_policy", "Destination policy", get_destination_policy)
+        .route(Method::PUT, "/policy/destination", "set_destination_policy", "Replace the destination policy", set_destination_policy)
+        .route(Method::GET, "/routing/exit-rules", "get_exit_rules", "Exit routing rules", get_exit_rules)
+        .route(Method::PUT, "/routing/exit-rules", "set_exit_rules", "Replace the exit routing rules", set_exit_rules)
+        .route(Method::GET, "/profiles", "list_profiles", "Connection profiles", list_profiles)
+        .route(Method::PUT, "/profiles", "save_profile", "Add or replace a profile", save_profile)
+        .route(Method::POST, "/profiles/current", "save_current_profile", "Save the current settings as a profile", save_current_profile)
+        .route(Method::POST, "/profiles/import", "import_profile", "Import an exported profile", import_profile)
+        .route(Method::DELETE, "/profiles/:name", "delete_profile", "Delete a profile", delete_profile)
+        .route(Method::POST, "/profiles/:name/duplicate", "duplicate_profile", "Copy a profile", duplicate_profile)
+        .route(Method::GET, "/profiles/:name/export", "export_profile", "Export a profile", export_profile)
+        .route(Method::POST, "/profiles/:name/apply", "apply_profile", "Switch to a profile", apply_profile)
+        .route(Method::GET, "/system-proxy", "get_system_proxy_status", "System proxy state", get_system_proxy_status)
+        .route(Method::PUT, "/system-proxy", "toggle_system_proxy", "Enable or disable the system proxy", toggle_system_proxy)
+        .route(Method::PUT, "/workers", "set_worker_config", "Set worker URLs and token", set_worker_config)
+        .route(Method::GET, "/workers/validate", "validate_worker_token", "Check the worker token", validate_worker_token)
+        .route(Method::PUT, "/hsm", "set_hsm_config", "Configure the HSM", set_hsm_config)
+        .route(Method::PUT, "/settings/update-interval", "set_update_interval", "Set the update interval", set_update_interval)
+        .route(Method::PUT, "/settings/geoip", "set_geoip_path", "Set the GeoIP database path", set_geoip_path)
+        .route(Method::PUT, "/settings/insecure-hosts", "set_insecure_hosts", "Set hosts allowed over plain HTTP", set_insecure_hosts)
+        .route(Method::GET, "/config/effective", "get_effective_config", "Effective configuration and its sources", get_effective_config)
+        .route(Method::GET, "/config/history", "list_config_history", "Settings snapshots", list_config_history)
+        .route_with_query(Method::GET, "/config/history/:id/diff", "diff_config_snapshots", "Changes since a snapshot", &["to"], diff_config_snapshots)
+        .route(Method::POST, "/config/history/:id/restore", "restore_config_snapshot", "Restore a snapshot", restore_config_snapshot)
+        .route(Method::POST, "/config/rollback", "rollback_config", "Restore the last settings that connected", rollback_config)
+        .route(Method::POST, "/config/export", "export_config_bundle", "Export an encrypted configuration bundle", export_config_bundle)
+        .route(Method::POST, "/config/import/preview", "preview_config_bundle", "Show what importing a bundle changes", preview_config_bundle)
+        .route(Method::POST, "/config/import", "import_config_bundle", "Import a configuration bundle", import_config_bundle)
+        .route(Method::GET, "/streams", "list_streams", "Open streams", list_streams)
+        .route(Method::DELETE, "/streams/:id", "close_stream", "Close a stream", close_stream)
+        .route(Method::POST, "/streams/close", "close_streams_to", "Close streams to a destination", close_streams_to)
+        .route(Method::PUT, "/streams/:id/bandwidth", "set_stream_bandwidth_limit", "Limit a stream's bandwidth", set_stream_bandwidth_limit)
+        .route(Method::GET, "/bandwidth", "get_bandwidth_limits", "Bandwidth limits", get_bandwidth_limits)
+        .route(Method::PUT, "/bandwidth", "set_bandwidth_limits", "Set bandwidth limits", set_bandwidth_limits)
+        .route(Method::GET, "/logs", "get_logs", "Log entries", get_logs)
+        .route(Method::DELETE, "/logs", "clear_logs", "Clear the log file", clear_logs)
+        .route(Method::GET, "/logs/page", "get_logs_page", "Page of log entries across segments", get_logs_page)
+        .route(Method::POST, "/logs/query", "query_logs", "Filtered log entries with per-level totals", query_logs)
+        .route(Method::GET, "/logs/path", "get_log_file_path", "Path of the log file", get_log_file_path)
+        .route(Method::PUT, "/logs/limit", "set_log_limit", "Set the log line limit", set_log_limit)
+        .route(Method::GET, "/logs/trace", "get_trace_filters", "Captured tracing targets", get_trace_filters)
+        .route(Method::PUT, "/logs/trace", "set_trace_filter", "Set the captured level of a tracing target", set_trace_filter)
+        .route(Method::PUT, "/logs/redaction", "set_log_redaction", "Set the redaction level", set_log_redaction)
+        .route(Method::GET, "/metrics/current", "get_metrics", "Current resource metrics", get_metrics)
+        .route(Method::GET, "/metrics/traffic", "get_traffic_stats", "Traffic totals", get_traffic_stats)
+        .route_with_query(Method::GET, "/metrics/history", "load_metrics", "Stored metric points", &["limit"], load_metrics)
+        .route_with_query(Method::GET, "/diagnostics/ping", "ping_host", "Average ping time", &["host", "count"], ping_host)
+        .route_with_query(Method::GET, "/diagnostics/ping-series", "ping_host_series", "Individual ping times", &["host", "count"], ping_host_series)
+        .route_with_query(Method::GET, "/diagnostics/dns", "dns_lookup", "Resolve a name through Tor", &["host"], dns_lookup)
+        .route_with_query(Method::GET, "/diagnostics/traceroute", "traceroute_host", "Trace the route to a host", &["host", "maxHops"], traceroute_host)
+        .route(Method::GET, "/forwards", "list_forwards", "Port forwards", list_forwards)
+        .route(Method::POST, "/forwards", "add_forward", "Add a port forward", add_forward)
+        .route(Method::DELETE, "/forwards/:port", "remove_forward", "Remove a port forward", remove_forward)
+        .route(Method::GET, "/listeners", "list_listeners", "SOCKS and HTTP listeners", list_listeners)
+        .route(Method::PUT, "/listeners", "set_listener", "Add or replace a listener", set_listener)
+        .route(Method::DELETE, "/listeners/:port", "remove_listener", "Remove a listener", remove_listener)
+        .route(Method::GET, "/dns", "get_dns_listener", "DNS listener ports", get_dns_listener)
+        .route(Method::PUT, "/dns", "set_dns_port", "Set the DNS listener port", set_dns_port)
+        .route(Method::GET, "/secure-key", "get_secure_key", "Read the stored AES key", get_secure_key)
+        .route(Method::PUT, "/secure-key", "set_secure_key", "Store the AES key", set_secure_key);
+    #[cfg(feature = "gui")]
+    let api = api.route(Method::GET, "/metrics/frames", "get_frame_metrics", "Renderer frame metrics", get_frame_metrics);
+    api
+}
+
+/// OpenAPI 3 description of `operations`, relative to [`API_PREFIX`].
+fn openapi_document(operations: &[Operation]) -> serde_json::Value {
+    let mut paths = serde_json::Map::new();
+    for op in operations {
+        let mut parameters = Vec::new();
+        let mut path = String::new();
+        for segment in op.path.split('/').filter(|s| !s.is_empty()) {
+            path.push('/');
+            match segment.strip_prefix(':') {
+                Some(name) => {
+                    path.push_str(&format!("{{{name}}}"));
+                    parameters.push(serde_json::json!({
+                        "name": name, "in": "path", "required": true,
+                        "schema": { "type": "string" }
+                    }));
+                }
+                None => path.push_str(segment),
+            }
+        }
+        for name in op.query {
+            parameters.push(serde_json::json!({
+                "name": name, "in": "query", "required": false,
+                "schema": { "type": "string" }
+            }));
+        }
+        let mut entry = serde_json::json!({
+            "operationId": op.id,
+            "summary": op.summary,
+            "parameters": parameters,
+            "responses": {
+                "200": { "description": "JSON result" },
+                "204": { "description": "Done" },
+                "401": { "description": "Missing or invalid bearer token" },
+                "429": { "description": "Rate limit exceeded" }
+            }
+        });
+        if op.method == Method::POST || op.method == Method::PUT {
+            entry["requestBody"] = serde_json::json!({
+                "required": false,
+                "content": { "application/json": { "schema": { "type": "object" } } }
+            });
+        }
+        let item = paths.entry(path).or_insert_with(|| serde_json::json!({}));
+        item[op.method.as_str().to_ascii_lowercase()] = entry;
+    }
+    serde_json::json!({
+        "openapi": "3.0.3",
+        "info": { "title": "Torwell84 local API", "version": env!("CARGO_PKG_VERSION") },
+        "servers": [{ "url": API_PREFIX }],
+        "components": {
+            "securitySchemes": { "bearer": { "type": "http", "scheme": "bearer" } }
+        },
+        "security": [{ "bearer": [] }],
+        "paths": paths
+    })
+}
+
+/// Router serving the API for `state`.
+pub fn router(state: AppState) -> Router {
+    let ApiRouter { router, operations } = api_routes();
+    let openapi = openapi_document(&operations);
+    let protected =
+        router.route_layer(middleware::from_fn_with_state(state.clone(), require_token));
+    let api = Router::new()
+        .route(
+            "/openapi.json",
+            get(move || std::future::ready(Json(openapi.clone()))),
+        )
+        .merge(protected);
+    Router::new().nest(API_PREFIX, api).with_state(state)
+}
+
+/// Serve the API on `127.0.0.1:port`. Port `0` picks a free port.
+pub async fn start_http_api(state: AppState, port: u16) -> anyhow::Result<ListenerHandle> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let port = listener.local_addr()?.port();
+
+    let token_path = api_token_path();
+    let mut token = state.create_session().await;
+    write_token(&token_path, &token)?;
+
+    let app = router(state.clone());
+    let server = tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            log::error!("HTTP API failed: {e}");
         }
     });
+
+    let renew = tokio::spawn(async move {
+        let _guard = TokenFileGuard(token_path.clone());
+        // Renewed at half the session TTL so the published token never expires
+        let mut interval = tokio::time::interval(state.session.renew_interval());
+        loop {
+            interval.tick().await;
+            if !state.session.renew(&token).await {
+                // Revoked through the API; hand out a fresh one
+                token = state.create_session().await;
+                if let Err(e) = write_token(&token_path, &token) {
+                    log::warn!("failed to write API token: {e}");
+                }
+            }
+        }
+    });
+
+    Ok(ListenerHandle::new(
+        port,
+        vec![server.abort_handle(), renew.abort_handle()],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openapi_lists_every_route() {
+        let routes = api_routes();
+        let doc = openapi_document(&routes.operations);
+        let paths = doc["paths"].as_object().unwrap();
+        for op in &routes.operations {
+            let path = op
+                .path
+                .replace(":id", "{id}")
+                .replace(":port", "{port}")
+                .replace(":ip", "{ip}");
+            let method = op.method.as_str().to_ascii_lowercase();
+            assert_eq!(paths[&path][&method]["operationId"], op.id);
+        }
+        assert_eq!(
+            paths["/streams/{id}/bandwidth"]["put"]["parameters"][0]["in"],
+            "path"
+        );
+    }
+
+    #[test]
+    fn operation_ids_are_unique() {
+        let routes = api_routes();
+        let mut ids: Vec<_> = routes.operations.iter().map(|op| op.id).collect();
+        ids.sort_unstable();
+        let len = ids.len();
+        ids.dedup();
+        assert_eq!(ids.len(), len);
+    }
+
+    #[test]
+    fn maps_errors_to_status_codes() {
+        let status = |e: Error| ApiError(e).into_response().status();
+        assert_eq!(status(Error::InvalidToken), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(Error::RateLimitExceeded("api".into())),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(status(Error::NotConnected), StatusCode::CONFLICT);
+    }
 }
@@ -0,0 +1,154 @@
+use crate::socks::{self, OpenError, SocksContext};
+use crate::streams::StreamTarget;
+use anyhow::Result;
+use arti_client::TorClient;
+use base64::Engine;
+use log::debug;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tor_rtcompat::PreferredRuntime;
+
+/// Upper bound for the request head of a CONNECT request
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+/// Parsed `CONNECT host:port` request.
+#[derive(Debug, PartialEq, Eq)]
+struct ConnectRequest {
+    host: String,
+    port: u16,
+    /// Username and isolation key from `Proxy-Authorization: Basic`
+    credentials: Option<(String, String)>,
+}
+
+/// Parse the head of an HTTP proxy request. Returns `None` for anything other
+/// than a well-formed CONNECT request.
+fn parse_connect(head: &str) -> Option<ConnectRequest> {
+    let mut lines = head.split("\r\n");
+    let mut parts = lines.next()?.split_whitespace();
+    if !parts.next()?.eq_ignore_ascii_case("CONNECT") {
+        return None;
+    }
+    let authority = parts.next()?;
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    let (host, port) = authority.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    let port = port.parse::<u16>().ok()?;
+
+    let credentials = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if !name.trim().eq_ignore_ascii_case("proxy-authorization") {
+            return None;
+        }
+        let encoded = value.trim().strip_prefix("Basic ")?;
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .ok()?;
+        let decoded = String::from_utf8_lossy(&decoded).to_string();
+        let (user, pass) = decoded.split_once(':').unwrap_or((decoded.as_str(), ""));
+        Some((user.to_string(), format!("{user}\u{0}{pass}")))
+    });
+
+    Some(ConnectRequest {
+        host: host.to_string(),
+        port,
+        credentials,
+    })
+}
+
+async fn read_head(socket: &mut TcpStream) -> Result<Option<String>> {
+    let mut head = Vec::with_capacity(512);
+    let mut byte = [0u8; 1];
+    // Read byte-wise so no tunnelled data is consumed with the head
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_LEN || socket.read(&mut byte).await? == 0 {
+            return Ok(None);
+        }
+        head.push(byte[0]);
+    }
+    Ok(Some(String::from_utf8_lossy(&head).to_string()))
+}
+
+async fn respond(socket: &mut TcpStream, status: &str) -> Result<()> {
+    socket
+        .write_all(format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").as_bytes())
+        .await?;
+    Ok(())
+}
+
+/// Serve one connection on an HTTP CONNECT listener.
+pub async fn handle_connect(
+    mut socket: TcpStream,
+    peer: SocketAddr,
+    local_addr: SocketAddr,
+    client: TorClient<PreferredRuntime>,
+    ctx: SocksContext,
+) -> Result<()> {
+    let head = match read_head(&mut socket).await? {
+        Some(head) => head,
+        None => return respond(&mut socket, "400 Bad Request").await,
+    };
+    let request = match parse_connect(&head) {
+        Some(request) => request,
+        None => return respond(&mut socket, "405 Method Not Allowed").await,
+    };
+
+    let key = request.credentials.as_ref().map(|(_, key)| key.as_str());
+    match ctx.open_stream(&client, &request.host, request.port, key).await {
+        Ok(stream) => {
+            socket
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?;
+            let target = StreamTarget {
+                host: request.host,
+                port: request.port,
+                isolation_group: request.credentials.map(|(group, _)| group),
+                peer,
+                pid: None,
+            };
+            socks::serve_stream(socket, stream, &ctx, target, local_addr).await;
+        }
+        Err(OpenError::NotAllowed) => respond(&mut socket, "403 Forbidden").await?,
+        Err(OpenError::Unreachable(e)) => {
+            debug!("CONNECT to {}:{} failed: {}", request.host, request.port, e);
+            respond(&mut socket, "502 Bad Gateway").await?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_connect_with_credentials() {
+        let head = "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\nProxy-Authorization: Basic d29yazpzZWNyZXQ=\r\n\r\n";
+        let req = parse_connect(head).unwrap();
+        assert_eq!(req.host, "example.com");
+        assert_eq!(req.port, 443);
+        assert_eq!(
+            req.credentials,
+            Some(("work".to_string(), "work\u{0}secret".to_string()))
+        );
+    }
+
+    #[test]
+    fn parses_ipv6_authority() {
+        let req = parse_connect("CONNECT [2001:db8::1]:22 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.host, "2001:db8::1");
+        assert_eq!(req.port, 22);
+        assert!(req.credentials.is_none());
+    }
+
+    #[test]
+    fn rejects_other_methods() {
+        assert!(parse_connect("GET http://example.com/ HTTP/1.1\r\n\r\n").is_none());
+        assert!(parse_connect("CONNECT example.com HTTP/1.1\r\n\r\n").is_none());
+    }
+}
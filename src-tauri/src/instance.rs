@@ -0,0 +1,365 @@
+//! Single-instance guard. The first instance holds a lock file and answers
+//! commands of later launches on a Unix socket next to it, so scripts and
+//! desktop shortcuts can drive the running app.
+
+use crate::paths::paths;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// File name of the lock held by the running instance
+pub const LOCK_FILE: &str = "torwell84.lock";
+/// File name of the socket the running instance listens on
+pub const SOCKET_NAME: &str = "torwell84.sock";
+pub const USAGE: &str =
+    "usage: torwell84 [--connect] [--disconnect] [--new-identity] [--show] [--status]";
+/// A lock older than this whose socket refuses connections was left behind
+/// by an instance that did not exit cleanly
+#[cfg(unix)]
+const STALE_LOCK_AGE: Duration = Duration::from_secs(2);
+/// Connecting through Tor may take a while
+#[cfg(unix)]
+const REPLY_TIMEOUT: Duration = Duration::from_secs(180);
+/// Requests longer than this close the connection
+#[cfg(unix)]
+const MAX_REQUEST_LEN: u64 = 4096;
+
+/// Command given on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InstanceCommand {
+    Connect,
+    Disconnect,
+    NewIdentity,
+    Show,
+    Status,
+}
+
+impl InstanceCommand {
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "--connect" => Some(Self::Connect),
+            "--disconnect" => Some(Self::Disconnect),
+            "--new-identity" => Some(Self::NewIdentity),
+            "--show" => Some(Self::Show),
+            "--status" => Some(Self::Status),
+            _ => None,
+        }
+    }
+}
+
+/// Parse the command line arguments, without the program name.
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Vec<InstanceCommand>, String> {
+    args.into_iter()
+        .map(|arg| {
+            InstanceCommand::from_arg(&arg).ok_or_else(|| format!("unknown argument '{arg}'"))
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Request {
+    commands: Vec<InstanceCommand>,
+}
+
+/// Result of one forwarded command.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reply {
+    pub ok: bool,
+    pub message: String,
+}
+
+impl From<Result<String, String>> for Reply {
+    fn from(result: Result<String, String>) -> Self {
+        match result {
+            Ok(message) => Self { ok: true, message },
+            Err(message) => Self { ok: false, message },
+        }
+    }
+}
+
+/// Executes a command inside the running instance.
+pub type Handler =
+    Arc<dyn Fn(InstanceCommand) -> BoxFuture<'static, Result<String, String>> + Send + Sync>;
+
+/// Directory holding the lock and socket. Uses the runtime dir where the
+/// platform has one.
+pub fn instance_dir() -> PathBuf {
+    paths().runtime_dir()
+}
+
+/// Outcome of [`acquire`].
+#[cfg(unix)]
+pub enum Instance {
+    /// No other instance runs, this process holds the lock
+    Primary(InstanceLock),
+    /// Another instance listens on the contained socket
+    Running(PathBuf),
+}
+
+/// Lock of the primary instance. Removes the lock file and socket on drop.
+#[cfg(unix)]
+pub struct InstanceLock {
+    lock_path: PathBuf,
+    socket_path: PathBuf,
+    listener: std::os::unix::net::UnixListener,
+}
+
+#[cfg(unix)]
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.socket_path);
+        let _ = std::fs::remove_file(&self.lock_path);
+    }
+}
+
+/// Take the instance lock in `dir` or find the instance holding it.
+///
+/// The socket is bound right away, so later launches can connect before
+/// [`InstanceLock::serve`] runs.
+#[cfg(unix)]
+pub fn acquire(dir: &std::path::Path) -> std::io::Result<Instance> {
+    use std::io::{ErrorKind, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    std::fs::create_dir_all(dir)?;
+    let lock_path = dir.join(LOCK_FILE);
+    let socket_path = dir.join(SOCKET_NAME);
+    loop {
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+        {
+            Ok(mut file) => {
+                let _ = writeln!(file, "{}", std::process::id());
+                let _ = std::fs::remove_file(&socket_path);
+                let listener = match UnixListener::bind(&socket_path) {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        let _ = std::fs::remove_file(&lock_path);
+                        return Err(e);
+                    }
+                };
+                let lock = InstanceLock {
+                    lock_path,
+                    socket_path,
+                    listener,
+                };
+                std::fs::set_permissions(
+                    &lock.socket_path,
+                    std::fs::Permissions::from_mode(0o600),
+                )?;
+                return Ok(Instance::Primary(lock));
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                if UnixStream::connect(&socket_path).is_ok() {
+                    return Ok(Instance::Running(socket_path));
+                }
+                // A fresh lock belongs to an instance that is still binding
+                let age = std::fs::metadata(&lock_path)
+                    .and_then(|meta| meta.modified())
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok());
+                match age {
+                    Some(age) if age < STALE_LOCK_AGE => {
+                        std::thread::sleep(Duration::from_millis(100))
+                    }
+                    _ => {
+                        log::warn!("removing stale instance lock {}", lock_path.display());
+                        if let Err(e) = std::fs::remove_file(&lock_path) {
+                            if e.kind() != ErrorKind::NotFound {
+                                return Err(e);
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl InstanceLock {
+    /// Answer forwarded commands with `handler`. The lock is released when
+    /// the returned task ends.
+    pub fn serve(self, handler: Handler) -> std::io::Result<tokio::task::JoinHandle<()>> {
+        self.listener.set_nonblocking(true)?;
+        let listener = tokio::net::UnixListener::from_std(self.listener.try_clone()?)?;
+        Ok(tokio::spawn(async move {
+            let _lock = self;
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::warn!("instance socket accept failed: {e}");
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_client(stream, handler).await {
+                        log::debug!("instance client failed: {e}");
+                    }
+                });
+            }
+        }))
+    }
+}
+
+#[cfg(unix)]
+async fn handle_client(stream: tokio::net::UnixStream, handler: Handler) -> std::io::Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(read.take(MAX_REQUEST_LEN))
+        .read_line(&mut line)
+        .await?;
+    let request: Request = serde_json::from_str(&line)?;
+    for command in request.commands {
+        log::info!("forwarded command {command:?}");
+        let reply = Reply::from(handler(command).await);
+        let mut out = serde_json::to_vec(&reply)?;
+        out.push(b'\n');
+        write.write_all(&out).await?;
+    }
+    Ok(())
+}
+
+/// Send `commands` to the instance listening on `socket`, returning one
+/// reply per command.
+#[cfg(unix)]
+pub fn forward(
+    socket: &std::path::Path,
+    commands: &[InstanceCommand],
+) -> std::io::Result<Vec<Reply>> {
+    use std::io::{BufRead, BufReader, Write};
+
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    let mut request = serde_json::to_vec(&Request {
+        commands: commands.to_vec(),
+    })?;
+    request.push(b'\n');
+    stream.write_all(&request)?;
+
+    let mut replies = Vec::with_capacity(commands.len());
+    for line in BufReader::new(stream).lines().take(commands.len()) {
+        replies.push(serde_json::from_str(&line?)?);
+    }
+    if replies.len() < commands.len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "running instance closed the connection",
+        ));
+    }
+    Ok(replies)
+}
+
+/// [`forward`] `commands` and print the replies. Returns the exit code.
+#[cfg(unix)]
+pub fn forward_and_print(socket: &std::path::Path, commands: &[InstanceCommand]) -> i32 {
+    match forward(socket, commands) {
+        Ok(replies) => {
+            let mut code = 0;
+            for reply in replies {
+                if reply.ok {
+                    println!("{}", reply.message);
+                } else {
+                    eprintln!("{}", reply.message);
+                    code = 1;
+                }
+            }
+            code
+        }
+        Err(e) => {
+            eprintln!("failed to reach running instance: {e}");
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn parses_known_arguments() {
+        let args = ["--connect", "--status"].map(String::from);
+        assert_eq!(
+            parse_args(args).unwrap(),
+            vec![InstanceCommand::Connect, InstanceCommand::Status]
+        );
+        assert!(parse_args(["--bogus".to_string()]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn second_instance_forwards_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let Instance::Primary(lock) = acquire(dir.path()).unwrap() else {
+            panic!("expected to take the lock");
+        };
+        let handler: Handler = Arc::new(|command: InstanceCommand| {
+            async move {
+                match command {
+                    InstanceCommand::Status => Ok("CONNECTED".to_string()),
+                    _ => Err("not supported".to_string()),
+                }
+            }
+            .boxed()
+        });
+        let task = lock.serve(handler).unwrap();
+
+        let Instance::Running(socket) = acquire(dir.path()).unwrap() else {
+            panic!("expected the running instance");
+        };
+        let replies = tokio::task::spawn_blocking(move || {
+            forward(&socket, &[InstanceCommand::Status, InstanceCommand::Show])
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            replies,
+            vec![
+                Reply {
+                    ok: true,
+                    message: "CONNECTED".into()
+                },
+                Reply {
+                    ok: false,
+                    message: "not supported".into()
+                },
+            ]
+        );
+
+        task.abort();
+        let _ = task.await;
+        assert!(!dir.path().join(LOCK_FILE).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_lock_is_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join(LOCK_FILE);
+        std::fs::write(&lock_path, "1\n").unwrap();
+        let old = std::time::SystemTime::now() - Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&lock_path)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        assert!(matches!(acquire(dir.path()).unwrap(), Instance::Primary(_)));
+    }
+}
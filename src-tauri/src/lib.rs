@@ -1,40 +1,115 @@
+pub mod api;
+pub mod bundle;
 pub mod icmp;
-mod commands;
-mod core;
-mod error;
-#[cfg(feature = "mobile")]
-mod http_bridge;
+#[cfg(feature = "gui")]
+pub mod commands;
+pub mod config;
+pub mod control;
+pub mod core;
+mod daemon;
+pub mod dns;
+pub mod error;
+pub mod events;
+pub mod forward;
+#[cfg(feature = "gui")]
+mod gui;
+pub mod history;
+pub mod http_bridge;
+mod http_connect;
+pub mod instance;
+pub mod listeners;
+pub mod logstore;
+pub mod paths;
+pub mod policy;
+pub mod profiles;
+pub mod redact;
+#[cfg(feature = "gui")]
 pub mod renderer;
-mod secure_http;
-mod session;
-mod state;
-mod socks;
-mod system_proxy;
-mod tor_manager;
+pub mod routing;
+#[cfg(all(feature = "rpc", unix))]
+pub mod rpc;
+pub mod secure_http;
+pub mod session;
+pub mod settings;
+pub mod shaping;
+pub mod state;
+pub mod socks;
+pub mod streams;
+pub mod system_proxy;
+pub mod telemetry;
+pub mod tor_manager;
+pub mod trace;
 
+pub use daemon::{run_daemon, DaemonOptions};
+pub use events::{EventSink, TrayStatus};
 pub use tor_manager::load_bridge_presets_from_str;
 
 #[cfg(fuzzing)]
 pub use secure_http::{fuzz_parse_max_age, fuzz_tls_version};
 
+#[cfg(feature = "gui")]
+use config::ConfigStore;
+#[cfg(feature = "gui")]
 use open;
+#[cfg(feature = "gui")]
 use secure_http::SecureHttpClient;
+#[cfg(feature = "gui")]
 use state::AppState;
-use std::time::Duration;
+#[cfg(feature = "gui")]
 use tauri::{CustomMenuItem, SystemTray, SystemTrayEvent, SystemTrayMenu, Manager};
+#[cfg(feature = "gui")]
 use arti_client::TorClient;
+#[cfg(feature = "gui")]
 use tor_rtcompat::PreferredRuntime;
 
+/// Run the desktop application.
+#[cfg(feature = "gui")]
 pub fn run() {
+    if std::env::args().skip(1).any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", instance::USAGE);
+        return;
+    }
+    let commands = match instance::parse_args(std::env::args().skip(1)) {
+        Ok(commands) => commands,
+        Err(e) => {
+            eprintln!("{e}\n{}", instance::USAGE);
+            std::process::exit(2);
+        }
+    };
+    #[cfg(unix)]
+    let instance_lock = match instance::acquire(&instance::instance_dir()) {
+        Ok(instance::Instance::Primary(lock)) => Some(lock),
+        Ok(instance::Instance::Running(socket)) => {
+            // A plain second launch brings the running window forward
+            let commands = if commands.is_empty() {
+                vec![instance::InstanceCommand::Show]
+            } else {
+                commands
+            };
+            std::process::exit(instance::forward_and_print(&socket, &commands));
+        }
+        Err(e) => {
+            eprintln!("single-instance check failed: {e}");
+            None
+        }
+    };
+    if !commands.is_empty() && commands.iter().all(|c| *c == instance::InstanceCommand::Status) {
+        #[cfg(unix)]
+        drop(instance_lock);
+        println!("not running");
+        std::process::exit(1);
+    }
+
+    let migrated = paths::migrate_from_working_dir();
+    trace::install();
+    let config = std::sync::Arc::new(ConfigStore::load(state::config_path()));
     let http_client = tauri::async_runtime::block_on(async {
-        SecureHttpClient::init(secure_http::DEFAULT_CONFIG_PATH, None, None, None, None)
+        SecureHttpClient::init_with(config.cert_settings())
             .await
             .expect("failed to initialize http client")
     });
-    let app_state: AppState<TorClient<PreferredRuntime>> = AppState::new(http_client.clone());
-
-    #[cfg(feature = "mobile")]
-    http_bridge::start(app_state.clone());
+    let app_state: AppState<TorClient<PreferredRuntime>> =
+        AppState::with_config(http_client.clone(), config);
 
     let quit = CustomMenuItem::new("quit", "Quit");
     let show = CustomMenuItem::new("show", "Show");
@@ -88,7 +163,7 @@ pub fn run() {
                     let state = (*app.state::<AppState>()).clone();
                     let handle = app.clone();
                     tauri::async_runtime::spawn(async move {
-                        if let Err(e) = commands::perform_connect(handle.clone(), state.clone()).await {
+                        if let Err(e) = api::perform_connect(Some(std::sync::Arc::new(handle)), state.clone()).await {
                             log::error!("tray connect failed: {}", e);
                         }
                         state.update_tray_menu().await;
@@ -98,7 +173,7 @@ pub fn run() {
                     let state = (*app.state::<AppState>()).clone();
                     let handle = app.clone();
                     tauri::async_runtime::spawn(async move {
-                        if let Err(e) = commands::perform_disconnect(handle.clone(), state.clone()).await {
+                        if let Err(e) = api::perform_disconnect(Some(std::sync::Arc::new(handle)), state.clone()).await {
                             log::error!("tray disconnect failed: {}", e);
                         }
                         state.update_tray_menu().await;
@@ -108,7 +183,7 @@ pub fn run() {
                     let state = (*app.state::<AppState>()).clone();
                     let handle = app.clone();
                     tauri::async_runtime::spawn(async move {
-                        if let Err(e) = commands::perform_reconnect(handle.clone(), state.clone()).await {
+                        if let Err(e) = api::perform_reconnect(Some(std::sync::Arc::new(handle)), state.clone()).await {
                             log::error!("tray reconnect failed: {}", e);
                         }
                         state.update_tray_menu().await;
@@ -142,7 +217,7 @@ pub fn run() {
                     }
                 }
                 "open_settings_file" => {
-                    if let Err(e) = open::that(crate::state::DEFAULT_CONFIG_PATH) {
+                    if let Err(e) = open::that(crate::state::config_path()) {
                         log::error!("failed to open settings file: {e}");
                     }
                 }
@@ -152,12 +227,23 @@ pub fn run() {
                         state.clear_tray_warning().await;
                     });
                 }
-                _ => {}
+                other => {
+                    if let Some(name) = other.strip_prefix(gui::PROFILE_ITEM_PREFIX) {
+                        let name = name.to_string();
+                        let state = (*app.state::<AppState>()).clone();
+                        let handle = app.clone();
+                        tauri::async_runtime::spawn(async move {
+                            if let Err(e) = api::apply_profile(Some(std::sync::Arc::new(handle)), &state, name).await {
+                                log::error!("tray profile switch failed: {}", e);
+                            }
+                        });
+                    }
+                }
             },
             _ => {}
         })
         .manage(app_state)
-        .setup(|app| {
+        .setup(move |app| {
             if cfg!(debug_assertions) {
                 app.handle().plugin(
                     tauri_plugin_log::Builder::default()
@@ -172,10 +258,37 @@ pub fn run() {
             let state_for_setup = state.clone();
             let handle_for_setup = handle.clone();
             let state_for_cb = state.clone();
+            let state_for_policy = state.clone();
+            let instance_handler = gui::instance_handler(handle.clone());
+            let handler_for_setup = instance_handler.clone();
 
             tauri::async_runtime::block_on(async move {
+                #[cfg(unix)]
+                {
+                    if let Some(lock) = instance_lock {
+                        if let Err(e) = lock.serve(handler_for_setup) {
+                            log::warn!("failed to serve instance socket: {}", e);
+                        }
+                    }
+                }
+                #[cfg(not(unix))]
+                let _ = handler_for_setup;
                 state_for_setup.register_handle(handle_for_setup).await;
+                for (from, to) in migrated {
+                    let _ = state_for_setup
+                        .add_log(
+                            log::Level::Info,
+                            format!("migrated {} to {}", from.display(), to.display()),
+                            None,
+                        )
+                        .await;
+                }
                 state_for_setup.update_tray_menu().await;
+                state_for_setup.start_control_port().await;
+                state_for_setup.start_http_api().await;
+                state_for_setup.start_metrics_exporter().await;
+                state_for_setup.watch_config();
+                state_for_setup.start_trace_capture();
                 http_client
                     .set_warning_callback(move |msg| {
                         let st = state_for_cb.clone();
@@ -184,14 +297,34 @@ pub fn run() {
                         });
                     })
                     .await;
+                let mgr = state_for_setup.tor_manager.read().await.clone();
+                mgr.destination_policy()
+                    .set_warning_callback(move |msg| {
+                        let st = state_for_policy.clone();
+                        tauri::async_runtime::spawn(async move {
+                            st.emit_security_warning(msg).await;
+                        });
+                    })
+                    .await;
             });
             let renderer = state.renderer_service();
             renderer.attach_handle(handle.clone());
             renderer.start_render_loop();
+            std::sync::Arc::new(state.clone()).start_stream_updates(std::sync::Arc::new(handle.clone()));
             let state_for_metrics = state.clone();
             tokio::spawn(async move {
                 state_for_metrics.load_metrics(None);
             });
+            if !commands.is_empty() {
+                tauri::async_runtime::spawn(async move {
+                    for command in commands {
+                        match instance_handler(command).await {
+                            Ok(msg) => log::info!("{:?}: {}", command, msg),
+                            Err(e) => log::warn!("{:?} failed: {}", command, e),
+                        }
+                    }
+                });
+            }
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
@@ -209,6 +342,22 @@ pub fn run() {
             commands::set_middle_country,
             commands::set_bridges,
             commands::set_torrc_config,
+            commands::get_destination_policy,
+            commands::set_destination_policy,
+            commands::get_exit_rules,
+            commands::set_exit_rules,
+            commands::list_profiles,
+            commands::save_profile,
+            commands::save_current_profile,
+            commands::duplicate_profile,
+            commands::delete_profile,
+            commands::export_profile,
+            commands::import_profile,
+            commands::apply_profile,
+            commands::list_config_history,
+            commands::diff_config_snapshots,
+            commands::restore_config_snapshot,
+            commands::rollback_config,
             commands::generate_torrc_profile,
             commands::set_worker_config,
             commands::validate_worker_token,
@@ -218,14 +367,34 @@ pub fn run() {
             commands::get_metrics,
             commands::list_circuits,
             commands::close_circuit,
+            commands::list_streams,
+            commands::close_stream,
+            commands::close_streams_to,
+            commands::get_bandwidth_limits,
+            commands::set_bandwidth_limits,
+            commands::get_dns_listener,
+            commands::set_dns_port,
+            commands::list_forwards,
+            commands::add_forward,
+            commands::remove_forward,
+            commands::list_listeners,
+            commands::set_listener,
+            commands::remove_listener,
+            commands::set_stream_bandwidth_limit,
             commands::get_logs,
+            commands::get_logs_page,
+            commands::query_logs,
             commands::clear_logs,
             commands::get_log_file_path,
             commands::set_log_limit,
+            commands::set_log_redaction,
+            commands::get_trace_filters,
+            commands::set_trace_filter,
             commands::load_metrics,
             commands::set_update_interval,
             commands::set_geoip_path,
             commands::set_insecure_hosts,
+            commands::get_effective_config,
             commands::build_circuit,
             commands::ping_host,
             commands::ping_host_series,
@@ -234,6 +403,9 @@ pub fn run() {
             commands::lookup_country,
             commands::get_secure_key,
             commands::set_secure_key,
+            commands::export_config_bundle,
+            commands::preview_config_bundle,
+            commands::import_config_bundle,
             commands::reconnect,
             commands::show_dashboard,
             commands::request_token,
@@ -0,0 +1,273 @@
+use crate::error::{Error, Result};
+use crate::socks::ListenerHandle;
+use arti_client::config::BoolOrAuto;
+use arti_client::StreamPrefs;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tor_geoip::CountryCode;
+
+/// Protocol spoken by a local listener.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ListenerKind {
+    #[default]
+    Socks,
+    HttpConnect,
+}
+
+/// Address family preference for exit connections.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum IpPreference {
+    #[default]
+    Any,
+    Ipv4Only,
+    Ipv6Only,
+    PreferIpv4,
+    PreferIpv6,
+}
+
+/// How streams arriving on a listener are isolated from each other.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum IsolationMode {
+    /// Isolate by SOCKS username/password or proxy credentials
+    #[default]
+    Credentials,
+    /// All streams of the listener share circuits not used by other listeners
+    PerListener,
+    /// Every stream gets its own circuit
+    PerConnection,
+    /// No isolation beyond arti's defaults
+    Shared,
+}
+
+/// Stream preferences applied to every stream opened by a listener.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamProfile {
+    #[serde(default)]
+    pub exit_country: Option<String>,
+    #[serde(default)]
+    pub ip_preference: IpPreference,
+    #[serde(default = "default_allow_onion")]
+    pub allow_onion: bool,
+    #[serde(default)]
+    pub isolation: IsolationMode,
+}
+
+fn default_allow_onion() -> bool {
+    true
+}
+
+impl Default for StreamProfile {
+    fn default() -> Self {
+        Self {
+            exit_country: None,
+            ip_preference: IpPreference::Any,
+            allow_onion: default_allow_onion(),
+            isolation: IsolationMode::Credentials,
+        }
+    }
+}
+
+impl StreamProfile {
+    pub fn validate(&self) -> Result<()> {
+        self.exit_country_code().map(|_| ())
+    }
+
+    pub(crate) fn exit_country_code(&self) -> Result<Option<CountryCode>> {
+        match self.exit_country.as_deref().map(str::trim) {
+            None | Some("") => Ok(None),
+            Some(code) => code
+                .to_ascii_uppercase()
+                .parse::<CountryCode>()
+                .map(Some)
+                .map_err(|_| invalid(format!("invalid exit country '{}'", code))),
+        }
+    }
+
+    /// Whether streams to `host` may be opened through this profile.
+    pub fn allows_host(&self, host: &str) -> bool {
+        self.allow_onion || !host.to_ascii_lowercase().trim_end_matches('.').ends_with(".onion")
+    }
+
+    /// Apply exit country, address family and onion settings to `prefs`.
+    /// Isolation is applied by the listener because it needs shared state.
+    pub fn apply(&self, prefs: &mut StreamPrefs) {
+        if let Ok(Some(cc)) = self.exit_country_code() {
+            prefs.exit_country(cc);
+        }
+        match self.ip_preference {
+            IpPreference::Any => {}
+            IpPreference::Ipv4Only => {
+                prefs.ipv4_only();
+            }
+            IpPreference::Ipv6Only => {
+                prefs.ipv6_only();
+            }
+            IpPreference::PreferIpv4 => {
+                prefs.ipv4_preferred();
+            }
+            IpPreference::PreferIpv6 => {
+                prefs.ipv6_preferred();
+            }
+        }
+        prefs.connect_to_onion_services(BoolOrAuto::Explicit(self.allow_onion));
+    }
+}
+
+/// An additional local proxy listener with its own stream profile.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ListenerSpec {
+    pub port: u16,
+    #[serde(default)]
+    pub kind: ListenerKind,
+    #[serde(flatten)]
+    pub profile: StreamProfile,
+}
+
+impl ListenerSpec {
+    pub fn validate(&self) -> Result<()> {
+        if self.port == 0 {
+            return Err(invalid("listener port must be non-zero"));
+        }
+        self.profile.validate()
+    }
+}
+
+fn invalid(reason: impl ToString) -> Error {
+    Error::ConfigError {
+        step: "listeners::validate".into(),
+        source_message: reason.to_string(),
+        backtrace: format!("{:?}", std::backtrace::Backtrace::capture()),
+    }
+}
+
+/// Listener as shown in the UI.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ListenerInfo {
+    #[serde(flatten)]
+    pub spec: ListenerSpec,
+    pub active: bool,
+}
+
+/// Configured listeners and the handles of those currently running.
+#[derive(Clone, Default)]
+pub struct ListenerManager {
+    specs: Arc<Mutex<Vec<ListenerSpec>>>,
+    running: Arc<Mutex<HashMap<u16, ListenerHandle>>>,
+}
+
+impl ListenerManager {
+    pub fn with_specs(specs: Vec<ListenerSpec>) -> Self {
+        let manager = Self::default();
+        *manager.specs.lock().unwrap_or_else(|p| p.into_inner()) = specs;
+        manager
+    }
+
+    fn running(&self) -> std::sync::MutexGuard<'_, HashMap<u16, ListenerHandle>> {
+        self.running.lock().unwrap_or_else(|p| p.into_inner())
+    }
+
+    pub fn specs(&self) -> Vec<ListenerSpec> {
+        self.specs.lock().unwrap_or_else(|p| p.into_inner()).clone()
+    }
+
+    pub fn list(&self) -> Vec<ListenerInfo> {
+        let running = self.running();
+        self.specs()
+            .into_iter()
+            .map(|spec| ListenerInfo {
+                active: running.contains_key(&spec.port),
+                spec,
+            })
+            .collect()
+    }
+
+    /// Add or replace the listener on `spec.port`. Returns the previous spec.
+    pub fn upsert(&self, spec: ListenerSpec) -> Result<Option<ListenerSpec>> {
+        spec.validate()?;
+        self.running().remove(&spec.port);
+        let mut specs = self.specs.lock().unwrap_or_else(|p| p.into_inner());
+        match specs.iter_mut().find(|s| s.port == spec.port) {
+            Some(existing) => Ok(Some(std::mem::replace(existing, spec))),
+            None => {
+                specs.push(spec);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Remove a listener and stop it.
+    pub fn remove(&self, port: u16) -> Option<ListenerSpec> {
+        self.running().remove(&port);
+        let mut specs = self.specs.lock().unwrap_or_else(|p| p.into_inner());
+        let idx = specs.iter().position(|s| s.port == port)?;
+        Some(specs.remove(idx))
+    }
+
+    pub fn set_running(&self, handle: ListenerHandle) {
+        self.running().insert(handle.port(), handle);
+    }
+
+    /// Stop all listeners. The configuration is kept.
+    pub fn stop_all(&self) {
+        self.running().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_defaults_from_json() {
+        let spec: ListenerSpec = serde_json::from_str(r#"{"port": 9150, "exitCountry": "de"}"#).unwrap();
+        assert_eq!(spec.kind, ListenerKind::Socks);
+        assert!(spec.profile.allow_onion);
+        assert_eq!(spec.profile.isolation, IsolationMode::Credentials);
+        assert!(spec.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_specs() {
+        let mut spec = ListenerSpec {
+            port: 0,
+            kind: ListenerKind::HttpConnect,
+            profile: StreamProfile::default(),
+        };
+        assert!(spec.validate().is_err());
+        spec.port = 8118;
+        spec.profile.exit_country = Some("ZZZ".into());
+        assert!(spec.validate().is_err());
+    }
+
+    #[test]
+    fn onion_access() {
+        let mut profile = StreamProfile::default();
+        assert!(profile.allows_host("abc.onion"));
+        profile.allow_onion = false;
+        assert!(!profile.allows_host("abc.ONION."));
+        assert!(profile.allows_host("example.com"));
+    }
+
+    #[test]
+    fn upsert_replaces_existing() {
+        let manager = ListenerManager::default();
+        let spec = ListenerSpec {
+            port: 9150,
+            kind: ListenerKind::Socks,
+            profile: StreamProfile::default(),
+        };
+        assert!(manager.upsert(spec.clone()).unwrap().is_none());
+        let mut http = spec.clone();
+        http.kind = ListenerKind::HttpConnect;
+        assert_eq!(manager.upsert(http).unwrap(), Some(spec));
+        assert_eq!(manager.list().len(), 1);
+        assert!(manager.remove(9150).is_some());
+    }
+}
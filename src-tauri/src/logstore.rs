@@ -0,0 +1,947 @@
+//! Segmented log storage. Entries are appended as JSON lines to the active
+//! segment at the log path. Once it reaches its line or size limit it is
+//! moved to `archive/` and compressed with gzip in the background; archived
+//! segments are dropped beyond a count limit or once older than the
+//! retention period.
+//! Segments carry increasing sequence numbers so pages can be read across
+//! them with a cursor.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::state::{LogEntry, DEFAULT_MAX_LOG_LINES};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Default size of a log segment in KiB
+pub const DEFAULT_LOG_SEGMENT_KB: usize = 1024;
+/// Default number of archived log segments kept
+pub const DEFAULT_LOG_SEGMENTS: usize = 10;
+/// Default age in days after which archived segments are removed
+pub const DEFAULT_LOG_RETENTION_DAYS: u64 = 14;
+/// Entries returned per page when no limit is given
+pub const DEFAULT_PAGE_SIZE: usize = 200;
+/// Largest page that can be requested
+pub const MAX_PAGE_SIZE: usize = 5000;
+
+const SEGMENT_PREFIX: &str = "torwell-";
+/// Compiled size limit for query regexes
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Rotation and retention limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogLimits {
+    /// Lines per segment
+    pub max_lines: usize,
+    /// Bytes per segment
+    pub segment_bytes: u64,
+    /// Archived segments kept
+    pub max_segments: usize,
+    /// Age after which archived segments are removed, `None` keeps them
+    pub retention: Option<Duration>,
+}
+
+impl Default for LogLimits {
+    fn default() -> Self {
+        Self {
+            max_lines: DEFAULT_MAX_LOG_LINES,
+            segment_bytes: DEFAULT_LOG_SEGMENT_KB as u64 * 1024,
+            max_segments: DEFAULT_LOG_SEGMENTS,
+            retention: Some(Duration::from_secs(
+                DEFAULT_LOG_RETENTION_DAYS * 24 * 60 * 60,
+            )),
+        }
+    }
+}
+
+impl LogLimits {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self {
+            max_lines: cfg.max_log_lines,
+            segment_bytes: cfg.max_log_segment_kb as u64 * 1024,
+            max_segments: cfg.max_log_segments,
+            retention: (cfg.log_retention_days > 0)
+                .then(|| Duration::from_secs(cfg.log_retention_days * 24 * 60 * 60)),
+        }
+    }
+}
+
+/// Position of a line: segment sequence number and line index within the
+/// segment. Serialized as `"<segment>:<line>"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor {
+    pub segment: u64,
+    pub line: u64,
+}
+
+impl Cursor {
+    pub fn parse(value: &str) -> Result<Self> {
+        value
+            .split_once(':')
+            .and_then(|(segment, line)| {
+                Some(Self {
+                    segment: segment.parse().ok()?,
+                    line: line.parse().ok()?,
+                })
+            })
+            .ok_or_else(|| Error::InvalidConfig {
+                key: "cursor".into(),
+                message: format!("invalid log cursor '{value}'"),
+            })
+    }
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.segment, self.line)
+    }
+}
+
+/// Entries in chronological order. `next_cursor` continues with older
+/// entries and is `None` once the oldest entry was returned.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogPage {
+    pub entries: Vec<LogEntry>,
+    pub next_cursor: Option<String>,
+}
+
+struct Segment {
+    seq: u64,
+    path: PathBuf,
+    /// `false` while the segment waits for compression
+    compressed: bool,
+}
+
+/// Sequence number of an archived segment named
+/// `torwell-<seq>-<timestamp>.log[.gz]`.
+fn segment_seq(name: &str) -> Option<u64> {
+    let (seq, rest) = name.strip_prefix(SEGMENT_PREFIX)?.split_once('-')?;
+    if !(rest.ends_with(".log") || rest.ends_with(".log.gz")) {
+        return None;
+    }
+    seq.parse().ok()
+}
+
+/// Archives written by earlier versions, named `torwell-<timestamp>.log`.
+fn is_legacy_archive(name: &str) -> bool {
+    name.strip_prefix(SEGMENT_PREFIX)
+        .and_then(|rest| rest.strip_suffix(".log"))
+        .is_some_and(|ts| !ts.is_empty() && ts.bytes().all(|b| b.is_ascii_digit()))
+}
+
+struct Inner {
+    limits: LogLimits,
+    /// Sequence number of the active segment
+    seq: u64,
+    lines: u64,
+    bytes: u64,
+}
+
+pub struct LogStore {
+    path: PathBuf,
+    archive_dir: PathBuf,
+    inner: Mutex<Inner>,
+    /// Held while archived segments are compressed, pruned or cleared
+    archive_lock: Arc<Mutex<()>>,
+}
+
+impl LogStore {
+    /// Open the log at `path`. Segments left uncompressed by an interrupted
+    /// rotation and archives of earlier versions are compressed here.
+    pub fn open(path: PathBuf, limits: LogLimits) -> Self {
+        let archive_dir = path
+            .parent()
+            .map(|p| p.join("archive"))
+            .unwrap_or_else(|| PathBuf::from("archive"));
+        let (lines, bytes) = count_lines(&path).unwrap_or((0, 0));
+        let store = Self {
+            path,
+            archive_dir,
+            inner: Mutex::new(Inner {
+                limits,
+                seq: 1,
+                lines,
+                bytes,
+            }),
+            archive_lock: Arc::default(),
+        };
+        if let Err(e) = store.recover() {
+            log::warn!(
+                "failed to prepare log archive {}: {}",
+                store.archive_dir.display(),
+                e
+            );
+        }
+        store
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn limits(&self) -> LogLimits {
+        self.lock().limits
+    }
+
+    /// Change the limits, rotating and pruning right away if they are now
+    /// exceeded.
+    pub fn set_limits(&self, limits: LogLimits) -> Result<()> {
+        let pending = {
+            let mut inner = self.lock();
+            inner.limits = limits;
+            if inner.lines > 0
+                && (inner.lines >= limits.max_lines as u64 || inner.bytes >= limits.segment_bytes)
+            {
+                self.rotate(&mut inner)?
+            } else {
+                None
+            }
+        };
+        self.archive(pending, limits)?;
+        Ok(())
+    }
+
+    /// Append one entry. The active segment is rotated first if the entry
+    /// would exceed its limits.
+    pub fn append(&self, entry: &LogEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let len = line.len() as u64;
+        let mut inner = self.lock();
+        let rotate = inner.lines > 0
+            && (inner.lines >= inner.limits.max_lines as u64
+                || inner.bytes + len > inner.limits.segment_bytes);
+        let pending = if rotate {
+            self.rotate(&mut inner)?
+        } else {
+            None
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        inner.lines += 1;
+        inner.bytes += len;
+        let limits = inner.limits;
+        drop(inner);
+        if rotate {
+            self.archive(pending, limits)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the active segment and all archived ones.
+    pub fn clear(&self) -> Result<()> {
+        let mut inner = self.lock();
+        let _archive = lock(&self.archive_lock);
+        remove_if_exists(&self.path)?;
+        for segment in self.segments()? {
+            remove_if_exists(&segment.path)?;
+        }
+        inner.lines = 0;
+        inner.bytes = 0;
+        Ok(())
+    }
+
+    /// Up to `limit` entries before `before`, or the newest entries without
+    /// a cursor. Segments are streamed one at a time, newest first, so only
+    /// the entries of the page are held in memory.
+    pub fn read_page(&self, before: Option<Cursor>, limit: usize) -> Result<LogPage> {
+        let mut scan = Scan::new(&Matcher::default(), &|entry| entry, before, limit, false);
+        self.scan(&mut scan)?;
+        let (entries, next_cursor) = scan.finish();
+        Ok(LogPage {
+            entries,
+            next_cursor,
+        })
+    }
+
+    /// Entries matching `query`, paged like [`Self::read_page`]. The first
+    /// page, without a cursor, also carries the number of matches per level
+    /// over all segments; later pages stop reading once they are full.
+    pub fn query(&self, query: &LogQuery) -> Result<LogQueryPage> {
+        self.query_with(query, &|entry| entry)
+    }
+
+    /// Like [`Self::query`], with every entry passed through `prepare`, for
+    /// example to redact it, before it is matched and returned.
+    pub fn query_with(
+        &self,
+        query: &LogQuery,
+        prepare: &dyn Fn(LogEntry) -> LogEntry,
+    ) -> Result<LogQueryPage> {
+        let matcher = query.matcher()?;
+        let before = query.cursor.as_deref().map(Cursor::parse).transpose()?;
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let mut scan = Scan::new(&matcher, prepare, before, limit, before.is_none());
+        self.scan(&mut scan)?;
+        let totals = scan.count.then(|| std::mem::take(&mut scan.totals));
+        let (entries, next_cursor) = scan.finish();
+        Ok(LogQueryPage {
+            entries,
+            next_cursor,
+            totals,
+        })
+    }
+
+    fn scan(&self, scan: &mut Scan<'_>) -> Result<()> {
+        // The active segment is opened under the lock and read up to its
+        // length at that point, so appends and rotations are not held up by
+        // the read. Archived segments are immutable once listed.
+        let (active, segments) = {
+            let inner = self.lock();
+            let active = match File::open(&self.path) {
+                Ok(file) => {
+                    let len = file.metadata()?.len();
+                    Some((file.take(len), inner.seq))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+            (active, self.segments()?)
+        };
+        if let Some((reader, seq)) = active {
+            scan.segment(reader, seq)?;
+        }
+        for segment in segments.iter().rev() {
+            if scan.done() {
+                break;
+            }
+            // pruned since it was listed
+            let Some(reader) = open_segment(segment)? else {
+                continue;
+            };
+            scan.segment(reader, segment.seq)?;
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        lock(&self.inner)
+    }
+
+    /// Archived segments ordered by sequence number.
+    fn segments(&self) -> io::Result<Vec<Segment>> {
+        list_segments(&self.archive_dir)
+    }
+
+    /// Compress uncompressed segments and legacy archives and continue the
+    /// sequence after the newest segment.
+    fn recover(&self) -> io::Result<()> {
+        let mut pending = Vec::new();
+        let mut legacy = Vec::new();
+        if let Ok(dir) = fs::read_dir(&self.archive_dir) {
+            for entry in dir {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.ends_with(".log") && segment_seq(&name).is_some() {
+                    pending.push(entry.path());
+                } else if is_legacy_archive(&name) {
+                    legacy.push((name, entry.path()));
+                }
+            }
+        }
+        for path in pending {
+            compress(&path, &path.with_extension("log.gz"))?;
+        }
+        // left by a compression that was interrupted
+        if let Ok(dir) = fs::read_dir(&self.archive_dir) {
+            for entry in dir {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with(SEGMENT_PREFIX) && name.ends_with(".log.gz.tmp") {
+                    remove_if_exists(&entry.path())?;
+                }
+            }
+        }
+        let mut next = self.segments()?.last().map_or(1, |s| s.seq + 1);
+        legacy.sort();
+        for (name, path) in legacy {
+            let ts = name
+                .trim_start_matches(SEGMENT_PREFIX)
+                .trim_end_matches(".log");
+            let target = self
+                .archive_dir
+                .join(format!("{SEGMENT_PREFIX}{next:08}-{ts}.log.gz"));
+            compress(&path, &target)?;
+            next += 1;
+        }
+        let mut inner = self.lock();
+        inner.seq = next;
+        let limits = inner.limits;
+        drop(inner);
+        prune(&self.archive_dir, &limits)
+    }
+
+    /// Move the active segment into the archive and start a new one. Returns
+    /// the moved segment, which [`Self::archive`] compresses outside the lock.
+    fn rotate(&self, inner: &mut Inner) -> io::Result<Option<PathBuf>> {
+        fs::create_dir_all(&self.archive_dir)?;
+        let name = format!(
+            "{SEGMENT_PREFIX}{:08}-{}.log",
+            inner.seq,
+            Utc::now().format("%Y%m%d%H%M%S")
+        );
+        let archived = self.archive_dir.join(name);
+        let pending = match fs::rename(&self.path, &archived) {
+            Ok(()) => Some(archived),
+            // removed from outside, nothing to archive
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        inner.seq += 1;
+        inner.lines = 0;
+        inner.bytes = 0;
+        Ok(pending)
+    }
+
+    /// Compress a segment moved aside by [`Self::rotate`] and prune the
+    /// archive. Runs on a blocking task when called within a Tokio runtime so
+    /// that writers do not wait for gzip; errors are then only logged.
+    fn archive(&self, pending: Option<PathBuf>, limits: LogLimits) -> io::Result<()> {
+        let archive_dir = self.archive_dir.clone();
+        let archive_lock = self.archive_lock.clone();
+        let job = move || -> io::Result<()> {
+            let _archive = lock(&archive_lock);
+            if let Some(path) = pending {
+                match compress(&path, &path.with_extension("log.gz")) {
+                    // removed by a clear in the meantime
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    res => res?,
+                }
+            }
+            prune(&archive_dir, &limits)
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(move || {
+                    if let Err(e) = job() {
+                        log::warn!("failed to archive log segment: {}", e);
+                    }
+                });
+                Ok(())
+            }
+            Err(_) => job(),
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|p| p.into_inner())
+}
+
+/// Segments in `dir` ordered by sequence number. A segment that is being
+/// compressed is listed once, by its compressed file if that is complete.
+fn list_segments(dir: &Path) -> io::Result<Vec<Segment>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut segments = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if let Some(seq) = segment_seq(name) {
+            segments.push(Segment {
+                seq,
+                path: entry.path(),
+                compressed: name.ends_with(".gz"),
+            });
+        }
+    }
+    segments.sort_by_key(|s| (s.seq, !s.compressed));
+    segments.dedup_by_key(|s| s.seq);
+    Ok(segments)
+}
+
+/// Reader over an archived segment, `None` if it was removed since it was
+/// listed. A pending segment compressed in the meantime is read from the
+/// compressed file.
+fn open_segment(segment: &Segment) -> io::Result<Option<Box<dyn Read>>> {
+    let path = if segment.compressed {
+        segment.path.clone()
+    } else {
+        match File::open(&segment.path) {
+            Ok(file) => return Ok(Some(Box::new(file))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        segment.path.with_extension("log.gz")
+    };
+    match File::open(path) {
+        Ok(file) => Ok(Some(Box::new(GzDecoder::new(file)))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Remove archived segments beyond the count limit or retention period.
+fn prune(dir: &Path, limits: &LogLimits) -> io::Result<()> {
+    let segments = list_segments(dir)?;
+    let excess = segments.len().saturating_sub(limits.max_segments);
+    let cutoff = limits
+        .retention
+        .and_then(|age| SystemTime::now().checked_sub(age));
+    for (i, segment) in segments.iter().enumerate() {
+        let expired = cutoff.is_some_and(|cutoff| {
+            fs::metadata(&segment.path)
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| modified < cutoff)
+        });
+        if i < excess || expired {
+            remove_if_exists(&segment.path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Filters, cursor and page size of a log query. All filters are
+/// optional and combined.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LogQuery {
+    /// Levels to include, e.g. `["WARN", "ERROR"]`; all if empty
+    pub levels: Vec<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Case-insensitive substring of the message
+    pub contains: Option<String>,
+    /// Regular expression matched against the message
+    pub regex: Option<String>,
+    /// Only entries with (`true`) or without (`false`) a stack trace
+    pub has_stack: Option<bool>,
+    /// Prefix of the `tracing` target, e.g. `tor_guardmgr`
+    pub target: Option<String>,
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Result of [`LogStore::query`]. `totals` counts the entries matching all
+/// filters except `levels`, per level and over every segment. It is only
+/// set on the first page, the counts do not change while paging.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogQueryPage {
+    pub entries: Vec<LogEntry>,
+    pub next_cursor: Option<String>,
+    pub totals: Option<BTreeMap<String, u64>>,
+}
+
+impl LogQuery {
+    fn matcher(&self) -> Result<Matcher> {
+        let regex = self
+            .regex
+            .as_deref()
+            .map(|pattern| {
+                RegexBuilder::new(pattern)
+                    .size_limit(REGEX_SIZE_LIMIT)
+                    .build()
+                    .map_err(|e| Error::InvalidConfig {
+                        key: "regex".into(),
+                        message: e.to_string(),
+                    })
+            })
+            .transpose()?;
+        Ok(Matcher {
+            levels: self.levels.iter().map(|l| l.to_uppercase()).collect(),
+            since: self.since,
+            until: self.until,
+            contains: self.contains.as_ref().map(|c| c.to_lowercase()),
+            regex,
+            has_stack: self.has_stack,
+            target: self.target.clone(),
+        })
+    }
+}
+
+/// Compiled form of a [`LogQuery`].
+#[derive(Default)]
+struct Matcher {
+    levels: Vec<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    contains: Option<String>,
+    regex: Option<Regex>,
+    has_stack: Option<bool>,
+    target: Option<String>,
+}
+
+impl Matcher {
+    /// Whether `entry` passes every filter but the level set.
+    fn matches(&self, entry: &LogEntry) -> bool {
+        if self.since.is_some() || self.until.is_some() {
+            let Ok(time) = DateTime::parse_from_rfc3339(&entry.timestamp) else {
+                return false;
+            };
+            let time = time.with_timezone(&Utc);
+            if self.since.is_some_and(|since| time < since)
+                || self.until.is_some_and(|until| time > until)
+            {
+                return false;
+            }
+        }
+        if let Some(has_stack) = self.has_stack {
+            if entry.stack.is_some() != has_stack {
+                return false;
+            }
+        }
+        if let Some(target) = &self.target {
+            if !entry
+                .target
+                .as_deref()
+                .is_some_and(|t| t.starts_with(target.as_str()))
+            {
+                return false;
+            }
+        }
+        if let Some(contains) = &self.contains {
+            if !entry.message.to_lowercase().contains(contains) {
+                return false;
+            }
+        }
+        self.regex
+            .as_ref()
+            .map_or(true, |regex| regex.is_match(&entry.message))
+    }
+
+    fn level_matches(&self, entry: &LogEntry) -> bool {
+        self.levels.is_empty()
+            || self
+                .levels
+                .iter()
+                .any(|l| l.eq_ignore_ascii_case(&entry.level))
+    }
+}
+
+/// State of a backwards read over the segments, newest first.
+struct Scan<'a> {
+    matcher: &'a Matcher,
+    prepare: &'a dyn Fn(LogEntry) -> LogEntry,
+    before: Cursor,
+    limit: usize,
+    /// Keep reading past a full page to count matches per level
+    count: bool,
+    page: VecDeque<(Cursor, LogEntry)>,
+    totals: BTreeMap<String, u64>,
+}
+
+impl<'a> Scan<'a> {
+    fn new(
+        matcher: &'a Matcher,
+        prepare: &'a dyn Fn(LogEntry) -> LogEntry,
+        before: Option<Cursor>,
+        limit: usize,
+        count: bool,
+    ) -> Self {
+        Self {
+            matcher,
+            prepare,
+            before: before.unwrap_or(Cursor {
+                segment: u64::MAX,
+                line: u64::MAX,
+            }),
+            limit,
+            count,
+            page: VecDeque::new(),
+            totals: BTreeMap::new(),
+        }
+    }
+
+    fn done(&self) -> bool {
+        !self.count && self.page.len() >= self.limit
+    }
+
+    /// Read one segment, keeping its last matching entries before the
+    /// cursor in front of those already in the page.
+    fn segment(&mut self, reader: impl Read, seq: u64) -> io::Result<()> {
+        if !self.count && seq > self.before.segment {
+            return Ok(());
+        }
+        let room = self.limit.saturating_sub(self.page.len());
+        let mut kept = VecDeque::with_capacity(room.min(1024));
+        for (index, line) in BufReader::new(reader).lines().enumerate() {
+            let cursor = Cursor {
+                segment: seq,
+                line: index as u64,
+            };
+            if !self.count && cursor >= self.before {
+                break;
+            }
+            let Ok(entry) = serde_json::from_str::<LogEntry>(&line?) else {
+                continue;
+            };
+            let entry = (self.prepare)(entry);
+            if !self.matcher.matches(&entry) {
+                continue;
+            }
+            if self.count {
+                *self.totals.entry(entry.level.to_uppercase()).or_default() += 1;
+            }
+            if room == 0 || cursor >= self.before || !self.matcher.level_matches(&entry) {
+                continue;
+            }
+            if kept.len() == room {
+                kept.pop_front();
+            }
+            kept.push_back((cursor, entry));
+        }
+        while let Some(item) = kept.pop_back() {
+            self.page.push_front(item);
+        }
+        Ok(())
+    }
+
+    /// Entries in chronological order and the cursor of the next page.
+    fn finish(self) -> (Vec<LogEntry>, Option<String>) {
+        let next_cursor = if self.page.len() >= self.limit {
+            self.page.front().map(|(cursor, _)| cursor.to_string())
+        } else {
+            None
+        };
+        (
+            self.page.into_iter().map(|(_, entry)| entry).collect(),
+            next_cursor,
+        )
+    }
+}
+
+/// Write `source` gzip compressed to `target` and remove it.
+fn compress(source: &Path, target: &Path) -> io::Result<()> {
+    let mut tmp = target.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut input = File::open(source)?;
+    let mut encoder = GzEncoder::new(File::create(&tmp)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::rename(&tmp, target)?;
+    fs::remove_file(source)
+}
+
+fn count_lines(path: &Path) -> io::Result<(u64, u64)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut lines = 0;
+    let mut bytes = 0;
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        let read = reader.read_until(b'\n', &mut buf)?;
+        if read == 0 {
+            return Ok((lines, bytes));
+        }
+        lines += 1;
+        bytes += read as u64;
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(i: usize) -> LogEntry {
+        LogEntry {
+            level: "INFO".into(),
+            timestamp: Utc::now().to_rfc3339(),
+            message: format!("line {i}"),
+            stack: None,
+            target: None,
+            fields: Default::default(),
+        }
+    }
+
+    fn messages(page: &LogPage) -> Vec<String> {
+        messages_of(&page.entries)
+    }
+
+    fn messages_of(entries: &[LogEntry]) -> Vec<String> {
+        entries.iter().map(|e| e.message.clone()).collect()
+    }
+
+    fn limits(max_lines: usize, max_segments: usize) -> LogLimits {
+        LogLimits {
+            max_lines,
+            max_segments,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rotates_into_bounded_compressed_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LogStore::open(dir.path().join("torwell.log"), limits(3, 2));
+        for i in 0..11 {
+            store.append(&entry(i)).unwrap();
+        }
+        let mut names: Vec<String> = fs::read_dir(dir.path().join("archive"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 2);
+        assert!(names[0].starts_with("torwell-00000002-") && names[0].ends_with(".log.gz"));
+
+        let reopened = LogStore::open(dir.path().join("torwell.log"), limits(3, 2));
+        reopened.append(&entry(11)).unwrap();
+        let page = reopened.read_page(None, 100).unwrap();
+        assert_eq!(messages(&page).first().unwrap(), "line 3");
+        assert_eq!(messages(&page).last().unwrap(), "line 11");
+    }
+
+    #[tokio::test]
+    async fn reads_segments_while_they_are_compressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LogStore::open(dir.path().join("torwell.log"), limits(2, 10));
+        for i in 0..7 {
+            store.append(&entry(i)).unwrap();
+        }
+        let all: Vec<String> = (0..7).map(|i| format!("line {i}")).collect();
+        assert_eq!(messages(&store.read_page(None, 100).unwrap()), all);
+
+        let archive = dir.path().join("archive");
+        let names = || -> Vec<String> {
+            fs::read_dir(&archive)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+                .collect()
+        };
+        for _ in 0..200 {
+            if names().iter().all(|n| n.ends_with(".log.gz")) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(names().len(), 3);
+        assert!(names().iter().all(|n| n.ends_with(".log.gz")));
+        assert_eq!(messages(&store.read_page(None, 100).unwrap()), all);
+    }
+
+    #[test]
+    fn pages_across_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LogStore::open(dir.path().join("torwell.log"), limits(4, 10));
+        for i in 0..10 {
+            store.append(&entry(i)).unwrap();
+        }
+        let first = store.read_page(None, 4).unwrap();
+        assert_eq!(messages(&first), ["line 6", "line 7", "line 8", "line 9"]);
+        let cursor = Cursor::parse(first.next_cursor.as_deref().unwrap()).unwrap();
+        let second = store.read_page(Some(cursor), 4).unwrap();
+        assert_eq!(messages(&second), ["line 2", "line 3", "line 4", "line 5"]);
+        let cursor = Cursor::parse(second.next_cursor.as_deref().unwrap()).unwrap();
+        let last = store.read_page(Some(cursor), 4).unwrap();
+        assert_eq!(messages(&last), ["line 0", "line 1"]);
+        assert!(last.next_cursor.is_none());
+        assert!(Cursor::parse("nope").is_err());
+    }
+
+    #[test]
+    fn query_filters_and_counts_across_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LogStore::open(dir.path().join("torwell.log"), limits(3, 10));
+        for i in 0..10 {
+            let mut entry = entry(i);
+            if i % 2 == 0 {
+                entry.level = "WARN".into();
+                entry.stack = Some("trace".into());
+            }
+            if i == 9 {
+                entry.target = Some("tor_guardmgr::sample".into());
+            }
+            store.append(&entry).unwrap();
+        }
+        let query = LogQuery {
+            levels: vec!["warn".into()],
+            has_stack: Some(true),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let first = store.query(&query).unwrap();
+        assert_eq!(messages_of(&first.entries), ["line 6", "line 8"]);
+        let totals = first.totals.unwrap();
+        assert_eq!(totals.get("WARN"), Some(&5));
+        assert_eq!(totals.get("INFO"), None);
+
+        let next = store
+            .query(&LogQuery {
+                cursor: first.next_cursor.clone(),
+                ..query.clone()
+            })
+            .unwrap();
+        assert_eq!(messages_of(&next.entries), ["line 2", "line 4"]);
+        assert!(next.totals.is_none());
+
+        let regex = store
+            .query(&LogQuery {
+                regex: Some(r"^line [13]$".into()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(messages_of(&regex.entries), ["line 1", "line 3"]);
+        assert_eq!(regex.totals.unwrap().get("INFO"), Some(&2));
+        let future = store
+            .query(&LogQuery {
+                since: Some(Utc::now() + chrono::Duration::hours(1)),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(future.entries.is_empty() && future.totals.unwrap().is_empty());
+        let traced = store
+            .query(&LogQuery {
+                target: Some("tor_guardmgr".into()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(messages_of(&traced.entries), ["line 9"]);
+        assert!(store
+            .query(&LogQuery {
+                regex: Some("(".into()),
+                ..Default::default()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn migrates_legacy_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("archive");
+        fs::create_dir_all(&archive).unwrap();
+        let line = serde_json::to_string(&entry(0)).unwrap();
+        fs::write(archive.join("torwell-20240101000000.log"), line).unwrap();
+        fs::write(archive.join("metrics-20240101000000.json"), "{}").unwrap();
+
+        let store = LogStore::open(dir.path().join("torwell.log"), LogLimits::default());
+        assert!(archive
+            .join("torwell-00000001-20240101000000.log.gz")
+            .exists());
+        assert!(archive.join("metrics-20240101000000.json").exists());
+        store.append(&entry(1)).unwrap();
+        let page = store.read_page(None, 10).unwrap();
+        assert_eq!(messages(&page), ["line 0", "line 1"]);
+        store.clear().unwrap();
+        assert!(store.read_page(None, 10).unwrap().entries.is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+//! Locations of configuration, data and cache files. They follow the
+//! platform conventions of [`ProjectDirs`] unless portable mode keeps
+//! everything in one directory, for example next to the executable.
+
+use directories::ProjectDirs;
+use once_cell::sync::Lazy;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Application name used for the platform directories
+pub const APP_NAME: &str = "torwell84";
+/// Environment variable enabling portable mode. Its value is the portable
+/// root; `1` uses the directory of the executable.
+pub const PORTABLE_ENV: &str = "TORWELL_PORTABLE";
+/// File next to the executable that enables portable mode
+pub const PORTABLE_MARKER: &str = "torwell84.portable";
+
+/// Files older builds kept relative to the working directory, with the
+/// location they moved to.
+const LEGACY_FILES: [(&str, fn(&AppPaths) -> PathBuf); 5] = [
+    ("src-tauri/app_config.json", AppPaths::config_file),
+    (
+        "src-tauri/certs/cert_config.json",
+        AppPaths::cert_config_file,
+    ),
+    ("src-tauri/certs/server.pem", AppPaths::cert_file),
+    ("metrics.json", AppPaths::metrics_file),
+    ("torwell.log", AppPaths::log_file),
+];
+
+/// Resolved base directories.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppPaths {
+    pub config_dir: PathBuf,
+    pub data_dir: PathBuf,
+    pub cache_dir: PathBuf,
+    /// Directory for sockets and locks, if the platform has one
+    pub runtime_dir: Option<PathBuf>,
+    pub portable: bool,
+}
+
+impl AppPaths {
+    /// Resolve the directories for this process, honouring portable mode.
+    pub fn resolve() -> Self {
+        if let Some(root) = portable_root() {
+            return Self::portable(&root);
+        }
+        match ProjectDirs::from("", "", APP_NAME) {
+            Some(proj) => Self {
+                config_dir: proj.config_dir().to_path_buf(),
+                data_dir: proj.data_dir().to_path_buf(),
+                cache_dir: proj.cache_dir().to_path_buf(),
+                runtime_dir: proj.runtime_dir().map(Path::to_path_buf),
+                portable: false,
+            },
+            // No home directory, e.g. a system account without one
+            None => Self::portable(&std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."))),
+        }
+    }
+
+    /// Keep everything below `root`.
+    pub fn portable(root: &Path) -> Self {
+        Self {
+            config_dir: root.join("config"),
+            data_dir: root.join("data"),
+            cache_dir: root.join("cache"),
+            runtime_dir: None,
+            portable: true,
+        }
+    }
+
+    pub fn config_file(&self) -> PathBuf {
+        self.config_dir.join("app_config.json")
+    }
+
+    pub fn cert_config_file(&self) -> PathBuf {
+        self.config_dir.join("cert_config.json")
+    }
+
+    /// Pinned server certificate, rewritten by certificate updates
+    pub fn cert_file(&self) -> PathBuf {
+        self.data_dir.join("server.pem")
+    }
+
+    pub fn log_file(&self) -> PathBuf {
+        self.data_dir.join("torwell.log")
+    }
+
+    pub fn metrics_file(&self) -> PathBuf {
+        self.data_dir.join("metrics.json")
+    }
+
+    pub fn shader_cache_dir(&self) -> PathBuf {
+        self.cache_dir.join("shader_cache")
+    }
+
+    /// Guard and key state of the embedded Tor client
+    pub fn arti_state_dir(&self) -> PathBuf {
+        self.data_dir.join("arti")
+    }
+
+    /// Directory documents cached by the embedded Tor client
+    pub fn arti_cache_dir(&self) -> PathBuf {
+        self.cache_dir.join("arti")
+    }
+
+    /// Directory for the instance lock and sockets
+    pub fn runtime_dir(&self) -> PathBuf {
+        self.runtime_dir
+            .clone()
+            .unwrap_or_else(|| self.data_dir.clone())
+    }
+}
+
+fn portable_root() -> Option<PathBuf> {
+    let exe_dir = || {
+        std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(Path::to_path_buf))
+    };
+    match std::env::var_os(PORTABLE_ENV) {
+        Some(value) if value == "1" => exe_dir(),
+        Some(value) if !value.is_empty() => Some(PathBuf::from(value)),
+        _ => exe_dir().filter(|dir| dir.join(PORTABLE_MARKER).exists()),
+    }
+}
+
+static PATHS: Lazy<AppPaths> = Lazy::new(AppPaths::resolve);
+
+/// Directories of this process, resolved on first use.
+pub fn paths() -> &'static AppPaths {
+    &PATHS
+}
+
+/// `path` with its parent directory created.
+pub fn ensure_parent(path: PathBuf) -> PathBuf {
+    if let Some(parent) = path.parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    path
+}
+
+/// Copy files older builds kept relative to `base` to their location in
+/// `paths`. Files already present at the new location are left alone, so
+/// this only does something on the first run. The old files stay in place
+/// as they may belong to a source checkout. Returns what was copied.
+pub fn migrate_legacy_files(base: &Path, paths: &AppPaths) -> Vec<(PathBuf, PathBuf)> {
+    let mut moved = Vec::new();
+    for (legacy, target) in LEGACY_FILES {
+        let from = base.join(legacy);
+        let to = target(paths);
+        if from == to || !from.is_file() || to.exists() {
+            continue;
+        }
+        let result = std::fs::create_dir_all(to.parent().unwrap_or(Path::new(".")))
+            .and_then(|_| std::fs::copy(&from, &to));
+        match result {
+            Ok(_) => {
+                log::info!("migrated {} to {}", from.display(), to.display());
+                moved.push((from, to));
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => log::warn!(
+                "failed to migrate {} to {}: {}",
+                from.display(),
+                to.display(),
+                e
+            ),
+        }
+    }
+    moved
+}
+
+/// [`migrate_legacy_files`] from the working directory into [`paths`].
+pub fn migrate_from_working_dir() -> Vec<(PathBuf, PathBuf)> {
+    match std::env::current_dir() {
+        Ok(dir) => migrate_legacy_files(&dir, paths()),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn portable_layout_stays_below_root() {
+        let paths = AppPaths::portable(Path::new("/opt/torwell84"));
+        assert_eq!(
+            paths.config_file(),
+            Path::new("/opt/torwell84/config/app_config.json")
+        );
+        assert_eq!(
+            paths.cert_file(),
+            Path::new("/opt/torwell84/data/server.pem")
+        );
+        assert_eq!(paths.runtime_dir(), Path::new("/opt/torwell84/data"));
+        assert!(paths.arti_cache_dir().starts_with("/opt/torwell84/cache"));
+    }
+
+    #[test]
+    fn migrates_relative_files_once() {
+        let old = tempfile::tempdir().unwrap();
+        let new = tempfile::tempdir().unwrap();
+        let paths = AppPaths::portable(new.path());
+        std::fs::create_dir_all(old.path().join("src-tauri/certs")).unwrap();
+        std::fs::write(old.path().join("src-tauri/app_config.json"), "{}").unwrap();
+        std::fs::write(old.path().join("src-tauri/certs/server.pem"), "PEM").unwrap();
+
+        let moved = migrate_legacy_files(old.path(), &paths);
+        assert_eq!(moved.len(), 2);
+        assert_eq!(std::fs::read_to_string(paths.cert_file()).unwrap(), "PEM");
+        assert!(old.path().join("src-tauri/app_config.json").exists());
+
+        std::fs::write(old.path().join("src-tauri/app_config.json"), "{\"x\":1}").unwrap();
+        assert!(migrate_legacy_files(old.path(), &paths).is_empty());
+        assert_eq!(std::fs::read_to_string(paths.config_file()).unwrap(), "{}");
+    }
+}
@@ -0,0 +1,349 @@
+use crate::error::{Error, Result};
+use governor::clock::DefaultClock;
+use governor::state::{InMemoryState, NotKeyed};
+use governor::{Quota, RateLimiter};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::sync::{Arc, RwLock};
+use tokio::sync::Mutex;
+
+/// Ports that carry plaintext protocols (FTP, Telnet, HTTP, POP3, IMAP).
+pub const DEFAULT_WARN_PORTS: &[u16] = &[21, 23, 80, 110, 143];
+
+/// User editable destination policy for proxied streams.
+///
+/// `block` and `allow` contain patterns of the following kinds:
+/// - a port (`25`) or port range (`6660-6669`)
+/// - a host glob using `*` and `?` (`*.example.com`)
+/// - an IP address or CIDR block (`10.0.0.0/8`, `fd00::/8`)
+/// - `.onion` as shorthand for all onion services
+///
+/// A destination matching any `allow` pattern is always permitted. Otherwise
+/// it is refused if it matches a `block` pattern. Streams to a port listed in
+/// `warn_ports` are permitted but raise a security warning.
+///
+/// CIDR patterns only apply to destinations given as IP literals, since
+/// hostnames are resolved by the exit relay.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DestinationPolicyConfig {
+    #[serde(default)]
+    pub block: Vec<String>,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default = "default_warn_ports")]
+    pub warn_ports: Vec<u16>,
+}
+
+fn default_warn_ports() -> Vec<u16> {
+    DEFAULT_WARN_PORTS.to_vec()
+}
+
+impl Default for DestinationPolicyConfig {
+    fn default() -> Self {
+        Self {
+            block: Vec::new(),
+            allow: Vec::new(),
+            warn_ports: default_warn_ports(),
+        }
+    }
+}
+
+/// Outcome of evaluating a destination against the policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyDecision {
+    Allow,
+    Warn(String),
+    Block(String),
+}
+
+/// A single destination pattern as described on [`DestinationPolicyConfig`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Matcher {
+    Ports(u16, u16),
+    Host(String),
+    Cidr(IpAddr, u8),
+}
+
+impl Matcher {
+    pub(crate) fn parse(pattern: &str) -> Result<Self> {
+        let p = pattern.trim().to_ascii_lowercase();
+        if p.is_empty() {
+            return Err(Self::invalid(pattern, "empty pattern"));
+        }
+        if p == ".onion" {
+            return Ok(Matcher::Host("*.onion".into()));
+        }
+        if p.chars().all(|c| c.is_ascii_digit() || c == '-') {
+            let (lo, hi) = p.split_once('-').unwrap_or((&p, &p));
+            let lo = lo.parse::<u16>().map_err(|e| Self::invalid(pattern, e))?;
+            let hi = hi.parse::<u16>().map_err(|e| Self::invalid(pattern, e))?;
+            if lo > hi {
+                return Err(Self::invalid(pattern, "port range is reversed"));
+            }
+            return Ok(Matcher::Ports(lo, hi));
+        }
+        let (addr, prefix) = match p.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (p.as_str(), None),
+        };
+        if let Ok(ip) = addr.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let bits = match prefix {
+                Some(bits) => bits.parse::<u8>().map_err(|e| Self::invalid(pattern, e))?,
+                None => max,
+            };
+            if bits > max {
+                return Err(Self::invalid(pattern, "prefix length too large"));
+            }
+            return Ok(Matcher::Cidr(ip, bits));
+        }
+        if prefix.is_some() {
+            return Err(Self::invalid(pattern, "invalid CIDR block"));
+        }
+        Ok(Matcher::Host(p))
+    }
+
+    fn invalid(pattern: &str, reason: impl ToString) -> Error {
+        Error::ConfigError {
+            step: "policy::parse".into(),
+            source_message: format!("invalid pattern '{}': {}", pattern, reason.to_string()),
+            backtrace: format!("{:?}", std::backtrace::Backtrace::capture()),
+        }
+    }
+
+    pub(crate) fn matches(&self, host: &str, ip: Option<IpAddr>, port: u16) -> bool {
+        match self {
+            Matcher::Ports(lo, hi) => (*lo..=*hi).contains(&port),
+            Matcher::Host(glob) => glob_match(glob, host),
+            Matcher::Cidr(net, bits) => ip.map(|ip| cidr_contains(*net, *bits, ip)).unwrap_or(false),
+        }
+    }
+}
+
+/// Match `text` against a glob supporting `*` and `?`.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+fn cidr_contains(net: IpAddr, bits: u8, ip: IpAddr) -> bool {
+    match (net, ip) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - bits as u32).unwrap_or(0);
+            u32::from(net) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - bits as u32).unwrap_or(0);
+            u128::from(net) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Lower-case `host` and parse it as an IP literal if possible.
+pub(crate) fn normalise_destination(host: &str) -> (String, Option<IpAddr>) {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let ip = host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse::<IpAddr>()
+        .ok();
+    (host, ip)
+}
+
+#[derive(Debug, Clone, Default)]
+struct CompiledPolicy {
+    config: DestinationPolicyConfig,
+    block: Vec<(String, Matcher)>,
+    allow: Vec<(String, Matcher)>,
+}
+
+impl CompiledPolicy {
+    fn compile(config: DestinationPolicyConfig) -> Result<Self> {
+        let compile_list = |list: &[String]| -> Result<Vec<(String, Matcher)>> {
+            list.iter()
+                .map(|p| Matcher::parse(p).map(|m| (p.clone(), m)))
+                .collect()
+        };
+        Ok(Self {
+            block: compile_list(&config.block)?,
+            allow: compile_list(&config.allow)?,
+            config,
+        })
+    }
+
+    fn evaluate(&self, host: &str, port: u16) -> PolicyDecision {
+        let (host, ip) = normalise_destination(host);
+        if self.allow.iter().any(|(_, m)| m.matches(&host, ip, port)) {
+            return PolicyDecision::Allow;
+        }
+        if let Some((pattern, _)) = self.block.iter().find(|(_, m)| m.matches(&host, ip, port)) {
+            return PolicyDecision::Block(format!(
+                "Blocked stream to {}:{} (matches '{}')",
+                host, port, pattern
+            ));
+        }
+        if self.config.warn_ports.contains(&port) {
+            return PolicyDecision::Warn(format!(
+                "Plaintext stream to {}:{} through Tor; traffic is visible to the exit relay",
+                host, port
+            ));
+        }
+        PolicyDecision::Allow
+    }
+}
+
+/// Destination policy consulted before a proxied stream is opened.
+#[derive(Clone)]
+pub struct DestinationPolicy {
+    compiled: Arc<RwLock<CompiledPolicy>>,
+    warning_cb: Arc<Mutex<Option<Box<dyn Fn(String) + Send + Sync>>>>,
+    security_warning_limiter: Arc<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>,
+}
+
+impl Default for DestinationPolicy {
+    fn default() -> Self {
+        Self {
+            compiled: Arc::new(RwLock::new(
+                CompiledPolicy::compile(DestinationPolicyConfig::default())
+                    .expect("default policy is valid"),
+            )),
+            warning_cb: Arc::new(Mutex::new(None)),
+            security_warning_limiter: Arc::new(RateLimiter::direct(Quota::per_minute(
+                NonZeroU32::new(6).unwrap(),
+            ))),
+        }
+    }
+}
+
+impl DestinationPolicy {
+    /// Provide a callback to emit security warnings
+    pub async fn set_warning_callback<F>(&self, cb: F)
+    where
+        F: Fn(String) + Send + Sync + 'static,
+    {
+        *self.warning_cb.lock().await = Some(Box::new(cb));
+    }
+
+    /// Validate and install a new policy configuration.
+    pub fn set_config(&self, config: DestinationPolicyConfig) -> Result<()> {
+        let compiled = CompiledPolicy::compile(config)?;
+        *self
+            .compiled
+            .write()
+            .unwrap_or_else(|poison| poison.into_inner()) = compiled;
+        Ok(())
+    }
+
+    pub fn config(&self) -> DestinationPolicyConfig {
+        self.compiled
+            .read()
+            .unwrap_or_else(|poison| poison.into_inner())
+            .config
+            .clone()
+    }
+
+    pub fn evaluate(&self, host: &str, port: u16) -> PolicyDecision {
+        self.compiled
+            .read()
+            .unwrap_or_else(|poison| poison.into_inner())
+            .evaluate(host, port)
+    }
+
+    /// Evaluate a destination and emit a rate limited warning for blocked or
+    /// plaintext streams. Returns `false` if the stream must be refused.
+    pub async fn check(&self, host: &str, port: u16) -> bool {
+        match self.evaluate(host, port) {
+            PolicyDecision::Allow => true,
+            PolicyDecision::Warn(msg) => {
+                log::warn!("{}", msg);
+                self.notify(msg).await;
+                true
+            }
+            PolicyDecision::Block(msg) => {
+                log::warn!("{}", msg);
+                self.notify(msg).await;
+                false
+            }
+        }
+    }
+
+    async fn notify(&self, msg: String) {
+        if self.security_warning_limiter.check().is_ok() {
+            if let Some(cb) = self.warning_cb.lock().await.as_ref() {
+                cb(msg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(block: &[&str], allow: &[&str]) -> CompiledPolicy {
+        CompiledPolicy::compile(DestinationPolicyConfig {
+            block: block.iter().map(|s| s.to_string()).collect(),
+            allow: allow.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_match("*.example.com", "www.example.com"));
+        assert!(!glob_match("*.example.com", "example.com"));
+        assert!(glob_match("mail?.example.*", "mail1.example.org"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn blocks_ports_hosts_cidrs_and_onions() {
+        let p = policy(&["25", "6660-6669", "*.tracker.net", "10.0.0.0/8", ".onion"], &[]);
+        assert!(matches!(p.evaluate("smtp.example.com", 25), PolicyDecision::Block(_)));
+        assert!(matches!(p.evaluate("irc.example.com", 6667), PolicyDecision::Block(_)));
+        assert!(matches!(p.evaluate("a.tracker.net", 443), PolicyDecision::Block(_)));
+        assert!(matches!(p.evaluate("10.1.2.3", 443), PolicyDecision::Block(_)));
+        assert!(matches!(p.evaluate("abc.onion", 443), PolicyDecision::Block(_)));
+        assert_eq!(p.evaluate("example.com", 443), PolicyDecision::Allow);
+    }
+
+    #[test]
+    fn allow_overrides_block_and_warn() {
+        let p = policy(&["*.example.com"], &["safe.example.com"]);
+        assert_eq!(p.evaluate("safe.example.com", 80), PolicyDecision::Allow);
+        assert!(matches!(p.evaluate("www.example.com", 443), PolicyDecision::Block(_)));
+        assert!(matches!(p.evaluate("other.org", 80), PolicyDecision::Warn(_)));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for bad in ["", "70000", "20-10", "10.0.0.0/40", "host/8"] {
+            let cfg = DestinationPolicyConfig {
+                block: vec![bad.into()],
+                ..Default::default()
+            };
+            assert!(CompiledPolicy::compile(cfg).is_err(), "{bad} should be rejected");
+        }
+    }
+}
@@ -0,0 +1,347 @@
+//! Named connection profiles. A profile bundles the routing settings,
+//! proxy listeners with their isolation modes, exit rules and the system
+//! proxy choice so users can switch between setups in one step. Profiles
+//! are kept in `profiles.json` in the data directory.
+
+use crate::error::{Error, Result};
+use crate::listeners::ListenerSpec;
+use crate::paths::paths;
+use crate::routing::ExitRoutingConfig;
+use crate::settings::{write_atomic, RoutingSettings};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Schema version written by this build
+pub const PROFILES_VERSION: u32 = 1;
+/// Longest accepted profile name
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Location of the profile file.
+pub fn profiles_path() -> PathBuf {
+    paths().data_dir.join("profiles.json")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    #[serde(default)]
+    pub routing: RoutingSettings,
+    /// Additional listeners, each with its stream isolation mode
+    #[serde(default)]
+    pub listeners: Vec<ListenerSpec>,
+    #[serde(default)]
+    pub exit_rules: ExitRoutingConfig,
+    #[serde(default = "default_system_proxy_enabled")]
+    pub system_proxy_enabled: bool,
+}
+
+fn default_system_proxy_enabled() -> bool {
+    true
+}
+
+impl Profile {
+    pub fn validate(&self) -> Result<()> {
+        validate_name(&self.name)?;
+        for spec in &self.listeners {
+            spec.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Whether switching from `current` to this profile needs a new Tor
+    /// client. Entry and middle countries, bridges and the torrc are only
+    /// read when connecting; everything else is applied live.
+    pub fn needs_reconnect(&self, current: &RoutingSettings) -> bool {
+        self.routing.entry_country != current.entry_country
+            || self.routing.middle_country != current.middle_country
+            || self.routing.bridges != current.bridges
+            || self.routing.torrc != current.torrc
+    }
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::InvalidConfig {
+        key: "profile".into(),
+        message: message.into(),
+    }
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.trim().is_empty() {
+        return Err(invalid("profile name must not be empty"));
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(invalid(format!(
+            "profile name is longer than {MAX_NAME_LEN} bytes"
+        )));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(invalid("profile name contains control characters"));
+    }
+    Ok(())
+}
+
+/// Contents of `profiles.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profiles {
+    pub version: u32,
+    /// Name of the profile applied last
+    #[serde(default)]
+    pub active: Option<String>,
+    #[serde(default)]
+    pub profiles: Vec<Profile>,
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Self {
+            version: PROFILES_VERSION,
+            active: None,
+            profiles: Vec::new(),
+        }
+    }
+}
+
+impl Profiles {
+    fn find(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    fn upsert(&mut self, profile: Profile) {
+        match self.profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(existing) => *existing = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+}
+
+/// Profile file and its current contents.
+pub struct ProfileStore {
+    path: PathBuf,
+    current: Mutex<Profiles>,
+}
+
+impl ProfileStore {
+    /// Load the profiles at `path`. A missing or unreadable file yields an
+    /// empty list; an unreadable file is kept as `.bak`.
+    pub fn open(path: PathBuf) -> Self {
+        let profiles = match std::fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<Profiles>(&contents) {
+                Ok(profiles) if profiles.version <= PROFILES_VERSION => profiles,
+                Ok(profiles) => {
+                    log::warn!(
+                        "ignoring profiles file {}: version {} is newer than supported version {}",
+                        path.display(),
+                        profiles.version,
+                        PROFILES_VERSION
+                    );
+                    Profiles::default()
+                }
+                Err(e) => {
+                    let mut backup = path.clone().into_os_string();
+                    backup.push(".bak");
+                    log::warn!(
+                        "ignoring profiles file {}: {}, moved to {}",
+                        path.display(),
+                        e,
+                        Path::new(&backup).display()
+                    );
+                    let _ = std::fs::rename(&path, &backup);
+                    Profiles::default()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Profiles::default(),
+            Err(e) => {
+                log::warn!("failed to read profiles file {}: {}", path.display(), e);
+                Profiles::default()
+            }
+        };
+        Self {
+            path,
+            current: Mutex::new(profiles),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn list(&self) -> Profiles {
+        self.current
+            .lock()
+            .unwrap_or_else(|p| p.into_inner())
+            .clone()
+    }
+
+    /// Names of all profiles in the order they were created.
+    pub fn names(&self) -> Vec<String> {
+        self.list().profiles.into_iter().map(|p| p.name).collect()
+    }
+
+    pub fn active(&self) -> Option<String> {
+        self.list().active
+    }
+
+    pub fn get(&self, name: &str) -> Result<Profile> {
+        self.list()
+            .find(name)
+            .cloned()
+            .ok_or_else(|| invalid(format!("unknown profile '{name}'")))
+    }
+
+    /// Add `profile` or replace the profile of the same name.
+    pub fn save(&self, profile: Profile) -> Result<()> {
+        profile.validate()?;
+        self.update(|p| {
+            p.upsert(profile);
+            Ok(())
+        })
+    }
+
+    /// Copy profile `from` under the new name `to`.
+    pub fn duplicate(&self, from: &str, to: &str) -> Result<Profile> {
+        validate_name(to)?;
+        let mut copy = self.get(from)?;
+        copy.name = to.to_string();
+        let result = copy.clone();
+        self.update(|p| {
+            if p.find(to).is_some() {
+                return Err(invalid(format!("profile '{to}' already exists")));
+            }
+            p.profiles.push(copy);
+            Ok(())
+        })?;
+        Ok(result)
+    }
+
+    /// Remove a profile. Returns `false` if there was none of that name.
+    pub fn delete(&self, name: &str) -> Result<bool> {
+        let mut removed = false;
+        self.update(|p| {
+            let before = p.profiles.len();
+            p.profiles.retain(|profile| profile.name != name);
+            removed = p.profiles.len() != before;
+            if p.active.as_deref() == Some(name) {
+                p.active = None;
+            }
+            Ok(())
+        })?;
+        Ok(removed)
+    }
+
+    /// Replace all profiles, e.g. from an imported bundle. The active
+    /// profile is kept if a profile of that name remains.
+    pub fn replace_all(&self, profiles: Vec<Profile>) -> Result<()> {
+        for profile in &profiles {
+            profile.validate()?;
+        }
+        self.update(|p| {
+            p.profiles = profiles;
+            if let Some(active) = p.active.clone() {
+                if p.find(&active).is_none() {
+                    p.active = None;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    pub fn set_active(&self, name: &str) -> Result<()> {
+        self.update(|p| {
+            if p.find(name).is_none() {
+                return Err(invalid(format!("unknown profile '{name}'")));
+            }
+            p.active = Some(name.to_string());
+            Ok(())
+        })
+    }
+
+    /// Serialize a single profile for sharing.
+    pub fn export(&self, name: &str) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&self.get(name)?)?)
+    }
+
+    /// Add a profile exported by [`Self::export`], replacing one of the
+    /// same name.
+    pub fn import(&self, json: &str) -> Result<Profile> {
+        let profile: Profile =
+            serde_json::from_str(json).map_err(|e| invalid(format!("invalid profile: {e}")))?;
+        self.save(profile.clone())?;
+        Ok(profile)
+    }
+
+    /// Apply `change` and write the result. The in-memory profiles are only
+    /// replaced once the file was written.
+    fn update(&self, change: impl FnOnce(&mut Profiles) -> Result<()>) -> Result<()> {
+        let mut current = self.current.lock().unwrap_or_else(|p| p.into_inner());
+        let mut next = current.clone();
+        change(&mut next)?;
+        if next != *current {
+            next.version = PROFILES_VERSION;
+            let serialized = serde_json::to_vec_pretty(&next)?;
+            write_atomic(&self.path, &serialized)?;
+            *current = next;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(name: &str) -> Profile {
+        Profile {
+            name: name.into(),
+            routing: RoutingSettings {
+                exit_country: Some("DE".into()),
+                bridges: vec!["obfs4 1.2.3.4:443".into()],
+                ..Default::default()
+            },
+            listeners: Vec::new(),
+            exit_rules: ExitRoutingConfig::default(),
+            system_proxy_enabled: false,
+        }
+    }
+
+    #[test]
+    fn duplicate_and_delete_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("profiles.json");
+        let store = ProfileStore::open(path.clone());
+        store.save(profile("work")).unwrap();
+        store.duplicate("work", "work copy").unwrap();
+        store.set_active("work").unwrap();
+        assert!(store.duplicate("work", "work copy").is_err());
+
+        let reopened = ProfileStore::open(path);
+        assert_eq!(reopened.names(), vec!["work", "work copy"]);
+        assert_eq!(reopened.active().as_deref(), Some("work"));
+        assert!(reopened.delete("work").unwrap());
+        assert_eq!(reopened.active(), None);
+        assert!(!reopened.delete("work").unwrap());
+    }
+
+    #[test]
+    fn export_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ProfileStore::open(dir.path().join("profiles.json"));
+        store.save(profile("travel")).unwrap();
+        let json = store.export("travel").unwrap();
+
+        let other = ProfileStore::open(dir.path().join("other.json"));
+        assert_eq!(other.import(&json).unwrap(), profile("travel"));
+        assert!(other.import(r#"{"name": ""}"#).is_err());
+    }
+
+    #[test]
+    fn only_circuit_settings_need_a_reconnect() {
+        let work = profile("work");
+        let mut current = work.routing.clone();
+        current.exit_country = Some("NL".into());
+        assert!(!work.needs_reconnect(&current));
+        current.bridges.clear();
+        assert!(work.needs_reconnect(&current));
+    }
+}
@@ -0,0 +1,328 @@
+//! Redaction of addresses in log entries, connection events and exported
+//! diagnostics. IP addresses, hostnames, onion addresses, relay
+//! fingerprints, bridge certificates and URL credentials are replaced with
+//! pseudonyms such as `[ip:3fa91c2d]`. Pseudonyms are a keyed hash of the
+//! value, so the same address maps to the same pseudonym across entries
+//! while the key, kept in `redaction.key` in the data directory, prevents
+//! reversing them by hashing candidate addresses.
+
+use crate::paths::paths;
+use crate::state::LogEntry;
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// Location of the pseudonym key.
+pub fn redaction_key_path() -> PathBuf {
+    paths().data_dir.join("redaction.key")
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactionLevel {
+    /// Keep everything
+    None,
+    /// Pseudonymize addresses but keep ports, URL paths, top-level domains
+    /// and loopback addresses
+    #[default]
+    Partial,
+    /// Pseudonymize every address and drop ports and URL paths
+    Strict,
+}
+
+/// File extensions not mistaken for top-level domains.
+const FILE_EXTENSIONS: &[&str] = &[
+    "bak", "cfg", "conf", "csv", "db", "dll", "dylib", "exe", "gz", "html", "ini", "js", "json",
+    "key", "lock", "log", "md", "pem", "png", "py", "rs", "sh", "so", "sock", "svg", "tar", "tmp",
+    "toml", "ts", "txt", "xml", "yaml", "yml", "zip",
+];
+
+static URL: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"\b[A-Za-z][A-Za-z0-9+.-]*://[^\s"'<>]+"#).unwrap());
+static CERT: Lazy<Regex> = Lazy::new(|| Regex::new(r"\bcert=[A-Za-z0-9+/]+=*").unwrap());
+static TOKEN: Lazy<Regex> = Lazy::new(|| Regex::new(r"[0-9A-Za-z_.:\[\]-]+").unwrap());
+
+pub struct Redactor {
+    key: [u8; 32],
+}
+
+impl Redactor {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key }
+    }
+
+    /// Use the key at `path`, creating it on first use. If it can be
+    /// neither read nor written a key for this run is used, so pseudonyms
+    /// only correlate within the run.
+    pub fn open(path: &Path) -> Self {
+        match std::fs::read(path) {
+            Ok(bytes) if bytes.len() == 32 => {
+                let mut key = [0u8; 32];
+                key.copy_from_slice(&bytes);
+                return Self::new(key);
+            }
+            Ok(_) => log::warn!("ignoring malformed redaction key {}", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("failed to read redaction key {}: {}", path.display(), e),
+        }
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        if let Err(e) = write_key(path, &key) {
+            log::warn!("failed to write redaction key {}: {}", path.display(), e);
+        }
+        Self::new(key)
+    }
+
+    /// Stable pseudonym of `value`, e.g. `[host:3fa91c2d]`.
+    pub fn pseudonym(&self, kind: &str, value: &str) -> String {
+        let hash = blake3::keyed_hash(&self.key, value.to_ascii_lowercase().as_bytes());
+        format!("[{kind}:{}]", &hash.to_hex()[..8])
+    }
+
+    pub fn redact(&self, level: RedactionLevel, text: &str) -> String {
+        if level == RedactionLevel::None {
+            return text.to_string();
+        }
+        let text = URL.replace_all(text, |caps: &Captures| self.url(level, &caps[0]));
+        let text = CERT.replace_all(&text, |caps: &Captures| {
+            format!("cert={}", self.pseudonym("cert", &caps[0][5..]))
+        });
+        TOKEN
+            .replace_all(&text, |caps: &Captures| self.token(level, &caps[0]))
+            .into_owned()
+    }
+
+    pub fn redact_entry(&self, level: RedactionLevel, mut entry: LogEntry) -> LogEntry {
+        if level != RedactionLevel::None {
+            entry.message = self.redact(level, &entry.message);
+            entry.stack = entry.stack.map(|stack| self.redact(level, &stack));
+            for value in entry.fields.values_mut() {
+                *value = self.redact(level, value);
+            }
+        }
+        entry
+    }
+
+    /// Redact the authority of a URL. Credentials are dropped; strict also
+    /// drops the path.
+    fn url(&self, level: RedactionLevel, url: &str) -> String {
+        let trimmed = url.trim_end_matches(['.', ',', ';', ':', '!', '?', ')']);
+        let trailing = &url[trimmed.len()..];
+        let (scheme, rest) = trimmed.split_once("://").unwrap_or(("", trimmed));
+        let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+        let (authority, path) = rest.split_at(end);
+        let host_port = authority.rsplit_once('@').map_or(authority, |(_, h)| h);
+        let (host, port) = split_port(host_port);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let mut out = format!("{scheme}://{}", self.host(level, host, true));
+        if level == RedactionLevel::Partial {
+            if let Some(port) = port {
+                out.push(':');
+                out.push_str(port);
+            }
+            out.push_str(path);
+        }
+        out.push_str(trailing);
+        out
+    }
+
+    /// Redact a bare word if it is an address, fingerprint or hostname.
+    fn token(&self, level: RedactionLevel, raw: &str) -> String {
+        let core = raw.trim_end_matches(['.', ':', '-']);
+        let trailing = &raw[core.len()..];
+        let strict = level == RedactionLevel::Strict;
+        let replaced = if let Ok(addr) = core.parse::<SocketAddr>() {
+            match self.ip(level, addr.ip()) {
+                Some(ip) if strict => ip,
+                Some(ip) => format!("{ip}:{}", addr.port()),
+                None => return raw.to_string(),
+            }
+        } else if let Ok(ip) = core
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+        {
+            match self.ip(level, ip) {
+                Some(ip) => ip,
+                None => return raw.to_string(),
+            }
+        } else if core.len() == 40 && core.bytes().all(|b| b.is_ascii_hexdigit()) {
+            self.pseudonym("fp", core)
+        } else {
+            let (host, port) = split_port(core);
+            if !is_hostname(host) {
+                return raw.to_string();
+            }
+            let host = self.host(level, host, false);
+            match port {
+                Some(port) if !strict => format!("{host}:{port}"),
+                _ => host,
+            }
+        };
+        format!("{replaced}{trailing}")
+    }
+
+    /// Pseudonym of `ip`, or `None` for loopback and unspecified addresses
+    /// in partial mode.
+    fn ip(&self, level: RedactionLevel, ip: IpAddr) -> Option<String> {
+        if level == RedactionLevel::Partial && (ip.is_loopback() || ip.is_unspecified()) {
+            return None;
+        }
+        Some(self.pseudonym("ip", &ip.to_string()))
+    }
+
+    /// Pseudonym of a host in a URL or a bare hostname. Partial keeps the
+    /// top-level domain of bare hostnames and leaves loopback hosts alone.
+    fn host(&self, level: RedactionLevel, host: &str, in_url: bool) -> String {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return self.ip(level, ip).unwrap_or_else(|| host.to_string());
+        }
+        if let Some(onion) = host.strip_suffix(".onion") {
+            let service = onion.rsplit('.').next().unwrap_or(onion);
+            return self.pseudonym("onion", service);
+        }
+        if level == RedactionLevel::Partial {
+            if host.eq_ignore_ascii_case("localhost") {
+                return host.to_string();
+            }
+            if !in_url {
+                if let Some((_, tld)) = host.rsplit_once('.') {
+                    return format!("{}.{tld}", self.pseudonym("host", host));
+                }
+            }
+        }
+        self.pseudonym("host", host)
+    }
+}
+
+fn write_key(path: &Path, key: &[u8; 32]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    std::io::Write::write_all(&mut options.open(path)?, key)
+}
+
+/// Split a trailing `:<port>` off `value`.
+fn split_port(value: &str) -> (&str, Option<&str>) {
+    match value.rsplit_once(':') {
+        Some((host, port))
+            if !port.is_empty()
+                && port.len() <= 5
+                && port.bytes().all(|b| b.is_ascii_digit())
+                && (!host.contains(':') || host.ends_with(']')) =>
+        {
+            (host, Some(port))
+        }
+        _ => (value, None),
+    }
+}
+
+/// Whether `value` looks like a DNS name with a top-level domain that is
+/// not a common file extension.
+fn is_hostname(value: &str) -> bool {
+    let labels: Vec<&str> = value.split('.').collect();
+    let Some(tld) = labels.last() else {
+        return false;
+    };
+    labels.len() >= 2
+        && (2..=63).contains(&tld.len())
+        && tld.bytes().all(|b| b.is_ascii_alphabetic())
+        && !FILE_EXTENSIONS.contains(&tld.to_ascii_lowercase().as_str())
+        && labels.iter().all(|label| {
+            (1..=63).contains(&label.len())
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor() -> Redactor {
+        Redactor::new([7u8; 32])
+    }
+
+    #[test]
+    fn partial_keeps_context_and_correlates() {
+        let r = redactor();
+        let level = RedactionLevel::Partial;
+        let text = r.redact(
+            level,
+            "connect to 203.0.113.5:443 via example.com failed, retrying example.com",
+        );
+        let ip = r.pseudonym("ip", "203.0.113.5");
+        let host = r.pseudonym("host", "example.com");
+        assert_eq!(
+            text,
+            format!("connect to {ip}:443 via {host}.com failed, retrying {host}.com")
+        );
+        assert_eq!(
+            r.redact(
+                level,
+                "socks on 127.0.0.1:9050, log torwell.log, tor_proto::channel"
+            ),
+            "socks on 127.0.0.1:9050, log torwell.log, tor_proto::channel"
+        );
+        assert_eq!(
+            r.redact(level, "worker https://user:pw@w.example.net:8443/api?x=1"),
+            format!(
+                "worker https://{}:8443/api?x=1",
+                r.pseudonym("host", "w.example.net")
+            )
+        );
+        assert_eq!(r.redact(level, &text), text);
+        assert_eq!(r.redact(RedactionLevel::None, "10.0.0.1"), "10.0.0.1");
+    }
+
+    #[test]
+    fn strict_redacts_bridges_and_onions() {
+        let r = redactor();
+        let fp = "A".repeat(40);
+        let line = format!("obfs4 [2001:db8::1]:443 {fp} cert=AbC+/1= iat-mode=0");
+        let text = r.redact(RedactionLevel::Strict, &line);
+        assert_eq!(
+            text,
+            format!(
+                "obfs4 {} {} cert={} iat-mode=0",
+                r.pseudonym("ip", "2001:db8::1"),
+                r.pseudonym("fp", &fp),
+                r.pseudonym("cert", "AbC+/1=")
+            )
+        );
+        let onion = format!("{}.onion", "a".repeat(56));
+        assert_eq!(
+            r.redact(
+                RedactionLevel::Strict,
+                &format!("http://{onion}/index, 127.0.0.1")
+            ),
+            format!(
+                "http://{}, {}",
+                r.pseudonym("onion", &"a".repeat(56)),
+                r.pseudonym("ip", "127.0.0.1")
+            )
+        );
+    }
+
+    #[test]
+    fn key_is_created_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("redaction.key");
+        let first = Redactor::open(&path).pseudonym("ip", "192.0.2.1");
+        assert_eq!(std::fs::read(&path).unwrap().len(), 32);
+        assert_eq!(Redactor::open(&path).pseudonym("ip", "192.0.2.1"), first);
+    }
+}
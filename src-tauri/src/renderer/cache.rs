@@ -1,4 +1,5 @@
 use crate::error::Result;
+use crate::paths::paths;
 use blake3::Hasher;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
@@ -7,7 +8,6 @@ use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-use directories::ProjectDirs;
 
 #[derive(Clone, Copy, Debug)]
 pub struct ShaderSource {
@@ -37,13 +37,7 @@ impl ShaderCache {
     pub fn new() -> Result<Self> {
         let dir = std::env::var("TORWELL_SHADER_CACHE_DIR")
             .map(PathBuf::from)
-            .or_else(|_| -> Result<PathBuf> {
-                if let Some(proj) = ProjectDirs::from("", "", "Torwell") {
-                    Ok(proj.data_dir().join("shader_cache"))
-                } else {
-                    Ok(PathBuf::from("./shader_cache"))
-                }
-            })?;
+            .unwrap_or_else(|_| paths().shader_cache_dir());
         if !dir.exists() {
             fs::create_dir_all(&dir)?;
         }
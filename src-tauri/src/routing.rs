@@ -0,0 +1,184 @@
+use crate::error::{Error, Result};
+use crate::policy::{normalise_destination, Matcher};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use tor_geoip::CountryCode;
+
+/// Route streams whose destination matches `pattern` through an exit in
+/// `exit_country`. `None` (or `"any"`) lets Tor pick any exit.
+///
+/// Patterns use the same syntax as the destination policy.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExitRule {
+    pub pattern: String,
+    #[serde(default)]
+    pub exit_country: Option<String>,
+}
+
+/// Ordered exit routing table. The first matching rule wins; streams that
+/// match no rule use `default_country`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExitRoutingConfig {
+    #[serde(default)]
+    pub rules: Vec<ExitRule>,
+    #[serde(default)]
+    pub default_country: Option<String>,
+}
+
+/// Exit selected for a single stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitRoute {
+    /// Pattern of the matching rule, `None` if the default applies
+    pub rule: Option<String>,
+    pub country: Option<CountryCode>,
+}
+
+fn parse_country(code: Option<&str>) -> Result<Option<CountryCode>> {
+    match code.map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(c) if c.eq_ignore_ascii_case("any") => Ok(None),
+        Some(c) => c
+            .to_ascii_uppercase()
+            .parse::<CountryCode>()
+            .map(Some)
+            .map_err(|_| Error::ConfigError {
+                step: "routing::parse".into(),
+                source_message: format!("invalid exit country '{}'", c),
+                backtrace: format!("{:?}", std::backtrace::Backtrace::capture()),
+            }),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct CompiledRules {
+    config: ExitRoutingConfig,
+    rules: Vec<(String, Matcher, Option<CountryCode>)>,
+    default: Option<CountryCode>,
+}
+
+impl CompiledRules {
+    fn compile(config: ExitRoutingConfig) -> Result<Self> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                Ok((
+                    rule.pattern.clone(),
+                    Matcher::parse(&rule.pattern)?,
+                    parse_country(rule.exit_country.as_deref())?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            rules,
+            default: parse_country(config.default_country.as_deref())?,
+            config,
+        })
+    }
+
+    fn route(&self, host: &str, port: u16) -> ExitRoute {
+        let (host, ip) = normalise_destination(host);
+        match self.rules.iter().find(|(_, m, _)| m.matches(&host, ip, port)) {
+            Some((pattern, _, country)) => ExitRoute {
+                rule: Some(pattern.clone()),
+                country: country.clone(),
+            },
+            None => ExitRoute {
+                rule: None,
+                country: self.default.clone(),
+            },
+        }
+    }
+}
+
+/// Exit routing table shared by all listeners.
+#[derive(Clone, Default)]
+pub struct ExitRouting {
+    compiled: Arc<RwLock<CompiledRules>>,
+}
+
+impl ExitRouting {
+    /// Validate and install a new rule table.
+    pub fn set_config(&self, config: ExitRoutingConfig) -> Result<()> {
+        let compiled = CompiledRules::compile(config)?;
+        *self
+            .compiled
+            .write()
+            .unwrap_or_else(|poison| poison.into_inner()) = compiled;
+        Ok(())
+    }
+
+    pub fn config(&self) -> ExitRoutingConfig {
+        self.compiled
+            .read()
+            .unwrap_or_else(|poison| poison.into_inner())
+            .config
+            .clone()
+    }
+
+    /// Country of the default rule.
+    pub fn default_country(&self) -> Option<CountryCode> {
+        self.compiled
+            .read()
+            .unwrap_or_else(|poison| poison.into_inner())
+            .default
+            .clone()
+    }
+
+    pub fn route(&self, host: &str, port: u16) -> ExitRoute {
+        self.compiled
+            .read()
+            .unwrap_or_else(|poison| poison.into_inner())
+            .route(host, port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str, country: Option<&str>) -> ExitRule {
+        ExitRule {
+            pattern: pattern.into(),
+            exit_country: country.map(String::from),
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let routing = ExitRouting::default();
+        routing
+            .set_config(ExitRoutingConfig {
+                rules: vec![
+                    rule("*.co.uk", Some("gb")),
+                    rule("*.de", Some("DE")),
+                    rule("news.*", Some("any")),
+                ],
+                default_country: Some("US".into()),
+            })
+            .unwrap();
+        let gb: CountryCode = "GB".parse().unwrap();
+        let route = routing.route("www.bbc.co.uk", 443);
+        assert_eq!(route.country, Some(gb));
+        assert_eq!(route.rule.as_deref(), Some("*.co.uk"));
+        assert_eq!(routing.route("news.de", 443).country, Some("DE".parse().unwrap()));
+        assert_eq!(routing.route("news.example.org", 443).country, None);
+        let fallback = routing.route("example.org", 443);
+        assert!(fallback.rule.is_none());
+        assert_eq!(fallback.country, routing.default_country());
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let routing = ExitRouting::default();
+        assert!(routing
+            .set_config(ExitRoutingConfig {
+                rules: vec![rule("*.de", Some("Germany"))],
+                default_country: None,
+            })
+            .is_err());
+        assert!(routing.config().rules.is_empty());
+    }
+}
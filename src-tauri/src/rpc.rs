@@ -0,0 +1,156 @@
+use crate::paths::paths;
+use crate::session::SessionManager;
+use crate::socks::ListenerHandle;
+use arti_client::TorClient;
+use arti_rpcserver::{RpcMgr, RpcSession};
+use futures::{AsyncRead, AsyncReadExt, StreamExt};
+use log::{debug, error, info, warn};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tor_rpc_connect::auth::RpcAuth;
+use tor_rtcompat::{NetStreamListener, NetStreamProvider, PreferredRuntime};
+
+/// File name of the RPC socket inside [`rpc_dir`]
+pub const RPC_SOCKET_NAME: &str = "arti_rpc_socket";
+/// Longest line a client may send as its session token
+const MAX_TOKEN_LINE: usize = 256;
+/// Time a client has to present its session token
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Directory holding Torwell's RPC socket.
+pub fn rpc_dir() -> PathBuf {
+    paths().data_dir.join("rpc")
+}
+
+/// Removes the socket once the listener stops.
+struct CleanupGuard(PathBuf);
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Read the session token a client sends as its first line, before the RPC
+/// protocol starts. Reads byte by byte so no RPC data is consumed. Returns
+/// `None` if the connection closes or the line is too long.
+async fn read_token<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if stream.read(&mut byte).await? == 0 || line.len() > MAX_TOKEN_LINE {
+            return Ok(None);
+        }
+        match byte[0] {
+            b'\n' => break,
+            b => line.push(b),
+        }
+    }
+    let token = String::from_utf8_lossy(&line).trim().to_string();
+    Ok(Some(token))
+}
+
+/// Resolves once `token` was revoked or expired. Renews it meanwhile so
+/// long-lived connections stay authorized.
+async fn session_ended(sessions: &SessionManager, token: &str) {
+    let mut interval = tokio::time::interval(sessions.renew_interval());
+    loop {
+        interval.tick().await;
+        if !sessions.renew(token).await {
+            return;
+        }
+    }
+}
+
+/// Serve arti's RPC protocol for `client` on a Unix socket. A client first
+/// sends a token of a session in `sessions` on its own line and then speaks
+/// the RPC protocol, authenticating with `auth:inherent`. The connection is
+/// closed when the session ends.
+pub async fn start_rpc_server(
+    client: TorClient<PreferredRuntime>,
+    sessions: Arc<SessionManager>,
+) -> anyhow::Result<ListenerHandle> {
+    let dir = rpc_dir();
+    std::fs::create_dir_all(&dir)?;
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+    let socket_path = dir.join(RPC_SOCKET_NAME);
+    // A socket left behind by a previous run blocks the bind
+    let _ = std::fs::remove_file(&socket_path);
+
+    let runtime = client.runtime().clone();
+    let rpc_mgr = RpcMgr::new(move |_auth| {
+        // Every session gets its own isolation group
+        RpcSession::new_with_client(Arc::new(client.isolated_client()))
+    })
+    .map_err(|e| anyhow::anyhow!("failed to create RPC manager: {e}"))?;
+    rpc_mgr.register_rpc_methods(TorClient::<PreferredRuntime>::rpc_methods());
+
+    let addr = std::os::unix::net::SocketAddr::from_pathname(&socket_path)?;
+    let listener =
+        NetStreamProvider::<std::os::unix::net::SocketAddr>::listen(&runtime, &addr).await?;
+
+    info!("arti RPC listening on {}", socket_path.display());
+
+    let cleanup = CleanupGuard(socket_path.clone());
+    let task = tokio::spawn(async move {
+        let _cleanup = cleanup;
+        let mut incoming = listener.incoming();
+        while let Some(accepted) = incoming.next().await {
+            let mut stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("RPC accept error: {}", e);
+                    continue;
+                }
+            };
+            let sessions = sessions.clone();
+            let rpc_mgr = rpc_mgr.clone();
+            tokio::spawn(async move {
+                let presented = tokio::time::timeout(AUTH_TIMEOUT, read_token(&mut stream)).await;
+                let token = match presented {
+                    Ok(Ok(Some(token))) if sessions.validate(&token).await => token,
+                    _ => {
+                        warn!("RPC connection without a valid session token refused");
+                        return;
+                    }
+                };
+                let connection = rpc_mgr.new_connection(RpcAuth::Inherent);
+                let (input, output) = stream.split();
+                tokio::select! {
+                    res = connection.run(input, output) => {
+                        if let Err(e) = res {
+                            debug!("RPC connection error: {}", e);
+                        }
+                    }
+                    _ = session_ended(&sessions, &token) => {
+                        debug!("RPC session expired, closing connection");
+                    }
+                }
+            });
+        }
+    });
+
+    Ok(ListenerHandle::unix(socket_path, vec![task.abort_handle()]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_token_line_only() {
+        let mut stream = futures::io::Cursor::new(b"abc123\n{\"id\":1}".to_vec());
+        assert_eq!(
+            read_token(&mut stream).await.unwrap().as_deref(),
+            Some("abc123")
+        );
+        assert_eq!(stream.position(), 7);
+
+        let mut long = futures::io::Cursor::new(vec![b'a'; MAX_TOKEN_LINE + 10]);
+        assert_eq!(read_token(&mut long).await.unwrap(), None);
+        let mut closed = futures::io::Cursor::new(b"abc".to_vec());
+        assert_eq!(read_token(&mut closed).await.unwrap(), None);
+    }
+}
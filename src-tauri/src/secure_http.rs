@@ -1,5 +1,7 @@
+use crate::config::ConfigStore;
 use crate::core::executor::{TaskError, TaskScheduler};
 use crate::error::Error;
+use crate::paths::paths;
 use anyhow::anyhow;
 use governor::clock::DefaultClock;
 use governor::state::{InMemoryState, NotKeyed};
@@ -12,7 +14,6 @@ use rustls::CipherSuite;
 use rustls::version::{TLS12, TLS13};
 use rustls::{ClientConfig, RootCertStore};
 use rustls_pemfile as pemfile;
-use serde::Deserialize;
 use serde_json::Value;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
@@ -28,87 +29,19 @@ use urlencoding::encode;
 #[cfg(feature = "hsm")]
 use pkcs11::Ctx;
 
-/// Location of the pinned server certificate. The path is relative to the
-/// repository root so updates persist across runs.
-pub const DEFAULT_CERT_PATH: &str = "src-tauri/certs/server.pem";
 
 /// Default URL for retrieving updated certificates
 pub const DEFAULT_CERT_URL: &str = "https://certs.torwell.com/server.pem";
 
-/// Default location of the certificate configuration file
-pub const DEFAULT_CONFIG_PATH: &str = "src-tauri/certs/cert_config.json";
 
-#[derive(Deserialize)]
-struct CertConfig {
-    #[serde(default = "default_cert_path")]
-    cert_path: String,
-    #[serde(default)]
-    cert_path_windows: Option<String>,
-    #[serde(default)]
-    cert_path_macos: Option<String>,
-    #[serde(default = "default_cert_url")]
-    cert_url: String,
-    #[serde(default)]
-    fallback_cert_url: Option<String>,
-    #[serde(default = "default_min_tls_version")]
-    min_tls_version: Option<String>,
-    #[serde(default = "default_update_interval")]
-    update_interval: u64,
-}
-
-fn default_cert_path() -> String {
-    DEFAULT_CERT_PATH.to_string()
-}
-
-fn default_cert_url() -> String {
-    DEFAULT_CERT_URL.to_string()
-}
-
-fn default_min_tls_version() -> Option<String> {
-    Some("1.2".to_string())
-}
-
-fn default_update_interval() -> u64 {
-    60 * 60 * 24
-}
-
-impl CertConfig {
-    fn load<P: AsRef<Path>>(path: P) -> Self {
-        let data = std::fs::read_to_string(path).ok();
-        let mut cfg = if let Some(data) = data {
-            serde_json::from_str(&data).unwrap_or_default()
-        } else {
-            Self::default()
-        };
-
-        cfg.apply_platform_path();
-        cfg
-    }
-
-    fn apply_platform_path(&mut self) {
-        #[cfg(target_os = "windows")]
-        if let Some(p) = &self.cert_path_windows {
-            self.cert_path = p.clone();
-        }
-        #[cfg(target_os = "macos")]
-        if let Some(p) = &self.cert_path_macos {
-            self.cert_path = p.clone();
-        }
-    }
-}
-
-impl Default for CertConfig {
-    fn default() -> Self {
-        Self {
-            cert_path: DEFAULT_CERT_PATH.to_string(),
-            cert_path_windows: Some("%APPDATA%\\Torwell84\\server.pem".into()),
-            cert_path_macos: Some("/Library/Application Support/Torwell84/server.pem".into()),
-            cert_url: DEFAULT_CERT_URL.to_string(),
-            fallback_cert_url: None,
-            min_tls_version: default_min_tls_version(),
-            update_interval: default_update_interval(),
-        }
-    }
+/// Certificate settings resolved by [`crate::config::ConfigStore`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertSettings {
+    pub cert_path: String,
+    pub cert_url: String,
+    pub fallback_cert_url: Option<String>,
+    pub min_tls_version: Option<String>,
+    pub update_interval: Duration,
 }
 
 fn parse_max_age(header: &str) -> Option<u64> {
@@ -157,6 +90,8 @@ pub struct SecureHttpClient {
     security_warning_limiter: Arc<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>,
     scheduler: TaskScheduler,
     proxy_url: Arc<Mutex<Option<String>>>,
+    /// PKCS#11 module and slot used for client certificates
+    hsm_config: Arc<Mutex<(Option<String>, Option<u64>)>>,
 }
 
 impl Clone for SecureHttpClient {
@@ -177,6 +112,7 @@ impl Clone for SecureHttpClient {
             security_warning_limiter: self.security_warning_limiter.clone(),
             scheduler: self.scheduler.clone(),
             proxy_url: self.proxy_url.clone(),
+            hsm_config: self.hsm_config.clone(),
         }
     }
 }
@@ -278,11 +214,12 @@ impl SecureHttpClient {
             ))),
             scheduler: TaskScheduler::global(),
             proxy_url: Arc::new(Mutex::new(None)),
+            hsm_config: Arc::new(Mutex::new((None, None))),
         })
     }
 
     pub fn new_default() -> anyhow::Result<Self> {
-        Self::new(DEFAULT_CERT_PATH)
+        Self::new(paths().cert_file())
     }
 
     pub async fn set_proxy(&self, url: Option<String>) -> anyhow::Result<()> {
@@ -290,6 +227,11 @@ impl SecureHttpClient {
         self.reload_certificates().await
     }
 
+    /// HSM library path and slot last set with [`Self::set_hsm_config`]
+    pub async fn hsm_config(&self) -> (Option<String>, Option<u64>) {
+        self.hsm_config.lock().await.clone()
+    }
+
     /// Provide a callback to emit security warnings
     pub async fn set_warning_callback<F>(&self, cb: F)
     where
@@ -311,6 +253,12 @@ impl SecureHttpClient {
         *self.worker_token.lock().await = token;
     }
 
+    /// Configured proxy workers and authentication token
+    pub async fn worker_config(&self) -> (Vec<String>, Option<String>) {
+        let workers = self.worker_urls.lock().await.iter().cloned().collect();
+        (workers, self.worker_token.lock().await.clone())
+    }
+
     pub fn set_insecure_hosts(&self, hosts: Vec<String>) {
         let prepared = Self::prepare_insecure_hosts(hosts);
         let mut guard = self
@@ -407,16 +355,7 @@ impl SecureHttpClient {
         lib: Option<String>,
         slot: Option<u64>,
     ) -> anyhow::Result<()> {
-        if let Some(l) = lib {
-            std::env::set_var("TORWELL_HSM_LIB", &l);
-        } else {
-            std::env::remove_var("TORWELL_HSM_LIB");
-        }
-        if let Some(s) = slot {
-            std::env::set_var("TORWELL_HSM_SLOT", s.to_string());
-        } else {
-            std::env::remove_var("TORWELL_HSM_SLOT");
-        }
+        *self.hsm_config.lock().await = (lib, slot);
         self.reload_certificates().await
     }
 
@@ -497,6 +436,9 @@ impl SecureHttpClient {
 
     /// Initialize a client using settings from a configuration file and
     /// optionally start periodic updates.
+    /// Create a client from the certificate config at `config_path` layered
+    /// with the app config and environment. Explicit arguments take
+    /// precedence over all of them.
     pub async fn init<P: AsRef<Path>>(
         config_path: P,
         cert_path: Option<String>,
@@ -504,23 +446,11 @@ impl SecureHttpClient {
         fallback_cert_url: Option<String>,
         interval: Option<Duration>,
     ) -> anyhow::Result<Arc<Self>> {
-        let mut cfg = CertConfig::load(config_path);
-
-        if let Ok(env_url) = std::env::var("TORWELL_CERT_URL") {
-            cfg.cert_url = env_url;
-        }
-        if let Ok(env_path) = std::env::var("TORWELL_CERT_PATH") {
-            cfg.cert_path = env_path;
-        }
-        if let Ok(env_fallback) = std::env::var("TORWELL_FALLBACK_CERT_URL") {
-            cfg.fallback_cert_url = Some(env_fallback);
-        }
-        if let Ok(env_int) = std::env::var("TORWELL_UPDATE_INTERVAL") {
-            if let Ok(sec) = env_int.parse::<u64>() {
-                cfg.update_interval = sec;
-            }
-        }
-
+        let store = ConfigStore::open(
+            crate::state::config_path(),
+            config_path.as_ref().to_path_buf(),
+        );
+        let mut cfg = store.cert_settings();
         if let Some(path) = cert_path {
             cfg.cert_path = path;
         }
@@ -530,8 +460,16 @@ impl SecureHttpClient {
         if let Some(fallback) = fallback_cert_url {
             cfg.fallback_cert_url = Some(fallback);
         }
-        let update_interval = interval.unwrap_or_else(|| Duration::from_secs(cfg.update_interval));
+        if let Some(interval) = interval {
+            cfg.update_interval = interval;
+        }
+        Self::init_with(cfg).await
+    }
 
+    /// Create a client from resolved settings, fetch the current
+    /// certificate and schedule periodic updates.
+    pub async fn init_with(cfg: CertSettings) -> anyhow::Result<Arc<Self>> {
+        let update_interval = cfg.update_interval;
         if cfg.cert_url.contains("example.com") {
             log::warn!(
                 "certificate update URL still points to example.com; update cert_url in cert_config.json"
@@ -722,6 +660,7 @@ impl SecureHttpClient {
                 }
             }
         }
+        crate::telemetry::inc(&crate::telemetry::counters().cert_update_failures);
         {
             let mut cnt = self.update_failures.lock().await;
             *cnt += 1;
@@ -49,6 +49,17 @@ impl SessionManager {
         token
     }
 
+    /// Lifetime of a session that is not renewed.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Interval at which a session kept open by a listener is renewed, well
+    /// within its lifetime.
+    pub fn renew_interval(&self) -> Duration {
+        (self.ttl / 2).max(Duration::from_millis(10))
+    }
+
     /// Retrieve the startup token if it hasn't been taken yet.
     pub async fn take_startup_token(&self) -> Option<String> {
         self.startup_token.lock().await.take()
@@ -65,6 +76,25 @@ impl SessionManager {
             .unwrap_or(false)
     }
 
+    /// Extend a valid session by another TTL. Returns `false` if the token
+    /// is unknown or expired.
+    pub async fn renew(&self, token: &str) -> bool {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().await;
+        match sessions.get_mut(token) {
+            Some(exp) if *exp > now => {
+                *exp = now + self.ttl;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Invalidate a session token.
+    pub async fn revoke(&self, token: &str) {
+        self.sessions.lock().await.remove(token);
+    }
+
     /// Remove expired sessions.
     async fn cleanup(&self) {
         let now = Instant::now();
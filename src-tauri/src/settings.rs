@@ -0,0 +1,333 @@
+//! Routing and proxy settings changed at runtime. They are kept in a
+//! versioned `settings.json` next to the log file so they survive restarts.
+
+use crate::error::{Error, Result};
+use crate::paths::paths;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Schema version written by this build
+pub const SETTINGS_VERSION: u32 = 1;
+/// Environment variable overriding [`settings_path`]
+pub const SETTINGS_PATH_ENV: &str = "TORWELL_SETTINGS";
+
+/// Upgrades a settings document by one version. Index `n` migrates
+/// version `n` to `n + 1`.
+type Migration = fn(&mut serde_json::Value);
+
+const MIGRATIONS: [Migration; SETTINGS_VERSION as usize] = [migrate_v0];
+
+/// Location of the settings file, honouring `TORWELL_SETTINGS`.
+pub fn settings_path() -> PathBuf {
+    if let Some(path) = std::env::var_os(SETTINGS_PATH_ENV) {
+        return PathBuf::from(path);
+    }
+    paths().data_dir.join("settings.json")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Settings {
+    pub version: u32,
+    #[serde(default)]
+    pub routing: RoutingSettings,
+    #[serde(default)]
+    pub proxy: ProxySettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            routing: RoutingSettings::default(),
+            proxy: ProxySettings::default(),
+        }
+    }
+}
+
+/// Circuit preferences applied by the Tor manager.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoutingSettings {
+    #[serde(default)]
+    pub exit_country: Option<String>,
+    #[serde(default)]
+    pub entry_country: Option<String>,
+    #[serde(default)]
+    pub middle_country: Option<String>,
+    #[serde(default)]
+    pub bridges: Vec<String>,
+    #[serde(default)]
+    pub torrc: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxySettings {
+    #[serde(default = "default_system_proxy_enabled")]
+    pub system_proxy_enabled: bool,
+}
+
+impl Default for ProxySettings {
+    fn default() -> Self {
+        Self {
+            system_proxy_enabled: default_system_proxy_enabled(),
+        }
+    }
+}
+
+fn default_system_proxy_enabled() -> bool {
+    true
+}
+
+/// Version 0 files had no version and kept all keys at the top level.
+fn migrate_v0(doc: &mut serde_json::Value) {
+    let Some(obj) = doc.as_object_mut() else {
+        return;
+    };
+    let mut routing = serde_json::Map::new();
+    for key in [
+        "exit_country",
+        "entry_country",
+        "middle_country",
+        "bridges",
+        "torrc",
+    ] {
+        if let Some(value) = obj.remove(key) {
+            routing.insert(key.into(), value);
+        }
+    }
+    let mut proxy = serde_json::Map::new();
+    if let Some(value) = obj.remove("system_proxy_enabled") {
+        proxy.insert("system_proxy_enabled".into(), value);
+    }
+    obj.insert("routing".into(), routing.into());
+    obj.insert("proxy".into(), proxy.into());
+}
+
+fn config_error(step: &str, e: impl ToString) -> Error {
+    Error::ConfigError {
+        step: step.into(),
+        source_message: e.to_string(),
+        backtrace: format!("{:?}", std::backtrace::Backtrace::capture()),
+    }
+}
+
+/// Parse a settings document of any known version. Returns the settings
+/// and whether the document had to be migrated.
+pub fn parse_settings(contents: &str) -> Result<(Settings, bool)> {
+    let mut doc: serde_json::Value =
+        serde_json::from_str(contents).map_err(|e| config_error("settings::parse", e))?;
+    let version = match doc.get("version") {
+        None => 0,
+        Some(v) => v
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| config_error("settings::parse", "invalid version"))?,
+    };
+    if version > SETTINGS_VERSION {
+        return Err(config_error(
+            "settings::parse",
+            format!("version {version} is newer than supported version {SETTINGS_VERSION}"),
+        ));
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(&mut doc);
+    }
+    doc["version"] = SETTINGS_VERSION.into();
+    let settings = serde_json::from_value(doc).map_err(|e| config_error("settings::parse", e))?;
+    Ok((settings, version < SETTINGS_VERSION))
+}
+
+/// Settings written by a newer build, read as far as this build understands
+/// them. `None` if `contents` is not from a newer build.
+fn parse_newer(contents: &str) -> Option<Settings> {
+    let doc: serde_json::Value = serde_json::from_str(contents).ok()?;
+    let version = doc.get("version")?.as_u64()?;
+    if version <= u64::from(SETTINGS_VERSION) {
+        return None;
+    }
+    Some(serde_json::from_value(doc).unwrap_or_default())
+}
+
+/// Replace `path` with `contents` without leaving a partially written file
+/// behind: the data goes to a temporary file in the same directory which is
+/// then renamed over the target.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(dir)?;
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = dir.join(tmp_name);
+    let result = write_synced(&tmp, contents).and_then(|_| std::fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Settings file and its current contents.
+pub struct SettingsStore {
+    path: PathBuf,
+    current: Mutex<Settings>,
+    /// Set when the file is from a newer build, which must not be replaced
+    read_only: bool,
+}
+
+impl SettingsStore {
+    /// Load the settings at `path`. Older versions are migrated and written
+    /// back; a missing or unreadable file yields the defaults. A file from a
+    /// newer build is used as far as possible and never written.
+    pub fn open(path: PathBuf) -> Self {
+        let mut read_only = false;
+        let settings = match std::fs::read_to_string(&path) {
+            Ok(contents) => match parse_settings(&contents) {
+                Ok((settings, migrated)) => {
+                    if migrated {
+                        log::info!("migrated settings to version {}", SETTINGS_VERSION);
+                        if let Err(e) = Self::write(&path, &settings) {
+                            log::warn!("failed to write migrated settings: {}", e);
+                        }
+                    }
+                    settings
+                }
+                Err(e) => match parse_newer(&contents) {
+                    Some(settings) => {
+                        log::warn!("settings file {} is kept read-only: {}", path.display(), e);
+                        read_only = true;
+                        settings
+                    }
+                    None => {
+                        // Keep the unreadable file, the next update replaces it
+                        let mut backup = path.clone().into_os_string();
+                        backup.push(".bak");
+                        log::warn!(
+                            "ignoring settings file {}: {}, moved to {}",
+                            path.display(),
+                            e,
+                            Path::new(&backup).display()
+                        );
+                        let _ = std::fs::rename(&path, &backup);
+                        Settings::default()
+                    }
+                },
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Settings::default(),
+            Err(e) => {
+                log::warn!("failed to read settings file {}: {}", path.display(), e);
+                Settings::default()
+            }
+        };
+        Self {
+            path,
+            current: Mutex::new(settings),
+            read_only,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the file is from a newer build and updates are refused.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn get(&self) -> Settings {
+        self.current
+            .lock()
+            .unwrap_or_else(|p| p.into_inner())
+            .clone()
+    }
+
+    /// Apply `change` and write the result. The in-memory settings are only
+    /// replaced once the file was written.
+    pub fn update(&self, change: impl FnOnce(&mut Settings)) -> Result<()> {
+        let mut current = self.current.lock().unwrap_or_else(|p| p.into_inner());
+        let mut next = current.clone();
+        change(&mut next);
+        if next != *current {
+            if self.read_only {
+                return Err(config_error(
+                    "settings::write",
+                    format!(
+                        "{} is from a newer version and is not modified",
+                        self.path.display()
+                    ),
+                ));
+            }
+            Self::write(&self.path, &next)?;
+            *current = next;
+        }
+        Ok(())
+    }
+
+    fn write(path: &Path, settings: &Settings) -> Result<()> {
+        let serialized =
+            serde_json::to_vec_pretty(settings).map_err(|e| config_error("settings::write", e))?;
+        write_atomic(path, &serialized)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_unversioned_files() {
+        let (settings, migrated) = parse_settings(
+            r#"{"exit_country":"DE","bridges":["obfs4 1.2.3.4:443"],"system_proxy_enabled":false}"#,
+        )
+        .unwrap();
+        assert!(migrated);
+        assert_eq!(settings.version, SETTINGS_VERSION);
+        assert_eq!(settings.routing.exit_country.as_deref(), Some("DE"));
+        assert_eq!(settings.routing.bridges, vec!["obfs4 1.2.3.4:443"]);
+        assert!(!settings.proxy.system_proxy_enabled);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        assert!(parse_settings(r#"{"version":99}"#).is_err());
+    }
+
+    #[test]
+    fn newer_files_are_kept_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.json");
+        let contents = r#"{"version":99,"routing":{"bridges":["obfs4 1.2.3.4:443"]},"future":1}"#;
+        std::fs::write(&path, contents).unwrap();
+
+        let store = SettingsStore::open(path.clone());
+        assert!(store.is_read_only());
+        assert_eq!(store.get().routing.bridges, vec!["obfs4 1.2.3.4:443"]);
+        assert!(store.update(|s| s.routing.bridges.clear()).is_err());
+        assert_eq!(store.get().routing.bridges.len(), 1);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), contents);
+        assert!(!dir.path().join("settings.json.bak").exists());
+    }
+
+    #[test]
+    fn update_writes_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.json");
+        let store = SettingsStore::open(path.clone());
+        store
+            .update(|s| s.routing.torrc = "SafeLogging 1".into())
+            .unwrap();
+
+        let reopened = SettingsStore::open(path);
+        assert_eq!(reopened.get().routing.torrc, "SafeLogging 1");
+        assert!(!dir.path().join("settings.json.tmp").exists());
+    }
+}
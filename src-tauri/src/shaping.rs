@@ -0,0 +1,299 @@
+use crate::error::{Error, Result};
+use crate::streams::StreamRegistry;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upload and download limits in bytes per second. `None` means unlimited.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimit {
+    #[serde(default)]
+    pub upload_bps: Option<u64>,
+    #[serde(default)]
+    pub download_bps: Option<u64>,
+}
+
+impl RateLimit {
+    fn is_unlimited(&self) -> bool {
+        self.upload_bps.is_none() && self.download_bps.is_none()
+    }
+
+    /// Reject a rate of 0, which would stall the stream instead of limiting
+    /// it. `None` is the way to lift a limit.
+    fn validate(&self) -> Result<()> {
+        for (key, rate) in [
+            ("upload_bps", self.upload_bps),
+            ("download_bps", self.download_bps),
+        ] {
+            if rate == Some(0) {
+                return Err(Error::InvalidConfig {
+                    key: key.into(),
+                    message: "must be greater than 0".into(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bandwidth limits applied to proxied traffic. Per-stream limits are set
+/// separately because stream ids only live for the duration of a stream.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct BandwidthLimits {
+    #[serde(default)]
+    pub global: RateLimit,
+    /// Limits keyed by isolation group (the SOCKS username)
+    #[serde(default)]
+    pub groups: HashMap<String, RateLimit>,
+}
+
+/// Direction of traffic relative to the local client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Client to Tor
+    Upload,
+    /// Tor to client
+    Download,
+}
+
+/// Classic token bucket. Tokens may go negative so that a chunk larger than
+/// the bucket can still be sent once the debt has been paid off.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        Self {
+            rate,
+            capacity: rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    /// Withdraw `n` tokens and return how long the caller has to wait before
+    /// the bucket is out of debt again.
+    fn reserve(&mut self, n: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct DirBuckets {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl DirBuckets {
+    fn new(limit: &RateLimit) -> Self {
+        Self {
+            upload: limit.upload_bps.map(TokenBucket::new),
+            download: limit.download_bps.map(TokenBucket::new),
+        }
+    }
+
+    fn reserve(&mut self, dir: Direction, n: u64, now: Instant) -> Duration {
+        let bucket = match dir {
+            Direction::Upload => self.upload.as_mut(),
+            Direction::Download => self.download.as_mut(),
+        };
+        bucket.map(|b| b.reserve(n, now)).unwrap_or(Duration::ZERO)
+    }
+}
+
+#[derive(Default)]
+struct ShaperState {
+    limits: BandwidthLimits,
+    global: DirBuckets,
+    groups: HashMap<String, DirBuckets>,
+    streams: HashMap<u64, (RateLimit, DirBuckets)>,
+}
+
+/// Token bucket rate limiter shared by all SOCKS connections.
+#[derive(Clone, Default)]
+pub struct BandwidthShaper {
+    state: Arc<Mutex<ShaperState>>,
+}
+
+impl BandwidthShaper {
+    fn state(&self) -> std::sync::MutexGuard<'_, ShaperState> {
+        self.state.lock().unwrap_or_else(|poison| poison.into_inner())
+    }
+
+    /// Replace the global and per-group limits.
+    pub fn set_limits(&self, limits: BandwidthLimits) -> Result<()> {
+        limits.global.validate()?;
+        for limit in limits.groups.values() {
+            limit.validate()?;
+        }
+        let mut state = self.state();
+        state.global = DirBuckets::new(&limits.global);
+        state.groups = limits
+            .groups
+            .iter()
+            .map(|(group, limit)| (group.clone(), DirBuckets::new(limit)))
+            .collect();
+        state.limits = limits;
+        Ok(())
+    }
+
+    pub fn limits(&self) -> BandwidthLimits {
+        self.state().limits.clone()
+    }
+
+    /// Set or clear the limit of a single stream. Fails unless `id` is
+    /// open in `streams`; the entry is dropped by [`Self::forget_stream`]
+    /// once the stream has been unregistered.
+    pub fn set_stream_limit(
+        &self,
+        streams: &StreamRegistry,
+        id: u64,
+        limit: RateLimit,
+    ) -> Result<()> {
+        limit.validate()?;
+        // Checked under the shaper lock so that a stream closing concurrently
+        // is either rejected here or cleaned up by forget_stream
+        let mut state = self.state();
+        if !streams.contains(id) {
+            return Err(Error::InvalidConfig {
+                key: "id".into(),
+                message: format!("unknown stream {id}"),
+            });
+        }
+        if limit.is_unlimited() {
+            state.streams.remove(&id);
+        } else {
+            state.streams.insert(id, (limit, DirBuckets::new(&limit)));
+        }
+        Ok(())
+    }
+
+    /// Limit configured for a single stream, if any.
+    pub fn stream_limit(&self, id: u64) -> Option<RateLimit> {
+        self.state().streams.get(&id).map(|(limit, _)| *limit)
+    }
+
+    /// Drop per-stream state once a stream has finished.
+    pub fn forget_stream(&self, id: u64) {
+        self.state().streams.remove(&id);
+    }
+
+    /// Account for `n` bytes and return how long the caller should wait
+    /// before forwarding them. Every applicable bucket is charged and the
+    /// longest wait wins.
+    pub fn reserve(&self, stream: u64, group: Option<&str>, dir: Direction, n: u64) -> Duration {
+        let now = Instant::now();
+        let mut state = self.state();
+        let mut wait = state.global.reserve(dir, n, now);
+        if let Some(buckets) = group.and_then(|g| state.groups.get_mut(g)) {
+            wait = wait.max(buckets.reserve(dir, n, now));
+        }
+        if let Some((_, buckets)) = state.streams.get_mut(&stream) {
+            wait = wait.max(buckets.reserve(dir, n, now));
+        }
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streams::StreamTarget;
+
+    #[test]
+    fn bucket_allows_burst_then_throttles() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.reserve(1000, start), Duration::ZERO);
+        let wait = bucket.reserve(500, start);
+        assert_eq!(wait, Duration::from_millis(500));
+        // After the debt has been paid off no further wait is needed
+        assert_eq!(bucket.reserve(0, start + Duration::from_millis(500)), Duration::ZERO);
+    }
+
+    #[test]
+    fn unlimited_by_default() {
+        let shaper = BandwidthShaper::default();
+        assert_eq!(shaper.reserve(1, None, Direction::Upload, 1 << 30), Duration::ZERO);
+    }
+
+    #[test]
+    fn strictest_limit_wins() {
+        let shaper = BandwidthShaper::default();
+        let mut groups = HashMap::new();
+        groups.insert(
+            "slow".to_string(),
+            RateLimit {
+                upload_bps: Some(100),
+                download_bps: None,
+            },
+        );
+        shaper
+            .set_limits(BandwidthLimits {
+                global: RateLimit {
+                    upload_bps: Some(1000),
+                    download_bps: Some(1000),
+                },
+                groups,
+            })
+            .unwrap();
+        let wait = shaper.reserve(1, Some("slow"), Direction::Upload, 200);
+        assert_eq!(wait, Duration::from_secs(1));
+        assert_eq!(shaper.reserve(1, Some("slow"), Direction::Download, 200), Duration::ZERO);
+
+        let streams = StreamRegistry::new();
+        let guard = streams.register(StreamTarget {
+            host: "example.com".into(),
+            port: 443,
+            isolation_group: None,
+            peer: "127.0.0.1:40000".parse().unwrap(),
+            pid: None,
+        });
+        let id = guard.id();
+        let limit = RateLimit {
+            upload_bps: None,
+            download_bps: Some(10),
+        };
+        shaper.set_stream_limit(&streams, id, limit).unwrap();
+        assert!(shaper.reserve(id, None, Direction::Download, 20) >= Duration::from_secs(1));
+        drop(guard);
+        shaper.forget_stream(id);
+        assert!(shaper.stream_limit(id).is_none());
+        assert!(shaper.set_stream_limit(&streams, id, limit).is_err());
+    }
+
+    #[test]
+    fn zero_rate_is_rejected() {
+        let shaper = BandwidthShaper::default();
+        let zero = RateLimit {
+            upload_bps: Some(0),
+            download_bps: None,
+        };
+        let limits = BandwidthLimits {
+            global: zero,
+            ..Default::default()
+        };
+        assert!(matches!(
+            shaper.set_limits(limits),
+            Err(Error::InvalidConfig { key, .. }) if key == "upload_bps"
+        ));
+        assert_eq!(shaper.limits(), BandwidthLimits::default());
+    }
+}
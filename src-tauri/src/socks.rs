@@ -1,41 +1,325 @@
-use arti_client::TorClient;
+use crate::listeners::{IsolationMode, ListenerKind, ListenerSpec, StreamProfile};
+use crate::policy::DestinationPolicy;
+use crate::routing::{ExitRoute, ExitRouting};
+use crate::shaping::{BandwidthShaper, Direction};
+use crate::streams::{StreamGuard, StreamRegistry, StreamTarget};
+use arti_client::{DataStream, ErrorKind, HasKind, StreamPrefs, TorClient};
+use tor_geoip::CountryCode;
+use tor_circmgr::isolation::IsolationToken;
 use tor_rtcompat::PreferredRuntime;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
 use anyhow::Result;
-use log::error;
+use log::{debug, error};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+
+const COPY_BUF_SIZE: usize = 16 * 1024;
+/// Credential isolation groups kept at most
+const MAX_CREDENTIAL_TOKENS: usize = 1024;
+/// Idle time after which a credential isolation group is forgotten
+const CREDENTIAL_TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Shared state used by every connection accepted on a SOCKS listener.
+#[derive(Clone, Default)]
+pub struct SocksContext {
+    /// Registry of streams currently relayed through Tor
+    pub streams: StreamRegistry,
+    /// Destination policy consulted before each stream is opened
+    pub policy: DestinationPolicy,
+    /// Bandwidth limits applied inside the copy loop
+    pub shaper: BandwidthShaper,
+    /// Destination based exit country rules
+    pub routing: ExitRouting,
+    /// Isolation tokens keyed by SOCKS credentials, with their last use
+    isolation: Arc<Mutex<HashMap<String, (IsolationToken, Instant)>>>,
+    /// Stream preferences of the listener using this context
+    profile: Arc<StreamProfile>,
+    /// Token shared by all streams of the listener in per-listener mode
+    listener_token: Option<IsolationToken>,
+}
+
+/// Why a stream could not be opened through Tor.
+pub(crate) enum OpenError {
+    /// Refused by the destination policy or the listener profile
+    NotAllowed,
+    /// Tor could not reach the destination
+    Unreachable(String),
+}
+
+impl SocksContext {
+    /// Isolation token of the credentials `key`. Groups idle for longer
+    /// than [`CREDENTIAL_TOKEN_TTL`] are forgotten and the least recently
+    /// used one is dropped beyond [`MAX_CREDENTIAL_TOKENS`], so clients
+    /// sending ever new usernames cannot grow the map without bound.
+    pub(crate) async fn isolation_token(&self, key: &str) -> IsolationToken {
+        let now = Instant::now();
+        let mut tokens = self.isolation.lock().await;
+        tokens.retain(|_, (_, used)| now.duration_since(*used) <= CREDENTIAL_TOKEN_TTL);
+        if !tokens.contains_key(key) && tokens.len() >= MAX_CREDENTIAL_TOKENS {
+            if let Some(oldest) = tokens
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(k, _)| k.clone())
+            {
+                tokens.remove(&oldest);
+            }
+        }
+        let entry = tokens
+            .entry(key.to_string())
+            .or_insert_with(|| (IsolationToken::new(), now));
+        entry.1 = now;
+        entry.0
+    }
+
+    /// Context for another listener sharing streams, policy, shaping and
+    /// credential isolation but using its own stream profile.
+    pub fn with_profile(&self, profile: StreamProfile) -> Self {
+        Self {
+            profile: Arc::new(profile),
+            listener_token: Some(IsolationToken::new()),
+            ..self.clone()
+        }
+    }
+
+    /// Check the destination and open a Tor stream with the listener's
+    /// preferences. `credentials` selects the isolation group.
+    pub(crate) async fn open_stream(
+        &self,
+        client: &TorClient<PreferredRuntime>,
+        host: &str,
+        port: u16,
+        credentials: Option<&str>,
+    ) -> std::result::Result<DataStream, OpenError> {
+        if !self.profile.allows_host(host) {
+            debug!("onion access disabled for this listener: {}", host);
+            return Err(OpenError::NotAllowed);
+        }
+        if !self.policy.check(host, port).await {
+            return Err(OpenError::NotAllowed);
+        }
+
+        let mut prefs = StreamPrefs::new();
+        self.profile.apply(&mut prefs);
+        match self.profile.isolation {
+            IsolationMode::Credentials => {
+                if let Some(key) = credentials {
+                    prefs.set_isolation(self.isolation_token(key).await);
+                }
+            }
+            IsolationMode::PerListener => {
+                if let Some(token) = self.listener_token {
+                    prefs.set_isolation(token);
+                }
+            }
+            IsolationMode::PerConnection => {
+                prefs.new_isolation_group();
+            }
+            IsolationMode::Shared => {}
+        }
+
+        let route = self.routing.route(host, port);
+        let (requested, fallback) = exit_countries(
+            &route,
+            self.profile.exit_country_code().ok().flatten(),
+            self.routing.default_country(),
+        );
+        set_exit_country(&mut prefs, requested.clone());
+
+        match (client.connect_with_prefs((host, port), &prefs).await, fallback) {
+            (Ok(stream), _) => Ok(stream),
+            (Err(e), Some(fallback)) if is_exit_unavailable(&e) => {
+                log::info!(
+                    "no exit in {:?} for {}:{} (rule '{}'), using default route",
+                    requested,
+                    host,
+                    port,
+                    route.rule.as_deref().unwrap_or_default()
+                );
+                set_exit_country(&mut prefs, fallback);
+                client
+                    .connect_with_prefs((host, port), &prefs)
+                    .await
+                    .map_err(|e| OpenError::Unreachable(e.to_string()))
+            }
+            (Err(e), _) => Err(OpenError::Unreachable(e.to_string())),
+        }
+    }
+}
+
+/// Exit country requested for a stream and, if it differs, the country to
+/// retry with when no exit there can serve the destination.
+///
+/// A matching rule overrides the listener's exit country and falls back to
+/// the default rule. Without a matching rule the listener's own country
+/// applies, then the default rule, and there is no retry.
+fn exit_countries(
+    route: &ExitRoute,
+    listener: Option<CountryCode>,
+    default: Option<CountryCode>,
+) -> (Option<CountryCode>, Option<Option<CountryCode>>) {
+    match &route.rule {
+        Some(_) if route.country != default => (route.country.clone(), Some(default)),
+        Some(_) => (route.country.clone(), None),
+        None => (listener.or(default), None),
+    }
+}
+
+fn set_exit_country(prefs: &mut StreamPrefs, country: Option<CountryCode>) {
+    match country {
+        Some(cc) => {
+            prefs.exit_country(cc);
+        }
+        None => {
+            prefs.any_exit_country();
+        }
+    }
+}
+
+/// Whether connecting failed because no exit could serve the request.
+fn is_exit_unavailable(err: &arti_client::Error) -> bool {
+    matches!(err.kind(), ErrorKind::NoExit | ErrorKind::NoPath)
+}
+
+/// Background listener tasks. Dropping the handle stops accepting new
+/// connections; streams that are already open keep running.
+pub struct ListenerHandle {
+    port: u16,
+    socket: Option<PathBuf>,
+    tasks: Vec<tokio::task::AbortHandle>,
+}
+
+impl ListenerHandle {
+    pub fn new(port: u16, tasks: Vec<tokio::task::AbortHandle>) -> Self {
+        Self {
+            port,
+            socket: None,
+            tasks,
+        }
+    }
+
+    /// Handle of a listener on the Unix socket at `socket`.
+    pub fn unix(socket: PathBuf, tasks: Vec<tokio::task::AbortHandle>) -> Self {
+        Self {
+            port: 0,
+            socket: Some(socket),
+            tasks,
+        }
+    }
+
+    /// Local port the listener is bound to, `0` for a Unix socket
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Path of the Unix socket the listener is bound to
+    pub fn socket(&self) -> Option<&Path> {
+        self.socket.as_deref()
+    }
+
+    /// Keep the listener running for the rest of the process and return its
+    /// port.
+    pub fn detach(mut self) -> u16 {
+        self.tasks.clear();
+        self.port
+    }
+}
+
+impl Drop for ListenerHandle {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Start the main SOCKS listener. It stops when the handle is dropped.
 pub async fn start_socks_proxy(
     client: TorClient<PreferredRuntime>,
-    port: u16
-) -> Result<u16> {
+    port: u16,
+    ctx: SocksContext,
+) -> Result<ListenerHandle> {
     let listener = if port == 0 {
         TcpListener::bind("127.0.0.1:0").await?
     } else {
         TcpListener::bind(("127.0.0.1", port)).await?
     };
-    let local_port = listener.local_addr()?.port();
+    let local_addr = listener.local_addr()?;
+    let task = spawn_listener(listener, client, ctx, ListenerKind::Socks);
+    Ok(ListenerHandle::new(local_addr.port(), vec![task.abort_handle()]))
+}
+
+/// Start an additional SOCKS or HTTP CONNECT listener with its own profile.
+pub async fn start_listener(
+    client: TorClient<PreferredRuntime>,
+    spec: ListenerSpec,
+    ctx: SocksContext,
+) -> Result<ListenerHandle> {
+    let listener = TcpListener::bind(("127.0.0.1", spec.port)).await?;
+    let port = listener.local_addr()?.port();
+    let ctx = ctx.with_profile(spec.profile);
+    let task = spawn_listener(listener, client, ctx, spec.kind);
+    Ok(ListenerHandle::new(port, vec![task.abort_handle()]))
+}
 
+fn spawn_listener(
+    listener: TcpListener,
+    client: TorClient<PreferredRuntime>,
+    ctx: SocksContext,
+    kind: ListenerKind,
+) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
+        let local_addr = match listener.local_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("listener address unavailable: {}", e);
+                return;
+            }
+        };
         loop {
             match listener.accept().await {
-                Ok((socket, _)) => {
+                Ok((socket, peer)) => {
                     let client = client.clone();
+                    let ctx = ctx.clone();
                     tokio::spawn(async move {
-                        if let Err(_e) = handle_socks_conn(socket, client).await {
-                            // debug!("SOCKS error: {}", e);
+                        let res = match kind {
+                            ListenerKind::Socks => {
+                                handle_socks_conn(socket, peer, local_addr, client, ctx).await
+                            }
+                            ListenerKind::HttpConnect => {
+                                crate::http_connect::handle_connect(socket, peer, local_addr, client, ctx).await
+                            }
+                        };
+                        if let Err(e) = res {
+                            debug!("{:?} listener error: {}", kind, e);
                         }
                     });
                 }
-                Err(e) => error!("SOCKS accept error: {}", e),
+                Err(e) => error!("proxy accept error: {}", e),
             }
         }
-    });
-
-    Ok(local_port)
+    })
 }
 
-async fn handle_socks_conn(mut socket: TcpStream, client: TorClient<PreferredRuntime>) -> Result<()> {
+async fn handle_socks_conn(
+    mut socket: TcpStream,
+    peer: SocketAddr,
+    local_addr: SocketAddr,
+    client: TorClient<PreferredRuntime>,
+    ctx: SocksContext,
+) -> Result<()> {
     // Handshake
     let mut buf = [0u8; 2];
     socket.read_exact(&mut buf).await?;
@@ -44,8 +328,22 @@ async fn handle_socks_conn(mut socket: TcpStream, client: TorClient<PreferredRun
     let mut methods = vec![0u8; nmethods];
     socket.read_exact(&mut methods).await?;
 
-    // Select No Auth (0x00)
-    socket.write_all(&[0x05, 0x00]).await?;
+    // Prefer username/password so clients can request stream isolation
+    let method = if methods.contains(&METHOD_USER_PASS) {
+        METHOD_USER_PASS
+    } else if methods.contains(&METHOD_NO_AUTH) {
+        METHOD_NO_AUTH
+    } else {
+        METHOD_NONE_ACCEPTABLE
+    };
+    socket.write_all(&[0x05, method]).await?;
+    if method == METHOD_NONE_ACCEPTABLE { return Ok(()); }
+
+    let credentials = if method == METHOD_USER_PASS {
+        Some(read_credentials(&mut socket).await?)
+    } else {
+        None
+    };
 
     // Request
     let mut head = [0u8; 4];
@@ -81,17 +379,186 @@ async fn handle_socks_conn(mut socket: TcpStream, client: TorClient<PreferredRun
     socket.read_exact(&mut p).await?;
     port = u16::from_be_bytes(p);
 
-    // Connect via Tor
-    let tor_stream = client.connect((dest.as_str(), port)).await;
-
-    match tor_stream {
-        Ok(mut stream) => {
-             socket.write_all(&[0x05, 0x00, 0x00, 0x01, 0,0,0,0, 0,0]).await?;
-             let _ = tokio::io::copy_bidirectional(&mut socket, &mut stream).await;
+    let key = credentials.as_ref().map(|(_, key)| key.as_str());
+    match ctx.open_stream(&client, &dest, port, key).await {
+        Ok(stream) => {
+             send_reply(&mut socket, REPLY_SUCCEEDED).await?;
+             let target = StreamTarget {
+                 host: dest,
+                 port,
+                 isolation_group: credentials.map(|(group, _)| group),
+                 peer,
+                 pid: None,
+             };
+             serve_stream(socket, stream, &ctx, target, local_addr).await;
+        }
+        Err(OpenError::NotAllowed) => {
+             send_reply(&mut socket, REPLY_NOT_ALLOWED).await?;
         }
-        Err(_) => {
-             socket.write_all(&[0x05, 0x04, 0x00, 0x01, 0,0,0,0, 0,0]).await?; // Host unreachable
+        Err(OpenError::Unreachable(e)) => {
+             debug!("SOCKS connect to {}:{} failed: {}", dest, port, e);
+             send_reply(&mut socket, REPLY_HOST_UNREACHABLE).await?;
         }
     }
     Ok(())
 }
+
+/// Read an RFC 1929 username/password sub-negotiation. Returns the username
+/// used as isolation group label and the key used to select an isolation
+/// token (username and password combined).
+async fn read_credentials(socket: &mut TcpStream) -> Result<(String, String)> {
+    let mut ver = [0u8; 2];
+    socket.read_exact(&mut ver).await?;
+    if ver[0] != 0x01 {
+        // RFC 1929: any other version is a failure and closes the connection
+        socket.write_all(&[0x01, 0x01]).await?;
+        anyhow::bail!("unsupported auth version {:#04x}", ver[0]);
+    }
+    let mut user = vec![0u8; ver[1] as usize];
+    socket.read_exact(&mut user).await?;
+    let mut plen = [0u8; 1];
+    socket.read_exact(&mut plen).await?;
+    let mut pass = vec![0u8; plen[0] as usize];
+    socket.read_exact(&mut pass).await?;
+
+    // Any credentials are accepted; they only select the isolation group
+    socket.write_all(&[0x01, 0x00]).await?;
+
+    let user = String::from_utf8_lossy(&user).to_string();
+    let pass = String::from_utf8_lossy(&pass).to_string();
+    let key = format!("{user}\u{0}{pass}");
+    Ok((user, key))
+}
+
+async fn send_reply(socket: &mut TcpStream, code: u8) -> Result<()> {
+    socket.write_all(&[0x05, code, 0x00, 0x01, 0,0,0,0, 0,0]).await?;
+    Ok(())
+}
+
+/// Register an established Tor stream with the context and relay it to the
+/// local client until either side closes or the stream is terminated.
+pub(crate) async fn serve_stream(
+    socket: TcpStream,
+    stream: DataStream,
+    ctx: &SocksContext,
+    target: StreamTarget,
+    local_addr: SocketAddr,
+) {
+    let peer = target.peer;
+    let lookup = target.pid.is_none();
+    let guard = ctx.streams.register(target);
+    guard.set_circuit(circuit_label(&stream));
+    if lookup {
+        ctx.streams.resolve_pid(guard.id(), peer, local_addr);
+    }
+    let _ = relay(socket, stream, &guard, &ctx.shaper).await;
+    // Unregister before forgetting the limit, see set_stream_limit
+    let id = guard.id();
+    drop(guard);
+    ctx.shaper.forget_stream(id);
+}
+
+/// Identifier of the circuit carrying `stream`, if arti exposes it.
+fn circuit_label(stream: &DataStream) -> Option<String> {
+    stream
+        .client_stream_ctrl()
+        .and_then(|ctrl| ctrl.circuit())
+        .map(|circ| circ.unique_id().to_string())
+}
+
+/// Copy data in both directions until either side closes or the stream is
+/// terminated through the registry.
+async fn relay(
+    socket: TcpStream,
+    stream: DataStream,
+    guard: &StreamGuard,
+    shaper: &BandwidthShaper,
+) -> std::io::Result<()> {
+    let (mut client_rd, mut client_wr) = socket.into_split();
+    let (mut tor_rd, mut tor_wr) = tokio::io::split(stream);
+    let upload = copy_shaped(&mut client_rd, &mut tor_wr, guard, shaper, Direction::Upload);
+    let download = copy_shaped(&mut tor_rd, &mut client_wr, guard, shaper, Direction::Download);
+    tokio::select! {
+        res = futures::future::try_join(upload, download) => res.map(|_| ()),
+        _ = guard.killed() => Ok(()),
+    }
+}
+
+async fn copy_shaped<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    guard: &StreamGuard,
+    shaper: &BandwidthShaper,
+    dir: Direction,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; COPY_BUF_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(());
+        }
+        let wait = shaper.reserve(guard.id(), guard.isolation_group(), dir, n as u64);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+            guard.add_throttled(wait);
+        }
+        writer.write_all(&buf[..n]).await?;
+        // Arti buffers stream data until flushed
+        writer.flush().await?;
+        match dir {
+            Direction::Upload => guard.add_bytes_out(n as u64),
+            Direction::Download => guard.add_bytes_in(n as u64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cc(code: &str) -> Option<CountryCode> {
+        Some(code.parse().unwrap())
+    }
+
+    #[test]
+    fn unsatisfiable_rule_falls_back_to_default_rule() {
+        let route = ExitRoute {
+            rule: Some("*.co.uk".into()),
+            country: cc("GB"),
+        };
+        // The listener's own country is not used as fallback for a rule
+        assert_eq!(
+            exit_countries(&route, cc("NL"), cc("DE")),
+            (cc("GB"), Some(cc("DE")))
+        );
+        assert_eq!(exit_countries(&route, cc("NL"), None), (cc("GB"), Some(None)));
+        assert_eq!(exit_countries(&route, None, cc("GB")), (cc("GB"), None));
+    }
+
+    #[tokio::test]
+    async fn credential_tokens_are_bounded() {
+        let ctx = SocksContext::default();
+        let first = ctx.isolation_token("user-0").await;
+        assert_eq!(ctx.isolation_token("user-0").await, first);
+        for i in 1..=MAX_CREDENTIAL_TOKENS {
+            ctx.isolation_token(&format!("user-{i}")).await;
+        }
+        assert_eq!(ctx.isolation.lock().await.len(), MAX_CREDENTIAL_TOKENS);
+        assert_ne!(ctx.isolation_token("user-0").await, first);
+    }
+
+    #[test]
+    fn listener_country_applies_without_rule() {
+        let route = ExitRoute {
+            rule: None,
+            country: cc("DE"),
+        };
+        assert_eq!(exit_countries(&route, cc("NL"), cc("DE")), (cc("NL"), None));
+        assert_eq!(exit_countries(&route, None, cc("DE")), (cc("DE"), None));
+    }
+}
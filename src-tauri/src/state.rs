@@ -1,32 +1,55 @@
+use crate::bundle::BundleContents;
+use crate::config::{Config, ConfigStore, CONFIG_POLL_INTERVAL};
+use crate::control::{self, ControlConfig};
 use crate::core::executor::{SchedulerSnapshot, TaskScheduler};
 use crate::error::{Error, Result};
+use crate::events::{
+    emit_event, EventSink, EventStream, TeeSink, TrayStatus, EVENT_BACKLOG,
+};
+use crate::forward::ForwardSpec;
+use crate::history::{self, ConfigHistory};
+use crate::http_bridge;
+use crate::telemetry;
+use crate::trace::{self, TraceStatus};
+use crate::listeners::ListenerSpec;
+use crate::logstore::{Cursor, LogLimits, LogPage, LogQuery, LogQueryPage, LogStore};
+use crate::routing::ExitRoutingConfig;
 use crate::icmp;
-use crate::secure_http;
+use crate::paths::{ensure_parent, paths};
+use crate::profiles::{self, Profile, ProfileStore};
+use crate::redact::{self, RedactionLevel, Redactor};
+#[cfg(feature = "gui")]
 use crate::renderer::RendererService;
 use crate::secure_http::SecureHttpClient;
 use crate::session::SessionManager;
+use crate::settings::{self, RoutingSettings, SettingsStore};
+use crate::socks::{ListenerHandle, SocksContext};
 use crate::tor_manager::{TorClientBehavior, TorManager};
 use arti_client::TorClient;
 use chrono::{DateTime, Duration as ChronoDuration, Utc};
-use directories::ProjectDirs;
 use log::Level;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use sysinfo::System;
-#[cfg(target_os = "macos")]
-use tauri::NativeImage;
-use tauri::{AppHandle, CustomMenuItem, SystemTrayMenu, Manager};
 use tokio::fs::{self, OpenOptions};
 use tokio::io::AsyncWriteExt;
 use tokio::sync::{Mutex, RwLock};
 use tor_rtcompat::PreferredRuntime;
 
-/// Default location of the application configuration file
-pub const DEFAULT_CONFIG_PATH: &str = "src-tauri/app_config.json";
+/// Environment variable overriding [`crate::paths::AppPaths::config_file`]
+pub const CONFIG_PATH_ENV: &str = "TORWELL_CONFIG";
+
+/// Location of the app config file, honouring `TORWELL_CONFIG`.
+pub fn config_path() -> PathBuf {
+    std::env::var_os(CONFIG_PATH_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| paths().config_file())
+}
 
 /// Default number of log lines retained if no configuration is provided
 pub const DEFAULT_MAX_LOG_LINES: usize = 1000;
@@ -42,47 +65,8 @@ pub const DEFAULT_METRIC_INTERVAL_SECS: u64 = 30;
 pub const DEFAULT_METRIC_FETCH_LIMIT: usize = 100;
 /// Default number of connection timeline events to retain
 pub const DEFAULT_MAX_CONNECTION_EVENTS: usize = 720;
-
-#[derive(Deserialize, Default)]
-struct AppConfig {
-    #[serde(default = "default_max_log_lines")]
-    max_log_lines: usize,
-    #[serde(default)]
-    geoip_path: Option<String>,
-    #[serde(default)]
-    metrics_file: Option<String>,
-    #[serde(default = "default_max_metric_lines")]
-    max_metric_lines: usize,
-    #[serde(default = "default_max_metric_mb")]
-    max_metric_mb: usize,
-    #[serde(default = "default_insecure_hosts")]
-    insecure_allowed_hosts: Vec<String>,
-}
-
-fn default_max_log_lines() -> usize {
-    DEFAULT_MAX_LOG_LINES
-}
-
-fn default_max_metric_lines() -> usize {
-    DEFAULT_MAX_METRIC_LINES
-}
-
-fn default_max_metric_mb() -> usize {
-    DEFAULT_MAX_METRIC_MB
-}
-
-fn default_insecure_hosts() -> Vec<String> {
-    vec!["127.0.0.1".into(), "localhost".into()]
-}
-
-impl AppConfig {
-    fn load<P: AsRef<Path>>(path: P) -> Self {
-        std::fs::read_to_string(path)
-            .ok()
-            .and_then(|s| serde_json::from_str(&s).ok())
-            .unwrap_or_default()
-    }
-}
+/// Interval in seconds between `streams-update` events
+pub const DEFAULT_STREAM_UPDATE_INTERVAL_SECS: u64 = 2;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct LogEntry {
@@ -91,6 +75,13 @@ pub struct LogEntry {
     pub message: String,
     #[serde(default)]
     pub stack: Option<String>,
+    /// `tracing` target of a captured event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Fields of a captured event and of its spans, the latter prefixed
+    /// with the span name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -194,18 +185,16 @@ pub struct AppState<C: TorClientBehavior = TorClient<PreferredRuntime>> {
     pub tor_manager: Arc<RwLock<Arc<TorManager<C>>>>,
     pub http_client: Arc<SecureHttpClient>,
     pub scheduler: TaskScheduler,
-    /// Path to the persistent log file
-    pub log_file: PathBuf,
-    /// Mutex used to serialize file writes
-    pub log_lock: Arc<Mutex<()>>,
+    /// Segmented persistent log
+    pub logs: Arc<LogStore>,
+    /// Pseudonymizes addresses in logs and connection events
+    pub redactor: Arc<Redactor>,
     /// Optional path to store metric points
     pub metrics_file: Option<PathBuf>,
     /// Mutex used to serialize metric writes
     pub metrics_lock: Arc<Mutex<()>>,
     /// Counter for connection retries
     pub retry_counter: Arc<Mutex<u32>>,
-    /// Maximum number of lines to retain in the log file
-    pub max_log_lines: Arc<Mutex<usize>>,
     /// Maximum number of metric lines retained
     pub max_metric_lines: usize,
     /// Maximum metrics file size in megabytes
@@ -238,8 +227,10 @@ pub struct AppState<C: TorClientBehavior = TorClient<PreferredRuntime>> {
     pub max_connection_events: usize,
     /// Session manager for authentication tokens
     pub session: Arc<SessionManager>,
-    /// Handle used to emit frontend events
-    pub app_handle: Arc<Mutex<Option<AppHandle>>>,
+    /// Sink receiving frontend events, `None` when running headless
+    pub event_sink: Arc<Mutex<Option<Arc<dyn EventSink>>>>,
+    /// Numbered copy of all events, served by the REST API
+    pub event_stream: Arc<EventStream>,
     /// Current warning shown in the tray menu
     pub tray_warning: Arc<Mutex<Option<String>>>,
     /// Flag to avoid concurrent auto reconnect attempts
@@ -247,226 +238,195 @@ pub struct AppState<C: TorClientBehavior = TorClient<PreferredRuntime>> {
     /// Timestamp when the client last entered the connected state
     pub connected_since: Arc<Mutex<Option<DateTime<Utc>>>>,
     /// GPU renderer service handle
+    #[cfg(feature = "gui")]
     pub renderer: RendererService,
     /// Flag indicating if system proxy should be enabled on connect
     pub system_proxy_enabled: Arc<Mutex<bool>>,
+    /// Running control port and the settings it was started with
+    pub control_listener: Arc<Mutex<Option<(ControlConfig, ListenerHandle)>>>,
+    /// Running REST API listener
+    pub api_listener: Arc<Mutex<Option<ListenerHandle>>>,
+    /// Running Prometheus exporter
+    pub metrics_listener: Arc<Mutex<Option<ListenerHandle>>>,
+    /// Persisted routing and proxy settings
+    pub settings: Arc<SettingsStore>,
+    /// Layered application configuration
+    pub config: Arc<ConfigStore>,
+    /// Named connection profiles
+    pub profiles: Arc<ProfileStore>,
+    /// Snapshots of earlier settings
+    pub history: Arc<ConfigHistory>,
 }
 
 impl<C: TorClientBehavior> Default for AppState<C> {
     fn default() -> Self {
-        let log_file = if let Some(proj) = ProjectDirs::from("", "", "torwell84") {
-            let path = proj.data_dir().join("torwell.log");
-            if let Some(parent) = path.parent() {
-                let _ = std::fs::create_dir_all(parent);
-            }
-            path
-        } else {
-            let path = std::env::current_dir()
-                .unwrap_or_else(|_| PathBuf::from("."))
-                .join("torwell.log");
-            if let Some(parent) = path.parent() {
-                let _ = std::fs::create_dir_all(parent);
-            }
-            path
-        };
+        let http_client =
+            Arc::new(SecureHttpClient::new_default().expect("failed to create http client"));
+        Self::new(http_client)
+    }
+}
 
-        let cfg = AppConfig::load(DEFAULT_CONFIG_PATH);
-        let mut max_log_lines = cfg.max_log_lines;
-        let mut max_metric_lines = cfg.max_metric_lines;
-        let mut max_metric_mb = cfg.max_metric_mb;
-        let mut geoip_path = cfg.geoip_path.clone();
-        let mut max_connection_events = DEFAULT_MAX_CONNECTION_EVENTS;
-        let metric_interval_secs = std::env::var("TORWELL_METRIC_INTERVAL")
-            .ok()
-            .and_then(|v| v.parse::<u64>().ok())
-            .unwrap_or(DEFAULT_METRIC_INTERVAL_SECS);
-        if let Ok(val) = std::env::var("TORWELL_MAX_LOG_LINES") {
-            if let Ok(n) = val.parse::<usize>() {
-                max_log_lines = n;
-            }
+impl<C: TorClientBehavior> AppState<C> {
+    /// Build a Tor manager from the effective configuration. `socks` carries
+    /// the runtime listener state, such as the destination policy, over from
+    /// the manager being replaced.
+    fn build_tor_manager(
+        cfg: &Config,
+        routing: &RoutingSettings,
+        session: &Arc<SessionManager>,
+        socks: SocksContext,
+    ) -> TorManager<C> {
+        TorManager::new_with_geoip(cfg.geoip_path.clone())
+            .with_socks_context(socks)
+            .with_socks_port(cfg.socks_port)
+            .with_dns_port(cfg.dns_port)
+            .with_dns_max_ttl(cfg.dns_max_ttl_secs)
+            .with_forwards(cfg.forwards.clone())
+            .with_listeners(cfg.listeners.clone())
+            .with_exit_rules(cfg.exit_rules.clone())
+            .with_routing(routing)
+            .with_rpc_sessions(session.clone())
+    }
+
+    /// Replace the Tor manager with one built from `cfg`. A connected manager
+    /// is disconnected first so its listeners free their ports. Returns
+    /// whether it was connected, in which case the new one has to be
+    /// connected in its place.
+    async fn replace_tor_manager(&self, cfg: &Config) -> Result<bool> {
+        let old_mgr = self.tor_manager.read().await.clone();
+        let was_connected = old_mgr.is_connected().await;
+        if was_connected {
+            old_mgr.disconnect().await?;
         }
-        if let Ok(val) = std::env::var("TORWELL_MAX_METRIC_LINES") {
-            if let Ok(n) = val.parse::<usize>() {
-                max_metric_lines = n;
-            }
+        let new_mgr = Arc::new(Self::build_tor_manager(
+            cfg,
+            &self.settings.get().routing,
+            &self.session,
+            old_mgr.socks_context(),
+        ));
+        *self.tor_manager.write().await = new_mgr;
+        Ok(was_connected)
+    }
+
+    /// Replace the Tor manager like [`Self::replace_tor_manager`] and
+    /// connect the new one if the old one was connected.
+    async fn rebuild_tor_manager(&self, cfg: &Config) -> Result<()> {
+        if self.replace_tor_manager(cfg).await? {
+            self.tor_manager.read().await.clone().connect().await?;
         }
-        if let Ok(val) = std::env::var("TORWELL_MAX_METRIC_MB") {
-            if let Ok(n) = val.parse::<usize>() {
-                max_metric_mb = n;
+        Ok(())
+    }
+
+    /// Re-read the config files and apply them. The Tor manager is rebuilt,
+    /// and reconnected if it was connected before, only when its listener,
+    /// forward or routing settings changed. The rest of the configuration is
+    /// applied even if that reconnect fails, and the failure is returned
+    /// afterwards.
+    pub async fn reload_config(&self) -> Result<()> {
+        if self.apply_reloaded_config().await? {
+            let mgr = self.tor_manager.read().await.clone();
+            if let Err(e) = mgr.connect().await {
+                log::warn!("failed to reconnect after configuration reload: {}", e);
+                return Err(e);
             }
         }
-        if let Ok(val) = std::env::var("TORWELL_MAX_CONNECTION_EVENTS") {
-            if let Ok(n) = val.parse::<usize>() {
-                max_connection_events = n.max(10);
-            }
+        Ok(())
+    }
+
+    /// Re-read the config files and apply them without connecting. Returns
+    /// whether a connected Tor manager was replaced and the new one still
+    /// has to be connected.
+    async fn apply_reloaded_config(&self) -> Result<bool> {
+        let old = self.config.reload();
+        let cfg = self.config.current();
+        if let Err(e) = self.logs.set_limits(LogLimits::from_config(&cfg)) {
+            log::warn!("failed to apply log limits: {}", e);
         }
-        if let Ok(p) = std::env::var("TORWELL_GEOIP_PATH") {
-            geoip_path = Some(p);
+        if let Err(e) = trace::set_filters(&cfg.trace_filters) {
+            log::warn!("failed to apply trace filters: {}", e);
         }
+        self.http_client
+            .set_insecure_hosts(cfg.insecure_allowed_hosts.clone());
 
-        let http_client =
-            Arc::new(SecureHttpClient::new_default().expect("failed to create http client"));
-        http_client.set_insecure_hosts(cfg.insecure_allowed_hosts.clone());
+        let replaced = if cfg.manager_changed(&old) {
+            self.replace_tor_manager(&cfg).await
+        } else {
+            Ok(false)
+        };
+        self.apply_control_config(&cfg).await;
+        log::info!("configuration reloaded from {}", self.config.path().display());
+        replaced
+    }
 
-        Self {
-            tor_manager: Arc::new(RwLock::new(Arc::new(TorManager::new_with_geoip(
-                geoip_path.clone(),
-            )))),
-            http_client,
-            scheduler: TaskScheduler::global(),
-            log_file,
-            log_lock: Arc::new(Mutex::new(())),
-            metrics_file: cfg
-                .metrics_file
-                .clone()
-                .or_else(|| std::env::var("TORWELL_METRICS_FILE").ok())
-                .map(PathBuf::from)
-                .or_else(|| {
-                    if let Some(proj) = ProjectDirs::from("", "", "torwell84") {
-                        let path = proj.data_dir().join("metrics.json");
-                        if let Some(p) = path.parent() {
-                            let _ = std::fs::create_dir_all(p);
-                        }
-                        Some(path)
-                    } else {
-                        let path = std::env::current_dir()
-                            .unwrap_or_else(|_| PathBuf::from("."))
-                            .join("metrics.json");
-                        if let Some(p) = path.parent() {
-                            let _ = std::fs::create_dir_all(p);
-                        }
-                        Some(path)
-                    }
-                }),
-            metrics_lock: Arc::new(Mutex::new(())),
-            retry_counter: Arc::new(Mutex::new(0)),
-            max_log_lines: Arc::new(Mutex::new(max_log_lines)),
-            max_metric_lines,
-            max_metric_mb,
-            memory_usage: Arc::new(Mutex::new(0)),
-            circuit_count: Arc::new(Mutex::new(0)),
-            oldest_circuit_age: Arc::new(Mutex::new(0)),
-            latency_ms: Arc::new(Mutex::new(0)),
-            cpu_usage: Arc::new(Mutex::new(0.0)),
-            network_throughput: Arc::new(Mutex::new(0)),
-            network_total: Arc::new(Mutex::new(0)),
-            prev_traffic: Arc::new(Mutex::new(0)),
-            max_memory_mb: std::env::var("TORWELL_MAX_MEMORY_MB")
-                .ok()
-                .and_then(|v| v.parse::<u64>().ok())
-                .unwrap_or(1024),
-            max_circuits: std::env::var("TORWELL_MAX_CIRCUITS")
-                .ok()
-                .and_then(|v| v.parse::<usize>().ok())
-                .unwrap_or(20),
-            metric_interval_secs,
-            connection_events: Arc::new(Mutex::new(Vec::new())),
-            max_connection_events,
-            session: SessionManager::new(Duration::from_secs(
-                std::env::var("TORWELL_SESSION_TTL")
-                    .ok()
-                    .and_then(|v| v.parse::<u64>().ok())
-                    .unwrap_or(DEFAULT_SESSION_TTL),
-            )),
-            app_handle: Arc::new(Mutex::new(None)),
-            tray_warning: Arc::new(Mutex::new(None)),
-            reconnect_in_progress: Arc::new(Mutex::new(false)),
-            connected_since: Arc::new(Mutex::new(None)),
-            renderer: RendererService::new(),
-            system_proxy_enabled: Arc::new(Mutex::new(true)), // Default to true as per user preference
-        }
+    /// Start the Tor control port if `control_port` is configured.
+    pub async fn start_control_port(&self) {
+        self.apply_control_config(&self.config.current()).await;
     }
-}
 
-impl<C: TorClientBehavior> AppState<C> {
-    pub fn new(http_client: Arc<SecureHttpClient>) -> Self {
-        let log_file = if let Some(proj) = ProjectDirs::from("", "", "torwell84") {
-            let path = proj.data_dir().join("torwell.log");
-            if let Some(parent) = path.parent() {
-                let _ = std::fs::create_dir_all(parent);
-            }
-            path
-        } else {
-            let path = std::env::current_dir()
-                .unwrap_or_else(|_| PathBuf::from("."))
-                .join("torwell.log");
-            if let Some(parent) = path.parent() {
-                let _ = std::fs::create_dir_all(parent);
-            }
-            path
-        };
+    async fn apply_control_config(&self, cfg: &Config) {
+        let wanted = cfg
+            .control_port
+            .map(|port| ControlConfig::new(port, cfg.control_password.clone()));
 
-        let cfg = AppConfig::load(DEFAULT_CONFIG_PATH);
-        let mut max_log_lines = cfg.max_log_lines;
-        let mut max_metric_lines = cfg.max_metric_lines;
-        let mut max_metric_mb = cfg.max_metric_mb;
-        let mut geoip_path = cfg.geoip_path.clone();
-        let mut max_connection_events = DEFAULT_MAX_CONNECTION_EVENTS;
-        let metric_interval_secs = std::env::var("TORWELL_METRIC_INTERVAL")
-            .ok()
-            .and_then(|v| v.parse::<u64>().ok())
-            .unwrap_or(DEFAULT_METRIC_INTERVAL_SECS);
-        if let Ok(val) = std::env::var("TORWELL_MAX_LOG_LINES") {
-            if let Ok(n) = val.parse::<usize>() {
-                max_log_lines = n;
-            }
+        let mut running = self.control_listener.lock().await;
+        if running.as_ref().map(|(config, _)| config) == wanted.as_ref() {
+            return;
         }
-        if let Ok(val) = std::env::var("TORWELL_MAX_METRIC_LINES") {
-            if let Ok(n) = val.parse::<usize>() {
-                max_metric_lines = n;
-            }
-        }
-        if let Ok(val) = std::env::var("TORWELL_MAX_METRIC_MB") {
-            if let Ok(n) = val.parse::<usize>() {
-                max_metric_mb = n;
-            }
-        }
-        if let Ok(val) = std::env::var("TORWELL_MAX_CONNECTION_EVENTS") {
-            if let Ok(n) = val.parse::<usize>() {
-                max_connection_events = n.max(10);
+        // Stop the old listener before binding the port again
+        running.take();
+        let Some(config) = wanted else { return };
+        match control::start_control_server(self.tor_manager.clone(), config.clone()).await {
+            Ok(handle) => {
+                log::info!(
+                    "control port on 127.0.0.1:{}, cookie {}",
+                    handle.port(),
+                    config.cookie_path.display()
+                );
+                *running = Some((config, handle));
             }
+            Err(e) => log::warn!("failed to start control port: {}", e),
         }
-        if let Ok(p) = std::env::var("TORWELL_GEOIP_PATH") {
-            geoip_path = Some(p);
-        }
+    }
+
+    /// Create the state from the config file at [`config_path`].
+    pub fn new(http_client: Arc<SecureHttpClient>) -> Self {
+        Self::with_config(http_client, Arc::new(ConfigStore::load(config_path())))
+    }
 
+    /// Create the state from an already loaded configuration.
+    pub fn with_config(http_client: Arc<SecureHttpClient>, config: Arc<ConfigStore>) -> Self {
+        let cfg = config.current();
         http_client.set_insecure_hosts(cfg.insecure_allowed_hosts.clone());
+        if let Err(e) = trace::set_filters(&cfg.trace_filters) {
+            log::warn!("failed to apply trace filters: {}", e);
+        }
+        let session = SessionManager::new(Duration::from_secs(cfg.session_ttl));
+        let settings = Arc::new(SettingsStore::open(settings::settings_path()));
 
         AppState {
-            tor_manager: Arc::new(RwLock::new(Arc::new(TorManager::new_with_geoip(
-                geoip_path.clone(),
+            tor_manager: Arc::new(RwLock::new(Arc::new(Self::build_tor_manager(
+                &cfg,
+                &settings.get().routing,
+                &session,
+                SocksContext::default(),
             )))),
             http_client,
             scheduler: TaskScheduler::global(),
-            log_file,
-            log_lock: Arc::new(Mutex::new(())),
-            metrics_file: cfg
-                .metrics_file
-                .clone()
-                .or_else(|| std::env::var("TORWELL_METRICS_FILE").ok())
-                .map(PathBuf::from)
-                .or_else(|| {
-                    if let Some(proj) = ProjectDirs::from("", "", "torwell84") {
-                        let path = proj.data_dir().join("metrics.json");
-                        if let Some(p) = path.parent() {
-                            let _ = std::fs::create_dir_all(p);
-                        }
-                        Some(path)
-                    } else {
-                        let path = std::env::current_dir()
-                            .unwrap_or_else(|_| PathBuf::from("."))
-                            .join("metrics.json");
-                        if let Some(p) = path.parent() {
-                            let _ = std::fs::create_dir_all(p);
-                        }
-                        Some(path)
-                    }
-                }),
+            logs: Arc::new(LogStore::open(
+                ensure_parent(paths().log_file()),
+                LogLimits::from_config(&cfg),
+            )),
+            redactor: Arc::new(Redactor::open(&redact::redaction_key_path())),
+            metrics_file: Some(
+                cfg.metrics_file
+                    .clone()
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| ensure_parent(paths().metrics_file())),
+            ),
             metrics_lock: Arc::new(Mutex::new(())),
             retry_counter: Arc::new(Mutex::new(0)),
-            max_log_lines: Arc::new(Mutex::new(max_log_lines)),
-            max_metric_lines,
-            max_metric_mb,
+            max_metric_lines: cfg.max_metric_lines,
+            max_metric_mb: cfg.max_metric_mb,
             memory_usage: Arc::new(Mutex::new(0)),
             circuit_count: Arc::new(Mutex::new(0)),
             oldest_circuit_age: Arc::new(Mutex::new(0)),
@@ -475,40 +435,44 @@ impl<C: TorClientBehavior> AppState<C> {
             network_throughput: Arc::new(Mutex::new(0)),
             network_total: Arc::new(Mutex::new(0)),
             prev_traffic: Arc::new(Mutex::new(0)),
-            max_memory_mb: std::env::var("TORWELL_MAX_MEMORY_MB")
-                .ok()
-                .and_then(|v| v.parse::<u64>().ok())
-                .unwrap_or(1024),
-            max_circuits: std::env::var("TORWELL_MAX_CIRCUITS")
-                .ok()
-                .and_then(|v| v.parse::<usize>().ok())
-                .unwrap_or(20),
-            metric_interval_secs,
+            max_memory_mb: cfg.max_memory_mb,
+            max_circuits: cfg.max_circuits,
+            metric_interval_secs: cfg.metric_interval_secs,
             connection_events: Arc::new(Mutex::new(Vec::new())),
-            max_connection_events,
-            session: SessionManager::new(Duration::from_secs(
-                std::env::var("TORWELL_SESSION_TTL")
-                    .ok()
-                    .and_then(|v| v.parse::<u64>().ok())
-                    .unwrap_or(DEFAULT_SESSION_TTL),
-            )),
-            app_handle: Arc::new(Mutex::new(None)),
+            max_connection_events: cfg.max_connection_events,
+            session,
+            event_sink: Arc::new(Mutex::new(None)),
+            event_stream: Arc::new(EventStream::new(EVENT_BACKLOG)),
             tray_warning: Arc::new(Mutex::new(None)),
             reconnect_in_progress: Arc::new(Mutex::new(false)),
             connected_since: Arc::new(Mutex::new(None)),
+            #[cfg(feature = "gui")]
             renderer: RendererService::new(),
-            system_proxy_enabled: Arc::new(Mutex::new(true)),
+            system_proxy_enabled: Arc::new(Mutex::new(settings.get().proxy.system_proxy_enabled)),
+            control_listener: Arc::new(Mutex::new(None)),
+            api_listener: Arc::new(Mutex::new(None)),
+            metrics_listener: Arc::new(Mutex::new(None)),
+            settings,
+            config,
+            profiles: Arc::new(ProfileStore::open(profiles::profiles_path())),
+            history: Arc::new(ConfigHistory::open(
+                history::history_path(),
+                history::HISTORY_LIMIT,
+            )),
         }
     }
 
-    pub async fn set_system_proxy_enabled(&self, enabled: bool) {
+    /// Enable or disable the system proxy on connect and persist the choice.
+    pub async fn set_system_proxy_enabled(&self, enabled: bool) -> Result<()> {
         *self.system_proxy_enabled.lock().await = enabled;
+        self.settings.update(|s| s.proxy.system_proxy_enabled = enabled)
     }
 
     pub async fn is_system_proxy_enabled(&self) -> bool {
         *self.system_proxy_enabled.lock().await
     }
 
+    #[cfg(feature = "gui")]
     pub fn renderer_service(&self) -> RendererService {
         self.renderer.clone()
     }
@@ -580,25 +544,24 @@ impl<C: TorClientBehavior> AppState<C> {
         message: String,
         stack: Option<String>,
     ) -> Result<()> {
-        let _guard = self.log_lock.lock().await;
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.log_file)
-            .await?;
-        let entry = LogEntry {
+        self.add_log_entry(LogEntry {
             level: level.to_string(),
             timestamp: Utc::now().to_rfc3339(),
             message,
             stack,
-        };
-        let json = serde_json::to_string(&entry)?;
-        file.write_all(json.as_bytes()).await?;
-        file.write_all(b"\n").await?;
-        drop(file);
+            target: None,
+            fields: BTreeMap::new(),
+        })
+        .await
+    }
+
+    /// Redact and store `entry` and forward it to the log endpoint.
+    pub async fn add_log_entry(&self, entry: LogEntry) -> Result<()> {
+        let entry = self.redactor.redact_entry(self.redaction_level(), entry);
+        self.logs.append(&entry)?;
 
         // Optional hook: forward logs to a central server if configured.
-        if let Ok(endpoint) = std::env::var("TORWELL_LOG_ENDPOINT") {
+        if let Some(endpoint) = self.config.read(|c| c.log_endpoint.clone()) {
             let client = self.http_client.clone();
             let payload = serde_json::to_value(&entry)?;
             tokio::spawn(async move {
@@ -607,47 +570,69 @@ impl<C: TorClientBehavior> AppState<C> {
                 }
             });
         }
-
-        self.trim_logs().await?;
         Ok(())
     }
 
-    async fn trim_logs(&self) -> Result<()> {
-        let contents = fs::read_to_string(&self.log_file).await.unwrap_or_default();
-        let mut lines: Vec<&str> = contents.lines().collect();
-        let limit = *self.max_log_lines.lock().await;
-        if lines.len() > limit {
-            let archive_dir = self
-                .log_file
-                .parent()
-                .map(|p| p.join("archive"))
-                .unwrap_or_else(|| PathBuf::from("archive"));
-            fs::create_dir_all(&archive_dir).await?;
-            let ts = Utc::now().format("%Y%m%d%H%M%S");
-            let archive_path = archive_dir.join(format!("torwell-{}.log", ts));
-            fs::rename(&self.log_file, &archive_path).await?;
-            lines = lines[lines.len() - limit..].to_vec();
-            fs::write(&self.log_file, lines.join("\n")).await?;
-        }
-        Ok(())
+    /// The newest entries, up to the configured line limit.
+    pub async fn read_logs(&self) -> Result<Vec<LogEntry>> {
+        let limit = self.logs.limits().max_lines;
+        Ok(self.read_logs_page(None, limit).await?.entries)
     }
 
-    pub async fn read_logs(&self) -> Result<Vec<LogEntry>> {
-        let _guard = self.log_lock.lock().await;
-        let contents = fs::read_to_string(&self.log_file).await.unwrap_or_default();
-        let mut entries = Vec::new();
-        for line in contents.lines() {
-            if let Ok(entry) = serde_json::from_str::<LogEntry>(line) {
-                entries.push(entry);
-            }
-        }
-        Ok(entries)
+    /// A page of entries before `cursor`, read on a blocking thread since
+    /// archived segments have to be decompressed.
+    pub async fn read_logs_page(&self, cursor: Option<Cursor>, limit: usize) -> Result<LogPage> {
+        let logs = self.logs.clone();
+        let mut page = tokio::task::spawn_blocking(move || logs.read_page(cursor, limit))
+            .await
+            .map_err(|e| Error::Io(e.to_string()))??;
+        page.entries = self.redact_entries(page.entries);
+        Ok(page)
     }
 
+    /// Run a log query on a blocking thread. Entries are redacted before
+    /// they are matched, so filters cannot probe for redacted text.
+    pub async fn query_logs(&self, query: LogQuery) -> Result<LogQueryPage> {
+        let logs = self.logs.clone();
+        let redactor = self.redactor.clone();
+        let level = self.redaction_level();
+        tokio::task::spawn_blocking(move || {
+            logs.query_with(&query, &|entry| redactor.redact_entry(level, entry))
+        })
+        .await
+        .map_err(|e| Error::Io(e.to_string()))?
+    }
+
+    /// Redact entries on their way out, covering entries written before
+    /// redaction was enabled or raised.
+    fn redact_entries(&self, entries: Vec<LogEntry>) -> Vec<LogEntry> {
+        let level = self.redaction_level();
+        entries
+            .into_iter()
+            .map(|entry| self.redactor.redact_entry(level, entry))
+            .collect()
+    }
+
+    /// Redaction level from the configuration.
+    pub fn redaction_level(&self) -> RedactionLevel {
+        self.config.read(|c| c.log_redaction)
+    }
+
+    /// Change the redaction level, kept as a runtime override of
+    /// `log_redaction`.
+    pub fn set_redaction_level(&self, level: RedactionLevel) -> Result<()> {
+        self.config
+            .set_override("log_redaction", serde_json::to_value(level)?)
+    }
+
+    /// Redact `text` at the configured level.
+    pub fn redact(&self, text: &str) -> String {
+        self.redactor.redact(self.redaction_level(), text)
+    }
+
+    /// Remove all log segments.
     pub async fn clear_log_file(&self) -> Result<()> {
-        let _guard = self.log_lock.lock().await;
-        fs::write(&self.log_file, b"").await?;
-        Ok(())
+        self.logs.clear()
     }
 
     async fn trim_metrics(&self) -> Result<()> {
@@ -734,98 +719,363 @@ impl<C: TorClientBehavior> AppState<C> {
         }
     }
 
-    /// Update the maximum number of log lines and trim existing logs
+    /// Update the number of lines per log segment, rotating the active
+    /// segment if it is already longer
     pub async fn set_max_log_lines(&self, limit: usize) -> Result<()> {
-        *self.max_log_lines.lock().await = limit;
-        self.trim_logs().await
+        let mut limits = self.logs.limits();
+        limits.max_lines = limit;
+        self.logs.set_limits(limits)
     }
 
-    /// Update the certificate update interval and restart the background task
-    pub async fn set_update_interval(&self, interval: u64) {
+    /// Update the certificate update interval and restart the background
+    /// task. The interval is kept as a runtime override of `update_interval`.
+    pub async fn set_update_interval(&self, interval: u64) -> Result<()> {
+        self.config
+            .set_override("update_interval", serde_json::json!(interval))?;
+        let cert = self.config.cert_settings();
+        let mut urls = vec![cert.cert_url];
+        urls.extend(cert.fallback_cert_url);
+
         if interval > 0 {
-            std::env::set_var("TORWELL_UPDATE_INTERVAL", interval.to_string());
-        } else {
-            std::env::remove_var("TORWELL_UPDATE_INTERVAL");
+            self.http_client
+                .clone()
+                .schedule_updates(urls, std::time::Duration::from_secs(interval))
+                .await;
         }
+        Ok(())
+    }
 
-        let cfg: serde_json::Value = std::fs::read_to_string(secure_http::DEFAULT_CONFIG_PATH)
-            .ok()
-            .and_then(|s| serde_json::from_str(&s).ok())
-            .unwrap_or_default();
+    /// Store the HSM module and slot as runtime overrides and pass them to
+    /// the HTTP client.
+    pub async fn set_hsm_config(&self, lib: Option<String>, slot: Option<u64>) -> Result<()> {
+        self.config.set_override("hsm_lib", serde_json::json!(lib))?;
+        self.config.set_override("hsm_slot", serde_json::json!(slot))?;
+        self.http_client
+            .set_hsm_config(lib, slot)
+            .await
+            .map_err(|e| Error::Io(e.to_string()))
+    }
+
+    /// Update the GeoIP directory and replace the Tor manager. The new
+    /// manager is built from the effective configuration and the persisted
+    /// routing settings like on startup, and reconnected if the old one was
+    /// connected.
+    pub async fn set_geoip_path(&self, path: Option<String>) -> Result<()> {
+        self.config
+            .set_override("geoip_path", serde_json::json!(path))?;
+        self.rebuild_tor_manager(&self.config.current()).await
+    }
 
-        let mut urls = vec![cfg
-            .get("cert_url")
-            .and_then(|v| v.as_str())
-            .unwrap_or(secure_http::DEFAULT_CERT_URL)
-            .to_string()];
-        if let Some(fb) = cfg.get("fallback_cert_url").and_then(|v| v.as_str()) {
-            urls.push(fb.to_string());
+    /// Set the exit country and persist the routing settings.
+    pub async fn set_exit_country(&self, country: Option<String>) -> Result<()> {
+        let mgr = self.tor_manager.read().await.clone();
+        mgr.set_exit_country(country).await?;
+        self.persist_routing(&mgr).await
+    }
+
+    /// Set the entry country and persist the routing settings.
+    pub async fn set_entry_country(&self, country: Option<String>) -> Result<()> {
+        let mgr = self.tor_manager.read().await.clone();
+        mgr.set_entry_country(country).await?;
+        self.persist_routing(&mgr).await
+    }
+
+    /// Set the middle country and persist the routing settings.
+    pub async fn set_middle_country(&self, country: Option<String>) -> Result<()> {
+        let mgr = self.tor_manager.read().await.clone();
+        mgr.set_middle_country(country).await?;
+        self.persist_routing(&mgr).await
+    }
+
+    /// Replace the bridge lines and persist the routing settings.
+    pub async fn set_bridges(&self, bridges: Vec<String>) -> Result<()> {
+        let mgr = self.tor_manager.read().await.clone();
+        mgr.set_bridges(bridges).await?;
+        self.persist_routing(&mgr).await
+    }
+
+    /// Apply a torrc snippet and persist the routing settings.
+    pub async fn set_torrc_config(&self, config: String) -> Result<()> {
+        let mgr = self.tor_manager.read().await.clone();
+        mgr.set_torrc_config(config).await?;
+        self.persist_routing(&mgr).await
+    }
+
+    async fn persist_routing(&self, mgr: &TorManager<C>) -> Result<()> {
+        let routing = mgr.routing_settings().await;
+        self.settings.update(|s| s.routing = routing)
+    }
+
+    /// Configure the local DNS listener and persist the port. The listener is
+    /// (re)started on the next connect.
+    pub async fn set_dns_port(&self, port: Option<u16>) -> Result<()> {
+        let port = port.filter(|p| *p != 0);
+        self.tor_manager.read().await.set_dns_port(port).await;
+        self.persist_config_value("dns_port", serde_json::json!(port))
+    }
+
+    /// Add a port forward and persist the forward list.
+    pub async fn add_forward(&self, spec: ForwardSpec) -> Result<()> {
+        let mgr = self.tor_manager.read().await.clone();
+        mgr.add_forward(spec).await?;
+        self.persist_config_value("forwards", serde_json::to_value(mgr.forward_specs())?)
+    }
+
+    /// Remove a port forward and persist the forward list.
+    pub async fn remove_forward(&self, listen_port: u16) -> Result<bool> {
+        let mgr = self.tor_manager.read().await.clone();
+        if !mgr.remove_forward(listen_port) {
+            return Ok(false);
+        }
+        self.persist_config_value("forwards", serde_json::to_value(mgr.forward_specs())?)?;
+        Ok(true)
+    }
+
+    /// Replace the exit routing rules and persist them.
+    pub async fn set_exit_rules(&self, rules: ExitRoutingConfig) -> Result<()> {
+        let mgr = self.tor_manager.read().await.clone();
+        mgr.exit_routing().set_config(rules.clone())?;
+        self.persist_config_value("exit_rules", serde_json::to_value(rules)?)
+    }
+
+    /// Add or replace a proxy listener and persist the listener list.
+    pub async fn set_listener(&self, spec: ListenerSpec) -> Result<()> {
+        let mgr = self.tor_manager.read().await.clone();
+        mgr.set_listener(spec).await?;
+        self.persist_config_value("listeners", serde_json::to_value(mgr.listener_specs())?)
+    }
+
+    /// Remove a proxy listener and persist the listener list.
+    pub async fn remove_listener(&self, port: u16) -> Result<bool> {
+        let mgr = self.tor_manager.read().await.clone();
+        if !mgr.remove_listener(port) {
+            return Ok(false);
         }
+        self.persist_config_value("listeners", serde_json::to_value(mgr.listener_specs())?)?;
+        Ok(true)
+    }
 
-        if let Ok(env_url) = std::env::var("TORWELL_CERT_URL") {
-            urls[0] = env_url;
+    /// Profile named `name` holding the current routing, listener, exit rule
+    /// and system proxy settings.
+    pub async fn capture_profile(&self, name: &str) -> Profile {
+        let mgr = self.tor_manager.read().await.clone();
+        Profile {
+            name: name.to_string(),
+            routing: mgr.routing_settings().await,
+            listeners: mgr.listener_specs(),
+            exit_rules: mgr.exit_routing().config(),
+            system_proxy_enabled: self.is_system_proxy_enabled().await,
         }
-        if let Ok(env_fb) = std::env::var("TORWELL_FALLBACK_CERT_URL") {
-            if urls.len() == 1 {
-                urls.push(env_fb);
-            } else {
-                urls[1] = env_fb;
-            }
+    }
+
+    /// Apply profile `name`, persist its settings and mark it active. What
+    /// the running client can pick up is changed live; the return value
+    /// tells whether a reconnect is needed for the rest. The switch is
+    /// recorded in the connection timeline.
+    pub async fn apply_profile(&self, name: &str) -> Result<bool> {
+        let profile = self.profiles.get(name)?;
+        let needs_reconnect = self.apply_settings(profile).await?;
+        self.profiles.set_active(name)?;
+        self.record_settings_event(
+            "PROFILE",
+            format!("switched to profile '{}'", name),
+            needs_reconnect,
+        )
+        .await;
+        Ok(needs_reconnect)
+    }
+
+    /// Apply and persist the routing, listener, exit rule and system proxy
+    /// settings of `profile`. Returns whether a reconnect is needed.
+    async fn apply_settings(&self, profile: Profile) -> Result<bool> {
+        let mgr = self.tor_manager.read().await.clone();
+        let current = mgr.routing_settings().await;
+        let needs_reconnect = profile.needs_reconnect(&current);
+
+        let routing = profile.routing;
+        mgr.set_exit_country(routing.exit_country).await?;
+        mgr.set_entry_country(routing.entry_country).await?;
+        mgr.set_middle_country(routing.middle_country).await?;
+        mgr.set_bridges(routing.bridges).await?;
+        if routing.torrc != current.torrc {
+            mgr.set_torrc_config(routing.torrc).await?;
         }
+        self.persist_routing(&mgr).await?;
 
-        if interval > 0 {
-            self.http_client
-                .clone()
-                .schedule_updates(urls, std::time::Duration::from_secs(interval))
-                .await;
+        for spec in mgr.listener_specs() {
+            if !profile.listeners.iter().any(|l| l.port == spec.port) {
+                mgr.remove_listener(spec.port);
+            }
+        }
+        let running = mgr.listener_specs();
+        for spec in profile.listeners {
+            if !running.contains(&spec) {
+                mgr.set_listener(spec).await?;
+            }
         }
+        self.persist_config_value("listeners", serde_json::to_value(mgr.listener_specs())?)?;
+        self.set_exit_rules(profile.exit_rules).await?;
+        self.set_system_proxy_enabled(profile.system_proxy_enabled)
+            .await?;
+        Ok(needs_reconnect)
     }
 
-    /// Update the GeoIP directory and replace the Tor manager
-    pub async fn set_geoip_path(&self, path: Option<String>) {
-        if let Some(ref p) = path {
-            std::env::set_var("TORWELL_GEOIP_PATH", p);
+    async fn record_settings_event(&self, status: &str, message: String, needs_reconnect: bool) {
+        let connected = {
+            let mgr = self.tor_manager.read().await.clone();
+            mgr.is_connected().await
+        };
+        let detail = if needs_reconnect && connected {
+            "reconnecting to apply bridges, torrc or guard settings"
         } else {
-            std::env::remove_var("TORWELL_GEOIP_PATH");
+            "applied without reconnect"
+        };
+        self.record_connection_event(status, Some(message), Some(detail.into()), None)
+            .await;
+        self.update_tray_menu().await;
+    }
+
+    /// Current settings in the shape of a profile named after the active one.
+    pub async fn current_settings(&self) -> Profile {
+        self.capture_profile(&self.profiles.active().unwrap_or_default())
+            .await
+    }
+
+    /// Add the current settings to the config history, attributed to
+    /// `command`.
+    pub async fn record_snapshot(&self, command: &str) {
+        let settings = self.current_settings().await;
+        self.history.record(command, settings);
+    }
+
+    /// Mark the current settings as known good after a successful connect.
+    pub async fn mark_settings_good(&self) {
+        let settings = self.current_settings().await;
+        self.history.mark_good(settings);
+    }
+
+    /// Apply the settings of snapshot `id`. Returns whether a reconnect is
+    /// needed, like [`Self::apply_profile`].
+    pub async fn restore_snapshot(&self, id: u64) -> Result<bool> {
+        let snapshot = self.history.get(id)?;
+        let needs_reconnect = self.apply_settings(snapshot.settings).await?;
+        self.record_snapshot("restore_snapshot").await;
+        self.record_settings_event(
+            "RESTORE",
+            format!(
+                "restored settings of snapshot {} ({})",
+                snapshot.id, snapshot.command
+            ),
+            needs_reconnect,
+        )
+        .await;
+        Ok(needs_reconnect)
+    }
+
+    /// Config file, settings, profiles and workers for an export bundle,
+    /// without secrets.
+    pub async fn bundle_contents(&self) -> BundleContents {
+        let app_config = std::fs::read_to_string(self.config.path())
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        BundleContents {
+            created: Utc::now(),
+            app_config,
+            settings: self.settings.get(),
+            profiles: self.profiles.list().profiles,
+            workers: self.http_client.worker_config().await.0,
+            secrets: None,
         }
-        let new_mgr = Arc::new(TorManager::new_with_geoip(path));
-        *self.tor_manager.write().await = new_mgr;
+    }
+
+    /// Write and apply the config file, settings, profiles and workers of
+    /// an imported bundle, already merged with the current configuration.
+    /// Returns whether a reconnect is needed, like [`Self::apply_profile`].
+    pub async fn import_bundle(&self, contents: BundleContents) -> Result<bool> {
+        let serialized = serde_json::to_vec_pretty(&contents.app_config)?;
+        settings::write_atomic(self.config.path(), &serialized)?;
+        self.settings.update(|s| *s = contents.settings.clone())?;
+        // Rebuilds the Tor manager with the new settings if ports, listeners
+        // or forwards changed. It is connected again by the caller once the
+        // whole bundle is applied.
+        let replaced = self.apply_reloaded_config().await?;
+
+        let cfg = self.config.current();
+        let needs_reconnect = self
+            .apply_settings(Profile {
+                name: String::new(),
+                routing: contents.settings.routing,
+                listeners: cfg.listeners,
+                exit_rules: cfg.exit_rules,
+                system_proxy_enabled: contents.settings.proxy.system_proxy_enabled,
+            })
+            .await?
+            || replaced;
+        self.profiles.replace_all(contents.profiles)?;
+        let (_, token) = self.http_client.worker_config().await;
+        let token = contents
+            .secrets
+            .and_then(|s| s.worker_token)
+            .or(token);
+        self.http_client
+            .set_worker_config(contents.workers, token)
+            .await;
+        self.record_snapshot("import_config_bundle").await;
+        self.record_settings_event(
+            "IMPORT",
+            "imported a configuration bundle".into(),
+            needs_reconnect,
+        )
+        .await;
+        Ok(needs_reconnect)
     }
 
     pub async fn set_insecure_hosts(&self, hosts: Vec<String>) -> Result<()> {
         self.http_client.set_insecure_hosts(hosts.clone());
-        Self::persist_insecure_hosts(&hosts)?;
+        self.persist_insecure_hosts(&hosts)?;
         Ok(())
     }
 
-    fn persist_insecure_hosts(hosts: &[String]) -> Result<()> {
-        let path = Path::new(DEFAULT_CONFIG_PATH);
-        let contents = std::fs::read_to_string(path).unwrap_or_default();
+    fn persist_insecure_hosts(&self, hosts: &[String]) -> Result<()> {
+        self.persist_config_value(
+            "insecure_allowed_hosts",
+            serde_json::Value::Array(
+                hosts
+                    .iter()
+                    .map(|h| serde_json::Value::String(h.clone()))
+                    .collect(),
+            ),
+        )
+    }
+
+    /// Write a single top-level key of the app config file, keeping all
+    /// other keys untouched, and reload the configuration so the file
+    /// watcher does not apply the change a second time.
+    fn persist_config_value(&self, key: &str, value: serde_json::Value) -> Result<()> {
+        let path = self.config.path();
+        let contents = std::fs::read_to_string(&path).unwrap_or_default();
         let mut config: serde_json::Value = if contents.trim().is_empty() {
             serde_json::json!({})
         } else {
             serde_json::from_str(&contents).unwrap_or_else(|_| serde_json::json!({}))
         };
 
-        config["insecure_allowed_hosts"] = serde_json::Value::Array(
-            hosts
-                .iter()
-                .map(|h| serde_json::Value::String(h.clone()))
-                .collect(),
-        );
+        config[key] = value;
 
         let serialized = serde_json::to_string_pretty(&config).map_err(|e| Error::ConfigError {
-            step: "state::persist_insecure_hosts".into(),
+            step: "state::persist_config_value".into(),
             source_message: e.to_string(),
             backtrace: format!("{:?}", std::backtrace::Backtrace::capture()),
         })?;
-        std::fs::write(path, serialized)?;
+        settings::write_atomic(path, serialized.as_bytes())?;
+        self.config.reload();
         Ok(())
     }
 
     /// Return the path to the log file as a string
     pub fn log_file_path(&self) -> String {
-        self.log_file.to_string_lossy().into()
+        self.logs.path().to_string_lossy().into()
     }
 
     /// Access the shared CPU task scheduler.
@@ -909,6 +1159,8 @@ impl<C: TorClientBehavior> AppState<C> {
         retry_count: Option<u32>,
     ) {
         let status = status.into();
+        let message = message.map(|m| self.redact(&m));
+        let detail = detail.map(|d| self.redact(&d));
         let timestamp = Utc::now();
         let (memory_bytes, circuit_count, _, _, _, _) = self.metrics().await;
         let latency = self.latency().await;
@@ -943,9 +1195,17 @@ impl<C: TorClientBehavior> AppState<C> {
         }
         let take = limit.unwrap_or(events.len()).min(events.len());
         let start = events.len().saturating_sub(take);
+        let level = self.redaction_level();
         events[start..]
             .iter()
-            .map(ConnectionEventSnapshot::from)
+            .map(|event| {
+                let mut snapshot = ConnectionEventSnapshot::from(event);
+                snapshot.message = snapshot
+                    .message
+                    .map(|m| self.redactor.redact(level, &m));
+                snapshot.detail = snapshot.detail.map(|d| self.redactor.redact(level, &d));
+                snapshot
+            })
             .collect()
     }
 
@@ -1063,8 +1323,9 @@ impl<C: TorClientBehavior> AppState<C> {
     }
 
     /// Start periodic collection of performance metrics and emit events
-    pub fn start_metrics_task(self: Arc<Self>, handle: AppHandle) {
+    pub fn start_metrics_task(self: Arc<Self>, sink: Option<Arc<dyn EventSink>>) {
         tokio::spawn(async move {
+            let events = Some(self.event_sink_for(sink.clone()));
             let interval_secs = self.metric_interval_secs;
             let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
             let mut sys = System::new();
@@ -1091,7 +1352,7 @@ impl<C: TorClientBehavior> AppState<C> {
                     let mgr = self.tor_manager.read().await.clone();
                     !mgr.is_connected().await
                 } {
-                    self.clone().start_auto_reconnect(handle.clone());
+                    self.clone().start_auto_reconnect(sink.clone());
                 }
 
                 let circ = crate::tor_manager::CircuitMetrics {
@@ -1179,8 +1440,18 @@ impl<C: TorClientBehavior> AppState<C> {
                     }
                 }
 
-                let frame_snapshot = self.renderer.metrics_snapshot();
-                let _ = handle.emit_all(
+                #[cfg(feature = "gui")]
+                let frame = {
+                    let snapshot = self.renderer.metrics_snapshot();
+                    serde_json::json!({
+                        "available": snapshot.available,
+                        "summary": snapshot.summary,
+                    })
+                };
+                #[cfg(not(feature = "gui"))]
+                let frame = serde_json::json!({ "available": false, "summary": null });
+                let _ = emit_event(
+                    events.as_ref(),
                     "metrics-update",
                     serde_json::json!({
                         "memory_bytes": mem,
@@ -1193,90 +1464,81 @@ impl<C: TorClientBehavior> AppState<C> {
                         "network_bytes": *self.network_throughput.lock().await,
                         "total_network_bytes": *self.network_total.lock().await,
                         "complete": circ.complete,
-                        "frame": {
-                            "available": frame_snapshot.available,
-                            "summary": frame_snapshot.summary,
-                        }
+                        "frame": frame,
                     }),
                 );
             }
         });
     }
 
+    /// Periodically emit the table of active SOCKS streams to the frontend
+    pub fn start_stream_updates(self: Arc<Self>, sink: Arc<dyn EventSink>) {
+        tokio::spawn(async move {
+            let sink = self.event_sink_for(Some(sink));
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(DEFAULT_STREAM_UPDATE_INTERVAL_SECS));
+            let mut was_empty = true;
+            loop {
+                interval.tick().await;
+                let streams = {
+                    let mgr = self.tor_manager.read().await.clone();
+                    mgr.streams().snapshot()
+                };
+                // Avoid flooding the frontend with empty tables while idle
+                if streams.is_empty() && was_empty {
+                    continue;
+                }
+                was_empty = streams.is_empty();
+                let _ = emit_event(Some(&sink), "streams-update", streams);
+            }
+        });
+    }
+
+    /// `sink` combined with [`Self::event_stream`], or the stream alone.
+    pub fn event_sink_for(&self, sink: Option<Arc<dyn EventSink>>) -> Arc<dyn EventSink> {
+        match sink {
+            Some(primary) => Arc::new(TeeSink {
+                primary,
+                stream: self.event_stream.clone(),
+            }),
+            None => self.event_stream.clone(),
+        }
+    }
+
+    /// Install the sink receiving frontend events
+    pub async fn register_event_sink(&self, sink: Arc<dyn EventSink>) {
+        *self.event_sink.lock().await = Some(sink);
+    }
+
     /// Store the application handle for emitting events
-    pub async fn register_handle(&self, handle: AppHandle) {
-        *self.app_handle.lock().await = Some(handle);
+    #[cfg(feature = "gui")]
+    pub async fn register_handle(&self, handle: tauri::AppHandle) {
+        self.register_event_sink(Arc::new(handle)).await;
     }
 
-    /// Build a new system tray menu based on the current state
-    async fn build_tray_menu(&self) -> SystemTrayMenu {
+    /// Snapshot of the values shown in the tray menu
+    pub async fn tray_status(&self) -> TrayStatus {
         let connected = {
             let mgr = self.tor_manager.read().await.clone();
             mgr.is_connected().await
         };
-        let status = if connected {
-            "Connected"
-        } else {
-            "Disconnected"
-        };
-        let memory_mb = *self.memory_usage.lock().await / 1024 / 1024;
-        let circuits = *self.circuit_count.lock().await;
-        let mem_label = if memory_mb > self.max_memory_mb {
-            format!("Memory: {} MB \u{26A0}\u{fe0f}", memory_mb)
-        } else {
-            format!("Memory: {} MB", memory_mb)
-        };
-        let circ_label = if circuits > self.max_circuits {
-            format!("Circuits: {} \u{26A0}\u{fe0f}", circuits)
-        } else {
-            format!("Circuits: {}", circuits)
-        };
-
-        let mut menu = SystemTrayMenu::new()
-            .add_item(CustomMenuItem::new("status", format!("Status: {}", status)).disabled())
-            .add_item(CustomMenuItem::new("memory", mem_label).disabled())
-            .add_item(CustomMenuItem::new("circuits", circ_label).disabled())
-            .add_item(CustomMenuItem::new("show", "Show"));
-
-        if connected {
-            menu = menu.add_item(CustomMenuItem::new("disconnect", "Disconnect"));
-        } else {
-            menu = menu.add_item(CustomMenuItem::new("connect", "Connect"));
+        TrayStatus {
+            connected,
+            memory_mb: *self.memory_usage.lock().await / 1024 / 1024,
+            circuits: *self.circuit_count.lock().await,
+            max_memory_mb: self.max_memory_mb,
+            max_circuits: self.max_circuits,
+            warning: self.tray_warning.lock().await.clone(),
+            profiles: self.profiles.names(),
+            active_profile: self.profiles.active(),
         }
-
-        menu = menu
-            .add_item(CustomMenuItem::new("reconnect", "Reconnect"))
-            .add_item(CustomMenuItem::new("show_dashboard", "Show Dashboard"))
-            .add_item(CustomMenuItem::new("show_logs", "Show Logs"))
-            .add_item(CustomMenuItem::new("open_logs_file", "Open Log File"))
-            .add_item(CustomMenuItem::new("settings", "Settings"))
-            .add_item(CustomMenuItem::new(
-                "open_settings_file",
-                "Open Settings File",
-            ))
-            .add_item(CustomMenuItem::new("quit", "Quit"));
-
-        if let Some(w) = self.tray_warning.lock().await.clone() {
-            let mut item =
-                CustomMenuItem::new("warning", format!("\u{26A0}\u{FE0F} {}", w)).disabled();
-            #[cfg(target_os = "macos")]
-            {
-                item = item.native_image(NativeImage::Caution);
-            }
-            menu = menu.add_item(item);
-        }
-
-        menu
     }
 
     /// Update the system tray menu with current status and warning if set
     pub async fn update_tray_menu(&self) {
-        if let Some(handle) = self.app_handle.lock().await.as_ref() {
-            let menu = self.build_tray_menu().await;
-            let tray = handle.tray_handle();
-            // Fully recreate the tray menu to avoid stale entries
-            let _ = tray.set_menu(SystemTrayMenu::new());
-            let _ = tray.set_menu(menu);
+        let sink = self.event_sink.lock().await.clone();
+        if let Some(sink) = sink {
+            sink.update_tray(&self.tray_status().await);
         }
     }
 
@@ -1288,53 +1550,16 @@ impl<C: TorClientBehavior> AppState<C> {
 
     /// Emit a security warning event to the frontend
     pub async fn emit_security_warning(&self, message: String) {
-        if let Some(handle) = self.app_handle.lock().await.as_ref() {
-            let _ = handle.emit_all("security-warning", message.clone());
-
-            #[cfg(target_os = "windows")]
-            {
-                use winrt_notification::{Duration, Toast};
-                let _ = Toast::new(Toast::POWERSHELL_APP_ID)
-                    .title("Torwell84 Warning")
-                    .text1(&message)
-                    .duration(Duration::Short)
-                    .show();
-            }
-
-            #[cfg(target_os = "linux")]
-            {
-                use std::process::Command;
-                let _ = Command::new("notify-send")
-                    .arg("Torwell84 Warning")
-                    .arg(&message)
-                    .output();
-            }
-
-            #[cfg(target_os = "macos")]
-            {
-                let _ = tauri::api::notification::Notification::new(
-                    &handle.config().tauri.bundle.identifier,
-                )
-                .title("Torwell84 Warning")
-                .body(&message)
-                .show();
-            }
-
-            #[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
-            {
-                let _ = tauri::api::notification::Notification::new(
-                    &handle.config().tauri.bundle.identifier,
-                )
-                .title("Torwell84 Warning")
-                .body(&message)
-                .show();
-            }
-        }
+        let sink = self.event_sink_for(self.event_sink.lock().await.clone());
+        let _ = emit_event(Some(&sink), "security-warning", message.clone());
+        sink.notify("Torwell84 Warning", &message);
     }
 
-    /// Attempt to reconnect if the Tor client is not connected
-    pub fn start_auto_reconnect(self: Arc<Self>, handle: AppHandle) {
+    /// Attempt to reconnect if the Tor client is not connected. Status
+    /// events go to `sink`, if given, and the event stream.
+    pub fn start_auto_reconnect(self: Arc<Self>, sink: Option<Arc<dyn EventSink>>) {
         tokio::spawn(async move {
+            let sink = Some(self.event_sink_for(sink));
             {
                 let mut flag = self.reconnect_in_progress.lock().await;
                 if *flag {
@@ -1343,7 +1568,8 @@ impl<C: TorClientBehavior> AppState<C> {
                 *flag = true;
             }
 
-            if let Err(e) = handle.emit_all(
+            if let Err(e) = emit_event(
+                sink.as_ref(),
                 "tor-status-update",
                 serde_json::json!({
                     "status": "CONNECTING",
@@ -1387,7 +1613,8 @@ impl<C: TorClientBehavior> AppState<C> {
                             | Error::ConfigError { step, source_message, .. } => (step, source_message),
                             _ => (String::new(), String::new()),
                         };
-                        let _ = handle.emit_all(
+                        let _ = emit_event(
+                            sink.as_ref(),
                             "tor-status-update",
                             serde_json::json!({
                                 "status": "RETRYING",
@@ -1400,7 +1627,8 @@ impl<C: TorClientBehavior> AppState<C> {
                         );
                     },
                     |progress, msg| {
-                        let _ = handle.emit_all(
+                        let _ = emit_event(
+                            sink.as_ref(),
                             "tor-status-update",
                             serde_json::json!({
                                 "status": "CONNECTING",
@@ -1414,7 +1642,8 @@ impl<C: TorClientBehavior> AppState<C> {
 
             match res {
                 Ok(_) => {
-                    if let Err(e) = handle.emit_all(
+                    if let Err(e) = emit_event(
+                        sink.as_ref(),
                         "tor-status-update",
                         serde_json::json!({
                             "status": "CONNECTED",
@@ -1439,7 +1668,8 @@ impl<C: TorClientBehavior> AppState<C> {
                         | Error::ConfigError { step, source_message, .. } => (step.clone(), source_message.clone()),
                         _ => (String::new(), String::new()),
                     };
-                    if let Err(em) = handle.emit_all(
+                    if let Err(em) = emit_event(
+                        sink.as_ref(),
                         "tor-status-update",
                         serde_json::json!({
                             "status": "ERROR",
@@ -1470,3 +1700,112 @@ impl<C: TorClientBehavior> AppState<C> {
         }
     }
 }
+
+impl AppState {
+    /// Re-read the configuration and restart the listeners that depend on it.
+    pub async fn reload(&self) {
+        if let Err(e) = self.reload_config().await {
+            log::error!("configuration reload failed: {}", e);
+        }
+        self.start_http_api().await;
+        self.start_metrics_exporter().await;
+    }
+
+    /// Poll the config files and [`Self::reload`] when one of them changes.
+    pub fn watch_config(&self) -> tokio::task::JoinHandle<()> {
+        let state = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CONFIG_POLL_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if state.config.files_changed() {
+                    log::info!("{} changed, reloading", state.config.path().display());
+                    state.reload().await;
+                }
+            }
+        })
+    }
+
+    /// Drain `tracing` events captured by [`trace`] into the log store.
+    /// Only the first call starts a task.
+    pub fn start_trace_capture(&self) -> Option<tokio::task::JoinHandle<()>> {
+        let mut receiver = trace::take_receiver()?;
+        let state = self.clone();
+        Some(tokio::spawn(async move {
+            while let Some(entry) = receiver.recv().await {
+                if let Err(e) = state.add_log_entry(entry).await {
+                    log::warn!("failed to store captured event: {}", e);
+                }
+            }
+        }))
+    }
+
+    /// Set the level captured for targets starting with `target`, or
+    /// remove the filter with `None`. Kept as a runtime override of
+    /// `trace_filters`.
+    pub fn set_trace_filter(&self, target: String, level: Option<String>) -> Result<TraceStatus> {
+        let mut filters = self.config.read(|c| c.trace_filters.clone());
+        match level {
+            Some(level) => {
+                trace::parse_level(&level)?;
+                filters.insert(target, level.to_ascii_lowercase());
+            }
+            None => {
+                filters.remove(&target);
+            }
+        }
+        self.config
+            .set_override("trace_filters", serde_json::to_value(&filters)?)?;
+        trace::set_filters(&self.config.read(|c| c.trace_filters.clone()))?;
+        Ok(trace::status())
+    }
+
+    /// Start, restart or stop the REST API to match the `api_port` setting.
+    /// Mobile builds always serve it, on [`http_bridge::DEFAULT_PORT`] unless
+    /// configured otherwise.
+    pub async fn start_http_api(&self) {
+        let port = self.config.read(|c| c.api_port);
+        #[cfg(feature = "mobile")]
+        let port = port.or(Some(http_bridge::DEFAULT_PORT));
+
+        let mut running = self.api_listener.lock().await;
+        if running.as_ref().map(|handle| handle.port()) == port {
+            return;
+        }
+        // Stop the old listener before binding the port again
+        running.take();
+        let Some(port) = port else { return };
+        match http_bridge::start_http_api(self.clone(), port).await {
+            Ok(handle) => {
+                log::info!(
+                    "REST API on 127.0.0.1:{}, token {}",
+                    handle.port(),
+                    http_bridge::api_token_path().display()
+                );
+                *running = Some(handle);
+            }
+            Err(e) => log::warn!("failed to start REST API: {}", e),
+        }
+    }
+
+    /// Start, restart or stop the Prometheus exporter to match the
+    /// `metrics_port` setting.
+    pub async fn start_metrics_exporter(&self) {
+        let port = self.config.read(|c| c.metrics_port);
+
+        let mut running = self.metrics_listener.lock().await;
+        if running.as_ref().map(|handle| handle.port()) == port {
+            return;
+        }
+        running.take();
+        let Some(port) = port else { return };
+        match telemetry::start_metrics_server(self.clone(), port).await {
+            Ok(handle) => {
+                log::info!("metrics exporter on 127.0.0.1:{}/metrics", handle.port());
+                *running = Some(handle);
+            }
+            Err(e) => log::warn!("failed to start metrics exporter: {}", e),
+        }
+    }
+}
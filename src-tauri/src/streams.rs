@@ -0,0 +1,397 @@
+use crate::telemetry;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Notify, Semaphore};
+
+/// Owner lookups running at once. Each one scans the fds of every process.
+const MAX_PID_LOOKUPS: usize = 4;
+
+static PID_LOOKUPS: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(MAX_PID_LOOKUPS));
+
+/// Information about a single proxied stream as shown in the stream table.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamInfo {
+    pub id: u64,
+    pub host: String,
+    pub port: u16,
+    pub isolation_group: Option<String>,
+    pub circuit: Option<String>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Total time the stream spent waiting on bandwidth limits
+    pub throttled_ms: u64,
+    pub started_at: String,
+    pub duration_secs: u64,
+    pub local_port: u16,
+    pub pid: Option<u32>,
+}
+
+/// Destination and origin of a stream about to be registered.
+#[derive(Clone, Debug)]
+pub struct StreamTarget {
+    pub host: String,
+    pub port: u16,
+    pub isolation_group: Option<String>,
+    pub peer: SocketAddr,
+    pub pid: Option<u32>,
+}
+
+struct StreamEntry {
+    id: u64,
+    host: String,
+    port: u16,
+    isolation_group: Option<String>,
+    circuit: Mutex<Option<String>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    throttled_ms: AtomicU64,
+    started_at: DateTime<Utc>,
+    local_port: u16,
+    pid: Mutex<Option<u32>>,
+    kill: Notify,
+}
+
+impl StreamEntry {
+    fn info(&self, now: DateTime<Utc>) -> StreamInfo {
+        StreamInfo {
+            id: self.id,
+            host: self.host.clone(),
+            port: self.port,
+            isolation_group: self.isolation_group.clone(),
+            circuit: self
+                .circuit
+                .lock()
+                .unwrap_or_else(|poison| poison.into_inner())
+                .clone(),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            throttled_ms: self.throttled_ms.load(Ordering::Relaxed),
+            started_at: self.started_at.to_rfc3339(),
+            duration_secs: (now - self.started_at).num_seconds().max(0) as u64,
+            local_port: self.local_port,
+            pid: *self.pid.lock().unwrap_or_else(|poison| poison.into_inner()),
+        }
+    }
+
+    fn matches_destination(&self, host: &str, port: Option<u16>) -> bool {
+        self.host.eq_ignore_ascii_case(host) && port.map(|p| p == self.port).unwrap_or(true)
+    }
+}
+
+#[derive(Default)]
+struct RegistryInner {
+    next_id: AtomicU64,
+    streams: Mutex<HashMap<u64, Arc<StreamEntry>>>,
+}
+
+/// Shared registry of all streams currently relayed through Tor.
+#[derive(Clone, Default)]
+pub struct StreamRegistry {
+    inner: Arc<RegistryInner>,
+}
+
+impl StreamRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn streams(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Arc<StreamEntry>>> {
+        self.inner
+            .streams
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+
+    /// Register a new stream. The stream is removed again when the returned
+    /// guard is dropped.
+    pub fn register(&self, target: StreamTarget) -> StreamGuard {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let entry = Arc::new(StreamEntry {
+            id,
+            host: target.host,
+            port: target.port,
+            isolation_group: target.isolation_group,
+            circuit: Mutex::new(None),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            throttled_ms: AtomicU64::new(0),
+            started_at: Utc::now(),
+            local_port: target.peer.port(),
+            pid: Mutex::new(target.pid),
+            kill: Notify::new(),
+        });
+        self.streams().insert(id, entry.clone());
+        telemetry::inc(&telemetry::counters().streams);
+        StreamGuard {
+            entry,
+            registry: self.clone(),
+        }
+    }
+
+    /// Return a snapshot of all active streams ordered by id.
+    pub fn snapshot(&self) -> Vec<StreamInfo> {
+        let now = Utc::now();
+        let mut list: Vec<StreamInfo> = self.streams().values().map(|e| e.info(now)).collect();
+        list.sort_by_key(|info| info.id);
+        list
+    }
+
+    /// Whether stream `id` is still open.
+    pub fn contains(&self, id: u64) -> bool {
+        self.streams().contains_key(&id)
+    }
+
+    /// Look up the process owning the client side of stream `id` in the
+    /// background and record it once found. The stream is relayed in the
+    /// meantime, so its pid stays empty for a moment.
+    pub fn resolve_pid(&self, id: u64, peer: SocketAddr, listener: SocketAddr) {
+        let registry = self.clone();
+        tokio::spawn(async move {
+            let Ok(_permit) = PID_LOOKUPS.acquire().await else {
+                return;
+            };
+            if !registry.contains(id) {
+                return;
+            }
+            let pid = tokio::task::spawn_blocking(move || lookup_local_pid(peer, listener))
+                .await
+                .ok()
+                .flatten();
+            if let Some(entry) = pid.and_then(|_| registry.streams().get(&id).cloned()) {
+                *entry
+                    .pid
+                    .lock()
+                    .unwrap_or_else(|poison| poison.into_inner()) = pid;
+            }
+        });
+    }
+
+    /// Number of active streams.
+    pub fn len(&self) -> usize {
+        self.streams().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Terminate a single stream. Returns `false` if no such stream exists.
+    pub fn close(&self, id: u64) -> bool {
+        match self.streams().get(&id) {
+            Some(entry) => {
+                entry.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Terminate all streams to `host`, optionally restricted to `port`.
+    /// Returns the number of streams that were signalled.
+    pub fn close_destination(&self, host: &str, port: Option<u16>) -> usize {
+        let streams = self.streams();
+        let mut closed = 0;
+        for entry in streams.values() {
+            if entry.matches_destination(host, port) {
+                entry.kill.notify_one();
+                closed += 1;
+            }
+        }
+        closed
+    }
+
+    /// Terminate every active stream.
+    pub fn close_all(&self) -> usize {
+        let streams = self.streams();
+        for entry in streams.values() {
+            entry.kill.notify_one();
+        }
+        streams.len()
+    }
+}
+
+/// Handle held by the relay task of a registered stream.
+pub struct StreamGuard {
+    entry: Arc<StreamEntry>,
+    registry: StreamRegistry,
+}
+
+impl StreamGuard {
+    pub fn id(&self) -> u64 {
+        self.entry.id
+    }
+
+    pub fn isolation_group(&self) -> Option<&str> {
+        self.entry.isolation_group.as_deref()
+    }
+
+    pub fn set_circuit(&self, circuit: Option<String>) {
+        *self
+            .entry
+            .circuit
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner()) = circuit;
+    }
+
+    /// Record bytes received from Tor and written to the local client.
+    pub fn add_bytes_in(&self, n: u64) {
+        self.entry.bytes_in.fetch_add(n, Ordering::Relaxed);
+        telemetry::counters()
+            .stream_bytes_in
+            .fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Record bytes read from the local client and sent into Tor.
+    pub fn add_bytes_out(&self, n: u64) {
+        self.entry.bytes_out.fetch_add(n, Ordering::Relaxed);
+        telemetry::counters()
+            .stream_bytes_out
+            .fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Record time spent waiting for bandwidth tokens.
+    pub fn add_throttled(&self, wait: std::time::Duration) {
+        self.entry
+            .throttled_ms
+            .fetch_add(wait.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Resolves once the stream has been asked to terminate.
+    pub async fn killed(&self) {
+        self.entry.kill.notified().await
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.registry.streams().remove(&self.entry.id);
+    }
+}
+
+/// Find the process owning the client side of a local TCP connection.
+///
+/// `peer` is the client's address as seen by the listener and `listener` is
+/// the listener's own address.
+#[cfg(target_os = "linux")]
+pub fn lookup_local_pid(peer: SocketAddr, listener: SocketAddr) -> Option<u32> {
+    let inode = ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .find_map(|table| find_socket_inode(&table, peer.port(), listener.port()))?;
+    let needle = format!("socket:[{inode}]");
+    for proc_entry in std::fs::read_dir("/proc").ok()?.flatten() {
+        let pid = match proc_entry.file_name().to_str().and_then(|s| s.parse::<u32>().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        let fds = match std::fs::read_dir(proc_entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds.flatten() {
+            if let Ok(link) = std::fs::read_link(fd.path()) {
+                if link.to_string_lossy() == needle {
+                    return Some(pid);
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lookup_local_pid(_peer: SocketAddr, _listener: SocketAddr) -> Option<u32> {
+    None
+}
+
+/// Parse a `/proc/net/tcp` style table and return the inode of the socket
+/// whose local port is `local_port` and remote port is `remote_port`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn find_socket_inode(table: &str, local_port: u16, remote_port: u16) -> Option<u64> {
+    fn port_of(field: &str) -> Option<u16> {
+        let (_, port) = field.rsplit_once(':')?;
+        u16::from_str_radix(port, 16).ok()
+    }
+
+    table.lines().skip(1).find_map(|line| {
+        let cols: Vec<&str> = line.split_whitespace().collect();
+        if cols.len() < 10 {
+            return None;
+        }
+        if port_of(cols[1])? == local_port && port_of(cols[2])? == remote_port {
+            cols[9].parse::<u64>().ok().filter(|inode| *inode != 0)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(host: &str, port: u16) -> StreamTarget {
+        StreamTarget {
+            host: host.into(),
+            port,
+            isolation_group: None,
+            peer: "127.0.0.1:40000".parse().unwrap(),
+            pid: None,
+        }
+    }
+
+    #[test]
+    fn guard_drop_unregisters_stream() {
+        let registry = StreamRegistry::new();
+        let guard = registry.register(target("example.com", 443));
+        guard.add_bytes_in(10);
+        guard.add_bytes_out(4);
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].bytes_in, 10);
+        assert_eq!(snapshot[0].bytes_out, 4);
+        assert_eq!(snapshot[0].local_port, 40000);
+        drop(guard);
+        assert!(registry.is_empty());
+    }
+
+    #[tokio::test]
+    async fn close_destination_signals_matching_streams() {
+        let registry = StreamRegistry::new();
+        let a = registry.register(target("example.com", 443));
+        let _b = registry.register(target("example.com", 80));
+        let _c = registry.register(target("other.org", 443));
+        assert_eq!(registry.close_destination("EXAMPLE.com", Some(443)), 1);
+        tokio::time::timeout(std::time::Duration::from_millis(100), a.killed())
+            .await
+            .expect("stream should be killed");
+        assert_eq!(registry.close_destination("example.com", None), 2);
+    }
+
+    #[test]
+    fn contains_tracks_open_streams() {
+        let registry = StreamRegistry::new();
+        let guard = registry.register(StreamTarget {
+            pid: Some(42),
+            ..target("example.com", 443)
+        });
+        let id = guard.id();
+        assert!(registry.contains(id));
+        assert_eq!(registry.snapshot()[0].pid, Some(42));
+        drop(guard);
+        assert!(!registry.contains(id));
+    }
+
+    #[test]
+    fn parses_proc_net_tcp() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n   0: 0100007F:9C40 0100007F:2382 01 00000000:00000000 00:00000000 00000000  1000        0 123456 1 0000000000000000 20 4 30 10 -1\n";
+        assert_eq!(find_socket_inode(table, 40000, 9090), Some(123456));
+        assert_eq!(find_socket_inode(table, 40001, 9090), None);
+    }
+}
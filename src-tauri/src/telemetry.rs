@@ -0,0 +1,368 @@
+//! Process-wide counters and their Prometheus text exposition.
+
+use crate::error::ConnectionStep;
+use crate::socks::ListenerHandle;
+use crate::state::AppState;
+use crate::tor_manager::TorClientBehavior;
+use axum::{
+    extract::State, http::header::CONTENT_TYPE, response::IntoResponse, routing::get, Router,
+};
+use once_cell::sync::Lazy;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Content type of the Prometheus text format
+pub const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+const STEPS: [ConnectionStep; 4] = [
+    ConnectionStep::BuildConfig,
+    ConnectionStep::Bootstrap,
+    ConnectionStep::Timeout,
+    ConnectionStep::RetriesExceeded,
+];
+
+/// Counters that outlive `TorManager` rebuilds on config reload.
+#[derive(Default)]
+pub struct Counters {
+    pub connects: AtomicU64,
+    pub connect_retries: AtomicU64,
+    connect_errors: [AtomicU64; 4],
+    pub circuit_builds: AtomicU64,
+    pub streams: AtomicU64,
+    pub stream_bytes_in: AtomicU64,
+    pub stream_bytes_out: AtomicU64,
+    pub cert_update_failures: AtomicU64,
+}
+
+fn step_index(step: &ConnectionStep) -> usize {
+    match step {
+        ConnectionStep::BuildConfig => 0,
+        ConnectionStep::Bootstrap => 1,
+        ConnectionStep::Timeout => 2,
+        ConnectionStep::RetriesExceeded => 3,
+    }
+}
+
+impl Counters {
+    /// Count a failed connection attempt at `step`.
+    pub fn record_error(&self, step: &ConnectionStep) {
+        self.connect_errors[step_index(step)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Failed connection attempts at `step`.
+    pub fn errors(&self, step: &ConnectionStep) -> u64 {
+        self.connect_errors[step_index(step)].load(Ordering::Relaxed)
+    }
+}
+
+static COUNTERS: Lazy<Counters> = Lazy::new(Counters::default);
+
+pub fn counters() -> &'static Counters {
+    &COUNTERS
+}
+
+/// Increment `counter` by one.
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Builder for the text exposition format.
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {name} {help}");
+        let _ = writeln!(self.0, "# TYPE {name} {kind}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{k}=\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {value}");
+    }
+
+    fn single(&mut self, name: &str, kind: &str, help: &str, value: f64) {
+        self.family(name, kind, help);
+        self.sample(name, &[], value);
+    }
+
+    /// Quantiles of a summary, given as `(p50, p95, p99)`.
+    fn quantiles(&mut self, name: &str, help: &str, values: (f64, f64, f64), count: Option<u64>) {
+        self.family(name, "summary", help);
+        for (q, v) in [("0.5", values.0), ("0.95", values.1), ("0.99", values.2)] {
+            self.sample(name, &[("quantile", q)], v);
+        }
+        if let Some(count) = count {
+            self.sample(&format!("{name}_count"), &[], count as f64);
+        }
+    }
+}
+
+/// Render all metrics of `state` in the Prometheus text format.
+pub async fn render<C: TorClientBehavior>(state: &AppState<C>) -> String {
+    let c = counters();
+    let mut out = Exposition::default();
+    let load = |v: &AtomicU64| v.load(Ordering::Relaxed) as f64;
+
+    let connected = {
+        let mgr = state.tor_manager.read().await.clone();
+        mgr.is_connected().await
+    };
+    out.single(
+        "torwell_connected",
+        "gauge",
+        "Whether the Tor client is connected.",
+        connected as u8 as f64,
+    );
+    out.single(
+        "torwell_connects_total",
+        "counter",
+        "Successful Tor connections.",
+        load(&c.connects),
+    );
+    out.single(
+        "torwell_connect_retries_total",
+        "counter",
+        "Connection attempts retried after a failure.",
+        load(&c.connect_retries),
+    );
+    out.family(
+        "torwell_connect_errors_total",
+        "counter",
+        "Failed connection attempts by connection step.",
+    );
+    for step in &STEPS {
+        out.sample(
+            "torwell_connect_errors_total",
+            &[("step", &step.to_string())],
+            c.errors(step) as f64,
+        );
+    }
+    out.single(
+        "torwell_connection_retry_attempts",
+        "gauge",
+        "Retries of the current connection attempt.",
+        state.retry_counter_value().await as f64,
+    );
+    out.single(
+        "torwell_circuit_builds_total",
+        "counter",
+        "Circuits built on request.",
+        load(&c.circuit_builds),
+    );
+    out.single(
+        "torwell_socks_streams_total",
+        "counter",
+        "Streams relayed through Tor.",
+        load(&c.streams),
+    );
+    out.family(
+        "torwell_socks_stream_bytes_total",
+        "counter",
+        "Bytes relayed through Tor streams by direction.",
+    );
+    out.sample(
+        "torwell_socks_stream_bytes_total",
+        &[("direction", "in")],
+        load(&c.stream_bytes_in),
+    );
+    out.sample(
+        "torwell_socks_stream_bytes_total",
+        &[("direction", "out")],
+        load(&c.stream_bytes_out),
+    );
+    out.single(
+        "torwell_certificate_update_failures_total",
+        "counter",
+        "Failed certificate updates.",
+        load(&c.cert_update_failures),
+    );
+    out.single(
+        "torwell_certificate_update_failures",
+        "gauge",
+        "Consecutive failed certificate updates.",
+        *state.http_client.update_failures.lock().await as f64,
+    );
+
+    out.single(
+        "torwell_memory_bytes",
+        "gauge",
+        "Resident memory of the process.",
+        *state.memory_usage.lock().await as f64,
+    );
+    out.single(
+        "torwell_cpu_usage_ratio",
+        "gauge",
+        "CPU usage of the process.",
+        *state.cpu_usage.lock().await as f64 / 100.0,
+    );
+    out.single(
+        "torwell_circuits",
+        "gauge",
+        "Open circuits.",
+        *state.circuit_count.lock().await as f64,
+    );
+    out.single(
+        "torwell_oldest_circuit_age_seconds",
+        "gauge",
+        "Age of the oldest open circuit.",
+        *state.oldest_circuit_age.lock().await as f64,
+    );
+    out.single(
+        "torwell_latency_seconds",
+        "gauge",
+        "Last measured network latency.",
+        *state.latency_ms.lock().await as f64 / 1000.0,
+    );
+    out.single(
+        "torwell_network_bytes_per_second",
+        "gauge",
+        "Network throughput of the host.",
+        *state.network_throughput.lock().await as f64,
+    );
+    out.single(
+        "torwell_network_bytes_total",
+        "counter",
+        "Network traffic of the host since start.",
+        *state.network_total.lock().await as f64,
+    );
+
+    let scheduler = state.scheduler_snapshot();
+    let us = |v: u64| v as f64 / 1_000_000.0;
+    out.quantiles(
+        "torwell_scheduler_task_latency_seconds",
+        "Latency of tasks run by the scheduler.",
+        (
+            us(scheduler.p50_us),
+            us(scheduler.p95_us),
+            us(scheduler.p99_us),
+        ),
+        Some(scheduler.total_tasks),
+    );
+    out.single(
+        "torwell_scheduler_queue_depth",
+        "gauge",
+        "Tasks waiting in the scheduler queue.",
+        scheduler.queue_depth as f64,
+    );
+
+    #[cfg(feature = "gui")]
+    {
+        let summary = state.renderer_service().metrics_snapshot().summary;
+        let ns = |p: &crate::renderer::Percentiles| {
+            (p.p50 as f64 / 1e9, p.p95 as f64 / 1e9, p.p99 as f64 / 1e9)
+        };
+        out.single(
+            "torwell_renderer_frames_total",
+            "counter",
+            "Frames rendered.",
+            summary.frames_total as f64,
+        );
+        out.single(
+            "torwell_renderer_dropped_frames_total",
+            "counter",
+            "Frames dropped by the renderer.",
+            summary.dropped_frames_total as f64,
+        );
+        out.quantiles(
+            "torwell_renderer_frame_interval_seconds",
+            "Time between rendered frames.",
+            ns(&summary.frame_interval_ns),
+            None,
+        );
+        out.quantiles(
+            "torwell_renderer_cpu_encode_seconds",
+            "CPU time to encode a frame.",
+            ns(&summary.cpu_encode_ns),
+            None,
+        );
+        out.quantiles(
+            "torwell_renderer_gpu_time_seconds",
+            "GPU time per frame.",
+            ns(&summary.gpu_time_ns),
+            None,
+        );
+        out.quantiles(
+            "torwell_renderer_queue_wait_seconds",
+            "Time frames waited in the queue.",
+            ns(&summary.queue_wait_ns),
+            None,
+        );
+    }
+
+    out.0
+}
+
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    ([(CONTENT_TYPE, CONTENT_TYPE_TEXT)], render(&state).await)
+}
+
+/// Serve `/metrics` on `127.0.0.1:port`. Port `0` picks a free port.
+pub async fn start_metrics_server(state: AppState, port: u16) -> anyhow::Result<ListenerHandle> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let port = listener.local_addr()?.port();
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(state);
+    let task = tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            log::error!("metrics exporter failed: {e}");
+        }
+    });
+    Ok(ListenerHandle::new(port, vec![task.abort_handle()]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_samples_with_labels() {
+        let mut out = Exposition::default();
+        out.family("torwell_test_total", "counter", "Test counter.");
+        out.sample("torwell_test_total", &[("step", "boot\"strap")], 2.0);
+        out.single("torwell_ratio", "gauge", "Test gauge.", 0.5);
+        assert_eq!(
+            out.0,
+            "# HELP torwell_test_total Test counter.\n\
+             # TYPE torwell_test_total counter\n\
+             torwell_test_total{step=\"boot\\\"strap\"} 2\n\
+             # HELP torwell_ratio Test gauge.\n\
+             # TYPE torwell_ratio gauge\n\
+             torwell_ratio 0.5\n"
+        );
+    }
+
+    #[test]
+    fn counts_errors_by_step() {
+        let counters = Counters::default();
+        counters.record_error(&ConnectionStep::Bootstrap);
+        counters.record_error(&ConnectionStep::Bootstrap);
+        assert_eq!(counters.errors(&ConnectionStep::Bootstrap), 2);
+        assert_eq!(counters.errors(&ConnectionStep::BuildConfig), 0);
+    }
+
+    #[test]
+    fn summary_includes_quantiles_and_count() {
+        let mut out = Exposition::default();
+        out.quantiles(
+            "torwell_latency_seconds",
+            "Latency.",
+            (0.1, 0.2, 0.3),
+            Some(7),
+        );
+        assert!(out
+            .0
+            .contains("torwell_latency_seconds{quantile=\"0.95\"} 0.2\n"));
+        assert!(out.0.contains("torwell_latency_seconds_count 7\n"));
+    }
+}
@@ -1,7 +1,17 @@
-use crate::commands::RelayInfo;
 use crate::error::{ConnectionStep, Error, Result};
+use crate::forward::{ForwardInfo, ForwardManager, ForwardSpec};
+use crate::listeners::{ListenerInfo, ListenerManager, ListenerSpec};
+use crate::paths::paths;
+use crate::policy::DestinationPolicy;
+use crate::routing::{ExitRouting, ExitRoutingConfig};
+use crate::session::SessionManager;
+use crate::settings::RoutingSettings;
+use crate::shaping::BandwidthShaper;
+use crate::socks::{ListenerHandle, SocksContext};
+use crate::streams::StreamRegistry;
+use crate::telemetry;
 use arti_client::config::{
-    TorClientConfigBuilder,
+    CfgPath, TorClientConfigBuilder,
 };
 use arti_client::{StreamPrefs, TorClient, TorClientConfig};
 use async_trait::async_trait;
@@ -54,6 +64,13 @@ fn log_and_convert_error(step: ConnectionStep, err: impl ToString) -> Error {
         backtrace: format!("{:?}", std::backtrace::Backtrace::capture()),
     }
 }
+
+/// [`log_and_convert_error`] for a failed connection attempt, counted in
+/// the exported metrics.
+fn attempt_error(step: ConnectionStep, err: impl ToString) -> Error {
+    telemetry::counters().record_error(&step);
+    log_and_convert_error(step, err)
+}
 use tor_rtcompat::PreferredRuntime;
 
 const INITIAL_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
@@ -94,6 +111,21 @@ pub struct CircuitMetrics {
     pub complete: bool,
 }
 
+/// Last bootstrap progress reported while connecting.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct BootstrapStatus {
+    pub progress: u8,
+    pub summary: String,
+}
+
+/// Information about a single relay in the active circuit.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RelayInfo {
+    pub nickname: String,
+    pub ip_address: String,
+    pub country: String,
+}
+
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
 pub struct BridgePreset {
     pub name: String,
@@ -224,6 +256,51 @@ pub trait TorClientBehavior: Send + Sync + Sized + 'static {
     fn retire_all_circs(&self);
     fn build_new_circuit(&self) -> impl std::future::Future<Output = std::result::Result<(), String>> + Send;
     async fn launch_socks(&self, port: u16) -> std::result::Result<u16, String>;
+    /// Launch a SOCKS listener that shares state such as the stream registry
+    /// with the manager and stops when the handle is dropped. Defaults to
+    /// [`TorClientBehavior::launch_socks`].
+    async fn launch_socks_with(
+        &self,
+        port: u16,
+        _ctx: SocksContext,
+    ) -> std::result::Result<ListenerHandle, String> {
+        let port = self.launch_socks(port).await?;
+        Ok(ListenerHandle::new(port, Vec::new()))
+    }
+    /// Launch a local DNS listener resolving through Tor. Clients that cannot
+    /// resolve names report the listener as unsupported.
+    async fn launch_dns(
+        &self,
+        _port: u16,
+        _max_ttl: std::time::Duration,
+    ) -> std::result::Result<ListenerHandle, String> {
+        Err("DNS listener not supported".into())
+    }
+    /// Launch a local TCP forward relaying through Tor.
+    async fn launch_forward(
+        &self,
+        _spec: ForwardSpec,
+        _ctx: SocksContext,
+    ) -> std::result::Result<ListenerHandle, String> {
+        Err("port forwarding not supported".into())
+    }
+    /// Launch an additional SOCKS or HTTP CONNECT listener with its own
+    /// stream profile.
+    async fn launch_listener(
+        &self,
+        _spec: ListenerSpec,
+        _ctx: SocksContext,
+    ) -> std::result::Result<ListenerHandle, String> {
+        Err("additional listeners not supported".into())
+    }
+    /// Launch arti's RPC listener for this client. Every RPC connection has
+    /// to present a token of a session in `sessions`.
+    async fn launch_rpc(
+        &self,
+        _sessions: Arc<SessionManager>,
+    ) -> std::result::Result<ListenerHandle, String> {
+        Err("RPC not supported".into())
+    }
 }
 
 #[async_trait]
@@ -291,14 +368,78 @@ impl TorClientBehavior for TorClient<PreferredRuntime> {
     }
 
     async fn launch_socks(&self, port: u16) -> std::result::Result<u16, String> {
-        crate::socks::start_socks_proxy(self.clone(), port)
+        self.launch_socks_with(port, SocksContext::default())
+            .await
+            .map(ListenerHandle::detach)
+    }
+
+    async fn launch_socks_with(
+        &self,
+        port: u16,
+        ctx: SocksContext,
+    ) -> std::result::Result<ListenerHandle, String> {
+        crate::socks::start_socks_proxy(self.clone(), port, ctx)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn launch_dns(
+        &self,
+        port: u16,
+        max_ttl: std::time::Duration,
+    ) -> std::result::Result<ListenerHandle, String> {
+        crate::dns::start_dns_server(self.clone(), port, max_ttl)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn launch_forward(
+        &self,
+        spec: ForwardSpec,
+        ctx: SocksContext,
+    ) -> std::result::Result<ListenerHandle, String> {
+        crate::forward::start_forward(self.clone(), spec, ctx)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn launch_listener(
+        &self,
+        spec: ListenerSpec,
+        ctx: SocksContext,
+    ) -> std::result::Result<ListenerHandle, String> {
+        crate::socks::start_listener(self.clone(), spec, ctx)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    #[cfg(all(feature = "rpc", unix))]
+    async fn launch_rpc(
+        &self,
+        sessions: Arc<SessionManager>,
+    ) -> std::result::Result<ListenerHandle, String> {
+        crate::rpc::start_rpc_server(self.clone(), sessions)
             .await
             .map_err(|e| e.to_string())
     }
 }
 pub struct TorManager<C = TorClient<PreferredRuntime>> {
     client: Arc<Mutex<Option<C>>>,
-    socks_port: Arc<Mutex<Option<u16>>>,
+    /// Main SOCKS listener of the connected client
+    socks_listener: Arc<Mutex<Option<ListenerHandle>>>,
+    bootstrap: Arc<std::sync::Mutex<BootstrapStatus>>,
+    /// Port requested for the main SOCKS listener, `None` picks a free one
+    requested_socks_port: Option<u16>,
+    socks_ctx: SocksContext,
+    dns_port: Arc<Mutex<Option<u16>>>,
+    dns_listener: Arc<Mutex<Option<ListenerHandle>>>,
+    /// Longest time the DNS listener caches an answer
+    dns_max_ttl: std::time::Duration,
+    /// Session store authorizing RPC connections, `None` disables RPC
+    rpc_sessions: Option<Arc<SessionManager>>,
+    rpc_listener: Arc<Mutex<Option<ListenerHandle>>>,
+    forwards: ForwardManager,
+    listeners: ListenerManager,
     isolation_tokens: Arc<Mutex<HashMap<String, (IsolationToken, std::time::Instant)>>>,
     exit_country: Arc<Mutex<Option<CountryCode>>>,
     entry_country: Arc<Mutex<Option<CountryCode>>>,
@@ -315,7 +456,17 @@ impl<C> Clone for TorManager<C> {
     fn clone(&self) -> Self {
         Self {
             client: Arc::clone(&self.client),
-            socks_port: Arc::clone(&self.socks_port),
+            socks_listener: Arc::clone(&self.socks_listener),
+            bootstrap: Arc::clone(&self.bootstrap),
+            requested_socks_port: self.requested_socks_port,
+            socks_ctx: self.socks_ctx.clone(),
+            dns_port: Arc::clone(&self.dns_port),
+            dns_listener: Arc::clone(&self.dns_listener),
+            dns_max_ttl: self.dns_max_ttl,
+            rpc_sessions: self.rpc_sessions.clone(),
+            rpc_listener: Arc::clone(&self.rpc_listener),
+            forwards: self.forwards.clone(),
+            listeners: self.listeners.clone(),
             isolation_tokens: Arc::clone(&self.isolation_tokens),
             exit_country: Arc::clone(&self.exit_country),
             entry_country: Arc::clone(&self.entry_country),
@@ -399,7 +550,17 @@ impl<C: TorClientBehavior> TorManager<C> {
 
         let manager = Self {
             client: Arc::new(Mutex::new(None)),
-            socks_port: Arc::new(Mutex::new(None)),
+            socks_listener: Arc::new(Mutex::new(None)),
+            bootstrap: Arc::new(std::sync::Mutex::new(BootstrapStatus::default())),
+            requested_socks_port: None,
+            socks_ctx: SocksContext::default(),
+            dns_port: Arc::new(Mutex::new(None)),
+            dns_listener: Arc::new(Mutex::new(None)),
+            dns_max_ttl: std::time::Duration::from_secs(crate::dns::DEFAULT_DNS_MAX_TTL_SECS),
+            rpc_sessions: None,
+            rpc_listener: Arc::new(Mutex::new(None)),
+            forwards: ForwardManager::default(),
+            listeners: ListenerManager::default(),
             isolation_tokens: Arc::new(Mutex::new(HashMap::new())),
             exit_country: Arc::new(Mutex::new(None)),
             entry_country: Arc::new(Mutex::new(None)),
@@ -493,6 +654,7 @@ impl<C: TorClientBehavior> TorManager<C> {
             }
         }
 
+        let mut custom_storage = false;
         let mut builder = if torrc.trim().is_empty() {
             TorClientConfigBuilder::default()
         } else {
@@ -501,6 +663,7 @@ impl<C: TorClientBehavior> TorManager<C> {
                 source_message: e.to_string(),
                 backtrace: format!("{:?}", std::backtrace::Backtrace::capture()),
             })?;
+            custom_storage = val.get("storage").is_some();
             let cfg: TorClientConfigBuilder = val.try_into().map_err(|e| Error::ConfigError {
                 step: "torrc_convert".into(),
                 source_message: e.to_string(),
@@ -509,6 +672,15 @@ impl<C: TorClientBehavior> TorManager<C> {
             cfg
         };
 
+        // Keep the client state with the other application data unless the
+        // torrc snippet configures its own storage
+        if !custom_storage {
+            builder
+                .storage()
+                .state_dir(CfgPath::new_literal(paths().arti_state_dir()))
+                .cache_dir(CfgPath::new_literal(paths().arti_cache_dir()));
+        }
+
         // Apply optimizations
         builder.address_filter().allow_local_addrs(true);
 
@@ -728,23 +900,52 @@ impl<C: TorClientBehavior> TorManager<C> {
             progress(100, "connected".into());
             return Ok(());
         }
-        progress(0, "starting".into());
+        let bootstrap = self.bootstrap.clone();
+        let mut tracked = |pct: u8, msg: String| {
+            *bootstrap.lock().unwrap_or_else(|p| p.into_inner()) = BootstrapStatus {
+                progress: pct,
+                summary: msg.clone(),
+            };
+            progress(pct, msg);
+        };
+        tracked(0, "starting".into());
         let config = self
             .build_config()
             .await
-            .map_err(|e| log_and_convert_error(ConnectionStep::BuildConfig, e))?;
-        let tor_client = C::create_bootstrapped_with_progress(config, progress)
+            .map_err(|e| attempt_error(ConnectionStep::BuildConfig, e))?;
+        let tor_client = C::create_bootstrapped_with_progress(config, &mut tracked)
             .await
-            .map_err(|e| log_and_convert_error(ConnectionStep::Bootstrap, e))?;
+            .map_err(|e| attempt_error(ConnectionStep::Bootstrap, e))?;
         // Start SOCKS listener
-        let port = tor_client
-            .launch_socks(0)
+        let socks = tor_client
+            .launch_socks_with(self.requested_socks_port.unwrap_or(0), self.socks_ctx.clone())
             .await
-            .map_err(|e| log_and_convert_error(ConnectionStep::Bootstrap, format!("failed to launch socks: {}", e)))?;
+            .map_err(|e| attempt_error(ConnectionStep::Bootstrap, format!("failed to launch socks: {}", e)))?;
+
+        if let Some(dns_port) = *self.dns_port.lock().await {
+            // A busy DNS port should not prevent the connection itself
+            match tor_client.launch_dns(dns_port, self.dns_max_ttl).await {
+                Ok(handle) => {
+                    log::info!("DNS listener on 127.0.0.1:{}", handle.port());
+                    *self.dns_listener.lock().await = Some(handle);
+                }
+                Err(e) => log::warn!("failed to launch DNS listener: {}", e),
+            }
+        }
+        #[cfg(feature = "rpc")]
+        if let Some(sessions) = &self.rpc_sessions {
+            match tor_client.launch_rpc(sessions.clone()).await {
+                Ok(handle) => *self.rpc_listener.lock().await = Some(handle),
+                Err(e) => log::warn!("failed to launch RPC listener: {}", e),
+            }
+        }
+        self.start_forwards(&tor_client).await;
+        self.start_listeners(&tor_client).await;
 
         *self.client.lock().await = Some(tor_client);
-        *self.socks_port.lock().await = Some(port);
+        *self.socks_listener.lock().await = Some(socks);
         self.spawn_circuit_prewarm();
+        telemetry::inc(&telemetry::counters().connects);
         Ok(())
     }
 
@@ -816,6 +1017,7 @@ impl<C: TorClientBehavior> TorManager<C> {
                                 attempt, last_error
                             )
                         };
+                        telemetry::counters().record_error(&ConnectionStep::RetriesExceeded);
                         return Err(log_and_convert_error(last_step, msg));
                     }
                     if start.elapsed() + delay > max_total_time {
@@ -825,8 +1027,10 @@ impl<C: TorClientBehavior> TorManager<C> {
                         } else {
                             format!("timeout after {:?}: {}", max_total_time, last_error)
                         };
+                        telemetry::counters().record_error(&ConnectionStep::Timeout);
                         return Err(log_and_convert_error(last_step, msg));
                     }
+                    telemetry::inc(&telemetry::counters().connect_retries);
                     tokio::time::sleep(delay).await;
                     delay = std::cmp::min(delay * 2, MAX_BACKOFF);
                 }
@@ -840,10 +1044,251 @@ impl<C: TorClientBehavior> TorManager<C> {
             log::error!("disconnect: not connected");
             return Err(Error::NotConnected);
         }
-        // Client is dropped here, which handles shutdown.
+        // Client is dropped here, which handles shutdown. The listeners hold
+        // clones of it and are stopped as well, freeing their ports.
+        self.socks_listener.lock().await.take();
+        self.socks_ctx.streams.close_all();
+        self.dns_listener.lock().await.take();
+        self.rpc_listener.lock().await.take();
+        self.forwards.stop_all();
+        self.listeners.stop_all();
+        *self.bootstrap.lock().unwrap_or_else(|p| p.into_inner()) = BootstrapStatus::default();
+        Ok(())
+    }
+
+    /// Progress of the current or last bootstrap.
+    pub fn bootstrap_status(&self) -> BootstrapStatus {
+        self.bootstrap
+            .lock()
+            .unwrap_or_else(|p| p.into_inner())
+            .clone()
+    }
+
+    async fn start_forwards(&self, client: &C) {
+        for spec in self.forwards.specs() {
+            let port = spec.listen_port;
+            match client.launch_forward(spec, self.socks_ctx.clone()).await {
+                Ok(handle) => self.forwards.set_running(handle),
+                Err(e) => log::warn!("failed to start forward on port {}: {}", port, e),
+            }
+        }
+    }
+
+    /// Serve arti's RPC interface while connected, authorizing connections
+    /// through `sessions`. Only effective with the `rpc` feature.
+    pub fn with_rpc_sessions(mut self, sessions: Arc<SessionManager>) -> Self {
+        self.rpc_sessions = Some(sessions);
+        self
+    }
+
+    /// Set the forwards started on every connect.
+    pub fn with_forwards(mut self, specs: Vec<ForwardSpec>) -> Self {
+        self.forwards = ForwardManager::with_specs(specs);
+        self
+    }
+
+    pub fn list_forwards(&self) -> Vec<ForwardInfo> {
+        self.forwards.list()
+    }
+
+    pub fn forward_specs(&self) -> Vec<ForwardSpec> {
+        self.forwards.specs()
+    }
+
+    /// Add a forward and start it right away when connected. A forward whose
+    /// listener cannot be started is not kept.
+    pub async fn add_forward(&self, spec: ForwardSpec) -> Result<()> {
+        self.forwards.insert(spec.clone())?;
+        let guard = self.client.lock().await;
+        if let Some(client) = guard.as_ref() {
+            let port = spec.listen_port;
+            match client.launch_forward(spec, self.socks_ctx.clone()).await {
+                Ok(handle) => self.forwards.set_running(handle),
+                Err(e) => {
+                    self.forwards.remove(port);
+                    log::error!("add_forward: failed to listen on {}: {}", port, e);
+                    return Err(Error::Io(e));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn start_listeners(&self, client: &C) {
+        for spec in self.listeners.specs() {
+            let port = spec.port;
+            match client.launch_listener(spec, self.socks_ctx.clone()).await {
+                Ok(handle) => self.listeners.set_running(handle),
+                Err(e) => log::warn!("failed to start listener on port {}: {}", port, e),
+            }
+        }
+    }
+
+    /// Set the additional listeners started on every connect.
+    pub fn with_listeners(mut self, specs: Vec<ListenerSpec>) -> Self {
+        self.listeners = ListenerManager::with_specs(specs);
+        self
+    }
+
+    pub fn list_listeners(&self) -> Vec<ListenerInfo> {
+        self.listeners.list()
+    }
+
+    pub fn listener_specs(&self) -> Vec<ListenerSpec> {
+        self.listeners.specs()
+    }
+
+    /// Add or replace a listener and (re)start it when connected. On failure
+    /// the previous configuration of the port is restored.
+    pub async fn set_listener(&self, spec: ListenerSpec) -> Result<()> {
+        let previous = self.listeners.upsert(spec.clone())?;
+        let guard = self.client.lock().await;
+        if let Some(client) = guard.as_ref() {
+            let port = spec.port;
+            match client.launch_listener(spec, self.socks_ctx.clone()).await {
+                Ok(handle) => self.listeners.set_running(handle),
+                Err(e) => {
+                    self.listeners.remove(port);
+                    if let Some(previous) = previous {
+                        let _ = self.listeners.upsert(previous.clone());
+                        if let Ok(handle) =
+                            client.launch_listener(previous, self.socks_ctx.clone()).await
+                        {
+                            self.listeners.set_running(handle);
+                        }
+                    }
+                    log::error!("set_listener: failed to listen on {}: {}", port, e);
+                    return Err(Error::Io(e));
+                }
+            }
+        }
         Ok(())
     }
 
+    /// Remove a listener. Returns `false` if no listener uses `port`.
+    pub fn remove_listener(&self, port: u16) -> bool {
+        self.listeners.remove(port).is_some()
+    }
+
+    /// Remove a forward, closing its listener. Returns `false` if no forward
+    /// uses `listen_port`.
+    pub fn remove_forward(&self, listen_port: u16) -> bool {
+        self.forwards.remove(listen_port).is_some()
+    }
+
+    /// Bind the main SOCKS listener to a fixed port instead of a random one.
+    pub fn with_socks_port(mut self, port: Option<u16>) -> Self {
+        self.requested_socks_port = port.filter(|p| *p != 0);
+        self
+    }
+
+    /// Port the main SOCKS listener is asked to bind, `None` for a random one.
+    pub fn requested_socks_port(&self) -> Option<u16> {
+        self.requested_socks_port
+    }
+
+    /// Set the port of the local DNS listener. Takes effect on the next
+    /// connect; `None` disables the listener.
+    pub fn with_dns_port(mut self, port: Option<u16>) -> Self {
+        self.dns_port = Arc::new(Mutex::new(port));
+        self
+    }
+
+    /// Cap the time the DNS listener caches an answer. Takes effect on the
+    /// next connect.
+    pub fn with_dns_max_ttl(mut self, secs: u64) -> Self {
+        self.dns_max_ttl = std::time::Duration::from_secs(secs);
+        self
+    }
+
+    /// Restore persisted country preferences, bridges and torrc. Invalid
+    /// country codes are logged and ignored.
+    pub fn with_routing(mut self, routing: &RoutingSettings) -> Self {
+        let parse = |country: &Option<String>| {
+            let cc = country.as_ref()?;
+            cc.parse::<CountryCode>()
+                .map_err(|e| log::warn!("ignoring persisted country {cc}: {e}"))
+                .ok()
+        };
+        self.exit_country = Arc::new(Mutex::new(parse(&routing.exit_country)));
+        self.entry_country = Arc::new(Mutex::new(parse(&routing.entry_country)));
+        self.middle_country = Arc::new(Mutex::new(parse(&routing.middle_country)));
+        self.bridges = Arc::new(Mutex::new(routing.bridges.clone()));
+        self.torrc_config = Arc::new(Mutex::new(routing.torrc.clone()));
+        self
+    }
+
+    /// Current country preferences, bridges and torrc.
+    pub async fn routing_settings(&self) -> RoutingSettings {
+        RoutingSettings {
+            exit_country: self.get_exit_country().await,
+            entry_country: self.get_entry_country().await,
+            middle_country: self.get_middle_country().await,
+            bridges: self.get_bridges().await,
+            torrc: self.torrc_config.lock().await.clone(),
+        }
+    }
+
+    pub async fn set_dns_port(&self, port: Option<u16>) {
+        *self.dns_port.lock().await = port;
+    }
+
+    pub async fn dns_port(&self) -> Option<u16> {
+        *self.dns_port.lock().await
+    }
+
+    /// Port of the running DNS listener, if any.
+    pub async fn dns_listener_port(&self) -> Option<u16> {
+        self.dns_listener.lock().await.as_ref().map(|h| h.port())
+    }
+
+    /// Socket of the running RPC listener.
+    pub async fn rpc_socket(&self) -> Option<std::path::PathBuf> {
+        let listener = self.rpc_listener.lock().await;
+        listener.as_ref().and_then(|h| h.socket()).map(|p| p.to_path_buf())
+    }
+
+    /// Registry of streams relayed through the SOCKS listener.
+    pub fn streams(&self) -> StreamRegistry {
+        self.socks_ctx.streams.clone()
+    }
+
+    /// Destination policy applied to streams on the SOCKS listener.
+    pub fn destination_policy(&self) -> DestinationPolicy {
+        self.socks_ctx.policy.clone()
+    }
+
+    /// Bandwidth shaper applied to streams on the SOCKS listener.
+    pub fn bandwidth_shaper(&self) -> BandwidthShaper {
+        self.socks_ctx.shaper.clone()
+    }
+
+    /// Destination based exit country rules used by all listeners.
+    pub fn exit_routing(&self) -> ExitRouting {
+        self.socks_ctx.routing.clone()
+    }
+
+    /// State shared by all listeners: streams, destination policy with its
+    /// warning callback, bandwidth limits and exit rules.
+    pub fn socks_context(&self) -> SocksContext {
+        self.socks_ctx.clone()
+    }
+
+    /// Reuse the listener state of a previous manager so that a rebuild
+    /// keeps the destination policy, warning callback and stream limits.
+    pub fn with_socks_context(mut self, ctx: SocksContext) -> Self {
+        self.socks_ctx = ctx;
+        self
+    }
+
+    /// Install the exit routing rules. Invalid rules are logged and ignored.
+    pub fn with_exit_rules(self, config: ExitRoutingConfig) -> Self {
+        if let Err(e) = self.socks_ctx.routing.set_config(config) {
+            log::warn!("ignoring invalid exit routing rules: {}", e);
+        }
+        self
+    }
+
     pub(crate) async fn lookup_country_code(&self, ip: &str) -> Result<String> {
         if ip.contains('?') {
             log::error!("lookup_country_code: invalid address {ip}");
@@ -952,6 +1397,10 @@ impl<C: TorClientBehavior> TorManager<C> {
         self.bridges.lock().await.clone()
     }
 
+    pub async fn get_socks_port(&self) -> Option<u16> {
+        self.socks_listener.lock().await.as_ref().map(|h| h.port())
+    }
+
     pub async fn is_connected(&self) -> bool {
         self.client.lock().await.is_some()
     }
@@ -1006,6 +1455,7 @@ impl<C: TorClientBehavior> TorManager<C> {
             .build_new_circuit()
             .await
             .map_err(|e| Error::Identity { step: "build_circuit".to_string(), source_message: e.to_string(), backtrace: format!("{:?}", std::backtrace::Backtrace::capture())})?;
+        telemetry::inc(&telemetry::counters().circuit_builds);
 
         Ok(())
     }
@@ -1035,6 +1485,31 @@ impl<C: TorClientBehavior> TorManager<C> {
 }
 
 impl TorManager {
+    fn connected_client(guard: &Option<TorClient<PreferredRuntime>>, op: &str) -> Result<TorClient<PreferredRuntime>> {
+        guard.clone().ok_or_else(|| {
+            log::error!("{op}: not connected");
+            Error::NotConnected
+        })
+    }
+
+    /// Resolve a hostname through the Tor network.
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let client = Self::connected_client(&*self.client.lock().await, "resolve")?;
+        client
+            .resolve(host)
+            .await
+            .map_err(|e| Error::Lookup(e.to_string()))
+    }
+
+    /// Reverse-resolve an address through the Tor network.
+    pub async fn resolve_ptr(&self, addr: IpAddr) -> Result<Vec<String>> {
+        let client = Self::connected_client(&*self.client.lock().await, "resolve_ptr")?;
+        client
+            .resolve_ptr(addr)
+            .await
+            .map_err(|e| Error::Lookup(e.to_string()))
+    }
+
     async fn resolve_circuit_with_policy(&self) -> Result<(Vec<RelayInfo>, bool)> {
         let client_guard = self.client.lock().await;
         let client = client_guard.as_ref().ok_or_else(|| {
@@ -1204,10 +1679,6 @@ impl TorManager {
         })
     }
 
-    pub async fn get_socks_port(&self) -> Option<u16> {
-        *self.socks_port.lock().await
-    }
-
     /// Return the total number of bytes sent and received through the Tor client.
     pub async fn traffic_stats(&self) -> Result<TrafficStats> {
         let _client_guard = self.client.lock().await;
@@ -1269,6 +1740,15 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn disconnect_releases_socks_listener() {
+        let manager: TorManager<DummyClient> = TorManager::new().with_socks_port(Some(9555));
+        manager.connect().await.unwrap();
+        assert_eq!(manager.get_socks_port().await, Some(9555));
+        manager.disconnect().await.unwrap();
+        assert_eq!(manager.get_socks_port().await, None);
+    }
+
     #[tokio::test]
     async fn geoip_cache_miss_and_hit() {
         let manager: TorManager<DummyClient> = TorManager::new();
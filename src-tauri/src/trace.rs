@@ -0,0 +1,316 @@
+//! Capture of `tracing` events into the log store. Arti reports bootstrap,
+//! guard and circuit problems through `tracing` rather than `log`, so a
+//! subscriber forwards matching events, with their target, level and the
+//! fields of the enclosing spans, to a queue drained by
+//! [`crate::state::AppState::start_trace_capture`]. Which targets are
+//! captured is set per target prefix by the `trace_filters` config key and
+//! can be changed at runtime.
+
+use crate::error::{Error, Result};
+use crate::state::LogEntry;
+use chrono::Utc;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::subscriber::Interest;
+use tracing::{span, Event, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Entries queued for the log store before further events are dropped
+const QUEUE_LEN: usize = 1024;
+
+/// Filters used when none are configured: arti and its `tor_*` crates at
+/// `info`.
+pub fn default_filters() -> BTreeMap<String, String> {
+    BTreeMap::from([
+        ("arti".to_string(), "info".to_string()),
+        ("tor_".to_string(), "info".to_string()),
+    ])
+}
+
+/// Parse `off`, `error`, `warn`, `info`, `debug` or `trace`.
+pub fn parse_level(level: &str) -> Result<LevelFilter> {
+    match level.to_ascii_lowercase().as_str() {
+        "off" => Ok(LevelFilter::OFF),
+        "error" => Ok(LevelFilter::ERROR),
+        "warn" => Ok(LevelFilter::WARN),
+        "info" => Ok(LevelFilter::INFO),
+        "debug" => Ok(LevelFilter::DEBUG),
+        "trace" => Ok(LevelFilter::TRACE),
+        _ => Err(Error::InvalidConfig {
+            key: "trace_filters".into(),
+            message: format!("unknown level '{level}'"),
+        }),
+    }
+}
+
+/// Check that every target is non-empty and every level is known.
+pub fn validate_filters(filters: &BTreeMap<String, String>) -> Result<()> {
+    Filters::parse(filters).map(|_| ())
+}
+
+/// Level per target prefix. The longest prefix matching an event's target
+/// wins; targets without a match are not captured.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Filters(Vec<(String, LevelFilter)>);
+
+impl Filters {
+    fn parse(filters: &BTreeMap<String, String>) -> Result<Self> {
+        let mut parsed = Vec::with_capacity(filters.len());
+        for (target, level) in filters {
+            if target.is_empty() {
+                return Err(Error::InvalidConfig {
+                    key: "trace_filters".into(),
+                    message: "target must not be empty".into(),
+                });
+            }
+            parsed.push((target.clone(), parse_level(level)?));
+        }
+        parsed.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+        Ok(Self(parsed))
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.0
+            .iter()
+            .find(|(prefix, _)| metadata.target().starts_with(prefix.as_str()))
+            .is_some_and(|(_, level)| metadata.level() <= level)
+    }
+}
+
+/// State of the capture, reported by `get_trace_filters`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceStatus {
+    /// Whether the subscriber is installed in this process
+    pub installed: bool,
+    pub filters: BTreeMap<String, String>,
+    /// Events dropped because the queue was full
+    pub dropped: u64,
+}
+
+struct Capture {
+    filters: RwLock<Filters>,
+    configured: RwLock<BTreeMap<String, String>>,
+    sender: mpsc::Sender<LogEntry>,
+    receiver: Mutex<Option<mpsc::Receiver<LogEntry>>>,
+    installed: AtomicBool,
+    dropped: AtomicU64,
+}
+
+static CAPTURE: OnceCell<Capture> = OnceCell::new();
+
+fn capture() -> &'static Capture {
+    CAPTURE.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+        Capture {
+            filters: RwLock::new(Filters::default()),
+            configured: RwLock::new(BTreeMap::new()),
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            installed: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+        }
+    })
+}
+
+/// Install the capturing subscriber as the global default. Returns `false`
+/// if another subscriber was installed first.
+pub fn install() -> bool {
+    let subscriber = tracing_subscriber::registry().with(CaptureLayer);
+    match tracing::subscriber::set_global_default(subscriber) {
+        Ok(()) => {
+            capture().installed.store(true, Ordering::Relaxed);
+            true
+        }
+        Err(e) => {
+            log::warn!("tracing capture not installed: {}", e);
+            false
+        }
+    }
+}
+
+/// Replace the filters. Callsites cached as disabled are re-evaluated.
+pub fn set_filters(filters: &BTreeMap<String, String>) -> Result<()> {
+    let parsed = Filters::parse(filters)?;
+    let capture = capture();
+    *capture.filters.write().unwrap_or_else(|p| p.into_inner()) = parsed;
+    *capture
+        .configured
+        .write()
+        .unwrap_or_else(|p| p.into_inner()) = filters.clone();
+    tracing::callsite::rebuild_interest_cache();
+    Ok(())
+}
+
+pub fn status() -> TraceStatus {
+    let capture = capture();
+    TraceStatus {
+        installed: capture.installed.load(Ordering::Relaxed),
+        filters: capture
+            .configured
+            .read()
+            .unwrap_or_else(|p| p.into_inner())
+            .clone(),
+        dropped: capture.dropped.load(Ordering::Relaxed),
+    }
+}
+
+/// Take the queue of captured entries. Only the first caller gets it.
+pub fn take_receiver() -> Option<mpsc::Receiver<LogEntry>> {
+    capture()
+        .receiver
+        .lock()
+        .unwrap_or_else(|p| p.into_inner())
+        .take()
+}
+
+fn enabled(metadata: &Metadata<'_>) -> bool {
+    capture()
+        .filters
+        .read()
+        .unwrap_or_else(|p| p.into_inner())
+        .enabled(metadata)
+}
+
+/// Message and fields of an event or span.
+#[derive(Default)]
+struct Fields {
+    message: Option<String>,
+    values: BTreeMap<String, String>,
+}
+
+impl Fields {
+    fn insert(&mut self, field: &Field, value: String) {
+        if field.name() == "message" {
+            self.message = Some(value);
+        } else {
+            self.values.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{value:?}"));
+    }
+}
+
+struct CaptureLayer;
+
+impl<S> Layer<S> for CaptureLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if enabled(metadata) {
+            Interest::always()
+        } else {
+            Interest::never()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        enabled(metadata)
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = BTreeMap::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(span_fields) = span.extensions().get::<Fields>() {
+                    for (key, value) in &span_fields.values {
+                        fields.insert(format!("{}.{}", span.name(), key), value.clone());
+                    }
+                }
+            }
+        }
+        let mut event_fields = Fields::default();
+        event.record(&mut event_fields);
+        fields.extend(event_fields.values);
+
+        let metadata = event.metadata();
+        let entry = LogEntry {
+            level: metadata.level().to_string(),
+            timestamp: Utc::now().to_rfc3339(),
+            message: event_fields.message.unwrap_or_default(),
+            stack: None,
+            target: Some(metadata.target().to_string()),
+            fields,
+        };
+        let capture = capture();
+        if capture.sender.try_send(entry).is_err() {
+            capture.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_wins() {
+        let mut filters = default_filters();
+        filters.insert("tor_guardmgr".into(), "debug".into());
+        filters.insert("tor_proto".into(), "off".into());
+        let filters = Filters::parse(&filters).unwrap();
+        assert_eq!(filters.0[0].0, "tor_guardmgr");
+        assert_eq!(filters.0.last().unwrap().0, "tor_");
+
+        assert!(Filters::parse(&BTreeMap::from([("".into(), "info".into())])).is_err());
+        assert!(Filters::parse(&BTreeMap::from([("arti".into(), "verbose".into())])).is_err());
+    }
+
+    #[test]
+    fn captures_events_with_span_fields() {
+        let mut receiver = take_receiver().unwrap();
+        set_filters(&BTreeMap::from([("tor_guardmgr".into(), "debug".into())])).unwrap();
+        let subscriber = tracing_subscriber::registry().with(CaptureLayer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(target: "tor_guardmgr", "select", guard = "g1");
+            let _enter = span.enter();
+            tracing::debug!(target: "tor_guardmgr::sample", attempt = 2, "guard unusable");
+            tracing::trace!(target: "tor_guardmgr", "too verbose");
+            tracing::error!(target: "hyper", "not captured");
+        });
+        let entry = receiver.try_recv().unwrap();
+        assert_eq!(entry.level, "DEBUG");
+        assert_eq!(entry.message, "guard unusable");
+        assert_eq!(entry.target.as_deref(), Some("tor_guardmgr::sample"));
+        assert_eq!(
+            entry.fields.get("select.guard").map(String::as_str),
+            Some("g1")
+        );
+        assert_eq!(entry.fields.get("attempt").map(String::as_str), Some("2"));
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -51,6 +51,7 @@ async fn schedule_updates_rotates_certificate() {
     assert_eq!(updated, NEW_CERT);
 }
 
+#[cfg(feature = "gui")]
 #[tokio::test]
 async fn tray_warning_after_failed_updates() {
     let server = MockServer::start_async().await;
@@ -89,7 +90,7 @@ async fn tray_warning_after_failed_updates() {
         max_memory_mb: 1024,
         max_circuits: 20,
         session: torwell84::session::SessionManager::new(Duration::from_secs(60)),
-        app_handle: Arc::new(tokio::sync::Mutex::new(None)),
+        event_sink: Arc::new(tokio::sync::Mutex::new(None)),
         tray_warning: Arc::new(tokio::sync::Mutex::new(None)),
     };
     app.manage(state);
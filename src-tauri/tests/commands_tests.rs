@@ -1,3 +1,5 @@
+#![cfg(feature = "gui")]
+
 use once_cell::sync::Lazy;
 use std::collections::VecDeque;
 use std::path::PathBuf;
@@ -91,7 +93,7 @@ fn mock_state() -> AppState<MockTorClient> {
         max_memory_mb: 1024,
         max_circuits: 20,
         session: SessionManager::new(std::time::Duration::from_secs(60)),
-        app_handle: Arc::new(Mutex::new(None)),
+        event_sink: Arc::new(Mutex::new(None)),
         tray_warning: Arc::new(Mutex::new(None)),
     }
 }
@@ -0,0 +1,39 @@
+use std::time::{Duration, SystemTime};
+
+use torwell84::config::{ConfigStore, Source};
+
+fn write_config(path: &std::path::Path, contents: &str, age: Duration) {
+    std::fs::write(path, contents).unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(SystemTime::now() - age)
+        .unwrap();
+}
+
+#[test]
+fn file_changes_are_picked_up() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("app_config.json");
+    write_config(&path, r#"{"max_log_lines": 200}"#, Duration::from_secs(60));
+    let store = ConfigStore::open(path.clone(), dir.path().join("cert_config.json"));
+    assert_eq!(store.current().max_log_lines, 200);
+    assert!(!store.files_changed());
+
+    write_config(&path, r#"{"max_log_lines": 300}"#, Duration::ZERO);
+    assert!(store.files_changed());
+    let old = store.reload();
+    assert_eq!(old.max_log_lines, 200);
+    assert_eq!(store.current().max_log_lines, 300);
+    assert_eq!(
+        store.effective().values["max_log_lines"].source,
+        Source::File
+    );
+
+    // A half written file keeps the previous values
+    write_config(&path, r#"{"max_log_lines": 4"#, Duration::from_secs(30));
+    store.reload();
+    assert_eq!(store.current().max_log_lines, 300);
+    assert_eq!(store.issues().len(), 1);
+}
@@ -1,109 +1,57 @@
-use std::path::PathBuf;
-use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
 
-use torwell84::secure_http::SecureHttpClient;
-use torwell84::session::SessionManager;
+use torwell84::http_bridge;
 use torwell84::state::AppState;
-use torwell84::tor_manager::{TorClientBehavior, TorClientConfig, TorManager};
 
-#[derive(Clone, Default)]
-struct DummyClient;
-
-#[async_trait::async_trait]
-impl TorClientBehavior for DummyClient {
-    async fn create_bootstrapped(_config: TorClientConfig) -> std::result::Result<Self, String> {
-        Ok(Self)
-    }
-
-    async fn create_bootstrapped_with_progress<P>(
-        _config: TorClientConfig,
-        _progress: &mut P,
-    ) -> std::result::Result<Self, String>
-    where
-        P: FnMut(u8, String) + Send,
-    {
-        Ok(Self)
-    }
-
-    fn reconfigure(&self, _config: &TorClientConfig) -> std::result::Result<(), String> {
-        Ok(())
-    }
-
-    fn retire_all_circs(&self) {}
-
-    async fn build_new_circuit(&self) -> std::result::Result<(), String> {
-        Ok(())
-    }
-}
-
-fn mock_state() -> AppState<DummyClient> {
-    AppState {
-        tor_manager: Arc::new(TorManager::new()),
-        http_client: Arc::new(SecureHttpClient::new_default().unwrap()),
-        log_file: PathBuf::from("bridge.log"),
-        log_lock: Arc::new(Mutex::new(())),
-        retry_counter: Arc::new(Mutex::new(0)),
-        max_log_lines: Arc::new(Mutex::new(1000)),
-        memory_usage: Arc::new(Mutex::new(0)),
-        circuit_count: Arc::new(Mutex::new(0)),
-        oldest_circuit_age: Arc::new(Mutex::new(0)),
-        latency_ms: Arc::new(Mutex::new(0)),
-        cpu_usage: Arc::new(Mutex::new(0.0)),
-        network_throughput: Arc::new(Mutex::new(0)),
-        prev_traffic: Arc::new(Mutex::new(0)),
-        max_memory_mb: 1024,
-        max_circuits: 20,
-        session: SessionManager::new(Duration::from_secs(60)),
-        app_handle: Arc::new(Mutex::new(None)),
-        tray_warning: Arc::new(Mutex::new(None)),
-    }
+async fn start_api() -> (AppState, torwell84::socks::ListenerHandle, String) {
+    let state: AppState = AppState::default();
+    let handle = http_bridge::start_http_api(state.clone(), 0).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let base = format!("http://127.0.0.1:{}/api/v1", handle.port());
+    (state, handle, base)
 }
 
-#[cfg(feature = "mobile")]
 #[tokio::test]
-async fn http_bridge_reports_disconnect() {
-    let state = mock_state();
-    torwell84::http_bridge::start(state.clone());
-    tokio::time::sleep(Duration::from_millis(100)).await;
+async fn rest_api_requires_token() {
+    let (_state, _handle, base) = start_api().await;
 
-    let body: String = reqwest::get("http://127.0.0.1:1421/status")
-        .await
-        .unwrap()
-        .json()
+    let res = reqwest::get(format!("{base}/status")).await.unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    let res = reqwest::Client::new()
+        .get(format!("{base}/status"))
+        .bearer_auth("not-a-session")
+        .send()
         .await
         .unwrap();
-    assert_eq!(body, "DISCONNECTED");
+    assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
 }
 
-#[cfg(feature = "mobile")]
 #[tokio::test]
-async fn http_bridge_reports_connect() {
-    let state = mock_state();
-    state.tor_manager.connect().await.unwrap();
-    torwell84::http_bridge::start(state.clone());
-    tokio::time::sleep(Duration::from_millis(100)).await;
+async fn rest_api_reports_disconnect() {
+    let (state, _handle, base) = start_api().await;
+    let token = state.create_session().await;
 
-    let body: String = reqwest::get("http://127.0.0.1:1421/status")
+    let body: String = reqwest::Client::new()
+        .get(format!("{base}/status"))
+        .bearer_auth(&token)
+        .send()
         .await
         .unwrap()
         .json()
         .await
         .unwrap();
-    assert_eq!(body, "CONNECTED");
+    assert_eq!(body, "DISCONNECTED");
 }
 
-#[cfg(feature = "mobile")]
 #[tokio::test]
-async fn http_bridge_sets_workers() {
-    let state = mock_state();
-    torwell84::http_bridge::start(state.clone());
-    tokio::time::sleep(Duration::from_millis(100)).await;
+async fn rest_api_sets_workers() {
+    let (state, _handle, base) = start_api().await;
+    let token = state.create_session().await;
 
-    let client = reqwest::Client::new();
-    let res = client
-        .post("http://127.0.0.1:1421/workers")
+    let res = reqwest::Client::new()
+        .put(format!("{base}/workers"))
+        .bearer_auth(&token)
         .json(&serde_json::json!({
             "workers": ["https://example.com"],
             "token": "abc"
@@ -118,90 +66,116 @@ async fn http_bridge_sets_workers() {
     assert_eq!(urls[0], "https://example.com");
 }
 
-#[cfg(feature = "mobile")]
 #[tokio::test]
-async fn http_bridge_validates_worker_token() {
-    use httpmock::prelude::*;
-    use urlencoding::encode;
-
-    let worker = MockServer::start_async().await;
-    let target = "https://example.com/hello";
-    let encoded = encode(target);
-    worker
-        .mock_async(|when, then| {
-            when.method(GET)
-                .path(format!("/proxy?url={}", encoded))
-                .header("X-Proxy-Token", "secret");
-            then.status(200).body("ok");
-        })
-        .await;
-
-    let state = mock_state();
-    torwell84::http_bridge::start(state.clone());
-    tokio::time::sleep(Duration::from_millis(100)).await;
-
+async fn rest_api_revokes_session() {
+    let (state, _handle, base) = start_api().await;
     let client = reqwest::Client::new();
-    client
-        .post("http://127.0.0.1:1421/workers")
-        .json(&serde_json::json!({
-            "workers": [worker.url("/proxy")],
-            "token": "secret"
-        }))
+    let token = state.create_session().await;
+
+    let res = client
+        .delete(format!("{base}/session"))
+        .bearer_auth(&token)
         .send()
         .await
         .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+    assert!(!state.validate_session(&token).await);
 
-    let valid: bool = client
-        .get("http://127.0.0.1:1421/validate")
+    let res = client
+        .get(format!("{base}/status"))
+        .bearer_auth(&token)
         .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn rest_api_serves_openapi_without_token() {
+    let (_state, _handle, base) = start_api().await;
+
+    let doc: serde_json::Value = reqwest::get(format!("{base}/openapi.json"))
         .await
         .unwrap()
         .json()
         .await
         .unwrap();
-    assert!(valid);
+    assert_eq!(doc["openapi"], "3.0.3");
+    assert!(doc["paths"]["/connect"]["post"].is_object());
+    assert!(doc["paths"]["/circuits/{id}"]["delete"].is_object());
 }
 
-#[cfg(feature = "mobile")]
 #[tokio::test]
-async fn http_bridge_validate_token_fails() {
-    use httpmock::prelude::*;
-    use urlencoding::encode;
-
-    let worker = MockServer::start_async().await;
-    let target = "https://example.com/hello";
-    let encoded = encode(target);
-    worker
-        .mock_async(|when, then| {
-            when.method(GET)
-                .path(format!("/proxy?url={}", encoded))
-                .header("X-Proxy-Token", "right");
-            then.status(200).body("ok");
-        })
-        .await;
-
-    let state = mock_state();
-    torwell84::http_bridge::start(state.clone());
-    tokio::time::sleep(Duration::from_millis(100)).await;
+async fn rest_api_streams_events_from_last_id() {
+    use torwell84::EventSink;
+
+    let (state, _handle, base) = start_api().await;
+    let token = state.create_session().await;
+    for status in ["CONNECTING", "CONNECTED"] {
+        state
+            .event_stream
+            .emit("tor-status-update", serde_json::json!({ "status": status }))
+            .unwrap();
+    }
 
-    let client = reqwest::Client::new();
-    client
-        .post("http://127.0.0.1:1421/workers")
-        .json(&serde_json::json!({
-            "workers": [worker.url("/proxy")],
-            "token": "wrong"
-        }))
+    let mut res = reqwest::Client::new()
+        .get(format!("{base}/events?access_token={token}"))
+        .header("Last-Event-ID", format!("{}-1", state.event_stream.epoch()))
         .send()
         .await
         .unwrap();
-
-    let valid: bool = client
-        .get("http://127.0.0.1:1421/validate")
+    assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+    let mut body = String::new();
+    while !body.contains("CONNECTED\"") {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), res.chunk())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        body.push_str(&String::from_utf8_lossy(&chunk));
+    }
+    assert!(body.contains("event: tor-status-update"));
+    assert!(body.contains(&format!("id: {}-2", state.event_stream.epoch())));
+    assert!(!body.contains("CONNECTING"));
+    assert!(!body.contains("event: resync"));
+
+    // An id from before a restart replays the backlog after a resync event
+    let mut res = reqwest::Client::new()
+        .get(format!("{base}/events?access_token={token}&lastEventId=0badc0de-2"))
         .send()
         .await
-        .unwrap()
-        .json()
+        .unwrap();
+    let mut body = String::new();
+    while !body.contains("CONNECTED\"") {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), res.chunk())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        body.push_str(&String::from_utf8_lossy(&chunk));
+    }
+    let resync = body.find("event: resync").unwrap();
+    assert!(resync < body.find("CONNECTING").unwrap());
+}
+
+#[tokio::test]
+async fn metrics_exporter_serves_prometheus_text() {
+    let state: AppState = AppState::default();
+    let handle = torwell84::telemetry::start_metrics_server(state, 0)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let res = reqwest::get(format!("http://127.0.0.1:{}/metrics", handle.port()))
         .await
         .unwrap();
-    assert!(!valid);
+    assert!(res.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    let body = res.text().await.unwrap();
+    assert!(body.contains("# TYPE torwell_connects_total counter"));
+    assert!(body.contains("torwell_connect_errors_total{step=\"bootstrap\"}"));
+    assert!(body.contains("torwell_connected 0\n"));
 }
@@ -1,3 +1,5 @@
+#![cfg(feature = "gui")]
+
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -51,7 +53,7 @@ fn mock_state() -> AppState<MockTorClient> {
         max_memory_mb: 1024,
         max_circuits: 20,
         session: SessionManager::new(std::time::Duration::from_secs(60)),
-        app_handle: Arc::new(Mutex::new(None)),
+        event_sink: Arc::new(Mutex::new(None)),
         tray_warning: Arc::new(Mutex::new(None)),
     }
 }
@@ -1,3 +1,5 @@
+#![cfg(feature = "gui")]
+
 use std::env;
 
 use blake3::Hasher;
@@ -8,7 +8,8 @@ use std::time::Duration;
 use tempfile::tempdir;
 use tokio::sync::mpsc;
 use torwell84::error::Error;
-use torwell84::secure_http::{SecureHttpClient, DEFAULT_CONFIG_PATH};
+use torwell84::paths::paths;
+use torwell84::secure_http::SecureHttpClient;
 use urlencoding::encode;
 
 const CA_PEM: &str = include_str!("../tests_data/ca.pem");
@@ -327,7 +328,7 @@ async fn init_using_default_constant() {
     fs::write(&cert_path, CA_PEM).unwrap();
 
     let _client = SecureHttpClient::init(
-        DEFAULT_CONFIG_PATH,
+        paths().cert_config_file(),
         Some(cert_path.to_string_lossy().to_string()),
         Some(server.url("/cert.pem")),
         None,
@@ -687,6 +688,7 @@ async fn update_fails_with_wrong_worker_token() {
     assert!(res.is_err());
 }
 
+#[cfg(feature = "gui")]
 #[tokio::test]
 async fn tray_warning_after_local_update_failures() {
     let server = MockServer::start_async().await;
@@ -725,7 +727,7 @@ async fn tray_warning_after_local_update_failures() {
         max_memory_mb: 1024,
         max_circuits: 20,
         session: torwell84::session::SessionManager::new(Duration::from_secs(60)),
-        app_handle: Arc::new(tokio::sync::Mutex::new(None)),
+        event_sink: Arc::new(tokio::sync::Mutex::new(None)),
         tray_warning: Arc::new(tokio::sync::Mutex::new(None)),
     };
     app.manage(state);
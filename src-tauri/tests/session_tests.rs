@@ -9,3 +9,24 @@ async fn session_create_and_validate() {
     tokio::time::sleep(Duration::from_millis(250)).await;
     assert!(!mgr.validate(&token).await);
 }
+
+#[tokio::test]
+async fn session_renew_and_revoke() {
+    let mgr = SessionManager::new(Duration::from_millis(200));
+    let token = mgr.create_session().await;
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(mgr.renew(&token).await);
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(mgr.validate(&token).await);
+    mgr.revoke(&token).await;
+    assert!(!mgr.validate(&token).await);
+    assert!(!mgr.renew(&token).await);
+}
+
+#[tokio::test]
+async fn renew_interval_stays_within_ttl() {
+    let mgr = SessionManager::new(Duration::from_secs(30));
+    assert_eq!(mgr.renew_interval(), Duration::from_secs(15));
+    let mgr = SessionManager::new(Duration::from_secs(1));
+    assert!(mgr.renew_interval() < mgr.ttl());
+}
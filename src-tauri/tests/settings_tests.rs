@@ -0,0 +1,28 @@
+use torwell84::settings::SETTINGS_PATH_ENV;
+use torwell84::state::AppState;
+
+#[tokio::test]
+async fn routing_settings_survive_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("settings.json");
+    std::env::set_var(SETTINGS_PATH_ENV, &path);
+
+    let state: AppState = AppState::default();
+    state.set_exit_country(Some("DE".into())).await.unwrap();
+    state
+        .set_bridges(vec!["obfs4 192.0.2.1:443 FINGERPRINT".into()])
+        .await
+        .unwrap();
+    state.set_system_proxy_enabled(false).await.unwrap();
+    drop(state);
+
+    let restarted: AppState = AppState::default();
+    let mgr = restarted.tor_manager.read().await.clone();
+    assert_eq!(mgr.get_exit_country().await.as_deref(), Some("DE"));
+    assert_eq!(mgr.get_bridges().await.len(), 1);
+    assert!(!restarted.is_system_proxy_enabled().await);
+
+    let doc: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(doc["version"], 1);
+}
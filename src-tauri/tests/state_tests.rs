@@ -4,11 +4,16 @@ use once_cell::sync::Lazy;
 use regex::Regex;
 use std::collections::VecDeque;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex as StdMutex};
 use std::time::Duration;
 use tokio::sync::Mutex;
 
 use torwell84::core::executor::TaskScheduler;
+use torwell84::logstore::{Cursor, LogLimits, LogQuery, LogStore};
+use torwell84::policy::DestinationPolicyConfig;
+use torwell84::redact::RedactionLevel;
+use torwell84::shaping::{BandwidthLimits, RateLimit};
 use torwell84::secure_http::SecureHttpClient;
 use torwell84::session::SessionManager;
 use torwell84::state::AppState;
@@ -86,7 +91,7 @@ async fn update_metrics_closes_circuits_on_limit() {
         max_memory_mb: 1,
         max_circuits: 1,
         session: SessionManager::new(Duration::from_secs(60)),
-        app_handle: Arc::new(Mutex::new(None)),
+        event_sink: Arc::new(Mutex::new(None)),
         tray_warning: Arc::new(Mutex::new(None)),
     };
     let _ = tokio::fs::remove_file("state.log").await;
@@ -124,7 +129,7 @@ async fn tray_warning_on_memory_limit() {
         max_memory_mb: 1,
         max_circuits: 10,
         session: SessionManager::new(Duration::from_secs(60)),
-        app_handle: Arc::new(Mutex::new(None)),
+        event_sink: Arc::new(Mutex::new(None)),
         tray_warning: Arc::new(Mutex::new(None)),
     };
     let _ = tokio::fs::remove_file("mem.log").await;
@@ -165,7 +170,7 @@ async fn tray_warning_on_circuit_limit() {
         max_memory_mb: 1024,
         max_circuits: 1,
         session: SessionManager::new(Duration::from_secs(60)),
-        app_handle: Arc::new(Mutex::new(None)),
+        event_sink: Arc::new(Mutex::new(None)),
         tray_warning: Arc::new(Mutex::new(None)),
     };
     let _ = tokio::fs::remove_file("circ.log").await;
@@ -201,7 +206,7 @@ async fn log_rotation_creates_archive() {
         max_memory_mb: 1,
         max_circuits: 1,
         session: SessionManager::new(Duration::from_secs(60)),
-        app_handle: Arc::new(Mutex::new(None)),
+        event_sink: Arc::new(Mutex::new(None)),
         tray_warning: Arc::new(Mutex::new(None)),
     };
 
@@ -234,7 +239,7 @@ async fn log_rotation_creates_archive() {
 #[tokio::test]
 async fn metrics_rotation_creates_archive() {
     let mut state = AppState::<DummyClient>::default();
-    state.log_file = PathBuf::from("metrics.log");
+    state.logs = Arc::new(LogStore::open(PathBuf::from("metrics.log"), LogLimits::default()));
     state.metrics_file = Some(PathBuf::from("metrics.json"));
 
     let _ = tokio::fs::remove_file("metrics.json").await;
@@ -275,9 +280,9 @@ async fn metrics_rotation_creates_archive() {
 #[tokio::test]
 async fn log_trim_handles_large_file() {
     let mut state = AppState::<DummyClient>::default();
-    state.log_file = PathBuf::from("large.log");
     let _ = tokio::fs::remove_file("large.log").await;
     let _ = tokio::fs::remove_dir_all("archive").await;
+    state.logs = Arc::new(LogStore::open(PathBuf::from("large.log"), LogLimits::default()));
 
     for _ in 0..=(torwell84::state::DEFAULT_MAX_LOG_LINES * 2) {
         state
@@ -296,6 +301,11 @@ async fn log_trim_handles_large_file() {
 
     let logs = state.read_logs().await.unwrap();
     assert_eq!(logs.len(), torwell84::state::DEFAULT_MAX_LOG_LINES);
+
+    let page = state.read_logs_page(None, 10).await.unwrap();
+    let cursor = Cursor::parse(page.next_cursor.as_deref().unwrap()).unwrap();
+    let older = state.read_logs_page(Some(cursor), 10).await.unwrap();
+    assert_eq!(older.entries.len(), 10);
 }
 
 #[tokio::test]
@@ -304,7 +314,7 @@ async fn metrics_limit_from_env_shows_warning() {
     std::env::set_var("TORWELL_MAX_METRIC_MB", "1");
 
     let mut state = AppState::<DummyClient>::default();
-    state.log_file = PathBuf::from("metric_warn.log");
+    state.logs = Arc::new(LogStore::open(PathBuf::from("metric_warn.log"), LogLimits::default()));
     state.metrics_file = Some(PathBuf::from("metric_warn.json"));
 
     let _ = tokio::fs::remove_file("metric_warn.json").await;
@@ -348,6 +358,7 @@ async fn metrics_limit_from_env_shows_warning() {
     std::env::remove_var("TORWELL_MAX_METRIC_MB");
 }
 
+#[cfg(feature = "gui")]
 #[tokio::test]
 async fn security_warning_emits_event() {
     let mut app = tauri::test::mock_app();
@@ -366,7 +377,7 @@ async fn security_warning_emits_event() {
         max_memory_mb: 1,
         max_circuits: 1,
         session: SessionManager::new(Duration::from_secs(60)),
-        app_handle: Arc::new(Mutex::new(None)),
+        event_sink: Arc::new(Mutex::new(None)),
         tray_warning: Arc::new(Mutex::new(None)),
     };
     app.manage(state);
@@ -409,7 +420,7 @@ async fn tray_menu_contains_metrics_items() {
         max_memory_mb: 1024,
         max_circuits: 20,
         session: SessionManager::new(Duration::from_secs(60)),
-        app_handle: Arc::new(Mutex::new(None)),
+        event_sink: Arc::new(Mutex::new(None)),
         tray_warning: Arc::new(Mutex::new(None)),
     };
     app.manage(state);
@@ -446,7 +457,7 @@ async fn update_metrics_emits_security_warning() {
         max_memory_mb: 1,
         max_circuits: 20,
         session: SessionManager::new(Duration::from_secs(60)),
-        app_handle: Arc::new(Mutex::new(None)),
+        event_sink: Arc::new(Mutex::new(None)),
         tray_warning: Arc::new(Mutex::new(None)),
     };
     app.manage(state);
@@ -491,7 +502,7 @@ async fn tray_warning_cycle() {
         max_memory_mb: 1,
         max_circuits: 20,
         session: SessionManager::new(Duration::from_secs(60)),
-        app_handle: Arc::new(Mutex::new(None)),
+        event_sink: Arc::new(Mutex::new(None)),
         tray_warning: Arc::new(Mutex::new(None)),
     };
     app.manage(state);
@@ -531,7 +542,7 @@ async fn warning_set_clear_rebuilds_menu() {
         max_memory_mb: 1,
         max_circuits: 20,
         session: SessionManager::new(Duration::from_secs(60)),
-        app_handle: Arc::new(Mutex::new(None)),
+        event_sink: Arc::new(Mutex::new(None)),
         tray_warning: Arc::new(Mutex::new(None)),
     };
     app.manage(state);
@@ -576,3 +587,132 @@ async fn tray_menu_after_cert_failure() {
     let tray = app.tray_handle();
     assert!(tray.try_get_item("warning").is_some());
 }
+
+#[tokio::test]
+async fn rebuilt_manager_keeps_destination_policy() {
+    let state = AppState::<DummyClient>::default();
+    let policy = DestinationPolicyConfig {
+        block: vec!["25".into()],
+        ..Default::default()
+    };
+    let warnings = Arc::new(AtomicUsize::new(0));
+    {
+        let mgr = state.tor_manager.read().await.clone();
+        mgr.destination_policy().set_config(policy.clone()).unwrap();
+        let warnings = warnings.clone();
+        mgr.destination_policy()
+            .set_warning_callback(move |_| {
+                warnings.fetch_add(1, Ordering::SeqCst);
+            })
+            .await;
+    }
+
+    state.set_geoip_path(None).await.unwrap();
+
+    let mgr = state.tor_manager.read().await.clone();
+    assert_eq!(mgr.destination_policy().config(), policy);
+    assert!(!mgr.destination_policy().check("mail.example.com", 25).await);
+    assert_eq!(warnings.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn rebuilt_manager_keeps_bandwidth_limits() {
+    let state = AppState::<DummyClient>::default();
+    let limits = BandwidthLimits {
+        global: RateLimit {
+            upload_bps: Some(1000),
+            download_bps: None,
+        },
+        ..Default::default()
+    };
+    let shaper = state.tor_manager.read().await.bandwidth_shaper();
+    shaper.set_limits(limits.clone()).unwrap();
+
+    state.set_geoip_path(None).await.unwrap();
+
+    let mgr = state.tor_manager.read().await.clone();
+    assert_eq!(mgr.bandwidth_shaper().limits(), limits);
+    assert!(mgr
+        .bandwidth_shaper()
+        .set_stream_limit(&mgr.streams(), 99, limits.global)
+        .is_err());
+}
+
+#[tokio::test]
+async fn geoip_change_keeps_routing_settings() {
+    let dir = tempfile::tempdir().unwrap();
+    std::env::set_var("TORWELL_SETTINGS", dir.path().join("settings.json"));
+    let state = AppState::<DummyClient>::default();
+    state
+        .set_bridges(vec!["Bridge 1.2.3.4:443 0123456789ABCDEF".into()])
+        .await
+        .unwrap();
+    state
+        .set_torrc_config("ExitNodes {de}".into())
+        .await
+        .unwrap();
+    let before = state.tor_manager.read().await.routing_settings().await;
+
+    state.set_geoip_path(None).await.unwrap();
+
+    let mgr = state.tor_manager.read().await.clone();
+    assert_eq!(mgr.routing_settings().await, before);
+}
+
+#[tokio::test]
+async fn geoip_change_keeps_socks_port() {
+    let state = AppState::<DummyClient>::default();
+    state
+        .config
+        .set_override("socks_port", serde_json::json!(9150))
+        .unwrap();
+
+    state.set_geoip_path(None).await.unwrap();
+
+    let mgr = state.tor_manager.read().await.clone();
+    assert_eq!(mgr.requested_socks_port(), Some(9150));
+}
+
+#[tokio::test]
+async fn log_queries_match_redacted_entries() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut state = AppState::<DummyClient>::default();
+    state.logs = Arc::new(LogStore::open(
+        dir.path().join("torwell.log"),
+        LogLimits::default(),
+    ));
+    state.set_redaction_level(RedactionLevel::None).unwrap();
+    state
+        .add_log(Level::Info, "connect to 203.0.113.5 failed".into(), None)
+        .await
+        .unwrap();
+    state.set_redaction_level(RedactionLevel::Partial).unwrap();
+
+    let contains = |text: &str| LogQuery {
+        contains: Some(text.into()),
+        ..Default::default()
+    };
+    let page = state.query_logs(contains("203.0.113")).await.unwrap();
+    assert!(page.entries.is_empty());
+    assert_eq!(page.totals, Some(Default::default()));
+    let page = state.query_logs(contains("connect to")).await.unwrap();
+    assert_eq!(page.entries.len(), 1);
+    assert!(!page.entries[0].message.contains("203.0.113.5"));
+}
+
+#[tokio::test]
+async fn geoip_change_reconnects_connected_manager() {
+    let flag = Arc::new(StdMutex::new(false));
+    DummyClient::push(DummyClient::new(flag.clone()));
+    DummyClient::push(DummyClient::new(flag));
+    let state = AppState::<DummyClient>::default();
+    let old = state.tor_manager.read().await.clone();
+    old.connect().await.unwrap();
+
+    state.set_geoip_path(None).await.unwrap();
+
+    assert!(!old.is_connected().await);
+    let mgr = state.tor_manager.read().await.clone();
+    assert!(!Arc::ptr_eq(&old, &mgr));
+    assert!(mgr.is_connected().await);
+}